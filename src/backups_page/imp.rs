@@ -0,0 +1,45 @@
+use adw::prelude::*;
+use gtk::subclass::prelude::*;
+use gtk::{glib, CompositeTemplate};
+use gtk::glib::subclass::InitializingObject;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use super::RemoteWidgets;
+
+// Object holding the state
+#[derive(CompositeTemplate, Default)]
+#[template(resource = "/co/veand/fridge/BackupsPage.ui")]
+pub struct BackupsPage {
+    #[template_child]
+    pub remotes_box: TemplateChild<gtk::Box>,
+    /// Widgets showing each remote, keyed by location
+    pub remotes: RefCell<HashMap<String, RemoteWidgets>>,
+    /// Number of the latest refresh, whose comparisons are the only ones still shown
+    pub generation: Cell<u64>,
+}
+
+// The central trait for subclassing a GObject
+#[glib::object_subclass]
+impl ObjectSubclass for BackupsPage {
+    // `NAME` needs to match `class` attribute of template
+    const NAME: &'static str = "BackupsPage";
+    type Type = super::BackupsPage;
+    type ParentType = gtk::Box;
+
+    fn class_init(klass: &mut Self::Class) {
+        klass.bind_template();
+    }
+
+    fn instance_init(obj: &InitializingObject<Self>) {
+        obj.init_template();
+    }
+}
+
+// Trait shared by all GObjects
+impl ObjectImpl for BackupsPage {}
+
+// Trait shared by all widgets
+impl WidgetImpl for BackupsPage {}
+
+impl BoxImpl for BackupsPage {}
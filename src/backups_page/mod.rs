@@ -0,0 +1,193 @@
+mod imp;
+
+use std::thread;
+
+use adw::prelude::*;
+use anyhow::Result;
+use chrono::Local;
+use gtk::glib;
+use gtk::glib::{clone, Continue};
+use gtk::subclass::prelude::ObjectSubclassIsExt;
+
+use crate::compare::{self, Comparison};
+use crate::config;
+use crate::fridge::{Snapshot, SnapshotRepositoryLocation};
+use crate::health::HealthStore;
+
+/// Comparison of every configured subvolume with a remote, or why they couldn't be compared
+type RemoteComparisons = Result<Vec<(String, Comparison)>, String>;
+
+/// Widgets showing one remote
+pub struct RemoteWidgets {
+    group: adw::PreferencesGroup,
+    connection_row: adw::ActionRow,
+    /// Rows showing the snapshots, replaced once they are listed
+    snapshot_rows: Vec<gtk::Widget>,
+}
+
+glib::wrapper! {
+    pub struct BackupsPage(ObjectSubclass<imp::BackupsPage>)
+        @extends gtk::Box, gtk::Widget,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::Orientable;
+}
+
+impl BackupsPage {
+    /// Shows every remote along with its last synchronization result, then compares the local
+    /// snapshots with the ones on the remotes in the background
+    pub fn refresh(&self, remotes: &[(SnapshotRepositoryLocation, bool)]) -> Result<()> {
+        let imp = self.imp();
+        // A refresh still running when another starts would otherwise overwrite newer rows
+        let generation = imp.generation.get() + 1;
+        imp.generation.set(generation);
+        while let Some(child) = imp.remotes_box.first_child() {
+            imp.remotes_box.remove(&child);
+        }
+        imp.remotes.borrow_mut().clear();
+
+        if remotes.is_empty() {
+            let label = gtk::Label::new(Some("No remotes configured"));
+            label.add_css_class("dim-label");
+            imp.remotes_box.append(&label);
+            return Ok(());
+        }
+
+        let cfg = config::load()?;
+        let machine = cfg.local.machine_id()?;
+        let health = HealthStore::load()?;
+        for (remote, _) in remotes {
+            let location = remote.to_string();
+            let widgets = Self::remote_widgets(&location, &health.get(&location).summary());
+            imp.remotes_box.append(&widgets.group);
+            imp.remotes.borrow_mut().insert(location, widgets);
+        }
+
+        let remotes: Vec<(String, SnapshotRepositoryLocation, bool)> = remotes.iter()
+            .map(|(remote, sudo)| (remote.to_string(), remote.for_machine(&machine), *sudo))
+            .collect();
+        let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        thread::spawn(move || {
+            for (location, remote, sudo) in remotes {
+                let comparisons: RemoteComparisons = cfg.snapshots.iter()
+                    .map(|snapshot_cfg| {
+                        compare::compare(&snapshot_cfg.name, &snapshot_cfg.snapshot_location(&cfg.local), true, &remote, sudo, 0)
+                            .map(|comparison| (snapshot_cfg.name.clone(), comparison))
+                    })
+                    .collect::<Result<_>>()
+                    .map_err(|e| format!("{:#}", e));
+                if sender.send((location, comparisons)).is_err() {
+                    return;
+                }
+            }
+        });
+        receiver.attach(None, clone!(@weak self as page => @default-return Continue(false), move |(location, comparisons)| {
+            if page.imp().generation.get() != generation {
+                return Continue(false);
+            }
+            page.show_comparisons(&location, comparisons);
+            Continue(true)
+        }));
+
+        Ok(())
+    }
+
+    fn remote_widgets(location: &str, health: &str) -> RemoteWidgets {
+        let group = adw::PreferencesGroup::builder()
+            .title(&glib::markup_escape_text(location))
+            .description(&glib::markup_escape_text(&format!("Last synchronization: {}", health)))
+            .build();
+
+        let connection_row = adw::ActionRow::builder()
+            .title("Connection")
+            .subtitle("Not tested")
+            .build();
+        for (label, action) in [("Test", "win.test-remote"), ("Sync Now", "win.sync-remote")] {
+            let button = gtk::Button::builder()
+                .label(label)
+                .valign(gtk::Align::Center)
+                .action_name(action)
+                .action_target(&location.to_variant())
+                .build();
+            connection_row.add_suffix(&button);
+        }
+        group.add(&connection_row);
+
+        let listing_row = adw::ActionRow::builder()
+            .title("Snapshots")
+            .subtitle("Listing…")
+            .build();
+        group.add(&listing_row);
+
+        RemoteWidgets {
+            group,
+            connection_row,
+            snapshot_rows: vec![listing_row.upcast()],
+        }
+    }
+
+    fn show_comparisons(&self, location: &str, comparisons: RemoteComparisons) {
+        let mut remotes = self.imp().remotes.borrow_mut();
+        let widgets = match remotes.get_mut(location) {
+            Some(widgets) => widgets,
+            None => return,
+        };
+        for row in widgets.snapshot_rows.drain(..) {
+            widgets.group.remove(&row);
+        }
+
+        let comparisons = match comparisons {
+            Ok(comparisons) => comparisons,
+            Err(e) => {
+                let row = adw::ActionRow::builder()
+                    .title("Could not list snapshots")
+                    .subtitle(&glib::markup_escape_text(&e))
+                    .build();
+                widgets.group.add(&row);
+                widgets.snapshot_rows.push(row.upcast());
+                return;
+            },
+        };
+
+        for (name, comparison) in comparisons {
+            let row = adw::ExpanderRow::builder()
+                .title(&name)
+                .subtitle(&format!(
+                    "{} · {} only here, {} only on remote, {} on both",
+                    compare::describe_lag(comparison.lag()),
+                    comparison.local_only.len(),
+                    comparison.remote_only.len(),
+                    comparison.both.len(),
+                ))
+                .build();
+            let sets = [
+                (&comparison.local_only, "Only on this computer"),
+                (&comparison.remote_only, "Only on remote"),
+                (&comparison.both, "On both"),
+            ];
+            for (snapshots, presence) in sets {
+                for snapshot in snapshots.iter().rev() {
+                    row.add_row(&Self::snapshot_row(snapshot, presence));
+                }
+            }
+            widgets.group.add(&row);
+            widgets.snapshot_rows.push(row.upcast());
+        }
+    }
+
+    fn snapshot_row(snapshot: &Snapshot, presence: &str) -> adw::ActionRow {
+        adw::ActionRow::builder()
+            .title(&snapshot.datetime.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string())
+            .subtitle(&format!("{} · {}", snapshot.suffix, presence))
+            .build()
+    }
+
+    /// Shows the outcome of a connection test of the remote at `location`
+    pub fn set_connection_status(&self, location: &str, result: &Result<()>) {
+        if let Some(widgets) = self.imp().remotes.borrow().get(location) {
+            let status = match result {
+                Ok(()) => "OK".to_string(),
+                Err(e) => format!("{:#}", e),
+            };
+            widgets.connection_row.set_subtitle(&glib::markup_escape_text(&status));
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::warn;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use thiserror::Error;
+
+/// Longest a wait goes without noticing that the operation was cancelled
+const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Error of an operation that stopped because it was cancelled
+#[derive(Debug, Error)]
+#[error("Cancelled")]
+pub struct Cancelled;
+
+/// Lets another thread cancel an operation and terminate the processes it runs
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    /// IDs of the child processes that are running
+    children: Arc<Mutex<Vec<u32>>>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks the operation as cancelled and asks its running child processes to terminate
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        for pid in self.children.lock().unwrap().iter() {
+            terminate(*pid);
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Fails with `Cancelled` once the operation is cancelled
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+
+        Ok(())
+    }
+
+    /// Turns a failure into `Cancelled` once the operation is cancelled, since terminating its
+    /// processes makes it fail in all sorts of ways
+    pub fn attribute<T>(&self, result: Result<T>) -> Result<T> {
+        match result {
+            Err(_) if self.is_cancelled() => Err(Cancelled.into()),
+            result => result,
+        }
+    }
+
+    /// Sleeps for `duration`, failing with `Cancelled` as soon as the operation is cancelled
+    pub fn wait(&self, duration: Duration) -> Result<()> {
+        let deadline = Instant::now() + duration;
+        loop {
+            self.check()?;
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            thread::sleep((deadline - now).min(WAIT_INTERVAL));
+        }
+    }
+
+    /// Terminates the child process with the given ID on cancellation, until the returned
+    /// registration is dropped
+    pub fn register(&self, pid: u32) -> Registration {
+        let mut children = self.children.lock().unwrap();
+        children.push(pid);
+        // A process started just after cancelling is terminated right away
+        if self.is_cancelled() {
+            terminate(pid);
+        }
+
+        Registration {
+            token: self.clone(),
+            pid,
+        }
+    }
+
+    fn unregister(&self, pid: u32) {
+        self.children.lock().unwrap().retain(|child| *child != pid);
+    }
+}
+
+/// Child process registered with a `CancelToken`, unregistered when dropped so that no early
+/// return leaves it behind to be signalled once its ID is reused
+#[must_use]
+pub struct Registration {
+    token: CancelToken,
+    pid: u32,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.token.unregister(self.pid);
+    }
+}
+
+fn terminate(pid: u32) {
+    // SIGTERM rather than SIGKILL, since sudo and ssh only pass on signals they can catch
+    if let Err(e) = kill(Pid::from_raw(pid as i32), Signal::SIGTERM) {
+        warn!("Could not terminate process {}: {}", pid, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::Command;
+
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn test_cancel() {
+        let token = CancelToken::new();
+        assert!(token.check().is_ok());
+        assert!(!token.attribute::<()>(Err(anyhow!("Broken pipe"))).unwrap_err().is::<Cancelled>());
+
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let registration = token.register(child.id());
+        token.cancel();
+        let status = child.wait().unwrap();
+        drop(registration);
+        assert!(token.children.lock().unwrap().is_empty());
+
+        assert_eq!(status.signal(), Some(Signal::SIGTERM as i32));
+        assert!(token.check().unwrap_err().is::<Cancelled>());
+        assert!(token.attribute::<()>(Err(anyhow!("Broken pipe"))).unwrap_err().is::<Cancelled>());
+        assert!(token.attribute(Ok(())).is_ok());
+
+        // Processes started after cancelling don't get to run either
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let _registration = token.register(child.id());
+        assert_eq!(child.wait().unwrap().signal(), Some(Signal::SIGTERM as i32));
+    }
+
+    #[test]
+    fn test_wait() {
+        let token = CancelToken::new();
+        let started = Instant::now();
+        assert!(token.wait(Duration::from_millis(150)).is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(150));
+
+        let cancel = token.clone();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            cancel.cancel();
+        });
+        let started = Instant::now();
+        assert!(token.wait(Duration::from_secs(60)).unwrap_err().is::<Cancelled>());
+        assert!(started.elapsed() < Duration::from_secs(10));
+        canceller.join().unwrap();
+    }
+}
@@ -0,0 +1,799 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Error;
+
+use crate::config::{self, Config};
+use crate::discover::{self, Mount};
+use crate::fridge;
+use crate::layers::{Layers, Origin};
+use crate::preflight;
+use crate::usage;
+
+/// How bad a problem in the configuration is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// Keeps fridge from loading the configuration or from doing what it asks for
+    Error,
+    /// Likely a mistake, but fridge can run with it
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// Problem found in a configuration document, with the line it is on if it can be told
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    /// File or environment variable the problem is in, if not the document checked
+    pub file: Option<String>,
+    /// Line number, starting at 1
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+/// What to look at beyond the document itself
+#[derive(Clone, Debug, Default)]
+pub struct CheckOpts {
+    /// Check that the sources of the snapshots are btrfs subvolumes
+    pub subvolumes: bool,
+    /// Connect to every enabled remote and check that it can receive snapshots
+    pub remotes: bool,
+    /// Whether fridge runs as root, and so doesn't need sudo
+    pub as_root: bool,
+}
+
+/// Keys a configuration file may contain, nested the way they are in the file
+#[derive(Clone, Copy)]
+enum Key {
+    Value,
+    Table(&'static [(&'static str, Key)]),
+    /// Array of tables, e.g. [[snapshots]]
+    Tables(&'static [(&'static str, Key)]),
+}
+
+const LOCAL_KEYS: &[(&str, Key)] = &[
+    ("sudo", Key::Value),
+    ("path", Key::Value),
+    ("snapshot_root", Key::Value),
+    ("suffix", Key::Value),
+    ("nice", Key::Value),
+    ("ionice", Key::Value),
+    ("machine", Key::Value),
+    ("min_free", Key::Value),
+    ("low_space", Key::Value),
+];
+
+const SNAPSHOT_KEYS: &[(&str, Key)] = &[
+    ("name", Key::Value),
+    ("path", Key::Value),
+    ("hourly", Key::Value),
+    ("daily", Key::Value),
+    ("weekly", Key::Value),
+    ("monthly", Key::Value),
+    ("yearly", Key::Value),
+    ("snapshot_dir", Key::Value),
+    ("recursive", Key::Value),
+    ("include", Key::Value),
+    ("exclude", Key::Value),
+    ("budget", Key::Value),
+];
+
+const GROUP_KEYS: &[(&str, Key)] = &[
+    ("name", Key::Value),
+    ("snapshots", Key::Value),
+];
+
+const RETENTION_KEYS: &[(&str, Key)] = &[
+    ("hourly", Key::Value),
+    ("daily", Key::Value),
+    ("weekly", Key::Value),
+    ("monthly", Key::Value),
+    ("yearly", Key::Value),
+    ("max_age", Key::Value),
+    ("budget", Key::Value),
+];
+
+const BANDWIDTH_WINDOW_KEYS: &[(&str, Key)] = &[
+    ("from", Key::Value),
+    ("to", Key::Value),
+    ("limit", Key::Value),
+];
+
+const REMOTE_KEYS: &[(&str, Key)] = &[
+    ("name", Key::Value),
+    ("enabled", Key::Value),
+    ("user", Key::Value),
+    ("host", Key::Value),
+    ("port", Key::Value),
+    ("path", Key::Value),
+    ("suffix", Key::Value),
+    ("sudo", Key::Value),
+    ("identity_file", Key::Value),
+    ("ssh_options", Key::Value),
+    ("host_key", Key::Value),
+    ("bwlimit", Key::Value),
+    ("bwlimit_schedule", Key::Tables(BANDWIDTH_WINDOW_KEYS)),
+    ("connect_timeout", Key::Value),
+    ("command_timeout", Key::Value),
+    ("retries", Key::Value),
+    ("verify", Key::Value),
+    ("min_free", Key::Value),
+    ("retention", Key::Table(RETENTION_KEYS)),
+];
+
+const RUN_CONDITIONS_KEYS: &[(&str, Key)] = &[
+    ("ac_only", Key::Value),
+    ("min_battery", Key::Value),
+    ("skip_metered", Key::Value),
+];
+
+const CONDITIONS_KEYS: &[(&str, Key)] = &[
+    ("snapshots", Key::Table(RUN_CONDITIONS_KEYS)),
+    ("syncs", Key::Table(RUN_CONDITIONS_KEYS)),
+];
+
+const NOTIFICATIONS_KEYS: &[(&str, Key)] = &[
+    ("snapshot_failed", Key::Value),
+    ("backup_failed", Key::Value),
+    ("backup_completed", Key::Value),
+    ("backup_completed_after", Key::Value),
+    ("backup_overdue", Key::Value),
+    ("backup_overdue_after", Key::Value),
+];
+
+const CONFIG_KEYS: &[(&str, Key)] = &[
+    ("local", Key::Table(LOCAL_KEYS)),
+    ("snapshots", Key::Tables(SNAPSHOT_KEYS)),
+    ("groups", Key::Tables(GROUP_KEYS)),
+    ("remotes", Key::Tables(REMOTE_KEYS)),
+    ("conditions", Key::Table(CONDITIONS_KEYS)),
+    ("notifications", Key::Table(NOTIFICATIONS_KEYS)),
+];
+
+/// Collects problems, each found at a path of keys like "remotes.1.retention.daily" in the
+/// merged configuration
+struct Problems {
+    origins: BTreeMap<String, Origin>,
+    /// Lines of the keys of each source, by path in that source
+    lines: HashMap<String, HashMap<String, usize>>,
+    problems: Vec<Problem>,
+}
+
+impl Problems {
+    fn add(&mut self, severity: Severity, path: &str, message: impl fmt::Display) {
+        let (file, line) = self.locate(path);
+        self.problems.push(Problem {
+            severity,
+            file,
+            line,
+            message: message.to_string(),
+        });
+    }
+
+    fn error(&mut self, path: &str, message: impl fmt::Display) {
+        self.add(Severity::Error, path, message);
+    }
+
+    fn warning(&mut self, path: &str, message: impl fmt::Display) {
+        self.add(Severity::Warning, path, message);
+    }
+
+    /// Source and line of the key at `path`, or of the closest table holding it
+    fn locate(&self, path: &str) -> (Option<String>, Option<usize>) {
+        let origin = match prefixes(path).find_map(|path| self.origins.get(path)) {
+            Some(origin) => origin,
+            None => return (None, None),
+        };
+        let line = self.lines.get(&origin.source)
+            .and_then(|lines| prefixes(&origin.path).find_map(|path| lines.get(path)))
+            .copied();
+        let file = Some(origin.source.clone()).filter(|source| !source.is_empty());
+        (file, line)
+    }
+}
+
+/// `path` followed by the paths of the tables holding it, e.g. "local.sudo" then "local"
+fn prefixes(path: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(path), |path| path.rfind('.').map(|i| &path[..i]))
+}
+
+/// Checks a configuration document for everything that would keep fridge from loading it or
+/// from doing what it asks for, and for likely mistakes
+///
+/// Unlike loading the configuration, this carries on past the first problem, so that all of
+/// them can be fixed at once.
+pub fn check(document: &str, opts: &CheckOpts) -> Vec<Problem> {
+    check_layers(&[(String::new(), document.to_string())], &[], opts)
+}
+
+/// Checks the configuration merged from `sources` and the environment `overrides`, both given
+/// as (name, contents), like [`check`] does a single document
+///
+/// Each problem is reported in the source that set the value causing it.
+pub fn check_layers(sources: &[(String, String)], overrides: &[(String, String)], opts: &CheckOpts) -> Vec<Problem> {
+    let mut syntax_errors = Vec::new();
+    let mut layers = Layers::default();
+    for (source, document) in sources {
+        match document.parse::<toml::Value>() {
+            Ok(toml::Value::Table(table)) => layers.add(source, table),
+            Ok(_) => (),
+            Err(e) => syntax_errors.push(Problem {
+                severity: Severity::Error,
+                file: Some(source.clone()).filter(|source| !source.is_empty()),
+                line: e.line_col().map(|(line, _)| line + 1),
+                message: format!("Could not parse configuration: {}", e),
+            }),
+        }
+    }
+    for (name, value) in overrides {
+        if let Err(e) = layers.add_env([(name.as_str(), value.as_str())]) {
+            syntax_errors.push(Problem {
+                severity: Severity::Error,
+                file: Some(name.clone()),
+                line: None,
+                message: message(&e),
+            });
+        }
+    }
+    // Without all of its layers, the configuration would have problems it doesn't have
+    if !syntax_errors.is_empty() {
+        return syntax_errors;
+    }
+
+    let mut problems = Problems {
+        origins: layers.origins,
+        lines: sources.iter().map(|(source, document)| (source.clone(), key_lines(document))).collect(),
+        problems: Vec::new(),
+    };
+
+    // Unknown keys are left out so that the rest can still be checked
+    let mut value = layers.value;
+    let mut unknown = Vec::new();
+    remove_unknown_keys(&mut value, CONFIG_KEYS, "", &mut unknown);
+    for (path, suggestion) in &unknown {
+        match suggestion {
+            Some(suggestion) => problems.error(path, format!("Unknown key {} (did you mean {}?)", path_name(path), suggestion)),
+            None => problems.error(path, format!("Unknown key {}", path_name(path))),
+        }
+    }
+
+    // A single document is read again for the lines of type errors
+    let result = match sources {
+        [(_, document)] if overrides.is_empty() && unknown.is_empty() => config::parse_unchecked(document),
+        _ => config::from_value(toml::Value::Table(value)),
+    };
+    let cfg = match result {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            let line = e.downcast_ref::<toml::de::Error>().and_then(|e| e.line_col()).map(|(line, _)| line + 1);
+            let file = match sources {
+                [(source, _)] if !source.is_empty() => Some(source.clone()),
+                _ => None,
+            };
+            problems.problems.push(Problem {
+                severity: Severity::Error,
+                file,
+                line: line.filter(|_| sources.len() == 1),
+                message: format!("Could not read configuration: {}", e),
+            });
+            return problems.problems;
+        },
+    };
+
+    check_local(&cfg, opts, &mut problems);
+    check_snapshots(&cfg, opts, &mut problems);
+    check_groups(&cfg, &mut problems);
+    check_remotes(&cfg, opts, &mut problems);
+    if let Err(e) = cfg.notifications.to_notify_opts() {
+        problems.error("notifications.backup_overdue_after", message(&e));
+    }
+
+    problems.problems
+}
+
+fn check_local(cfg: &Config, opts: &CheckOpts, problems: &mut Problems) {
+    if let Err(e) = cfg.local.machine_id() {
+        problems.error("local.machine", message(&e));
+    }
+    if let Err(e) = cfg.local.priority() {
+        problems.error("local", message(&e));
+    }
+    if let Err(e) = cfg.local.space_guard() {
+        problems.error("local", message(&e));
+    }
+    if cfg.local.low_space.is_some() && cfg.local.min_free.is_none() {
+        problems.warning("local.low_space", "low_space has no effect unless min_free is set");
+    }
+    if let Err(e) = cfg.local.validate_snapshot_dirs() {
+        problems.error("local", message(&e));
+    }
+    if problems.origins.contains_key("local.path") {
+        problems.warning("local.path", "path in [local] is no longer used: snapshots are kept in <subvolume>/<suffix>, or in <snapshot_root>/<name> if snapshot_root is set");
+    }
+    if !cfg.local.sudo && !opts.as_root {
+        problems.warning("local.sudo", "Taking snapshots needs root: set sudo = true in [local] or run fridge as root");
+    }
+}
+
+fn check_snapshots(cfg: &Config, opts: &CheckOpts, problems: &mut Problems) {
+    let mounts = if opts.subvolumes {
+        discover::parse_mountinfo(&fs::read_to_string("/proc/self/mountinfo").unwrap_or_default())
+    } else {
+        Vec::new()
+    };
+    for (i, snapshot_cfg) in cfg.snapshots.iter().enumerate() {
+        let path = format!("snapshots.{}", i);
+        if let Err(e) = snapshot_cfg.validate() {
+            problems.error(&path, message(&e));
+            continue;
+        }
+        if cfg.snapshots[..i].iter().any(|other| other.name == snapshot_cfg.name) {
+            problems.error(&format!("{}.name", path), format!("Snapshot name {:?} is used more than once", snapshot_cfg.name));
+        }
+        if snapshot_cfg.tiers().iter().all(|(_, count)| *count == 0) {
+            problems.error(&path, format!("Snapshots of {} are deleted as soon as they are taken: every count is 0", snapshot_cfg.name));
+        }
+        if !snapshot_cfg.recursive && (!snapshot_cfg.include.is_empty() || !snapshot_cfg.exclude.is_empty()) {
+            problems.warning(&path, format!("Nested subvolume patterns of {} have no effect unless recursive = true", snapshot_cfg.name));
+        }
+        if opts.subvolumes {
+            if let Err(e) = fridge::check_subvolume(&snapshot_cfg.path, cfg.local.sudo || opts.as_root) {
+                problems.error(&format!("{}.path", path), message(&e));
+            }
+            let snapshot_dir = snapshot_cfg.snapshot_location(&cfg.local).path;
+            if is_kept_inside(&snapshot_dir, &snapshot_cfg.path, &mounts) {
+                problems.warning(&path, format!("Snapshots of {} are kept in {}, inside the subvolume they protect, and are lost when it is rolled back: set snapshot_dir or snapshot_root in [local] to a separate subvolume", snapshot_cfg.name, snapshot_dir));
+            }
+            // Budgets are kept with the sizes quota groups count
+            if snapshot_cfg.budget.is_some() && matches!(usage::qgroups(&snapshot_dir, cfg.local.sudo || opts.as_root), Ok(None)) {
+                problems.error(&format!("{}.budget", path), format!("Snapshots of {} can't be kept within their budget: quota groups are off at {}, see fridge usage --enable-qgroups", snapshot_cfg.name, snapshot_dir));
+            }
+        }
+    }
+}
+
+fn check_groups(cfg: &Config, problems: &mut Problems) {
+    // Each group is checked along with the valid ones before it
+    let mut valid = Vec::new();
+    for (i, group) in cfg.groups.iter().enumerate() {
+        valid.push(group.clone());
+        if let Err(e) = config::validate_groups(&valid, &cfg.snapshots) {
+            problems.error(&format!("groups.{}", i), message(&e));
+            valid.pop();
+        }
+    }
+}
+
+/// Whether `snapshot_dir` is on the subvolume mounted at `source` rather than on a separate
+/// one mounted inside it
+fn is_kept_inside(snapshot_dir: &str, source: &str, mounts: &[Mount]) -> bool {
+    let mount_of = |path: &str| mounts.iter()
+        .filter(|mount| Path::new(path).starts_with(&mount.mount_point))
+        .max_by_key(|mount| mount.mount_point.len())
+        .map(|mount| mount.mount_point.clone());
+    Path::new(snapshot_dir).starts_with(source) && mount_of(snapshot_dir) == mount_of(source)
+}
+
+fn check_remotes(cfg: &Config, opts: &CheckOpts, problems: &mut Problems) {
+    let mut locations = Vec::new();
+    for (i, remote) in cfg.remotes.iter().enumerate() {
+        let path = format!("remotes.{}", i);
+        if remote.path.is_empty() {
+            problems.error(&path, format!("Remote {} has no path to keep snapshots at: it no longer defaults to /, so set path = \"/\" to keep receiving there", remote.host.as_deref().unwrap_or("on this machine")));
+            continue;
+        }
+        if let Err(e) = remote.validate() {
+            problems.error(&path, message(&e));
+            continue;
+        }
+        if let Some(name) = &remote.name {
+            if cfg.remotes[..i].iter().any(|other| other.name.as_ref() == Some(name)) {
+                problems.error(&format!("{}.name", path), format!("Remote name {:?} is used more than once", name));
+            }
+        }
+        let location = match remote.to_location() {
+            Ok(location) => location,
+            Err(e) => {
+                problems.error(&path, message(&e));
+                continue;
+            },
+        };
+        if locations.contains(&location.to_string()) {
+            problems.error(&path, format!("Remote {} is configured more than once", location));
+        }
+        locations.push(location.to_string());
+
+        if let Some(retention) = &remote.retention {
+            let counts = [retention.hourly, retention.daily, retention.weekly, retention.monthly, retention.yearly];
+            // Under a budget, counts are only the least kept
+            if retention.budget.is_none() && counts.iter().all(|count| *count == Some(0)) {
+                problems.error(&format!("{}.retention", path), format!("Pruning {} would delete all but the latest snapshot: every count is 0", location));
+            }
+        }
+
+        // Receiving snapshots takes root on the receiving end
+        match (&remote.host, remote.user.as_deref()) {
+            // Scheduled runs are root, so this is only certain to fail when run by hand
+            (None, _) if !remote.sudo && !opts.as_root => {
+                problems.warning(&format!("{}.sudo", path), format!("Receiving snapshots at {} needs root: set sudo = true unless fridge only runs as root", location));
+            },
+            (Some(_), Some("root")) if remote.sudo => {
+                problems.warning(&format!("{}.sudo", path), format!("Remote {} logs in as root, so sudo is not needed", location));
+            },
+            (Some(_), Some(user)) if user != "root" && !remote.sudo => {
+                problems.error(&format!("{}.sudo", path), format!("Receiving snapshots at {} needs root, but {} logs in without sudo: set sudo = true or user = \"root\"", location, user));
+            },
+            (Some(_), None) if !remote.sudo => {
+                problems.warning(&format!("{}.sudo", path), format!("Receiving snapshots at {} needs root: without a user or sudo, the SSH configuration must log in as root", location));
+            },
+            _ => (),
+        }
+
+        if opts.remotes && remote.enabled {
+            if let Err(e) = preflight::preflight(&location, remote.sudo, &remote.preflight_opts()) {
+                problems.error(&path, format!("Could not reach remote {}: {}", location, e));
+            }
+        }
+    }
+}
+
+/// Removes the keys of `table` that aren't in `keys`, adding their paths to `unknown` along
+/// with a known key they might be a misspelling of
+fn remove_unknown_keys(table: &mut toml::value::Table, keys: &[(&str, Key)], path: &str, unknown: &mut Vec<(String, Option<String>)>) {
+    let mut names = Vec::new();
+    for (name, value) in table.iter_mut() {
+        let key_path = if path.is_empty() { name.clone() } else { format!("{}.{}", path, name) };
+        match keys.iter().find(|(key, _)| key == name) {
+            Some((_, Key::Value)) => (),
+            Some((_, Key::Table(keys))) => {
+                if let Some(table) = value.as_table_mut() {
+                    remove_unknown_keys(table, keys, &key_path, unknown);
+                }
+            },
+            Some((_, Key::Tables(keys))) => {
+                if let Some(array) = value.as_array_mut() {
+                    for (i, value) in array.iter_mut().enumerate() {
+                        if let Some(table) = value.as_table_mut() {
+                            remove_unknown_keys(table, keys, &format!("{}.{}", key_path, i), unknown);
+                        }
+                    }
+                }
+            },
+            None => {
+                let suggestion = keys.iter().map(|(key, _)| *key).find(|key| edit_distance(key, name) <= 2);
+                unknown.push((key_path, suggestion.map(|key| key.to_string())));
+                names.push(name.clone());
+            },
+        }
+    }
+    for name in names {
+        table.remove(&name);
+    }
+}
+
+/// Name of the key at `path` the way it is written in the file, e.g. `daily` in [remotes.retention]
+fn path_name(path: &str) -> String {
+    let segments: Vec<&str> = path.split('.').collect();
+    let (name, tables) = segments.split_last().unwrap();
+    let tables: Vec<&str> = tables.iter().copied().filter(|segment| segment.parse::<usize>().is_err()).collect();
+    if tables.is_empty() {
+        format!("`{}`", name)
+    } else {
+        format!("`{}` in [{}]", name, tables.join("."))
+    }
+}
+
+/// Number of single character insertions, deletions and substitutions turning `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Lines of the tables and keys of `document` by their path, e.g. "remotes.1.retention.daily"
+/// for the daily key of the retention table of the second remote
+fn key_lines(document: &str) -> HashMap<String, usize> {
+    let mut lines = HashMap::new();
+    // Index of the last table of each array of tables
+    let mut arrays: HashMap<String, usize> = HashMap::new();
+    let mut table = String::new();
+    for (i, line) in document.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix("[[").and_then(|line| line.split("]]").next()) {
+            let path = table_path(header, &arrays, true);
+            let index = *arrays.entry(path.clone()).and_modify(|index| *index += 1).or_insert(0);
+            // Tables nested in the previous table of the array no longer apply
+            arrays.retain(|array, _| !array.starts_with(&format!("{}.", path)));
+            lines.entry(path.clone()).or_insert(line_number);
+            table = format!("{}.{}", path, index);
+            lines.insert(table.clone(), line_number);
+        } else if let Some(header) = line.strip_prefix('[').and_then(|line| line.split(']').next()) {
+            table = table_path(header, &arrays, false);
+            lines.insert(table.clone(), line_number);
+        } else if let Some((key, _)) = line.split_once('=') {
+            let key = key.trim().trim_matches('"');
+            if key.is_empty() {
+                continue;
+            }
+            let path = if table.is_empty() { key.to_string() } else { format!("{}.{}", table, key) };
+            lines.entry(path).or_insert(line_number);
+        }
+    }
+
+    lines
+}
+
+/// Path of the table in the header `header`, going into the last table of every array of
+/// tables on the way, but not into the last segment of an array header
+fn table_path(header: &str, arrays: &HashMap<String, usize>, array_header: bool) -> String {
+    let segments: Vec<&str> = header.split('.').map(|segment| segment.trim().trim_matches('"')).collect();
+    let mut path = String::new();
+    for (i, segment) in segments.iter().enumerate() {
+        if !path.is_empty() {
+            path.push('.');
+        }
+        path.push_str(segment);
+        let last = i == segments.len() - 1;
+        if let Some(index) = arrays.get(&path).filter(|_| !(last && array_header)) {
+            path = format!("{}.{}", path, index);
+        }
+    }
+
+    path
+}
+
+/// Message of `e` along with its causes, on one line
+fn message(e: &Error) -> String {
+    format!("{:#}", e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCUMENT: &str = r#"[local]
+sudo = true
+
+[[snapshots]]
+name = "root"
+path = "/"
+hourly = 24
+
+[[snapshots]]
+name = "home"
+path = "/home"
+daily = 7
+
+[[remotes]]
+host = "nas"
+user = "root"
+path = "/backup"
+
+[remotes.retention]
+daily = 14
+
+[[remotes]]
+path = "/mnt/backup"
+sudo = true
+"#;
+
+    fn check_offline(document: &str) -> Vec<Problem> {
+        check(document, &CheckOpts::default())
+    }
+
+    /// Replaces the first occurrence of `from` in the sample document
+    fn sample_with(from: &str, to: &str) -> String {
+        assert!(DOCUMENT.contains(from));
+        DOCUMENT.replacen(from, to, 1)
+    }
+
+    #[test]
+    fn test_key_lines() {
+        let lines = key_lines(DOCUMENT);
+        assert_eq!(lines.get("local.sudo"), Some(&2));
+        assert_eq!(lines.get("snapshots.0"), Some(&4));
+        assert_eq!(lines.get("snapshots.1.daily"), Some(&12));
+        assert_eq!(lines.get("remotes.0.retention"), Some(&19));
+        assert_eq!(lines.get("remotes.0.retention.daily"), Some(&20));
+        assert_eq!(lines.get("remotes.1.path"), Some(&23));
+    }
+
+    #[test]
+    fn test_valid() {
+        assert_eq!(check_offline(DOCUMENT), vec![]);
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let problems = check_offline(&sample_with("daily = 14", "dialy = 14"));
+        assert_eq!(problems, vec![Problem {
+            severity: Severity::Error,
+            file: None,
+            line: Some(20),
+            message: "Unknown key `dialy` in [remotes.retention] (did you mean daily?)".to_string(),
+        }]);
+
+        let problems = check_offline(&sample_with("[local]", "[backups]\nkeep = true\n\n[local]"));
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].line, Some(1));
+        assert_eq!(problems[0].message, "Unknown key `backups`");
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        let problems = check_offline(&sample_with("daily = 7", "daily = "));
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].severity, Severity::Error);
+        assert_eq!(problems[0].line, Some(12));
+
+        let problems = check_offline(&sample_with("daily = 7", "daily = \"7\""));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].message.contains("daily"));
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let problems = check_offline(&sample_with("name = \"home\"", "name = \"root\""));
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].line, Some(10));
+        assert!(problems[0].message.contains("used more than once"));
+
+        let problems = check_offline(&sample_with("daily = 7", "daily = 0"));
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].line, Some(9));
+        assert!(problems[0].message.contains("every count is 0"));
+
+        let problems = check_offline(&sample_with("daily = 7", "daily = 7\nexclude = [\"var/lib/docker\"]"));
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].severity, Severity::Warning);
+        assert!(check_offline(&sample_with("daily = 7", "daily = 7\nrecursive = true\nexclude = [\"var/lib/docker\"]")).is_empty());
+
+        assert!(check_offline(&sample_with("daily = 7", "daily = 7\nbudget = \"50G\"")).is_empty());
+        let problems = check_offline(&sample_with("daily = 7", "daily = 7\nbudget = \"half\""));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].message.contains("budget"));
+    }
+
+    #[test]
+    fn test_groups() {
+        let problems = check_offline(&format!("{}\n[[groups]]\nname = \"system\"\nsnapshots = [\"root\", \"home\"]\n", DOCUMENT));
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].line, Some(26));
+        assert!(problems[0].message.contains("different number of snapshots"));
+
+        let document = sample_with("hourly = 24", "daily = 7");
+        assert!(check_offline(&format!("{}\n[[groups]]\nname = \"system\"\nsnapshots = [\"root\", \"home\"]\n", document)).is_empty());
+        let problems = check_offline(&format!("{}\n[[groups]]\nname = \"system\"\nsnapshots = [\"root\", \"srv\"]\n", document));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].message.contains("no snapshot configuration named \"srv\""));
+    }
+
+    #[test]
+    fn test_free_space() {
+        let problems = check_offline(&sample_with("sudo = true\n", "sudo = true\nmin_free = \"lots\"\n"));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].message.contains("Could not parse free space"));
+
+        let problems = check_offline(&sample_with("sudo = true\n", "sudo = true\nlow_space = \"prune\"\n"));
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].severity, Severity::Warning);
+        assert!(check_offline(&sample_with("sudo = true\n", "sudo = true\nmin_free = \"5%\"\nlow_space = \"prune\"\n")).is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_root() {
+        assert!(check_offline(&sample_with("sudo = true\n", "sudo = true\nsnapshot_root = \"/snapshots\"\n")).is_empty());
+        let problems = check_offline(&sample_with("sudo = true\n", "sudo = true\nsnapshot_root = \"snapshots\"\n"));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].message.contains("snapshot root"));
+
+        let problems = check_offline(&sample_with("sudo = true\n", "sudo = true\npath = \"/\"\n"));
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].severity, Severity::Warning);
+        assert_eq!(problems[0].line, Some(3));
+        assert!(problems[0].message.contains("no longer used"));
+    }
+
+    #[test]
+    fn test_kept_inside() {
+        let mounts = discover::parse_mountinfo(include_str!("../tests/fixtures/mountinfo-opensuse.txt"));
+        assert!(is_kept_inside("/.fridge", "/", &mounts));
+        assert!(is_kept_inside("/home/.snapshots", "/home", &mounts));
+        // Separate subvolumes mounted for the snapshots
+        assert!(!is_kept_inside("/.snapshots/fridge", "/", &mounts));
+        assert!(!is_kept_inside("/home/.snapshots", "/", &mounts));
+        assert!(!is_kept_inside("/srv/snapshots/root", "/", &mounts));
+    }
+
+    #[test]
+    fn test_remotes() {
+        let problems = check_offline(&sample_with("path = \"/backup\"\n", ""));
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].line, Some(14));
+        assert_eq!(problems[0].message, "Remote nas has no path to keep snapshots at: it no longer defaults to /, so set path = \"/\" to keep receiving there");
+
+        let document = sample_with("daily = 14", "hourly = 0\ndaily = 0\nweekly = 0\nmonthly = 0\nyearly = 0");
+        let problems = check_offline(&document);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].line, Some(19));
+    }
+
+    #[test]
+    fn test_layers() {
+        let sources = vec![
+            ("fridge.toml".to_string(), DOCUMENT.to_string()),
+            ("conf.d/10-home.toml".to_string(), "[[snapshots]]\nname = \"home\"\ndaily = 0\n\n[local]\nnicee = 5\n".to_string()),
+        ];
+        let problems = check_layers(&sources, &[], &CheckOpts::default());
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[0].file.as_deref(), Some("conf.d/10-home.toml"));
+        assert_eq!(problems[0].line, Some(6));
+        assert!(problems[0].message.contains("nicee"));
+        // The snapshot configuration is where it was first set
+        assert_eq!(problems[1].file.as_deref(), Some("fridge.toml"));
+        assert_eq!(problems[1].line, Some(9));
+
+        let overrides = vec![("FRIDGE__REMOTES__nas__PORT".to_string(), "\"ssh\"".to_string())];
+        let problems = check_layers(&sources[..1], &overrides, &CheckOpts::default());
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].line, None);
+
+        let overrides = vec![("FRIDGE__SUDO".to_string(), "true".to_string())];
+        let problems = check_layers(&sources[..1], &overrides, &CheckOpts::default());
+        assert_eq!(problems[0].file.as_deref(), Some("FRIDGE__SUDO"));
+    }
+
+    #[test]
+    fn test_sudo() {
+        // Logging in as another user without sudo can't receive snapshots
+        let problems = check_offline(&sample_with("user = \"root\"", "user = \"li\""));
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].severity, Severity::Error);
+        assert_eq!(problems[0].line, Some(14));
+
+        let problems = check_offline(&sample_with("user = \"root\"", "user = \"root\"\nsudo = true"));
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].severity, Severity::Warning);
+        assert_eq!(problems[0].line, Some(17));
+
+        let document = sample_with("sudo = true\n", "sudo = false\n");
+        assert_eq!(check_offline(&document).len(), 1);
+        assert_eq!(check(&document, &CheckOpts { as_root: true, ..Default::default() }), vec![]);
+
+        // Receiving on this machine without sudo works when fridge runs as root
+        let document = DOCUMENT.replace("path = \"/mnt/backup\"\nsudo = true", "path = \"/mnt/backup\"");
+        let problems = check_offline(&document);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].severity, Severity::Warning);
+        assert_eq!(check(&document, &CheckOpts { as_root: true, ..Default::default() }), vec![]);
+    }
+}
@@ -0,0 +1,591 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::{ArgAction, Parser, Subcommand};
+
+use crate::cancel::CancelToken;
+use crate::check::{self, CheckOpts, Severity};
+use crate::config::{self, Config, RemoteConfig, SnapshotConfig};
+use crate::discover;
+use crate::fridge;
+use crate::groups;
+use crate::health::HealthStore;
+use crate::history::{self, format_bytes, Filter, Operation, Status};
+use crate::preflight;
+use crate::retention::{self, PruneOpts};
+use crate::runner::{self, Job};
+use crate::usage;
+use crate::verify::{self, VerifyMode, VerifyOpts};
+
+/// Command line interface, used instead of the window when arguments are given
+#[derive(Debug, Parser)]
+#[command(name = "fridge", version, about = "Takes btrfs snapshots and backs them up to remotes")]
+pub struct Cli {
+    /// Print more details, can be repeated
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// Use this configuration file instead of /etc/fridge/fridge.toml and its drop-in files
+    #[arg(long, value_name = "FILE", global = true)]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Take the snapshots that are due, back them up to the enabled remotes and prune both,
+    /// sending desktop notifications like the window does, e.g. from a timer
+    Run {
+        /// Only take and prune local snapshots
+        #[arg(long)]
+        no_backup: bool,
+        /// Wait up to this many minutes for the run conditions of deferred jobs to be met,
+        /// such as AC power, before skipping them
+        #[arg(long, value_name = "MINUTES", default_value_t = 60)]
+        wait: u64,
+    },
+    /// Show the health of every configured remote
+    Health,
+    /// Check that the configured remotes can receive snapshots
+    Preflight {
+        /// Only check the remote at this location (e.g. "li@192.168.0.2:22:/backup")
+        remote: Option<String>,
+    },
+    /// Check that the snapshots on the configured remotes match their sources
+    Verify {
+        /// Only verify the remote at this location
+        remote: Option<String>,
+        /// Compare a checksum of every file
+        #[arg(long, conflicts_with = "sample")]
+        checksum: bool,
+        /// Compare the checksums of this many files of each snapshot
+        #[arg(long, value_name = "FILES")]
+        sample: Option<usize>,
+    },
+    /// List the machines with snapshots on the configured remotes
+    Machines {
+        /// Only list the machines on the remote at this location
+        remote: Option<String>,
+    },
+    /// Delete the snapshots that the retention policies of the configured remotes don't keep
+    Prune {
+        /// Only prune the remote at this location
+        remote: Option<String>,
+        /// List the snapshots that would be deleted without deleting them
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// Show the snapshots, transfers, prunes, deletions and restores fridge carried out
+    History {
+        /// Only show operations on this subvolume (e.g. "root")
+        #[arg(long)]
+        subvolume: Option<String>,
+        /// Only show operations on locations containing this text (e.g. "192.168.0.2")
+        #[arg(long)]
+        remote: Option<String>,
+        /// Only show operations that ended with this status (success or failure)
+        #[arg(long)]
+        status: Option<Status>,
+        /// Only show operations of this kind (snapshot, transfer, prune, delete or restore)
+        #[arg(long)]
+        operation: Option<Operation>,
+        /// Only show operations started on or after this date (YYYY-MM-DD)
+        #[arg(long, value_parser = parse_date)]
+        since: Option<DateTime<Utc>>,
+        /// Only show operations started before this date (YYYY-MM-DD)
+        #[arg(long, value_parser = parse_date)]
+        until: Option<DateTime<Utc>>,
+        /// Only show this many of the most recent operations
+        #[arg(short = 'l', long)]
+        limit: Option<usize>,
+    },
+    /// Show the space used by each local snapshot and by the snapshots of each subvolume
+    Usage {
+        /// Only show the snapshots of this subvolume (e.g. "root")
+        subvolume: Option<String>,
+        /// Enable quota groups for exact counts, at a cost to the performance of the filesystem
+        #[arg(long)]
+        enable_qgroups: bool,
+    },
+    /// Restore a snapshot as a new writable subvolume, along with the nested subvolumes
+    /// snapshotted with it, or every member of a group snapshot
+    Restore {
+        /// Snapshot or group snapshot to restore (e.g. "root@2023-01-02_03:04:05_daily")
+        snapshot: String,
+        /// Path to create the subvolume at, which must not exist yet, or directory to create
+        /// the subvolume of each member of a group in
+        target: String,
+        /// Print the commands that would restore the snapshot without running them
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// Find the mounted btrfs subvolumes and propose snapshot configurations for them
+    Discover {
+        /// Add the proposed configurations to the configuration file
+        #[arg(long)]
+        apply: bool,
+    },
+    /// Check, show, import or export the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Replace a file of the state shared by every user with standard input, as the window
+    /// does through sudo
+    #[command(hide = true)]
+    State {
+        /// File in the state directory (e.g. "health.toml")
+        file: String,
+        /// Append standard input to the file instead
+        #[arg(long)]
+        append: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Report every problem in the configuration, along with the file and line it is on
+    Check {
+        /// File to check on its own instead of the merged configuration
+        file: Option<String>,
+        /// Don't connect to the remotes to check that they are reachable
+        #[arg(long)]
+        offline: bool,
+    },
+    /// Print the configuration merged from its files and environment variables, with the
+    /// origin of each value
+    Show,
+    /// Check a configuration file and replace the current configuration file with it
+    Import {
+        /// File to import, or "-" to read it from standard input
+        file: String,
+    },
+    /// Print the configuration file
+    Export {
+        /// Write it to this file instead
+        file: Option<String>,
+    },
+}
+
+pub fn run(cli: &Cli) -> Result<()> {
+    if let Some(path) = &cli.config {
+        config::use_config_file(path);
+    }
+
+    match &cli.command {
+        Command::Run { no_backup, wait } => run_jobs(*no_backup, *wait),
+        Command::Health => health(cli.verbose),
+        Command::Preflight { remote } => preflight(remote.as_deref()),
+        Command::Verify { remote, checksum, sample } => {
+            let mode = match (checksum, sample) {
+                (true, _) => Some(VerifyMode::Checksum),
+                (false, Some(n)) => Some(VerifyMode::Sample(*n)),
+                (false, None) => None,
+            };
+            verify(remote.as_deref(), mode, cli.verbose)
+        },
+        Command::Machines { remote } => machines(remote.as_deref(), cli.verbose),
+        Command::Prune { remote, dry_run } => prune(remote.as_deref(), *dry_run, cli.verbose),
+        Command::History { subvolume, remote, status, operation, since, until, limit } => {
+            let filter = Filter {
+                operation: *operation,
+                subvolume: subvolume.clone(),
+                target: remote.clone(),
+                status: *status,
+                since: *since,
+                until: *until,
+            };
+            history(&filter, *limit, cli.verbose)
+        },
+        Command::Usage { subvolume, enable_qgroups } => usage(subvolume.as_deref(), *enable_qgroups, cli.verbose),
+        Command::Restore { snapshot, target, dry_run } => restore(snapshot, target, *dry_run, cli.verbose),
+        Command::Discover { apply } => discover(*apply),
+        Command::Config { command: ConfigCommand::Check { file, offline } } => check_config(file.as_deref(), *offline),
+        Command::Config { command: ConfigCommand::Show } => show_config(),
+        Command::Config { command: ConfigCommand::Import { file } } => import_config(file),
+        Command::Config { command: ConfigCommand::Export { file } } => export_config(file.as_deref()),
+        Command::State { file, append } => save_state(file, *append),
+    }
+}
+
+/// Parses a date given as YYYY-MM-DD into midnight UTC
+fn parse_date(s: &str) -> Result<DateTime<Utc>> {
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|_| anyhow!("Could not parse date {:?}, expected YYYY-MM-DD", s))?;
+    Ok(DateTime::from_utc(date.and_hms(0, 0, 0), Utc))
+}
+
+/// Returns the enabled remotes along with their location strings, or only the one at `filter`
+/// whether it is enabled or not
+fn remotes(cfg: &Config, filter: Option<&str>) -> Result<Vec<(String, RemoteConfig)>> {
+    let mut remotes = Vec::new();
+    for remote in &cfg.remotes {
+        let location = remote.to_location()?.to_string();
+        let selected = match filter {
+            Some(filter) => filter == location,
+            None => remote.enabled,
+        };
+        if selected {
+            remotes.push((location, remote.clone()));
+        }
+    }
+
+    if let (Some(filter), true) = (filter, remotes.is_empty()) {
+        bail!("Could not find remote {} in configuration", filter);
+    }
+
+    Ok(remotes)
+}
+
+fn run_jobs(no_backup: bool, wait: u64) -> Result<()> {
+    let jobs: &[Job] = if no_backup { &[Job::Snapshot] } else { &[Job::Snapshot, Job::Backup] };
+    runner::run(jobs, Duration::from_secs(wait.saturating_mul(60)), &CancelToken::new())
+}
+
+fn health(verbose: u8) -> Result<()> {
+    let store = HealthStore::load()?;
+    for (location, _) in remotes(&config::load()?, None)? {
+        let health = store.get(&location);
+        println!("{}: {}", location, health.summary());
+        if verbose > 0 {
+            if let Some(at) = health.last_error_at {
+                println!("  last error at {}: {}", at.to_rfc3339(), health.last_error.as_deref().unwrap_or_default());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn preflight(filter: Option<&str>) -> Result<()> {
+    let mut failures = 0;
+    for (location, remote) in remotes(&config::load()?, filter)? {
+        match preflight::preflight(&remote.to_location()?, remote.sudo, &remote.preflight_opts()) {
+            Ok(()) => println!("{}: OK", location),
+            Err(e) => {
+                println!("{}: {}", location, e);
+                failures += 1;
+            },
+        }
+    }
+
+    if failures > 0 {
+        bail!("{} remote(s) failed the preflight check", failures);
+    }
+
+    Ok(())
+}
+
+/// Verifies with `mode`, or with the mode configured for each remote if not given
+fn verify(filter: Option<&str>, mode: Option<VerifyMode>, verbose: u8) -> Result<()> {
+    let cfg = config::load()?;
+    let machine = cfg.local.machine_id()?;
+    let mut failures = 0;
+    for (location, remote) in remotes(&cfg, filter)? {
+        let dst = remote.to_location()?.for_machine(&machine);
+        for snapshot_cfg in &cfg.snapshots {
+            let opts = VerifyOpts {
+                name: snapshot_cfg.name.clone(),
+                src: snapshot_cfg.snapshot_location(&cfg.local),
+                src_sudo: cfg.local.sudo,
+                dst: dst.clone(),
+                dst_sudo: remote.sudo,
+                mode: mode.or(dst.verify),
+                verbose: verbose as i32,
+            };
+            for report in verify::verify(&opts)? {
+                if report.is_ok() {
+                    println!("{}: {}: OK", location, report.snapshot);
+                } else {
+                    println!("{}: {}: {}", location, report.snapshot, report.problems.join(", "));
+                    failures += 1;
+                }
+            }
+        }
+    }
+
+    if failures > 0 {
+        bail!("{} snapshot(s) failed verification", failures);
+    }
+
+    Ok(())
+}
+
+fn prune(filter: Option<&str>, dry_run: bool, verbose: u8) -> Result<()> {
+    let cfg = config::load()?;
+    let machine = cfg.local.machine_id()?;
+    for (location, remote) in remotes(&cfg, filter)? {
+        let dst = remote.to_location()?.for_machine(&machine);
+        let policy = match &dst.retention {
+            Some(policy) => policy.clone(),
+            None => {
+                println!("{}: no retention policy configured", location);
+                continue;
+            },
+        };
+
+        for unit in groups::units(&cfg) {
+            let (first, others) = unit.members.split_first().unwrap();
+            let opts = PruneOpts {
+                name: first.name.clone(),
+                members: others.iter().map(|member| (member.name.clone(), member.snapshot_location(&cfg.local))).collect(),
+                src: first.snapshot_location(&cfg.local),
+                src_sudo: cfg.local.sudo,
+                dst: dst.clone(),
+                dst_sudo: remote.sudo,
+                policy: policy.clone(),
+                dry_run,
+                verbose: verbose as i32,
+            };
+            for snapshot in retention::prune(&opts)? {
+                if dry_run {
+                    println!("{}: would delete {}", location, snapshot);
+                } else {
+                    println!("{}: deleted {}", location, snapshot);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn machines(filter: Option<&str>, verbose: u8) -> Result<()> {
+    let cfg = config::load()?;
+    let machine = cfg.local.machine_id()?;
+    for (location, remote) in remotes(&cfg, filter)? {
+        for other in fridge::list_machines(&remote.to_location()?, remote.sudo, verbose as i32)? {
+            if other == machine {
+                println!("{}: {} (this machine)", location, other);
+            } else {
+                println!("{}: {}", location, other);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn history(filter: &Filter, limit: Option<usize>, verbose: u8) -> Result<()> {
+    let records = history::load(filter)?;
+    let skip = limit.map_or(0, |limit| records.len().saturating_sub(limit));
+    for record in &records[skip..] {
+        let size = record.bytes.map(|bytes| format!(", {}", history::format_bytes(bytes))).unwrap_or_default();
+        println!("{} {} {}: {} at {} in {}s{}",
+            record.started.format("%Y-%m-%d %H:%M:%S"),
+            record.operation,
+            record.status,
+            record.snapshot.as_deref().unwrap_or(&record.subvolume),
+            record.target,
+            record.duration().num_seconds(),
+            size);
+        if let Some(error) = &record.error {
+            if verbose > 0 {
+                println!("  {}", error);
+            } else {
+                println!("  {}", error.lines().next().unwrap_or_default());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the space each snapshot uses, and the total of each snapshot configuration
+fn usage(filter: Option<&str>, enable_qgroups: bool, verbose: u8) -> Result<()> {
+    let cfg = config::load()?;
+    let snapshot_cfgs: Vec<&SnapshotConfig> = cfg.snapshots.iter()
+        .filter(|snapshot_cfg| filter.is_none_or(|filter| snapshot_cfg.name == filter))
+        .collect();
+    if let (Some(filter), true) = (filter, snapshot_cfgs.is_empty()) {
+        bail!("Could not find snapshot configuration {} in configuration", filter);
+    }
+    if enable_qgroups {
+        eprintln!("Warning: {}", usage::QGROUP_WARNING);
+    }
+
+    let mut estimated_anywhere = false;
+    for snapshot_cfg in snapshot_cfgs {
+        let location = snapshot_cfg.snapshot_location(&cfg.local);
+        if enable_qgroups {
+            usage::enable_qgroups(&location.path, cfg.local.sudo)?;
+            println!("Enabled quota groups at {}, counts are exact once their rescan finished", location.path);
+        }
+        let qgroups = usage::qgroups(&location.path, cfg.local.sudo)?;
+        let mut usages = Vec::new();
+        for snapshot in fridge::list_snapshots(&snapshot_cfg.name, &location, cfg.local.sudo, verbose as i32)? {
+            let usage = usage::measure(&snapshot, qgroups.as_ref(), cfg.local.sudo)?;
+            println!("{}: {} exclusive, {} referenced", snapshot.full_name, format_bytes(usage.exclusive), format_bytes(usage.referenced));
+            usages.push(usage);
+        }
+        let (total, estimated) = usage::total_exclusive(&usages);
+        let about = if estimated { "about " } else { "" };
+        println!("{}: {} snapshot(s) using {}{} exclusive", snapshot_cfg.name, usages.len(), about, format_bytes(total));
+        estimated_anywhere |= estimated;
+    }
+
+    if estimated_anywhere {
+        eprintln!("Sizes are estimated with btrfs filesystem du since quota groups are off. Pass --enable-qgroups for exact counts. {}.", usage::QGROUP_WARNING);
+    }
+
+    Ok(())
+}
+
+/// Restores `snapshot` to `target`, or each member of a group snapshot to `target/<member>`
+fn restore(snapshot: &str, target: &str, dry_run: bool, verbose: u8) -> Result<()> {
+    let cfg = config::load()?;
+    let name = snapshot.split_once('@').map_or(snapshot, |(name, _)| name);
+    let unit = groups::units(&cfg).into_iter()
+        .find(|unit| unit.name == name)
+        .ok_or_else(|| anyhow!("Could not find snapshot configuration or group {} in configuration", name))?;
+
+    // Every member is found before anything is restored
+    let mut restores = Vec::new();
+    for member in &unit.members {
+        let full_name = format!("{}@{}", member.name, groups::stamp(snapshot));
+        let location = member.snapshot_location(&cfg.local);
+        let member_snapshot = fridge::list_snapshots(&member.name, &location, cfg.local.sudo, verbose as i32)?
+            .into_iter()
+            .find(|other| other.full_name == full_name)
+            .ok_or_else(|| anyhow!("Could not find snapshot {} in {}", full_name, location))?;
+        let dst = if unit.is_group() {
+            PathBuf::from(target).join(&member.name).to_str().unwrap().to_string()
+        } else {
+            target.to_string()
+        };
+        restores.push(fridge::RestoreOpts {
+            snapshot: member_snapshot,
+            location,
+            dst,
+            sudo: cfg.local.sudo,
+            dry_run,
+            verbose: verbose as i32,
+        });
+    }
+
+    if unit.is_group() {
+        fridge::restore_group(&restores, target, cfg.local.sudo, dry_run, verbose as i32)
+    } else {
+        fridge::restore(&restores[0])
+    }
+}
+
+/// Lists the subvolumes found along with the snapshot configurations proposed for them
+///
+/// Without a configuration file, the proposals replace the default configurations rather than
+/// being added to them, since those are a guess at the same.
+fn discover(apply: bool) -> Result<()> {
+    let subvolumes = discover::discover(!config::is_root())?;
+    let configured = config::is_configured()?;
+    let existing = if configured { config::load()?.snapshots } else { Vec::new() };
+    for subvolume in &subvolumes {
+        println!("{}: {}", subvolume.mount_point, subvolume.description());
+    }
+
+    let proposals = discover::propose(&subvolumes, &existing);
+    if proposals.is_empty() {
+        println!("Every subvolume found is configured already");
+        return Ok(());
+    }
+    if !apply {
+        println!("\nProposed configuration, added with --apply:\n");
+        print!("{}", config::replace_section("", "snapshots", &proposals)?);
+        return Ok(());
+    }
+
+    // Only what the configuration file itself holds is written back, not the drop-in files
+    let document = config::read_document()?;
+    let mut snapshots = if configured { config::parse_unchecked(&document)?.snapshots } else { Vec::new() };
+    snapshots.extend(proposals.iter().cloned());
+    write_config(&config::replace_section(&document, "snapshots", &snapshots)?)?;
+    for proposal in &proposals {
+        println!("Added snapshots of {} as {}", proposal.path, proposal.name);
+    }
+
+    Ok(())
+}
+
+/// Checks `file` on its own, or the configuration merged from all of its layers
+fn check_config(file: Option<&str>, offline: bool) -> Result<()> {
+    let opts = CheckOpts {
+        subvolumes: true,
+        remotes: !offline,
+        as_root: config::is_root(),
+    };
+    let (name, problems) = match file {
+        Some(file) => {
+            let document = fs::read_to_string(file).map_err(|e| anyhow!("Could not read {}: {}", file, e))?;
+            (file.to_string(), check::check(&document, &opts))
+        },
+        None => ("configuration".to_string(), check::check_layers(&config::sources()?, &config::env_overrides(), &opts)),
+    };
+
+    for problem in &problems {
+        let file = problem.file.as_deref().unwrap_or(&name);
+        match problem.line {
+            Some(line) => println!("{}:{}: {}", file, line, problem),
+            None => println!("{}: {}", file, problem),
+        }
+    }
+
+    let errors = problems.iter().filter(|problem| problem.severity == Severity::Error).count();
+    if errors > 0 {
+        bail!("{} error(s) in {}", errors, name);
+    }
+    if problems.is_empty() {
+        println!("{}: OK", name);
+    }
+
+    Ok(())
+}
+
+fn show_config() -> Result<()> {
+    print!("{}", config::load_layers()?.show());
+    Ok(())
+}
+
+fn import_config(file: &str) -> Result<()> {
+    let mut document = String::new();
+    let result = if file == "-" {
+        io::stdin().read_to_string(&mut document).map(|_| ())
+    } else {
+        fs::read_to_string(file).map(|s| document = s)
+    };
+    if let Err(e) = result {
+        bail!("Could not read {}: {}", file, e);
+    }
+
+    write_config(&document)?;
+    println!("Imported {} into {}", file, config::config_file().display());
+
+    Ok(())
+}
+
+fn save_state(file: &str, append: bool) -> Result<()> {
+    let mut contents = String::new();
+    io::stdin().read_to_string(&mut contents).map_err(|e| anyhow!("Could not read standard input: {}", e))?;
+    config::write_state(file, &contents, append)
+}
+
+/// Makes `document` the configuration file, through sudo unless running as root
+fn write_config(document: &str) -> Result<()> {
+    if config::is_root() {
+        config::write(document)
+    } else {
+        config::install(document)
+    }
+}
+
+fn export_config(file: Option<&str>) -> Result<()> {
+    let document = config::read_document()?;
+    match file {
+        Some(file) => fs::write(file, document).map_err(|e| anyhow!("Could not write {}: {}", file, e)),
+        None => {
+            print!("{}", document);
+            Ok(())
+        },
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::Result;
+use chrono::Duration;
+
+use crate::fridge::{list_snapshots, Snapshot, SnapshotRepositoryLocation};
+
+/// Which snapshots of a subvolume exist locally, on a remote or on both, oldest first
+#[derive(Default)]
+pub struct Comparison {
+    pub local_only: Vec<Snapshot>,
+    pub remote_only: Vec<Snapshot>,
+    pub both: Vec<Snapshot>,
+}
+
+impl Comparison {
+    pub fn new(local: Vec<Snapshot>, mut remote: Vec<Snapshot>) -> Self {
+        let mut comparison = Self::default();
+        for snapshot in local {
+            match remote.iter().position(|s| s.full_name == snapshot.full_name) {
+                Some(i) => {
+                    remote.remove(i);
+                    comparison.both.push(snapshot);
+                },
+                None => comparison.local_only.push(snapshot),
+            }
+        }
+        comparison.remote_only = remote;
+
+        comparison.local_only.sort_by_key(|snapshot| snapshot.datetime);
+        comparison.remote_only.sort_by_key(|snapshot| snapshot.datetime);
+        comparison.both.sort_by_key(|snapshot| snapshot.datetime);
+        comparison
+    }
+
+    /// How far the remote is behind, i.e. the time between the newest local snapshot and the
+    /// newest one the remote has as well, or `None` if the remote has none of the local ones
+    pub fn lag(&self) -> Option<Duration> {
+        let newest_local = self.local_only.iter()
+            .chain(self.both.iter())
+            .map(|snapshot| snapshot.datetime)
+            .max();
+        let newest_shared = self.both.iter()
+            .map(|snapshot| snapshot.datetime)
+            .max();
+
+        match (newest_local, newest_shared) {
+            (None, _) => Some(Duration::zero()),
+            (Some(_), None) => None,
+            (Some(local), Some(shared)) => Some(local.signed_duration_since(shared)),
+        }
+    }
+}
+
+/// Describes a lag for display, e.g. "2 day(s) behind"
+pub fn describe_lag(lag: Option<Duration>) -> String {
+    match lag {
+        None => "Never synchronized".to_string(),
+        Some(lag) if lag <= Duration::zero() => "Up-to-date".to_string(),
+        Some(lag) if lag.num_days() > 0 => format!("{} day(s) behind", lag.num_days()),
+        Some(lag) if lag.num_hours() > 0 => format!("{} hour(s) behind", lag.num_hours()),
+        Some(lag) => format!("{} minute(s) behind", lag.num_minutes()),
+    }
+}
+
+/// Compares the snapshots with the given name at `local` and `remote`
+pub fn compare(name: &str, local: &SnapshotRepositoryLocation, local_sudo: bool, remote: &SnapshotRepositoryLocation, remote_sudo: bool, verbose: i32) -> Result<Comparison> {
+    let local_snapshots = list_snapshots(name, local, local_sudo, verbose)?;
+    let remote_snapshots = list_snapshots(name, remote, remote_sudo, verbose)?;
+
+    Ok(Comparison::new(local_snapshots, remote_snapshots))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fridge::parse_snapshot_name;
+
+    use super::*;
+
+    fn snapshots(names: &[&str]) -> Vec<Snapshot> {
+        names.iter().map(|name| parse_snapshot_name(name, "/").unwrap()).collect()
+    }
+
+    fn names(snapshots: &[Snapshot]) -> Vec<&str> {
+        snapshots.iter().map(|snapshot| snapshot.full_name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_comparison() {
+        let comparison = Comparison::new(
+            snapshots(&["root@2000-01-03_00:00:00_daily", "root@2000-01-02_00:00:00_daily", "root@2000-01-04_12:00:00_hourly"]),
+            snapshots(&["root@2000-01-01_00:00:00_daily", "root@2000-01-02_00:00:00_daily", "root@2000-01-03_00:00:00_daily"]),
+        );
+        assert_eq!(names(&comparison.local_only), ["root@2000-01-04_12:00:00_hourly"]);
+        assert_eq!(names(&comparison.remote_only), ["root@2000-01-01_00:00:00_daily"]);
+        assert_eq!(names(&comparison.both), ["root@2000-01-02_00:00:00_daily", "root@2000-01-03_00:00:00_daily"]);
+        assert_eq!(comparison.lag(), Some(Duration::hours(36)));
+
+        let never_synced = Comparison::new(snapshots(&["root@2000-01-01_00:00:00_daily"]), Vec::new());
+        assert_eq!(never_synced.lag(), None);
+        assert_eq!(Comparison::new(Vec::new(), Vec::new()).lag(), Some(Duration::zero()));
+    }
+
+    #[test]
+    fn test_describe_lag() {
+        assert_eq!(describe_lag(None), "Never synchronized");
+        assert_eq!(describe_lag(Some(Duration::zero())), "Up-to-date");
+        assert_eq!(describe_lag(Some(Duration::hours(36))), "1 day(s) behind");
+        assert_eq!(describe_lag(Some(Duration::minutes(90))), "1 hour(s) behind");
+        assert_eq!(describe_lag(Some(Duration::minutes(5))), "5 minute(s) behind");
+    }
+}
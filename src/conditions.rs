@@ -0,0 +1,231 @@
+use anyhow::Result;
+use log::warn;
+use serde::Deserialize;
+use zbus::blocking::Connection;
+use zbus::{dbus_proxy, CacheProperties};
+
+#[dbus_proxy(
+    interface = "org.freedesktop.UPower",
+    default_service = "org.freedesktop.UPower",
+    default_path = "/org/freedesktop/UPower"
+)]
+trait UPower {
+    #[dbus_proxy(property)]
+    fn on_battery(&self) -> zbus::Result<bool>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.UPower.Device",
+    default_service = "org.freedesktop.UPower",
+    default_path = "/org/freedesktop/UPower/devices/DisplayDevice"
+)]
+trait UPowerDevice {
+    #[dbus_proxy(property)]
+    fn is_present(&self) -> zbus::Result<bool>;
+
+    #[dbus_proxy(property)]
+    fn percentage(&self) -> zbus::Result<f64>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.NetworkManager",
+    default_service = "org.freedesktop.NetworkManager",
+    default_path = "/org/freedesktop/NetworkManager"
+)]
+trait NetworkManager {
+    #[dbus_proxy(property)]
+    fn metered(&self) -> zbus::Result<u32>;
+}
+
+/// NMMetered values meaning the primary connection is metered
+const NM_METERED_YES: u32 = 1;
+const NM_METERED_GUESS_YES: u32 = 3;
+
+/// Conditions that must hold for a job to start
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct RunConditions {
+    /// Only run while on AC power
+    pub ac_only: bool,
+    /// Only run on battery if it is charged at least this many percent
+    pub min_battery: Option<u8>,
+    /// Don't run while the primary network connection is metered
+    pub skip_metered: bool,
+}
+
+impl RunConditions {
+    pub fn is_unconditional(&self) -> bool {
+        !self.ac_only && self.min_battery.is_none() && !self.skip_metered
+    }
+}
+
+/// Outcome of checking run conditions
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    Run,
+    /// The job should be retried later, for the given reason
+    Defer(String),
+}
+
+/// Checks run conditions against UPower and NetworkManager on the system bus
+pub fn check(conditions: &RunConditions) -> Result<Verdict> {
+    if conditions.is_unconditional() {
+        return Ok(Verdict::Run);
+    }
+
+    let connection = Connection::system()?;
+    Ok(check_on(&connection, conditions))
+}
+
+/// Checks run conditions against the services reachable through `connection`
+///
+/// A service that can't be queried, such as UPower on a machine without battery, counts as
+/// the condition being met.
+pub fn check_on(connection: &Connection, conditions: &RunConditions) -> Verdict {
+    if conditions.ac_only || conditions.min_battery.is_some() {
+        match battery_level(connection) {
+            Ok(None) => (),
+            Ok(Some(_)) if conditions.ac_only => return Verdict::Defer("running on battery power".to_string()),
+            Ok(Some(percentage)) => {
+                let min_battery = conditions.min_battery.unwrap_or(0);
+                if percentage < min_battery as f64 {
+                    return Verdict::Defer(format!("battery at {:.0}%, below {}%", percentage, min_battery));
+                }
+            },
+            Err(e) => warn!("Could not read power state from UPower: {}", e),
+        }
+    }
+
+    if conditions.skip_metered {
+        match is_metered(connection) {
+            Ok(true) => return Verdict::Defer("network connection is metered".to_string()),
+            Ok(false) => (),
+            Err(e) => warn!("Could not read metered state from NetworkManager: {}", e),
+        }
+    }
+
+    Verdict::Run
+}
+
+/// Returns the battery percentage when running on battery, or `None` when on AC power
+fn battery_level(connection: &Connection) -> zbus::Result<Option<f64>> {
+    let upower = UPowerProxyBlocking::builder(connection)
+        .cache_properties(CacheProperties::No)
+        .build()?;
+    if !upower.on_battery()? {
+        return Ok(None);
+    }
+
+    let device = UPowerDeviceProxyBlocking::builder(connection)
+        .cache_properties(CacheProperties::No)
+        .build()?;
+    if !device.is_present()? {
+        return Ok(Some(100.0));
+    }
+
+    Ok(Some(device.percentage()?))
+}
+
+fn is_metered(connection: &Connection) -> zbus::Result<bool> {
+    let network_manager = NetworkManagerProxyBlocking::builder(connection)
+        .cache_properties(CacheProperties::No)
+        .build()?;
+    let metered = network_manager.metered()?;
+
+    Ok(metered == NM_METERED_YES || metered == NM_METERED_GUESS_YES)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    use zbus::blocking::{Connection, ConnectionBuilder};
+    use zbus::{dbus_interface, Guid};
+
+    use super::*;
+
+    struct MockUPower {
+        on_battery: bool,
+    }
+
+    #[dbus_interface(name = "org.freedesktop.UPower")]
+    impl MockUPower {
+        #[dbus_interface(property)]
+        fn on_battery(&self) -> bool {
+            self.on_battery
+        }
+    }
+
+    struct MockUPowerDevice {
+        percentage: f64,
+    }
+
+    #[dbus_interface(name = "org.freedesktop.UPower.Device")]
+    impl MockUPowerDevice {
+        #[dbus_interface(property)]
+        fn is_present(&self) -> bool {
+            true
+        }
+
+        #[dbus_interface(property)]
+        fn percentage(&self) -> f64 {
+            self.percentage
+        }
+    }
+
+    struct MockNetworkManager {
+        metered: u32,
+    }
+
+    #[dbus_interface(name = "org.freedesktop.NetworkManager")]
+    impl MockNetworkManager {
+        #[dbus_interface(property)]
+        fn metered(&self) -> u32 {
+            self.metered
+        }
+    }
+
+    /// Serves mock UPower and NetworkManager objects on a private peer-to-peer connection
+    fn mock_services(on_battery: bool, percentage: f64, metered: u32) -> (Connection, Connection) {
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let guid = Guid::generate();
+        let server = thread::spawn(move || {
+            ConnectionBuilder::unix_stream(server_stream)
+                .server(&guid)
+                .p2p()
+                .serve_at("/org/freedesktop/UPower", MockUPower { on_battery }).unwrap()
+                .serve_at("/org/freedesktop/UPower/devices/DisplayDevice", MockUPowerDevice { percentage }).unwrap()
+                .serve_at("/org/freedesktop/NetworkManager", MockNetworkManager { metered }).unwrap()
+                .build()
+                .unwrap()
+        });
+        let client = ConnectionBuilder::unix_stream(client_stream)
+            .p2p()
+            .build()
+            .unwrap();
+
+        (server.join().unwrap(), client)
+    }
+
+    #[test]
+    fn test_check_on() {
+        let ac_only = RunConditions { ac_only: true, ..Default::default() };
+        let min_battery = RunConditions { min_battery: Some(50), ..Default::default() };
+        let skip_metered = RunConditions { skip_metered: true, ..Default::default() };
+
+        let (_server, client) = mock_services(false, 20.0, NM_METERED_YES);
+        assert_eq!(check_on(&client, &ac_only), Verdict::Run);
+        assert_eq!(check_on(&client, &min_battery), Verdict::Run);
+        assert!(matches!(check_on(&client, &skip_metered), Verdict::Defer(_)));
+
+        let (_server, client) = mock_services(true, 80.0, 4);
+        assert!(matches!(check_on(&client, &ac_only), Verdict::Defer(_)));
+        assert_eq!(check_on(&client, &min_battery), Verdict::Run);
+        assert_eq!(check_on(&client, &skip_metered), Verdict::Run);
+
+        let (_server, client) = mock_services(true, 20.0, NM_METERED_GUESS_YES);
+        assert_eq!(check_on(&client, &min_battery), Verdict::Defer("battery at 20%, below 50%".to_string()));
+        assert!(matches!(check_on(&client, &skip_metered), Verdict::Defer(_)));
+        assert_eq!(check_on(&client, &RunConditions::default()), Verdict::Run);
+    }
+}
@@ -1,38 +1,206 @@
-use std::fs;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str;
+use std::time::Duration;
 
-use anyhow::{Result,bail};
-use log::error;
-use serde::Deserialize;
+use anyhow::{Result, anyhow, bail};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
 
-const DEFAULT_HOURLY: usize = 24;
-const DEFAULT_DAILY: usize = 7;
-const DEFAULT_WEEKLY: usize = 4;
-const DEFAULT_MONTHLY: usize = 12;
-const DEFAULT_YEARLY: usize = 3;
+/// System-wide configuration file
+pub const CONFIG_PATH: &str = "/etc/fridge/fridge.toml";
+/// Directory of drop-in files merged over the configuration file, see [`crate::layers`]
+pub const CONFIG_DIR: &str = "/etc/fridge/conf.d";
+/// Source of the default configuration among the [`sources`] when there is no configuration file
+pub const DEFAULTS_SOURCE: &str = "defaults";
+/// Directory of the state shared by every user, see [`state_dir`]
+pub const STATE_DIR: &str = "/var/lib/fridge";
+/// Files in the state directory that [`write_state`] may write
+pub const STATE_FILES: &[&str] = &["health.toml", "pins.toml", "history.jsonl"];
 
-use crate::fridge::SnapshotOpts;
+pub const DEFAULT_HOURLY: usize = 24;
+pub const DEFAULT_DAILY: usize = 7;
+pub const DEFAULT_WEEKLY: usize = 4;
+pub const DEFAULT_MONTHLY: usize = 12;
+pub const DEFAULT_YEARLY: usize = 3;
+
+use crate::conditions::RunConditions;
+use crate::fridge::{SnapshotOpts, SnapshotRepositoryLocation};
+use crate::layers::{self, Layers};
+use crate::nested;
+use crate::notify::NotifyOpts;
+use crate::preflight::{PreflightOpts, RetryPolicy};
+use crate::retention::{self, RetentionPolicy};
+use crate::space::{self, LowSpaceAction, SpaceGuard, Threshold};
+use crate::ssh::SshOpts;
+use crate::throttle::{self, BandwidthLimit, BandwidthWindow, IoClass, Priority};
+use crate::verify::VerifyMode;
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Default)]
 pub struct Config {
 	pub local: LocalConfig,
 	pub snapshots: Vec<SnapshotConfig>,
+	pub groups: Vec<GroupConfig>,
 	pub remotes: Vec<RemoteConfig>,
+	pub conditions: ConditionsConfig,
+	pub notifications: NotificationsConfig,
+}
+
+impl Config {
+	/// Checks for mistakes in the whole configuration, short of looking at the filesystem or
+	/// connecting to remotes
+	pub fn validate(&self) -> Result<()> {
+		self.local.machine_id()?;
+		self.local.priority()?;
+		self.local.space_guard()?;
+		self.local.validate_snapshot_dirs()?;
+		validate_snapshots(&self.snapshots)?;
+		validate_groups(&self.groups, &self.snapshots)?;
+		validate_remotes(&self.remotes)?;
+		self.notifications.to_notify_opts()?;
+
+		Ok(())
+	}
+}
+
+/// Conditions under which snapshots and synchronizations may start
+#[derive(Clone, Debug, Deserialize, PartialEq, Default)]
+pub struct ConditionsConfig {
+	pub snapshots: RunConditions,
+	pub syncs: RunConditions,
+}
+
+/// Desktop notifications to send, per event
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct NotificationsConfig {
+	pub snapshot_failed: bool,
+	pub backup_failed: bool,
+	pub backup_completed: bool,
+	/// Only notify about completed backups that took at least this many seconds
+	pub backup_completed_after: u64,
+	pub backup_overdue: bool,
+	/// Age of the last successful backup that is notified about (e.g. "3d")
+	pub backup_overdue_after: String,
+}
+
+impl Default for NotificationsConfig {
+	fn default() -> Self {
+		Self {
+			snapshot_failed: true,
+			backup_failed: true,
+			backup_completed: true,
+			backup_completed_after: 600,
+			backup_overdue: true,
+			backup_overdue_after: "3d".to_string(),
+		}
+	}
+}
+
+impl NotificationsConfig {
+	pub fn to_notify_opts(&self) -> Result<NotifyOpts> {
+		Ok(NotifyOpts {
+			snapshot_failed: self.snapshot_failed,
+			backup_failed: self.backup_failed,
+			backup_completed_after: self.backup_completed.then(|| Duration::from_secs(self.backup_completed_after)),
+			backup_overdue_after: if self.backup_overdue {
+				Some(retention::parse_age(&self.backup_overdue_after)?)
+			} else {
+				None
+			},
+		})
+	}
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Default)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct LocalConfig {
 	pub sudo: bool,
-	pub path: String,
+	/// Directory holding a `<name>` snapshot directory for each subvolume without one of its
+	/// own, e.g. a separate top-level subvolume mounted for the purpose
+	pub snapshot_root: Option<String>,
+	/// Name of the snapshot directory inside each subvolume when neither `snapshot_root` nor the
+	/// subvolume's own snapshot directory is set
 	pub suffix: String,
+	pub nice: Option<i32>,
+	pub ionice: Option<String>,
+	/// Namespace of this machine's snapshots on remotes, none if not set or empty
+	pub machine: Option<String>,
+	/// Free space to keep on the filesystems holding snapshots, e.g. "10G" or "5%"
+	pub min_free: Option<String>,
+	/// What to do when a snapshot is due with less than `min_free` free: "refuse" to take it,
+	/// or "prune" the oldest unpinned snapshots until there is enough
+	pub low_space: Option<String>,
 }
 
-lazy_static! {
-	static ref DEFAULT_CONFIG: Config = Config {
-		local: LocalConfig {
+impl Default for LocalConfig {
+	fn default() -> Self {
+		Self {
 			sudo: false,
-			path: "/".to_string(),
+			snapshot_root: None,
 			suffix: ".snapshots".to_string(),
-		},
+			nice: None,
+			ionice: None,
+			machine: None,
+			min_free: None,
+			low_space: None,
+		}
+	}
+}
+
+impl LocalConfig {
+	/// Checks that the snapshot directory settings resolve to absolute paths
+	pub fn validate_snapshot_dirs(&self) -> Result<()> {
+		if let Some(path) = &self.snapshot_root {
+			if !Path::new(path).is_absolute() {
+				bail!("Could not use {:?} as snapshot root: the path must be absolute", path);
+			}
+		}
+		if self.suffix.is_empty() || self.suffix.contains('/') || self.suffix == "." || self.suffix == ".." {
+			bail!("Could not use {:?} as snapshot directory name: it must be a single path component", self.suffix);
+		}
+		Ok(())
+	}
+
+	/// Namespace of this machine's snapshots on remotes, empty for the flat layout
+	///
+	/// Remotes stay flat unless `machine` is set, so existing backups keep their parents.
+	pub fn machine_id(&self) -> Result<String> {
+		let machine = self.machine.clone().unwrap_or_default();
+		if machine.contains('/') || machine == "." || machine == ".." {
+			bail!("Could not use {:?} as machine name", machine);
+		}
+		Ok(machine)
+	}
+
+
+	/// Free space to keep before taking snapshots, if any
+	pub fn space_guard(&self) -> Result<Option<SpaceGuard>> {
+		let action = self.low_space.as_deref().map(LowSpaceAction::parse).transpose()?.unwrap_or_default();
+		match &self.min_free {
+			Some(min_free) => Ok(Some(SpaceGuard {
+				min_free: Threshold::parse(min_free)?,
+				action,
+			})),
+			None => Ok(None),
+		}
+	}
+
+	/// Priority of the processes sending snapshots, and receiving them on local remotes
+	pub fn priority(&self) -> Result<Priority> {
+		Ok(Priority {
+			nice: self.nice,
+			io_class: self.ionice.as_deref().map(IoClass::parse).transpose()?,
+		})
+	}
+}
+
+lazy_static! {
+	/// Configuration used when the configuration file doesn't exist
+	pub static ref DEFAULT_CONFIG: Config = Config {
+		local: LocalConfig::default(),
 		snapshots: vec![
 			SnapshotConfig {
 				name: "root".to_string(),
@@ -42,6 +210,11 @@ lazy_static! {
 				weekly: DEFAULT_WEEKLY,
 				monthly: DEFAULT_MONTHLY,
 				yearly: DEFAULT_YEARLY,
+				snapshot_dir: None,
+				recursive: false,
+				include: Vec::new(),
+				exclude: Vec::new(),
+				budget: None,
 			},
 			SnapshotConfig {
 				name: "home".to_string(),
@@ -51,13 +224,21 @@ lazy_static! {
 				weekly: DEFAULT_WEEKLY,
 				monthly: DEFAULT_MONTHLY,
 				yearly: DEFAULT_YEARLY,
+				snapshot_dir: None,
+				recursive: false,
+				include: Vec::new(),
+				exclude: Vec::new(),
+				budget: None,
 			},
 		],
+		groups: vec![],
 		remotes: vec![],
+		conditions: ConditionsConfig::default(),
+		notifications: NotificationsConfig::default(),
 	};
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Default)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Default)]
 pub struct SnapshotConfig {
 	pub name: String,
 	pub path: String,
@@ -66,32 +247,310 @@ pub struct SnapshotConfig {
 	pub weekly: usize,
 	pub monthly: usize,
 	pub yearly: usize,
+	/// Directory holding the snapshots, resolved through `[local]` if not set
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub snapshot_dir: Option<String>,
+	/// Whether the subvolumes nested in this one are snapshotted along with it
+	#[serde(skip_serializing_if = "std::ops::Not::not")]
+	pub recursive: bool,
+	/// Patterns of the nested subvolumes taking part in recursive snapshots, all if empty
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub include: Vec<String>,
+	/// Patterns of the nested subvolumes left out of recursive snapshots
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub exclude: Vec<String>,
+	/// Space the snapshots may use exclusively, e.g. "50G", making the counts above the number
+	/// of snapshots of each tier kept at least
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub budget: Option<String>,
 }
 
 impl SnapshotConfig {
-	pub fn to_snapshot_opts(&self, suffix: Option<&str>, sudo: bool, dry_run: bool, verbose: i32) -> SnapshotOpts {
+	/// Creates a configuration of the subvolume at `path` keeping the default number of snapshots
+	pub fn new(name: &str, path: &str) -> Self {
+		Self {
+			name: name.to_string(),
+			path: path.to_string(),
+			hourly: DEFAULT_HOURLY,
+			daily: DEFAULT_DAILY,
+			weekly: DEFAULT_WEEKLY,
+			monthly: DEFAULT_MONTHLY,
+			yearly: DEFAULT_YEARLY,
+			snapshot_dir: None,
+			recursive: false,
+			include: Vec::new(),
+			exclude: Vec::new(),
+			budget: None,
+		}
+	}
+
+	pub fn to_snapshot_opts(&self, local: &LocalConfig, suffix: Option<&str>, sudo: bool, dry_run: bool, verbose: i32) -> SnapshotOpts {
 		SnapshotOpts {
 			src: self.path.clone(),
+			dst: self.snapshot_location(local).path,
 			name: self.name.clone(),
 			suffix: suffix.map(|v| v.to_string()),
+			nested: Vec::new(),
 			sudo,
 			dry_run,
 			verbose,
 		}
 	}
+
+	/// Local repository holding the snapshots of this subvolume: its own snapshot directory,
+	/// else `<local.snapshot_root>/<name>`, else `<path>/<local.suffix>`
+	pub fn snapshot_location(&self, local: &LocalConfig) -> SnapshotRepositoryLocation {
+		let path = match (&self.snapshot_dir, &local.snapshot_root) {
+			(Some(snapshot_dir), _) => snapshot_dir.clone(),
+			(None, Some(snapshot_root)) => Path::new(snapshot_root).join(&self.name).to_str().unwrap().to_string(),
+			(None, None) => Path::new(&self.path).join(&local.suffix).to_str().unwrap().to_string(),
+		};
+		SnapshotRepositoryLocation {
+			path,
+			..Default::default()
+		}
+	}
+
+	/// Number of snapshots to keep of each tier, from hourly to yearly
+	pub fn tiers(&self) -> [(&'static str, usize); 5] {
+		[
+			("hourly", self.hourly),
+			("daily", self.daily),
+			("weekly", self.weekly),
+			("monthly", self.monthly),
+			("yearly", self.yearly),
+		]
+	}
+
+	/// Policy pruning the local snapshots down to the configured counts, or to the budget
+	pub fn retention_policy(&self) -> RetentionPolicy {
+		RetentionPolicy {
+			hourly: Some(self.hourly),
+			daily: Some(self.daily),
+			weekly: Some(self.weekly),
+			monthly: Some(self.monthly),
+			yearly: Some(self.yearly),
+			max_age: None,
+			// Checked when the configuration is validated
+			budget: self.budget.as_deref().and_then(|budget| space::parse_size(budget).ok()),
+		}
+	}
+
+	/// Checks for mistakes that would keep the subvolume from being snapshotted, short of
+	/// looking at the filesystem
+	pub fn validate(&self) -> Result<()> {
+		if self.name.is_empty() {
+			bail!("Could not use the snapshot configuration of {:?}: the name is empty", self.path);
+		}
+		if self.name.contains(['@', '/', nested::SEPARATOR]) || self.name == "." || self.name == ".." {
+			bail!("Could not use {:?} as snapshot name: it may not contain @, / or {}", self.name, nested::SEPARATOR);
+		}
+		if !Path::new(&self.path).is_absolute() {
+			bail!("Could not use {:?} as source of {}: the path must be absolute", self.path, self.name);
+		}
+		if let Some(snapshot_dir) = &self.snapshot_dir {
+			if !Path::new(snapshot_dir).is_absolute() {
+				bail!("Could not use {:?} as snapshot directory of {}: the path must be absolute", snapshot_dir, self.name);
+			}
+		}
+		if let Some(budget) = &self.budget {
+			space::parse_size(budget).map_err(|e| anyhow!("Could not use {:?} as budget of {}: {}", budget, self.name, e))?;
+		}
+
+		Ok(())
+	}
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Default)]
+/// Snapshot configurations whose snapshots are taken, pruned, sent and restored together, see
+/// [`crate::groups`]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct GroupConfig {
+	pub name: String,
+	/// Names of the snapshot configurations in the group
+	pub snapshots: Vec<String>,
+}
+
+/// Checks that every group has a unique name of its own and consists of snapshot
+/// configurations of `snapshots` that keep the same number of snapshots and are in no other
+/// group
+pub fn validate_groups(groups: &[GroupConfig], snapshots: &[SnapshotConfig]) -> Result<()> {
+	for (i, group) in groups.iter().enumerate() {
+		if group.name.is_empty() || group.name.contains(['@', '/', nested::SEPARATOR]) || group.name == "." || group.name == ".." {
+			bail!("Could not use {:?} as group name: it may not be empty or contain @, / or {}", group.name, nested::SEPARATOR);
+		}
+		if groups[..i].iter().any(|other| other.name == group.name) || snapshots.iter().any(|snapshot| snapshot.name == group.name) {
+			bail!("Could not use {:?} as group name: it is used more than once", group.name);
+		}
+		if group.snapshots.is_empty() {
+			bail!("Could not use group {}: it has no snapshots", group.name);
+		}
+		let mut tiers = None;
+		for (j, name) in group.snapshots.iter().enumerate() {
+			let snapshot = match snapshots.iter().find(|snapshot| &snapshot.name == name) {
+				Some(snapshot) => snapshot,
+				None => bail!("Could not use group {}: there is no snapshot configuration named {:?}", group.name, name),
+			};
+			if group.snapshots[..j].contains(name) || groups[..i].iter().any(|other| other.snapshots.contains(name)) {
+				bail!("Could not use group {}: {} is in a group more than once", group.name, name);
+			}
+			// Members are pruned together, so they have to keep the same snapshots
+			if tiers.is_some_and(|tiers| tiers != (snapshot.tiers(), &snapshot.budget)) {
+				bail!("Could not use group {}: {} keeps a different number of snapshots or budget than the other members", group.name, name);
+			}
+			tiers = Some((snapshot.tiers(), &snapshot.budget));
+		}
+	}
+
+	Ok(())
+}
+
+/// Checks that every snapshot configuration is valid and that their names are unique
+pub fn validate_snapshots(snapshots: &[SnapshotConfig]) -> Result<()> {
+	for (i, snapshot) in snapshots.iter().enumerate() {
+		snapshot.validate()?;
+		if snapshots[..i].iter().any(|other| other.name == snapshot.name) {
+			bail!("Could not use {:?} as snapshot name: it is used more than once", snapshot.name);
+		}
+	}
+
+	Ok(())
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Default)]
 pub struct RemoteConfig {
+	/// Name drop-in files and environment overrides refer to the remote by
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub name: Option<String>,
+	/// Whether backups are sent to this remote
+	pub enabled: bool,
 	pub user: Option<String>,
 	pub host: Option<String>,
 	pub port: Option<u16>,
 	pub path: String,
 	pub sudo: bool,
 	pub suffix: String,
+	pub identity_file: Option<String>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub ssh_options: Vec<String>,
+	pub host_key: Option<String>,
+	pub bwlimit: Option<String>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub bwlimit_schedule: Vec<BandwidthWindowConfig>,
+	/// Seconds to wait for the remote to accept a connection
+	pub connect_timeout: Option<u64>,
+	/// Seconds to wait for each preflight command on the remote
+	pub command_timeout: Option<u64>,
+	/// Number of attempts at synchronizing while the remote is unreachable
+	pub retries: Option<u32>,
+	/// Verification after each transfer: "metadata", "checksum" or "sample[:<files>]"
+	pub verify: Option<String>,
+	/// Free space to keep after receiving a snapshot, e.g. "10G" or "5%"
+	pub min_free: Option<String>,
+	pub retention: Option<RetentionConfig>,
+}
+
+/// Snapshots to keep on a remote (e.g. daily = 14, monthly = 6, max_age = "1y")
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionConfig {
+	pub hourly: Option<usize>,
+	pub daily: Option<usize>,
+	pub weekly: Option<usize>,
+	pub monthly: Option<usize>,
+	pub yearly: Option<usize>,
+	pub max_age: Option<String>,
+	/// Space the snapshots may use exclusively on the remote, e.g. "500G", making the counts
+	/// minimums
+	pub budget: Option<String>,
+}
+
+impl RetentionConfig {
+	pub fn to_policy(&self) -> Result<RetentionPolicy> {
+		Ok(RetentionPolicy {
+			hourly: self.hourly,
+			daily: self.daily,
+			weekly: self.weekly,
+			monthly: self.monthly,
+			yearly: self.yearly,
+			max_age: self.max_age.as_deref().map(retention::parse_age).transpose()?,
+			budget: self.budget.as_deref().map(space::parse_size).transpose()?,
+		})
+	}
+}
+
+/// Time of day window with its own bandwidth limit (e.g. from = "22:00", to = "06:00", limit = "0")
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BandwidthWindowConfig {
+	pub from: String,
+	pub to: String,
+	pub limit: String,
 }
 
 impl RemoteConfig {
+	/// Creates an enabled configuration of the repository at `path` on `host`, or on this
+	/// machine if `host` is not set
+	pub fn new(host: Option<&str>, path: &str) -> Self {
+		Self {
+			enabled: true,
+			host: host.map(|host| host.to_string()),
+			path: path.to_string(),
+			suffix: ".snapshots".to_string(),
+			..Default::default()
+		}
+	}
+
+	pub fn bandwidth_limit(&self) -> Result<BandwidthLimit> {
+		let mut schedule = Vec::new();
+		for window in &self.bwlimit_schedule {
+			schedule.push(BandwidthWindow {
+				from: throttle::parse_time_of_day(&window.from)?,
+				to: throttle::parse_time_of_day(&window.to)?,
+				rate: throttle::parse_rate(&window.limit)?,
+			});
+		}
+
+		Ok(BandwidthLimit {
+			rate: self.bwlimit.as_deref().map(throttle::parse_rate).transpose()?.flatten(),
+			schedule,
+		})
+	}
+
+	pub fn to_location(&self) -> Result<SnapshotRepositoryLocation> {
+		Ok(SnapshotRepositoryLocation {
+			user: self.user.clone(),
+			host: self.host.clone(),
+			port: self.port,
+			path: self.path.clone(),
+			ssh: SshOpts {
+				identity_file: self.identity_file.clone(),
+				options: self.ssh_options.clone(),
+				host_key_fingerprint: self.host_key.clone(),
+			},
+			bwlimit: self.bandwidth_limit()?,
+			verify: self.verify.as_deref().map(VerifyMode::parse).transpose()?,
+			min_free: self.min_free.as_deref().map(Threshold::parse).transpose()?,
+			retention: self.retention.as_ref().map(RetentionConfig::to_policy).transpose()?,
+		})
+	}
+
+	pub fn preflight_opts(&self) -> PreflightOpts {
+		let default = PreflightOpts::default();
+		PreflightOpts {
+			connect_timeout: self.connect_timeout.map_or(default.connect_timeout, Duration::from_secs),
+			command_timeout: self.command_timeout.map_or(default.command_timeout, Duration::from_secs),
+		}
+	}
+
+	pub fn retry_policy(&self) -> RetryPolicy {
+		let default = RetryPolicy::default();
+		RetryPolicy {
+			attempts: self.retries.unwrap_or(default.attempts).max(1),
+			..default
+		}
+	}
+
 	pub fn location_string(&self) -> Result<String> {
 		if let Some(user) = &self.user {
 			if let Some(host) = &self.host {
@@ -107,13 +566,59 @@ impl RemoteConfig {
 			Ok(self.path.clone())
 		}
 	}
+
+	/// Checks for mistakes that would keep backups from reaching the remote, short of
+	/// connecting to it
+	pub fn validate(&self) -> Result<()> {
+		if self.path.is_empty() {
+			bail!("Could not use remote {}: it has no path, which no longer defaults to /, so set path = \"/\" to keep receiving there", self.host.as_deref().unwrap_or("on this machine"));
+		}
+		if !Path::new(&self.path).is_absolute() {
+			bail!("Could not use {:?} as remote path: the path must be absolute", self.path);
+		}
+		match &self.host {
+			Some(host) if host.is_empty() || host.contains(['@', ':', '/']) => bail!("Could not use {:?} as remote host", host),
+			None if self.user.is_some() || self.port.is_some() => bail!("Could not use remote {}: a user or port is set without a host", self.path),
+			_ => (),
+		}
+		if self.user.as_ref().is_some_and(|user| user.is_empty()) {
+			bail!("Could not use an empty user for remote {}", self.path);
+		}
+		self.to_location()?;
+
+		Ok(())
+	}
+}
+
+/// Checks that every remote configuration is valid and that no remote is configured twice
+pub fn validate_remotes(remotes: &[RemoteConfig]) -> Result<()> {
+	let mut locations = Vec::new();
+	for (i, remote) in remotes.iter().enumerate() {
+		remote.validate()?;
+		if let Some(name) = &remote.name {
+			if remotes[..i].iter().any(|other| other.name.as_ref() == Some(name)) {
+				bail!("Could not use {:?} as remote name: it is used more than once", name);
+			}
+		}
+		let location = remote.to_location()?.to_string();
+		if locations.contains(&location) {
+			bail!("Could not use remote {}: it is configured more than once", location);
+		}
+		locations.push(location);
+	}
+
+	Ok(())
 }
 
 #[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
 struct RawConfig {
 	local: Option<RawLocalConfig>,
 	snapshots: Option<Vec<RawSnapshotConfig>>,
+	groups: Option<Vec<GroupConfig>>,
 	remotes: Option<Vec<RawRemoteConfig>>,
+	conditions: Option<RawConditionsConfig>,
+	notifications: Option<RawNotificationsConfig>,
 }
 
 impl From<RawConfig> for Config {
@@ -121,29 +626,107 @@ impl From<RawConfig> for Config {
 		Self {
 			local: raw.local.map_or(LocalConfig::default(), |local| local.into()),
 			snapshots: raw.snapshots.map_or(Vec::new(), |snapshots| snapshots.iter().map(|v| SnapshotConfig::from(v)).collect()),
+			groups: raw.groups.unwrap_or_default(),
 			remotes: raw.remotes.map_or(Vec::new(), |remotes| remotes.iter().map(|v| RemoteConfig::from(v)).collect()),
+			conditions: raw.conditions.map_or(ConditionsConfig::default(), |conditions| conditions.into()),
+			notifications: raw.notifications.map_or(NotificationsConfig::default(), |notifications| notifications.into()),
+		}
+	}
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct RawNotificationsConfig {
+	snapshot_failed: Option<bool>,
+	backup_failed: Option<bool>,
+	backup_completed: Option<bool>,
+	backup_completed_after: Option<u64>,
+	backup_overdue: Option<bool>,
+	backup_overdue_after: Option<String>,
+}
+
+impl From<RawNotificationsConfig> for NotificationsConfig {
+	fn from(raw: RawNotificationsConfig) -> Self {
+		let default = NotificationsConfig::default();
+		Self {
+			snapshot_failed: raw.snapshot_failed.unwrap_or(default.snapshot_failed),
+			backup_failed: raw.backup_failed.unwrap_or(default.backup_failed),
+			backup_completed: raw.backup_completed.unwrap_or(default.backup_completed),
+			backup_completed_after: raw.backup_completed_after.unwrap_or(default.backup_completed_after),
+			backup_overdue: raw.backup_overdue.unwrap_or(default.backup_overdue),
+			backup_overdue_after: raw.backup_overdue_after.unwrap_or(default.backup_overdue_after),
 		}
 	}
 }
 
 #[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct RawConditionsConfig {
+	snapshots: Option<RawRunConditions>,
+	syncs: Option<RawRunConditions>,
+}
+
+impl From<RawConditionsConfig> for ConditionsConfig {
+	fn from(raw: RawConditionsConfig) -> Self {
+		Self {
+			snapshots: raw.snapshots.map_or(RunConditions::default(), |conditions| conditions.into()),
+			syncs: raw.syncs.map_or(RunConditions::default(), |conditions| conditions.into()),
+		}
+	}
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct RawRunConditions {
+	ac_only: Option<bool>,
+	min_battery: Option<u8>,
+	skip_metered: Option<bool>,
+}
+
+impl From<RawRunConditions> for RunConditions {
+	fn from(raw: RawRunConditions) -> Self {
+		Self {
+			ac_only: raw.ac_only.unwrap_or(false),
+			min_battery: raw.min_battery,
+			skip_metered: raw.skip_metered.unwrap_or(false),
+		}
+	}
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
 struct RawLocalConfig {
 	sudo: bool,
-	path: Option<String>,
+	/// Former prefix of the snapshot directories, accepted so older configurations still load
+	/// but ignored: `config check` warns about it
+	#[serde(rename = "path")]
+	_path: Option<String>,
+	snapshot_root: Option<String>,
 	suffix: Option<String>,
+	nice: Option<i32>,
+	ionice: Option<String>,
+	machine: Option<String>,
+	min_free: Option<String>,
+	low_space: Option<String>,
 }
 
 impl From<RawLocalConfig> for LocalConfig {
 	fn from(raw: RawLocalConfig) -> Self {
 		Self {
 			sudo: raw.sudo,
-			path: raw.path.unwrap_or("/".to_string()),
+			snapshot_root: raw.snapshot_root,
 			suffix: raw.suffix.unwrap_or(".snapshots".to_string()),
+			nice: raw.nice,
+			ionice: raw.ionice,
+			machine: raw.machine,
+			min_free: raw.min_free,
+			low_space: raw.low_space,
 		}
 	}
 }
 
 #[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
 struct RawSnapshotConfig {
 	name: String,
 	path: String,
@@ -152,16 +735,35 @@ struct RawSnapshotConfig {
 	weekly: Option<usize>,
 	monthly: Option<usize>,
 	yearly: Option<usize>,
+	snapshot_dir: Option<String>,
+	recursive: Option<bool>,
+	include: Option<Vec<String>>,
+	exclude: Option<Vec<String>>,
+	budget: Option<String>,
 }
 
 #[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
 struct RawRemoteConfig {
+	name: Option<String>,
+	enabled: Option<bool>,
 	user: Option<String>,
 	host: Option<String>,
 	port: Option<u16>,
 	path: Option<String>,
 	suffix: Option<String>,
 	sudo: Option<bool>,
+	identity_file: Option<String>,
+	ssh_options: Option<Vec<String>>,
+	host_key: Option<String>,
+	bwlimit: Option<String>,
+	bwlimit_schedule: Option<Vec<BandwidthWindowConfig>>,
+	connect_timeout: Option<u64>,
+	command_timeout: Option<u64>,
+	retries: Option<u32>,
+	verify: Option<String>,
+	min_free: Option<String>,
+	retention: Option<RetentionConfig>,
 }
 
 impl From<RawSnapshotConfig> for SnapshotConfig {
@@ -169,11 +771,16 @@ impl From<RawSnapshotConfig> for SnapshotConfig {
 		SnapshotConfig{
 			name: raw.name,
 			path: raw.path,
+			snapshot_dir: raw.snapshot_dir,
 			hourly: raw.hourly.unwrap_or(0),
 			daily: raw.daily.unwrap_or(0),
 			weekly: raw.weekly.unwrap_or(0),
 			monthly: raw.monthly.unwrap_or(0),
 			yearly: raw.yearly.unwrap_or(0),
+			recursive: raw.recursive.unwrap_or(false),
+			include: raw.include.unwrap_or_default(),
+			exclude: raw.exclude.unwrap_or_default(),
+			budget: raw.budget,
 		}
 	}
 }
@@ -183,11 +790,16 @@ impl From<&RawSnapshotConfig> for SnapshotConfig {
 		SnapshotConfig{
 			name: raw.name.clone(),
 			path: raw.path.clone(),
+			snapshot_dir: raw.snapshot_dir.clone(),
 			hourly: raw.hourly.unwrap_or(0),
 			daily: raw.daily.unwrap_or(0),
 			weekly: raw.weekly.unwrap_or(0),
 			monthly: raw.monthly.unwrap_or(0),
 			yearly: raw.yearly.unwrap_or(0),
+			recursive: raw.recursive.unwrap_or(false),
+			include: raw.include.clone().unwrap_or_default(),
+			exclude: raw.exclude.clone().unwrap_or_default(),
+			budget: raw.budget.clone(),
 		}
 	}
 }
@@ -195,12 +807,25 @@ impl From<&RawSnapshotConfig> for SnapshotConfig {
 impl From<RawRemoteConfig> for RemoteConfig {
 	fn from(raw: RawRemoteConfig) -> Self {
 		RemoteConfig{
+			name: raw.name,
+			enabled: raw.enabled.unwrap_or(true),
 			user: raw.user,
 			host: raw.host,
 			port: raw.port,
-			path: raw.path.unwrap_or("/".to_string()),
+			path: raw.path.unwrap_or_default(),
 			suffix: raw.suffix.unwrap_or(".snapshots".to_string()),
 			sudo: raw.sudo.unwrap_or(false),
+			identity_file: raw.identity_file,
+			ssh_options: raw.ssh_options.unwrap_or_default(),
+			host_key: raw.host_key,
+			bwlimit: raw.bwlimit,
+			bwlimit_schedule: raw.bwlimit_schedule.unwrap_or_default(),
+			connect_timeout: raw.connect_timeout,
+			command_timeout: raw.command_timeout,
+			retries: raw.retries,
+			verify: raw.verify,
+			min_free: raw.min_free,
+			retention: raw.retention,
 		}
 	}
 }
@@ -208,41 +833,414 @@ impl From<RawRemoteConfig> for RemoteConfig {
 impl From<&RawRemoteConfig> for RemoteConfig {
 	fn from(raw: &RawRemoteConfig) -> Self {
 		RemoteConfig{
+			name: raw.name.clone(),
+			enabled: raw.enabled.unwrap_or(true),
 			user: raw.user.clone(),
 			host: raw.host.clone(),
 			port: raw.port,
-			path: raw.path.clone().unwrap_or("/".to_string()),
+			path: raw.path.clone().unwrap_or_default(),
 			suffix: raw.suffix.clone().unwrap_or(".snapshots".to_string()),
 			sudo: raw.sudo.unwrap_or(false),
+			identity_file: raw.identity_file.clone(),
+			ssh_options: raw.ssh_options.clone().unwrap_or_default(),
+			host_key: raw.host_key.clone(),
+			bwlimit: raw.bwlimit.clone(),
+			bwlimit_schedule: raw.bwlimit_schedule.clone().unwrap_or_default(),
+			connect_timeout: raw.connect_timeout,
+			command_timeout: raw.command_timeout,
+			retries: raw.retries,
+			verify: raw.verify.clone(),
+			min_free: raw.min_free.clone(),
+			retention: raw.retention.clone(),
+		}
+	}
+}
+
+/// File given with `--config`, used instead of the configuration file and its drop-in files
+static CONFIG_FILE: OnceCell<PathBuf> = OnceCell::new();
+
+/// Uses the file at `path` as the only configuration file from now on
+pub fn use_config_file(path: &Path) {
+	let _ = CONFIG_FILE.set(path.to_path_buf());
+}
+
+/// Configuration file that is read as a whole and written to, as opposed to drop-in files
+pub fn config_file() -> &'static Path {
+	CONFIG_FILE.get().map_or(Path::new(CONFIG_PATH), PathBuf::as_path)
+}
+
+/// Loads the configuration merged from its layers, see [`load_layers`]
+///
+/// A configuration with mistakes is an error rather than a reason to fall back to the
+/// defaults, which would snapshot and prune with settings nobody chose.
+pub fn load() -> Result<Config> {
+	load_layers()
+		.and_then(|layers| {
+			let config = from_value(toml::Value::Table(layers.value))?;
+			config.validate()?;
+			Ok(config)
+		})
+		.map_err(|e| anyhow!("Could not load configuration: {:#} (run `fridge config check` for details)", e))
+}
+
+/// Merges the configuration from [`sources`], then from the environment variables overriding
+/// single values
+pub fn load_layers() -> Result<Layers> {
+	let mut layers = Layers::default();
+	for (source, document) in sources()? {
+		let table = toml::from_str(&document).map_err(|e| anyhow!("Could not read {}: {}", source, e))?;
+		layers.add(&source, table);
+	}
+	let overrides = env_overrides();
+	layers.add_env(overrides.iter().map(|(name, value)| (name.as_str(), value.as_str())))?;
+
+	Ok(layers)
+}
+
+/// Files the configuration is merged from, in order, along with their contents
+///
+/// These are the file given with `--config`, or the configuration file followed by the `.toml`
+/// files in [`CONFIG_DIR`] in lexical order. A missing configuration file is replaced by the
+/// default snapshot configurations, like [`read_document`] does.
+pub fn sources() -> Result<Vec<(String, String)>> {
+	match CONFIG_FILE.get() {
+		Some(path) => {
+			let document = fs::read_to_string(path).map_err(|e| anyhow!("Could not read configuration file at {}: {}", path.display(), e))?;
+			Ok(vec![(path.display().to_string(), document)])
+		},
+		None => sources_at(Path::new(CONFIG_PATH), Path::new(CONFIG_DIR)),
+	}
+}
+
+fn sources_at(path: &Path, dir: &Path) -> Result<Vec<(String, String)>> {
+	let mut sources = Vec::new();
+	match fs::read_to_string(path) {
+		Ok(document) => sources.push((path.display().to_string(), document)),
+		Err(e) if e.kind() == ErrorKind::NotFound => sources.push((DEFAULTS_SOURCE.to_string(), replace_section("", "snapshots", &DEFAULT_CONFIG.snapshots)?)),
+		Err(e) => bail!("Could not read configuration file at {}: {}", path.display(), e),
+	}
+
+	let mut drop_ins: Vec<PathBuf> = match fs::read_dir(dir) {
+		Ok(entries) => entries
+			.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+			.filter(|path| path.extension().is_some_and(|extension| extension == "toml"))
+			.collect(),
+		Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+		Err(e) => bail!("Could not read configuration directory at {}: {}", dir.display(), e),
+	};
+	drop_ins.sort();
+	for drop_in in drop_ins {
+		let document = fs::read_to_string(&drop_in).map_err(|e| anyhow!("Could not read configuration file at {}: {}", drop_in.display(), e))?;
+		sources.push((drop_in.display().to_string(), document));
+	}
+
+	Ok(sources)
+}
+
+/// Whether the configuration was written by someone, rather than only the defaults
+pub fn is_configured() -> Result<bool> {
+	Ok(sources()?.iter().any(|(source, _)| source != DEFAULTS_SOURCE))
+}
+
+/// Environment variables overriding configuration values, sorted by name
+pub fn env_overrides() -> Vec<(String, String)> {
+	let mut overrides: Vec<(String, String)> = env::vars().filter(|(name, _)| name.starts_with(layers::ENV_PREFIX)).collect();
+	overrides.sort();
+	overrides
+}
+
+/// Whether this process runs as root, and so doesn't need sudo
+pub fn is_root() -> bool {
+	fs::metadata("/proc/self").is_ok_and(|m| m.uid() == 0)
+}
+
+/// Directory holding state kept between runs, such as the health of each remote
+///
+/// Every user shares it, so that the window sees what `fridge run` does as root and the other
+/// way around. Only root writes to it, see [`save_state`].
+pub fn state_dir() -> PathBuf {
+	PathBuf::from(STATE_DIR)
+}
+
+/// Replaces the state file `name` with `contents`, or appends them to it, through sudo unless
+/// running as root
+pub fn save_state(name: &str, contents: &str, append: bool) -> Result<()> {
+	if is_root() {
+		write_state(name, contents, append)
+	} else {
+		let mut args = vec!["state", name];
+		if append {
+			args.push("--append");
 		}
+		run_privileged(&args, contents).map_err(|e| anyhow!("Could not save {}: {}", name, e))
 	}
 }
 
-pub fn load() -> Config {
-	let path = "/etc/fridge/fridge.toml";
-	match parse_config_at(path) {
-		Ok(config) => config,
-		Err(e) => {
-			error!("Could not load configuration file at {}: {}", path, e);
-			DEFAULT_CONFIG.clone()
+/// Replaces the state file `name` with `contents` through a temporary file, so that readers
+/// never see a partially written file, or appends them to it in a single write
+pub fn write_state(name: &str, contents: &str, append: bool) -> Result<()> {
+	write_state_at(&state_dir(), name, contents, append)
+}
+
+/// Like [`write_state`], in `dir` rather than the state directory
+pub fn write_state_at(dir: &Path, name: &str, contents: &str, append: bool) -> Result<()> {
+	if !STATE_FILES.contains(&name) {
+		bail!("Could not write state file {:?}: expected one of {}", name, STATE_FILES.join(", "));
+	}
+
+	let path = dir.join(name);
+	let result = fs::create_dir_all(dir).and_then(|_| {
+		if append {
+			OpenOptions::new().create(true).append(true).open(&path)?.write_all(contents.as_bytes())
+		} else {
+			let tmp_path = path.with_extension("tmp");
+			fs::write(&tmp_path, contents)?;
+			fs::rename(&tmp_path, &path)
 		}
+	});
+	if let Err(e) = result {
+		bail!("Could not write {}: {}", path.display(), e);
 	}
+
+	Ok(())
+}
+
+/// Replaces the snapshot configurations in the configuration file, keeping everything else
+///
+/// Configurations set by drop-in files or environment variables are left to them, see
+/// [`own_entries`].
+pub fn save_snapshots(snapshots: &[SnapshotConfig]) -> Result<()> {
+	validate_snapshots(snapshots)?;
+	let (layers, loaded, main, document) = load_for_saving()?;
+	let snapshots = own_entries(&layers, &main, &document, "snapshots", snapshots, &loaded.snapshots, |snapshot| snapshot.name.clone())?;
+	install(&replace_section(&document, "snapshots", &snapshots)?)
+}
+
+/// Replaces the remote configurations in the configuration file, keeping everything else, and
+/// like [`save_snapshots`] the ones set elsewhere
+pub fn save_remotes(remotes: &[RemoteConfig]) -> Result<()> {
+	validate_remotes(remotes)?;
+	let (layers, loaded, main, document) = load_for_saving()?;
+	let remotes = own_entries(&layers, &main, &document, "remotes", remotes, &loaded.remotes, |remote| {
+		remote.name.clone().unwrap_or_else(|| format!("{}:{}", remote.host.as_deref().unwrap_or_default(), remote.path))
+	})?;
+	install(&replace_section(&document, "remotes", &remotes)?)
 }
 
-fn parse_config_at(path: &str) -> Result<Config> {
-	let s = fs::read_to_string(path)?;
-	let config: RawConfig = toml::from_str(&s)?;
-	Ok(config.into())
+/// The merged layers and the configuration they make, along with the source and contents of
+/// the configuration file
+fn load_for_saving() -> Result<(Layers, Config, String, String)> {
+	let layers = load_layers()?;
+	let loaded = from_value(toml::Value::Table(layers.value.clone()))?;
+	let main = sources()?.into_iter().next().map(|(source, _)| source).unwrap_or_default();
+	Ok((layers, loaded, main, read_document()?))
+}
+
+/// Entries of the `key` array to write to the configuration file `document`, read from `main`,
+/// given the `edited` entries and the `loaded` ones they were edited from, matched by `id`
+///
+/// Entries that other layers set anything in are theirs: an unchanged one is written as
+/// `document` has it, if at all, and changing or removing one is an error, since the other
+/// layers would still override the configuration file.
+fn own_entries<T: Serialize + PartialEq>(layers: &Layers, main: &str, document: &str, key: &str, edited: &[T], loaded: &[T], id: impl Fn(&T) -> String) -> Result<Vec<toml::Value>> {
+	let document: toml::Value = toml::from_str(document)?;
+	let main_entries = document.get(key).and_then(toml::Value::as_array).cloned().unwrap_or_default();
+	let main_entry = |i: usize| layers.origins.get(&format!("{}.{}", key, i))
+		.filter(|origin| origin.source == main)
+		.and_then(|origin| origin.path.rsplit('.').next()?.parse::<usize>().ok())
+		.and_then(|j| main_entries.get(j).cloned());
+
+	let mut entries = Vec::new();
+	for entry in edited {
+		let others = loaded.iter().position(|other| id(other) == id(entry))
+			.map(|i| (i, layers.other_sources(key, i, main)))
+			.filter(|(_, others)| !others.is_empty());
+		match others {
+			Some((i, others)) => {
+				if loaded[i] != *entry {
+					bail!("Could not save {}: it is set in {}, edit it there", id(entry), others.join(", "));
+				}
+				entries.extend(main_entry(i));
+			},
+			None => entries.push(toml::Value::try_from(entry)?),
+		}
+	}
+	for (i, entry) in loaded.iter().enumerate() {
+		let others = layers.other_sources(key, i, main);
+		if !others.is_empty() && !edited.iter().any(|other| id(other) == id(entry)) {
+			bail!("Could not remove {}: it is set in {}, remove it there", id(entry), others.join(", "));
+		}
+	}
+
+	Ok(entries)
+}
+
+/// Reads the configuration file as is, comments included, without its drop-in files
+///
+/// If it doesn't exist yet, this is a document with the default snapshot configurations, which
+/// are what [`load`] falls back to, so that saving another section keeps them.
+pub fn read_document() -> Result<String> {
+	let path = config_file();
+	match fs::read_to_string(path) {
+		Ok(document) => Ok(document),
+		Err(e) if e.kind() == ErrorKind::NotFound => replace_section("", "snapshots", &DEFAULT_CONFIG.snapshots),
+		Err(e) => bail!("Could not read configuration file at {}: {}", path.display(), e),
+	}
+}
+
+/// Parses and checks a whole configuration document
+pub fn parse_document(document: &str) -> Result<Config> {
+	let config = parse_unchecked(document)?;
+	config.validate()?;
+	Ok(config)
+}
+
+/// Parses a configuration document without checking it
+pub fn parse_unchecked(document: &str) -> Result<Config> {
+	let raw: RawConfig = toml::from_str(document)?;
+	Ok(raw.into())
+}
+
+/// Converts a parsed document into a configuration without checking it
+pub fn from_value(value: toml::Value) -> Result<Config> {
+	let raw: RawConfig = value.try_into()?;
+	Ok(raw.into())
+}
+
+/// Makes `document` the configuration file if it is valid, which takes root
+///
+/// This is the privileged end of [`install`], run as `fridge config import`.
+pub fn write(document: &str) -> Result<()> {
+	write_at(config_file(), document)
+}
+
+/// Replaces the file at `path` with `document` through a temporary file, so that readers never
+/// see a partially written configuration
+fn write_at(path: &Path, document: &str) -> Result<()> {
+	parse_document(document)?;
+	let result = path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| {
+		let tmp_path = path.with_extension("toml.tmp");
+		fs::write(&tmp_path, document)?;
+		fs::rename(&tmp_path, path)
+	});
+	if let Err(e) = result {
+		bail!("Could not write configuration file at {}: {}", path.display(), e);
+	}
+
+	Ok(())
+}
+
+/// Makes `document` the configuration file from an unprivileged process, by passing it to
+/// `fridge config import` through sudo like the snapshots are taken
+pub fn install(document: &str) -> Result<()> {
+	parse_document(document)?;
+	run_privileged(&["config", "import", "-"], document).map_err(|e| anyhow!("Could not install configuration: {}", e))
+}
+
+/// Runs this executable with `args` through sudo, passing `input` on its standard input
+fn run_privileged(args: &[&str], input: &str) -> Result<()> {
+	let exe = env::current_exe()?;
+	let mut command = Command::new("sudo");
+	command.arg(exe);
+	if let Some(path) = CONFIG_FILE.get() {
+		command.arg("--config").arg(path);
+	}
+	let mut child = command
+		.args(args)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::null())
+		.stderr(Stdio::piped())
+		.spawn()?;
+	if let Some(mut stdin) = child.stdin.take() {
+		stdin.write_all(input.as_bytes())?;
+	}
+	let output = child.wait_with_output()?;
+	if !output.status.success() {
+		bail!("{}", str::from_utf8(&output.stderr).unwrap_or_default().trim());
+	}
+
+	Ok(())
+}
+
+/// Returns `document` with its `key` section replaced by `value`, keeping everything else as
+/// it is, comments included
+pub fn replace_section<T: Serialize + ?Sized>(document: &str, key: &str, value: &T) -> Result<String> {
+	let mut document: toml_edit::Document = document.parse()?;
+	let mut section = toml::value::Table::new();
+	section.insert(key.to_string(), toml::Value::try_from(value)?);
+	let mut replacement: toml_edit::Document = toml::to_string(&section)?.parse()?;
+
+	// The new tables go where the old ones were, under the comment above the first of them,
+	// or after all other tables
+	let old = match document.get(key) {
+		Some(toml_edit::Item::Table(table)) => Some(table),
+		Some(toml_edit::Item::ArrayOfTables(array)) => array.get(0),
+		_ => None,
+	};
+	let prefix = old.and_then(|table| table.decor().prefix()).cloned();
+	let position = match old.and_then(toml_edit::Table::position) {
+		Some(position) => position,
+		None => {
+			let mut last = 0;
+			for_each_table(&mut document, &mut |table| last = last.max(table.position().unwrap_or_default()));
+			last + 1
+		},
+	};
+	for_each_table(&mut replacement, &mut |table| table.set_position(position));
+	let mut item = replacement.remove(key).unwrap_or_default();
+	let first = match &mut item {
+		toml_edit::Item::Table(table) => Some(table),
+		toml_edit::Item::ArrayOfTables(array) => array.get_mut(0),
+		_ => None,
+	};
+	if let (Some(first), Some(prefix)) = (first, prefix) {
+		first.decor_mut().set_prefix(prefix);
+	}
+
+	document.insert(key, item);
+	Ok(document.to_string())
+}
+
+/// Calls `f` with `table` and every table in it
+fn for_each_table(table: &mut toml_edit::Table, f: &mut dyn FnMut(&mut toml_edit::Table)) {
+	f(table);
+	for (_, item) in table.iter_mut() {
+		match item {
+			toml_edit::Item::Table(child) => for_each_table(child, f),
+			toml_edit::Item::ArrayOfTables(array) => {
+				for child in array.iter_mut() {
+					for_each_table(child, f);
+				}
+			},
+			_ => (),
+		}
+	}
 }
 
 #[cfg(test)]
 mod tests {
 
+use crate::testutil::TempDir;
+
 use super::*;
 
 static SAMPLE_CONFIG: &'static str = r#"
 [local]
 sudo = true
+nice = 10
+ionice = "idle"
+machine = "ThinkPad-T495"
+
+[conditions.snapshots]
+min_battery = 20
+
+[conditions.syncs]
+ac_only = true
+skip_metered = true
+
+[notifications]
+backup_completed = false
+backup_overdue_after = "1w"
 
 [[snapshots]]
 name = "root"
@@ -270,8 +1268,26 @@ suffix = "ThinkPad-T495"
 user = "li"
 host = "192.168.0.2"
 port = 22
+path = "/backup"
 sudo = true
 suffix = "ThinkPad-T495"
+identity_file = "/root/.ssh/fridge_ed25519"
+ssh_options = ["Compression=yes"]
+host_key = "SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s"
+bwlimit = "2M"
+connect_timeout = 5
+retries = 5
+verify = "sample:64"
+
+[remotes.retention]
+daily = 14
+monthly = 6
+max_age = "1y"
+
+[[remotes.bwlimit_schedule]]
+from = "22:00"
+to = "06:00"
+limit = "unlimited"
 "#;
 
 #[test]
@@ -280,8 +1296,14 @@ fn test_parse_config_str() {
 	assert_eq!(config, RawConfig{
 		local: Some(RawLocalConfig {
 			sudo: true,
-			path: None,
+			_path: None,
+			snapshot_root: None,
 			suffix: None,
+			nice: Some(10),
+			ionice: Some("idle".to_string()),
+			machine: Some("ThinkPad-T495".to_string()),
+			min_free: None,
+			low_space: None,
 		}),
 		snapshots: Some(vec![
 			RawSnapshotConfig {
@@ -292,6 +1314,11 @@ fn test_parse_config_str() {
 				weekly: Some(4),
 				monthly: Some(12),
 				yearly: Some(3),
+				snapshot_dir: None,
+				recursive: None,
+				include: None,
+				exclude: None,
+				budget: None,
 			},
 			RawSnapshotConfig {
 				name: "home".to_string(),
@@ -301,27 +1328,334 @@ fn test_parse_config_str() {
 				weekly: Some(4),
 				monthly: Some(12),
 				yearly: Some(0),
+				snapshot_dir: None,
+				recursive: None,
+				include: None,
+				exclude: None,
+				budget: None,
 			},
 		]),
+		groups: None,
 		remotes: Some(vec![
 			RawRemoteConfig {
+				name: None,
+				enabled: None,
 				user: None,
 				host: None,
 				port: None,
 				path: Some("/run/media/LILIS_5T/.snapshots".to_string()),
 				sudo: None,
 				suffix: Some("ThinkPad-T495".to_string()),
+				identity_file: None,
+				ssh_options: None,
+				host_key: None,
+				bwlimit: None,
+				bwlimit_schedule: None,
+				connect_timeout: None,
+				command_timeout: None,
+				retries: None,
+				verify: None,
+				min_free: None,
+				retention: None,
 			},
 			RawRemoteConfig {
+				name: None,
+				enabled: None,
 				user: Some("li".to_string()),
 				host: Some("192.168.0.2".to_string()),
 				port: Some(22),
-				path: None,
+				path: Some("/backup".to_string()),
 				sudo: Some(true),
 				suffix: Some("ThinkPad-T495".to_string()),
+				identity_file: Some("/root/.ssh/fridge_ed25519".to_string()),
+				ssh_options: Some(vec!["Compression=yes".to_string()]),
+				host_key: Some("SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s".to_string()),
+				bwlimit: Some("2M".to_string()),
+				bwlimit_schedule: Some(vec![
+					BandwidthWindowConfig {
+						from: "22:00".to_string(),
+						to: "06:00".to_string(),
+						limit: "unlimited".to_string(),
+					},
+				]),
+				connect_timeout: Some(5),
+				command_timeout: None,
+				retries: Some(5),
+				verify: Some("sample:64".to_string()),
+				min_free: None,
+				retention: Some(RetentionConfig {
+					daily: Some(14),
+					monthly: Some(6),
+					max_age: Some("1y".to_string()),
+					..Default::default()
+				}),
 			},
-		])
+		]),
+		conditions: Some(RawConditionsConfig {
+			snapshots: Some(RawRunConditions {
+				ac_only: None,
+				min_battery: Some(20),
+				skip_metered: None,
+			}),
+			syncs: Some(RawRunConditions {
+				ac_only: Some(true),
+				min_battery: None,
+				skip_metered: Some(true),
+			}),
+		}),
+		notifications: Some(RawNotificationsConfig {
+			snapshot_failed: None,
+			backup_failed: None,
+			backup_completed: Some(false),
+			backup_completed_after: None,
+			backup_overdue: None,
+			backup_overdue_after: Some("1w".to_string()),
+		}),
 	})
 }
 
+#[test]
+fn test_replace_section() {
+	let mut home = SnapshotConfig::new("home", "/home");
+	home.snapshot_dir = Some("/.snapshots/home".to_string());
+	let snapshots = vec![SnapshotConfig::new("root", "/"), home];
+
+	let document = replace_section(SAMPLE_CONFIG, "snapshots", &snapshots).unwrap();
+	let config: Config = toml::from_str::<RawConfig>(&document).unwrap().into();
+	let sample: Config = toml::from_str::<RawConfig>(SAMPLE_CONFIG).unwrap().into();
+	assert_eq!(config.snapshots, snapshots);
+	assert_eq!(config.snapshots[1].snapshot_location(&config.local).path, "/.snapshots/home");
+	assert_eq!(config.snapshots[0].snapshot_location(&config.local).path, "/.snapshots");
+	assert_eq!(config.local, sample.local);
+	assert_eq!(config.remotes, sample.remotes);
+	assert_eq!(config.notifications, sample.notifications);
+
+	let document = replace_section("", "snapshots", &snapshots).unwrap();
+	let config: Config = toml::from_str::<RawConfig>(&document).unwrap().into();
+	assert_eq!(config.snapshots, snapshots);
+
+	// Only the section itself is rewritten
+	let commented = "# Sent every night\n[local]\nsudo = true # not needed as root\n\n# What to snapshot\n[[snapshots]]\nname = \"var\"\npath = \"/var\"\n\n[[remotes]]\n# The NAS\npath = \"/backup\"\n";
+	let document = replace_section(commented, "snapshots", &snapshots).unwrap();
+	assert!(document.starts_with("# Sent every night\n[local]\nsudo = true # not needed as root\n\n# What to snapshot\n[[snapshots]]\n"));
+	assert!(document.ends_with("[[remotes]]\n# The NAS\npath = \"/backup\"\n"));
+	assert!(!document.contains("/var"));
+	let config: Config = toml::from_str::<RawConfig>(&document).unwrap().into();
+	assert_eq!(config.snapshots, snapshots);
+	assert_eq!(config.remotes.len(), 1);
+
+	let document = replace_section("# Nothing yet\n[local]\nsudo = true\n", "snapshots", &snapshots).unwrap();
+	assert!(document.starts_with("# Nothing yet\n[local]\nsudo = true\n"));
+	let config: Config = toml::from_str::<RawConfig>(&document).unwrap().into();
+	assert_eq!(config.snapshots, snapshots);
+}
+
+#[test]
+fn test_validate_snapshots() {
+	assert!(validate_snapshots(&[SnapshotConfig::new("root", "/"), SnapshotConfig::new("home", "/home")]).is_ok());
+	assert!(validate_snapshots(&[SnapshotConfig::new("root", "/"), SnapshotConfig::new("root", "/home")]).is_err());
+	assert!(validate_snapshots(&[SnapshotConfig::new("", "/")]).is_err());
+	assert!(validate_snapshots(&[SnapshotConfig::new("root@2000", "/")]).is_err());
+	assert!(validate_snapshots(&[SnapshotConfig::new("home", "home")]).is_err());
+
+	let mut root = SnapshotConfig::new("root", "/");
+	root.snapshot_dir = Some(".snapshots".to_string());
+	assert!(root.validate().is_err());
+}
+
+#[test]
+fn test_validate_groups() {
+	let snapshots = vec![SnapshotConfig::new("root", "/"), SnapshotConfig::new("home", "/home"), SnapshotConfig::new("srv", "/srv")];
+	let group = |name: &str, members: &[&str]| GroupConfig {
+		name: name.to_string(),
+		snapshots: members.iter().map(|member| member.to_string()).collect(),
+	};
+	assert!(validate_groups(&[group("system", &["root", "home"])], &snapshots).is_ok());
+	assert!(validate_groups(&[group("system", &["root", "var"])], &snapshots).is_err());
+	assert!(validate_groups(&[group("system", &[])], &snapshots).is_err());
+	assert!(validate_groups(&[group("root", &["home"])], &snapshots).is_err());
+	assert!(validate_groups(&[group("system", &["root", "home"]), group("data", &["home", "srv"])], &snapshots).is_err());
+	assert!(validate_groups(&[group("system", &["root", "home"]), group("system", &["srv"])], &snapshots).is_err());
+
+	let mut snapshots = snapshots;
+	snapshots[1].hourly = 0;
+	assert!(validate_groups(&[group("system", &["root", "home"])], &snapshots).is_err());
+}
+
+#[test]
+fn test_snapshot_location() {
+	let mut local = DEFAULT_CONFIG.local.clone();
+	let mut home = SnapshotConfig::new("home", "/home");
+	assert_eq!(home.snapshot_location(&local).path, "/home/.snapshots");
+
+	local.suffix = ".fridge".to_string();
+	assert_eq!(home.snapshot_location(&local).path, "/home/.fridge");
+
+	local.snapshot_root = Some("/snapshots".to_string());
+	assert_eq!(home.snapshot_location(&local).path, "/snapshots/home");
+
+	home.snapshot_dir = Some("/mnt/top/@snapshots/home".to_string());
+	assert_eq!(home.snapshot_location(&local).path, "/mnt/top/@snapshots/home");
+
+	assert!(local.validate_snapshot_dirs().is_ok());
+	local.snapshot_root = Some("snapshots".to_string());
+	assert!(local.validate_snapshot_dirs().is_err());
+	local.snapshot_root = None;
+	local.suffix = "a/b".to_string();
+	assert!(local.validate_snapshot_dirs().is_err());
+}
+
+#[test]
+fn test_machine_id() {
+	let mut local = DEFAULT_CONFIG.local.clone();
+	assert_eq!(local.machine_id().unwrap(), "");
+
+	local.machine = Some("ThinkPad-T495".to_string());
+	assert_eq!(local.machine_id().unwrap(), "ThinkPad-T495");
+
+	local.machine = Some("../other".to_string());
+	assert!(local.machine_id().is_err());
+}
+
+#[test]
+fn test_parse_document() {
+	let config = parse_document(SAMPLE_CONFIG).unwrap();
+	assert_eq!(config.snapshots.len(), 2);
+	assert_eq!(config.remotes.len(), 2);
+
+	assert!(parse_document("[[snapshots]]\nname = \"root\"\npath = \"/\"\n\n[[snapshots]]\nname = \"root\"\npath = \"/home\"\n").is_err());
+	assert!(parse_document("[notifications]\nbackup_overdue_after = \"soon\"\n").is_err());
+	assert!(parse_document("[[snapshots]]\nname = ").is_err());
+	assert!(parse_document("[local]\nsudo = true\nsnapshot_path = \"/\"\n").is_err());
+	assert!(parse_document("[[remotes]]\nhost = \"nas\"\n").is_err());
+	assert!(parse_document("[[snapshots]]\nname = \"root+var\"\npath = \"/\"\n").is_err());
+
+	let config = parse_document("[[snapshots]]\nname = \"root\"\npath = \"/\"\nrecursive = true\nexclude = [\"var/lib/docker/**\"]\n").unwrap();
+	assert!(config.snapshots[0].recursive);
+	assert!(config.snapshots[0].include.is_empty());
+	assert_eq!(config.snapshots[0].exclude, vec!["var/lib/docker/**"]);
+}
+
+#[test]
+fn test_write_at() {
+	let dir = TempDir::new("config");
+	let path = dir.join("fridge/fridge.toml");
+	write_at(&path, SAMPLE_CONFIG).unwrap();
+	assert_eq!(fs::read_to_string(&path).unwrap(), SAMPLE_CONFIG);
+
+	// An invalid document leaves the file as it was
+	assert!(write_at(&path, "[[snapshots]]\nname = ").is_err());
+	assert_eq!(fs::read_to_string(&path).unwrap(), SAMPLE_CONFIG);
+}
+
+#[test]
+fn test_sources_at() {
+	let dir = TempDir::new("sources");
+	let path = dir.join("fridge.toml");
+	let conf_d = dir.join("conf.d");
+	fs::create_dir_all(&conf_d).unwrap();
+
+	// Without a configuration file, drop-in files are merged over the defaults
+	fs::write(conf_d.join("20-home.toml"), "[[snapshots]]\nname = \"home\"\ndaily = 30\n").unwrap();
+	fs::write(conf_d.join("10-nas.toml"), "[[remotes]]\nname = \"nas\"\nhost = \"nas\"\npath = \"/backup\"\nsudo = true\n").unwrap();
+	fs::write(conf_d.join("README"), "Not a configuration file").unwrap();
+	let sources = sources_at(&path, &conf_d).unwrap();
+	let names: Vec<String> = sources.iter().map(|(source, _)| source.clone()).collect();
+	assert_eq!(names, vec![DEFAULTS_SOURCE.to_string(), conf_d.join("10-nas.toml").display().to_string(), conf_d.join("20-home.toml").display().to_string()]);
+
+	let mut layers = Layers::default();
+	for (source, document) in sources {
+		layers.add(&source, toml::from_str(&document).unwrap());
+	}
+	let config = from_value(toml::Value::Table(layers.value)).unwrap();
+	config.validate().unwrap();
+	assert_eq!(config.snapshots.len(), 2);
+	assert_eq!(config.snapshots[1].daily, 30);
+	assert_eq!(config.snapshots[1].hourly, DEFAULT_HOURLY);
+	assert_eq!(config.remotes[0].name.as_deref(), Some("nas"));
+
+	fs::write(&path, SAMPLE_CONFIG).unwrap();
+	assert_eq!(sources_at(&path, &conf_d).unwrap()[0], (path.display().to_string(), SAMPLE_CONFIG.to_string()));
+}
+
+#[test]
+fn test_write_state_at() {
+	let dir = TempDir::new("state");
+	write_state_at(&dir, "health.toml", "[remotes]\n", false).unwrap();
+	write_state_at(&dir, "pins.toml", "snapshots = []\n", false).unwrap();
+	write_state_at(&dir, "pins.toml", "snapshots = [\"root@2000-01-01_00:00:00_daily\"]\n", false).unwrap();
+	write_state_at(&dir, "history.jsonl", "{}\n", true).unwrap();
+	write_state_at(&dir, "history.jsonl", "{}\n", true).unwrap();
+	assert!(write_state_at(&dir, "../fridge.toml", "", false).is_err());
+
+	assert_eq!(fs::read_to_string(dir.join("pins.toml")).unwrap(), "snapshots = [\"root@2000-01-01_00:00:00_daily\"]\n");
+	assert_eq!(fs::read_to_string(dir.join("history.jsonl")).unwrap(), "{}\n{}\n");
+	assert!(!dir.join("pins.tmp").exists());
+}
+
+#[test]
+fn test_replace_remotes() {
+	let sample: Config = toml::from_str::<RawConfig>(SAMPLE_CONFIG).unwrap().into();
+	let mut remotes = sample.remotes.clone();
+	remotes[0].enabled = false;
+	remotes.push(RemoteConfig::new(Some("backup.example.com"), "/srv/backup"));
+
+	let document = replace_section(SAMPLE_CONFIG, "remotes", &remotes).unwrap();
+	let config: Config = toml::from_str::<RawConfig>(&document).unwrap().into();
+	assert_eq!(config.remotes, remotes);
+	assert_eq!(config.snapshots, sample.snapshots);
+}
+
+#[test]
+fn test_own_entries() {
+	let main = "[[snapshots]]\nname = \"root\"\npath = \"/\"\n\n[[snapshots]]\nname = \"home\"\npath = \"/home\"\n";
+	let mut layers = Layers::default();
+	layers.add("fridge.toml", toml::from_str(main).unwrap());
+	layers.add("conf.d/10-srv.toml", toml::from_str("[[snapshots]]\nname = \"home\"\ndaily = 14\n\n[[snapshots]]\nname = \"srv\"\npath = \"/srv\"\n").unwrap());
+	let loaded = from_value(toml::Value::Table(layers.value.clone())).unwrap().snapshots;
+	let own = |edited: &[SnapshotConfig]| own_entries(&layers, "fridge.toml", main, "snapshots", edited, &loaded, |snapshot| snapshot.name.clone());
+
+	// Only the configuration file's own entries are written, and home as it has it
+	let mut edited = loaded.clone();
+	edited[0].hourly = 12;
+	edited.push(SnapshotConfig::new("var", "/var"));
+	let entries = own(&edited).unwrap();
+	let names: Vec<&str> = entries.iter().map(|entry| entry["name"].as_str().unwrap()).collect();
+	assert_eq!(names, ["root", "home", "var"]);
+	assert_eq!(entries[0]["hourly"].as_integer(), Some(12));
+	assert!(entries[1].get("daily").is_none());
+
+	let mut edited = loaded.clone();
+	edited[1].daily = 7;
+	assert!(own(&edited).is_err());
+	assert!(own(&loaded[..2]).is_err());
+	assert_eq!(own(&loaded[1..]).unwrap().len(), 1);
+}
+
+#[test]
+fn test_validate_remotes() {
+	let local = RemoteConfig::new(None, "/run/media/backup");
+	let ssh = RemoteConfig::new(Some("192.168.0.2"), "/backup");
+	assert!(validate_remotes(&[local.clone(), ssh.clone()]).is_ok());
+	assert!(validate_remotes(&[ssh.clone(), ssh.clone()]).is_err());
+	let mut named = local.clone();
+	named.name = Some("backup".to_string());
+	let mut other = ssh.clone();
+	other.name = named.name.clone();
+	assert!(validate_remotes(&[named, other]).is_err());
+	assert!(RemoteConfig::new(None, "backup").validate().is_err());
+	assert!(RemoteConfig::new(Some(""), "/backup").validate().is_err());
+
+	let mut remote = local.clone();
+	remote.port = Some(22);
+	assert!(remote.validate().is_err());
+
+	let mut remote = ssh.clone();
+	remote.retention = Some(RetentionConfig {
+		max_age: Some("forever".to_string()),
+		..Default::default()
+	});
+	assert!(remote.validate().is_err());
+}
+
 }
@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::fs;
+use std::process::Command;
+use std::str;
+
+use anyhow::{Result, anyhow, bail};
+use log::warn;
+
+use crate::config::SnapshotConfig;
+
+/// Mount points under which filesystems are rather backup targets than something to snapshot,
+/// such as removable disks
+const SKIPPED_MOUNT_POINTS: &[&str] = &["/run/", "/media/", "/mnt/", "/tmp/"];
+
+/// Directory snapshots are kept in when the default one is taken by another tool, e.g. snapper
+const FALLBACK_SNAPSHOT_DIR: &str = ".fridge";
+
+/// Btrfs mount, from a line of /proc/self/mountinfo
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mount {
+    /// Path in the filesystem that is mounted, e.g. "/@home", or a directory in a subvolume
+    /// for bind mounts
+    pub root: String,
+    pub mount_point: String,
+    pub device: String,
+    /// Path of the mounted subvolume in the filesystem, e.g. "/@home"
+    pub subvol: Option<String>,
+    pub subvol_id: Option<u64>,
+    pub read_only: bool,
+}
+
+/// Subvolume of a btrfs filesystem, from a line of `btrfs subvolume list -a`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeEntry {
+    pub id: u64,
+    /// ID of the subvolume holding this one
+    pub parent: u64,
+    /// Path in the filesystem, e.g. "/@/home"
+    pub path: String,
+}
+
+/// Mounted subvolume that can be snapshotted
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subvolume {
+    pub id: Option<u64>,
+    /// Path in the filesystem, e.g. "/@/home"
+    pub subvol: String,
+    pub mount_point: String,
+    pub device: String,
+    /// Directory to keep the snapshots in, if the default `<mount point>/.snapshots` is taken
+    pub snapshot_dir: Option<String>,
+}
+
+impl Subvolume {
+    /// Name of the snapshots, after the mount point, e.g. "root" for / or "usr-local" for
+    /// /usr/local
+    pub fn name(&self) -> String {
+        match self.mount_point.trim_matches('/') {
+            "" => "root".to_string(),
+            // Home of the root user, as "root" is taken by /
+            "root" => "root-home".to_string(),
+            path => path.replace(['/', '@'], "-"),
+        }
+    }
+
+    /// Snapshot configuration keeping the default number of snapshots
+    pub fn proposal(&self) -> SnapshotConfig {
+        let mut snapshot_cfg = SnapshotConfig::new(&self.name(), &self.mount_point);
+        snapshot_cfg.snapshot_dir = self.snapshot_dir.clone();
+        snapshot_cfg
+    }
+
+    /// Describes the subvolume, e.g. "subvolume /@/home (ID 264) on /dev/nvme0n1p2"
+    pub fn description(&self) -> String {
+        match self.id {
+            Some(id) => format!("subvolume {} (ID {}) on {}", self.subvol, id, self.device),
+            None => format!("subvolume {} on {}", self.subvol, self.device),
+        }
+    }
+}
+
+/// Finds the mounted btrfs subvolumes of this machine, listing the subvolumes of each
+/// filesystem with `btrfs subvolume list` to tell more about them
+pub fn discover(sudo: bool) -> Result<Vec<Subvolume>> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").map_err(|e| anyhow!("Could not read mounts: {}", e))?;
+    let mounts = parse_mountinfo(&mountinfo);
+
+    let mut trees = HashMap::new();
+    for mount in &mounts {
+        if trees.contains_key(&mount.device) {
+            continue;
+        }
+        match list_subvolumes(&mount.mount_point, sudo) {
+            Ok(tree) => {
+                trees.insert(mount.device.clone(), tree);
+            },
+            Err(e) => warn!("{}", e),
+        }
+    }
+
+    Ok(find_subvolumes(&mounts, &trees))
+}
+
+/// Snapshot configurations of the `subvolumes` that aren't configured in `existing` yet
+pub fn propose(subvolumes: &[Subvolume], existing: &[SnapshotConfig]) -> Vec<SnapshotConfig> {
+    subvolumes.iter()
+        .filter(|subvolume| !existing.iter().any(|snapshot_cfg| snapshot_cfg.path == subvolume.mount_point))
+        .map(Subvolume::proposal)
+        .filter(|proposal| !existing.iter().any(|snapshot_cfg| snapshot_cfg.name == proposal.name))
+        .collect()
+}
+
+pub fn list_subvolumes(mount_point: &str, sudo: bool) -> Result<Vec<TreeEntry>> {
+    let (program, args) = if sudo {
+        ("sudo", vec!["btrfs", "subvolume", "list", "-a", mount_point])
+    } else {
+        ("btrfs", vec!["subvolume", "list", "-a", mount_point])
+    };
+    let output = Command::new(program)
+        .args(args)
+        .output()?;
+
+    if !output.status.success() {
+        bail!("Could not list the subvolumes at {}: {}", mount_point, str::from_utf8(&output.stderr).unwrap_or_default().trim());
+    }
+
+    Ok(parse_subvolume_list(str::from_utf8(&output.stdout)?))
+}
+
+/// Parses the btrfs mounts out of /proc/self/mountinfo
+pub fn parse_mountinfo(text: &str) -> Vec<Mount> {
+    let mut mounts = Vec::new();
+    for line in text.lines() {
+        // Optional fields come before the separator, filesystem fields after it
+        let (mount_fields, fs_fields) = match line.split_once(" - ") {
+            Some(fields) => fields,
+            None => continue,
+        };
+        let mount_fields: Vec<&str> = mount_fields.split(' ').collect();
+        let fs_fields: Vec<&str> = fs_fields.split(' ').collect();
+        if mount_fields.len() < 6 || fs_fields.len() < 3 || fs_fields[0] != "btrfs" {
+            continue;
+        }
+
+        let mut subvol = None;
+        let mut subvol_id = None;
+        for option in fs_fields[2].split(',') {
+            if let Some(value) = option.strip_prefix("subvol=") {
+                subvol = Some(unescape(value));
+            } else if let Some(value) = option.strip_prefix("subvolid=") {
+                subvol_id = value.parse().ok();
+            }
+        }
+
+        mounts.push(Mount {
+            root: unescape(mount_fields[3]),
+            mount_point: unescape(mount_fields[4]),
+            device: unescape(fs_fields[1]),
+            subvol,
+            subvol_id,
+            read_only: mount_fields[5].split(',').any(|option| option == "ro"),
+        });
+    }
+
+    mounts
+}
+
+/// Parses the output of `btrfs subvolume list -a`, e.g.
+/// "ID 258 gen 2105 top level 256 path @/var"
+pub fn parse_subvolume_list(text: &str) -> Vec<TreeEntry> {
+    let mut entries = Vec::new();
+    for line in text.lines() {
+        let (fields, path) = match line.split_once(" path ") {
+            Some(fields) => fields,
+            None => continue,
+        };
+        let fields: Vec<&str> = fields.split_whitespace().collect();
+        let id = fields.iter().position(|field| *field == "ID").and_then(|i| fields.get(i + 1)).and_then(|id| id.parse().ok());
+        let parent = fields.iter().position(|field| *field == "level").and_then(|i| fields.get(i + 1)).and_then(|id| id.parse().ok());
+        if let (Some(id), Some(parent)) = (id, parent) {
+            let path = path.trim().trim_start_matches("<FS_TREE>/");
+            entries.push(TreeEntry {
+                id,
+                parent,
+                path: format!("/{}", path),
+            });
+        }
+    }
+
+    entries
+}
+
+/// Subvolumes that are mounted on their own in `mounts`, with what the subvolume trees of
+/// their filesystems in `trees`, by device, tell about them
+pub fn find_subvolumes(mounts: &[Mount], trees: &HashMap<String, Vec<TreeEntry>>) -> Vec<Subvolume> {
+    let mut subvolumes: Vec<Subvolume> = Vec::new();
+    for mount in mounts {
+        if mount.read_only || is_skipped(&mount.mount_point) {
+            continue;
+        }
+        // Bind mounts of a directory rather than of a subvolume
+        let subvol = mount.subvol.clone().unwrap_or_else(|| mount.root.clone());
+        if mount.root != subvol {
+            continue;
+        }
+        // Subvolumes mounted in several places are snapshotted once
+        if subvolumes.iter().any(|other| other.device == mount.device && other.subvol == subvol) {
+            continue;
+        }
+
+        let tree = trees.get(&mount.device).map(Vec::as_slice).unwrap_or_default();
+        let id = mount.subvol_id.or_else(|| tree.iter().find(|entry| entry.path == subvol).map(|entry| entry.id));
+        subvolumes.push(Subvolume {
+            id,
+            subvol: subvol.clone(),
+            mount_point: mount.mount_point.clone(),
+            device: mount.device.clone(),
+            snapshot_dir: snapshot_dir(mount, &subvol, mounts, tree),
+        });
+    }
+
+    subvolumes
+}
+
+/// Directory for the snapshots of the subvolume mounted at `mount`, if `.snapshots` is taken
+/// by another tool: a subvolume is mounted there, or it holds snapper's numbered snapshots
+fn snapshot_dir(mount: &Mount, subvol: &str, mounts: &[Mount], tree: &[TreeEntry]) -> Option<String> {
+    let default_dir = join(&mount.mount_point, ".snapshots");
+    let mounted = mounts.iter().any(|other| other.mount_point == default_dir);
+    let snapper_prefix = join(subvol, ".snapshots/");
+    let snapper = tree.iter().any(|entry| {
+        entry.path.strip_prefix(&snapper_prefix)
+            .and_then(|rest| rest.strip_suffix("/snapshot"))
+            .is_some_and(|number| number.parse::<u64>().is_ok())
+    });
+
+    (mounted || snapper).then(|| join(&mount.mount_point, FALLBACK_SNAPSHOT_DIR))
+}
+
+fn is_skipped(mount_point: &str) -> bool {
+    SKIPPED_MOUNT_POINTS.iter().any(|prefix| mount_point.starts_with(prefix))
+        || mount_point.ends_with("/.snapshots")
+        || mount_point.contains("/.snapshots/")
+}
+
+fn join(path: &str, name: &str) -> String {
+    format!("{}/{}", path.trim_end_matches('/'), name)
+}
+
+/// Undoes the octal escapes of spaces and other special characters in mountinfo, e.g. \040
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4)
+            .and_then(|digits| str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                unescaped.push(byte);
+                i += 4;
+            },
+            (byte, _) => {
+                unescaped.push(byte);
+                i += 1;
+            },
+        }
+    }
+
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPENSUSE_MOUNTINFO: &str = include_str!("../tests/fixtures/mountinfo-opensuse.txt");
+    const OPENSUSE_SUBVOLUMES: &str = include_str!("../tests/fixtures/subvolumes-opensuse.txt");
+    const FEDORA_MOUNTINFO: &str = include_str!("../tests/fixtures/mountinfo-fedora.txt");
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mounts = parse_mountinfo(FEDORA_MOUNTINFO);
+        assert_eq!(mounts.len(), 7);
+        assert_eq!(mounts[0], Mount {
+            root: "/root".to_string(),
+            mount_point: "/".to_string(),
+            device: "/dev/nvme0n1p3".to_string(),
+            subvol: Some("/root".to_string()),
+            subvol_id: Some(257),
+            read_only: false,
+        });
+        assert_eq!(mounts[4].mount_point, "/mnt/data copy");
+        assert!(mounts[6].read_only);
+    }
+
+    #[test]
+    fn test_parse_subvolume_list() {
+        let entries = parse_subvolume_list(OPENSUSE_SUBVOLUMES);
+        assert_eq!(entries.len(), 12);
+        assert_eq!(entries[0], TreeEntry { id: 256, parent: 5, path: "/@".to_string() });
+        assert_eq!(entries[11], TreeEntry { id: 270, parent: 258, path: "/@/var/lib/machines".to_string() });
+    }
+
+    #[test]
+    fn test_find_subvolumes_opensuse() {
+        let trees = HashMap::from([("/dev/nvme0n1p2".to_string(), parse_subvolume_list(OPENSUSE_SUBVOLUMES))]);
+        let subvolumes = find_subvolumes(&parse_mountinfo(OPENSUSE_MOUNTINFO), &trees);
+        let names: Vec<String> = subvolumes.iter().map(Subvolume::name).collect();
+        assert_eq!(names, vec!["root", "home", "var", "opt", "root-home", "srv", "usr-local", "boot-grub2-x86_64-efi"]);
+
+        // Snapper keeps its snapshots of / in /.snapshots
+        assert_eq!(subvolumes[0].snapshot_dir.as_deref(), Some("/.fridge"));
+        assert_eq!(subvolumes[1].snapshot_dir, None);
+        assert_eq!(subvolumes[1].description(), "subvolume /@/home (ID 264) on /dev/nvme0n1p2");
+    }
+
+    #[test]
+    fn test_find_subvolumes_fedora() {
+        let subvolumes = find_subvolumes(&parse_mountinfo(FEDORA_MOUNTINFO), &HashMap::new());
+        let mount_points: Vec<&str> = subvolumes.iter().map(|subvolume| subvolume.mount_point.as_str()).collect();
+        // Not the bind mount, the second mount of the same subvolume, the removable disk or the
+        // read-only one
+        assert_eq!(mount_points, vec!["/", "/home", "/var/lib/data"]);
+        assert_eq!(subvolumes[2].name(), "var-lib-data");
+        assert_eq!(subvolumes[2].id, Some(5));
+    }
+
+    #[test]
+    fn test_propose() {
+        let subvolumes = find_subvolumes(&parse_mountinfo(FEDORA_MOUNTINFO), &HashMap::new());
+        let existing = vec![SnapshotConfig::new("root", "/")];
+        let proposals = propose(&subvolumes, &existing);
+        assert_eq!(proposals.len(), 2);
+        assert_eq!(proposals[0], SnapshotConfig::new("home", "/home"));
+        assert_eq!(proposals[1].path, "/var/lib/data");
+    }
+}
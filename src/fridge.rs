@@ -1,12 +1,26 @@
+use std::collections::BTreeMap;
+use std::fmt;
 use std::path::Path;
-use std::process::{Command, Stdio};
+use std::io::Read;
+use std::process::{Child, Command, ExitStatus, Stdio};
 use std::str;
+use std::thread::{self, JoinHandle};
 
-use anyhow::{Result, bail};
+use anyhow::{Result, anyhow, bail};
 use chrono::{DateTime, TimeZone, Utc};
-use log::{debug,info};
+use log::{debug,info,warn};
 use thiserror::Error;
 
+use crate::cancel::{CancelToken, Registration};
+use crate::history::{self, Operation, Record};
+use crate::nested;
+use crate::relay::{self, Sink};
+use crate::space::{self, Threshold};
+use crate::retention::{self, PruneOpts, RetentionPolicy};
+use crate::ssh::{self, SshOpts};
+use crate::throttle::{BandwidthLimit, Priority};
+use crate::verify::{self, VerifyMode, VerifyOpts};
+
 #[derive(Error, Debug)]
 pub enum FridgeError {
     #[error("Could not parse {what:?} in {snapshot:?}")]
@@ -48,114 +62,424 @@ impl Snapshot {
 
         Ok(())
     }
+
+    /// Deletes the snapshot from `location`, which may be remote
+    pub fn delete_from(&self, location: &SnapshotRepositoryLocation, sudo: bool, verbose: i32) -> Result<()> {
+        if !location.is_remote() {
+            return self.delete(sudo, verbose);
+        }
+
+        let path = Path::new(&location.path).join(&self.full_name).to_str().unwrap().to_string();
+        let (program, args) = ssh::remote_command(location, sudo, &["btrfs", "subvolume", "delete", &path])?;
+        if verbose > 0 {
+            debug!("{} {}", program, args.join(" "));
+        }
+
+        let output = Command::new(program)
+            .args(args)
+            .output()?;
+
+        if !output.status.success() {
+            bail!("Could not delete snapshot at {}: {}", &path, str::from_utf8(&output.stderr).unwrap());
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Default)]
 pub struct SnapshotOpts {
     pub src: String,
+    /// Directory to create the snapshot in, created as a subvolume if missing
+    pub dst: String,
     pub name: String,
     pub suffix: Option<String>,
+    /// Paths of the subvolumes nested in `src`, relative to it and parents first, to snapshot
+    /// along with it as members of a recursive snapshot, see [`crate::nested`]
+    pub nested: Vec<String>,
     pub sudo: bool,
     pub dry_run: bool,
     pub verbose: i32,
 }
 
 pub fn snapshot(opts: &SnapshotOpts) -> Result<()> {
+    snapshot_group(std::slice::from_ref(opts))
+}
+
+/// Takes a snapshot of each of `members` as one group snapshot, see [`crate::groups`]: all
+/// under the same date and tier, back-to-back after a single filesystem sync, and none of them
+/// if any fails
+pub fn snapshot_group(members: &[SnapshotOpts]) -> Result<()> {
     let date = Utc::now();
     let date_str = format!("{}", date.format("%Y-%m-%d_%H:%M:%S"));
-    let full_name = format!("{}@{}_{}", &opts.name, &date_str, opts.suffix.clone().unwrap_or("manual".to_string()));
-    let base_dst_path = Path::new(&opts.src).join(".snapshots");
-    let dst_path = base_dst_path.join(&full_name);
-    let dst = dst_path.to_str().unwrap();
-    if opts.dry_run {
-        info!("Would create snapshot of {} at {}", opts.src, dst);
+    if members.iter().any(|opts| opts.dry_run) {
+        for opts in members {
+            let full_name = format!("{}@{}_{}", &opts.name, &date_str, opts.suffix.clone().unwrap_or("manual".to_string()));
+            info!("Would create snapshot of {} at {}", opts.src, Path::new(&opts.dst).join(&full_name).display());
+            for path in &opts.nested {
+                info!("Would create snapshot of nested subvolume {} along with it", path);
+            }
+        }
         return Ok(());
     }
 
-    if !base_dst_path.exists() {
-        let base_dst_path_str = base_dst_path.to_str().unwrap();
-        eprintln!("{}", base_dst_path_str);
-        let (program, args) = if opts.sudo {
-            ("sudo", vec!["mkdir", "-p", base_dst_path_str])
+    for opts in members {
+        ensure_snapshot_dir(&opts.dst, opts.sudo, opts.verbose)?;
+    }
+    // Flushing once up front keeps the members close together in time
+    if members.len() > 1 {
+        sync_filesystems()?;
+    }
+
+    // Snapshots created so far, with the directory they are in
+    let mut created: Vec<(String, &str)> = Vec::new();
+    for opts in members {
+        let stamp = format!("{}_{}", &date_str, opts.suffix.clone().unwrap_or("manual".to_string()));
+        let full_name = format!("{}@{}", &opts.name, &stamp);
+        let dst_path = Path::new(&opts.dst).join(&full_name);
+        let result = create_snapshot(&opts.src, dst_path.to_str().unwrap(), opts.sudo);
+        history::record(Record::new(Operation::Snapshot, date, &opts.name, Some(&full_name), &opts.src, None, &result));
+        if let Err(e) = result {
+            if created.is_empty() {
+                return Err(e);
+            }
+            // Restoring part of a group would mix snapshots taken at different times, which is what it avoids
+            delete_incomplete(&created, opts.sudo, opts.verbose);
+            bail!("Could not snapshot {} along with the rest of its group: {}", opts.src, e);
+        }
+        created.push((full_name, &opts.dst));
+
+        for path in &opts.nested {
+            let member_full_name = format!("{}@{}", nested::member_name(&opts.name, path), &stamp);
+            let src_path = Path::new(&opts.src).join(path);
+            let src = src_path.to_str().unwrap();
+            let result = create_snapshot(src, Path::new(&opts.dst).join(&member_full_name).to_str().unwrap(), opts.sudo);
+            history::record(Record::new(Operation::Snapshot, date, &opts.name, Some(&member_full_name), src, None, &result));
+            if let Err(e) = result {
+                // Half a recursive snapshot would restore with empty directories, which is what it avoids
+                delete_incomplete(&created, opts.sudo, opts.verbose);
+                bail!("Could not snapshot nested subvolume {} of {}: {}", path, opts.src, e);
+            }
+            created.push((member_full_name, &opts.dst));
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes the snapshots `created` of a recursive or group snapshot that could not be taken
+/// whole, latest first
+fn delete_incomplete(created: &[(String, &str)], sudo: bool, verbose: i32) {
+    for (full_name, dst) in created.iter().rev() {
+        if let Err(e) = parse_snapshot_name(full_name, dst).and_then(|snapshot| snapshot.delete(sudo, verbose)) {
+            warn!("Could not delete snapshot {} of an incomplete snapshot: {}", full_name, e);
+        }
+    }
+}
+
+/// Flushes the filesystems to disk, so that snapshots taken right after it don't wait for it
+/// one by one
+fn sync_filesystems() -> Result<()> {
+    let output = Command::new("sync")
+        .output()?;
+
+    if !output.status.success() {
+        bail!("Could not sync filesystems: {}", str::from_utf8(&output.stderr).unwrap().trim());
+    }
+
+    Ok(())
+}
+
+fn create_snapshot(src: &str, dst: &str, sudo: bool) -> Result<()> {
+    let (program, args) = if sudo {
+        ("sudo", vec!["btrfs", "subvolume", "snapshot", "-r", src, dst])
+    } else {
+        ("btrfs", vec!["subvolume", "snapshot", "-r", src, dst])
+    };
+    let output = Command::new(program)
+        .args(args)
+        .output()?;
+
+    if !output.status.success() {
+        bail!("Could not create snapshot: {}", str::from_utf8(&output.stderr).unwrap());
+    }
+
+    info!("Created read-only snapshot at {}", dst);
+
+    Ok(())
+}
+
+/// Creates the snapshot directory `path` as a subvolume only root can enter, so that
+/// snapshots of the subvolume it sits in leave it out and other users can't read through
+/// the snapshots. An existing directory is used as it is.
+pub fn ensure_snapshot_dir(path: &str, sudo: bool, verbose: i32) -> Result<()> {
+    if Path::new(path).exists() {
+        return Ok(());
+    }
+
+    let parent = Path::new(path).parent().and_then(|parent| parent.to_str()).unwrap_or("/");
+    let steps: [&[&str]; 3] = [
+        &["mkdir", "-p", parent],
+        &["btrfs", "subvolume", "create", path],
+        &["chmod", "0700", path],
+    ];
+    for step in steps {
+        let (program, args) = if sudo {
+            ("sudo", step.to_vec())
         } else {
-            ("mkdir", vec!["-p", base_dst_path_str])
+            (step[0], step[1..].to_vec())
         };
-        Command::new(program)
+        if verbose > 0 {
+            debug!("{} {}", program, args.join(" "));
+        }
+
+        let output = Command::new(program)
             .args(args)
             .output()?;
+
+        if !output.status.success() {
+            bail!("Could not create snapshot directory {}: {}", path, str::from_utf8(&output.stderr).unwrap());
+        }
     }
 
-    let (program, args) = if opts.sudo {
-        ("sudo", vec!["btrfs", "subvolume", "snapshot", "-r", &opts.src, dst])
+    info!("Created snapshot directory {}", path);
+
+    Ok(())
+}
+
+/// Checks that `path` is a btrfs subvolume, which is all `btrfs subvolume snapshot` can take
+pub fn check_subvolume(path: &str, sudo: bool) -> Result<()> {
+    let (program, args) = if sudo {
+        ("sudo", vec!["btrfs", "subvolume", "show", path])
     } else {
-        ("btrfs", vec!["subvolume", "snapshot", "-r", &opts.src, dst])
+        ("btrfs", vec!["subvolume", "show", path])
     };
     let output = Command::new(program)
         .args(args)
         .output()?;
 
     if !output.status.success() {
-        bail!("Could not create snapshot: {}", str::from_utf8(&output.stderr).unwrap());
+        bail!("Could not find a btrfs subvolume at {}: {}", path, str::from_utf8(&output.stderr).unwrap().trim());
     }
 
-    info!("Created read-only snapshot at {}", dst);
+    Ok(())
+}
+
+pub struct RestoreOpts {
+    pub snapshot: Snapshot,
+    /// Local repository holding the snapshot and its members
+    pub location: SnapshotRepositoryLocation,
+    /// Path to create the writable subvolume at, which must not exist yet
+    pub dst: String,
+    pub sudo: bool,
+    pub dry_run: bool,
+    pub verbose: i32,
+}
+
+/// Restores a snapshot as a new writable subvolume at `opts.dst`, with writable snapshots of
+/// the members of a recursive snapshot in place of the empty directories of its nested
+/// subvolumes
+///
+/// Nothing is replaced: the restored subvolume can be inspected, then swapped in or set as
+/// default subvolume.
+pub fn restore(opts: &RestoreOpts) -> Result<()> {
+    restore_recorded(opts).map(|_| ())
+}
+
+/// Restores a snapshot like [`restore`], recording it in the history, and returns the
+/// subvolumes it created, outermost first
+fn restore_recorded(opts: &RestoreOpts) -> Result<Vec<String>> {
+    let started = Utc::now();
+    let result = restore_steps(opts);
+    if !opts.dry_run {
+        history::record(Record::new(Operation::Restore, started, &opts.snapshot.name, Some(&opts.snapshot.full_name), &opts.dst, None, &result));
+    }
+
+    result
+}
+
+fn restore_steps(opts: &RestoreOpts) -> Result<Vec<String>> {
+    if Path::new(&opts.dst).exists() {
+        bail!("Could not restore snapshot {} to {}: the path exists already", &opts.snapshot.full_name, &opts.dst);
+    }
+
+    let members = nested::members(&opts.snapshot, &opts.location, opts.sudo, opts.verbose)?;
+    let src = Path::new(&opts.snapshot.path).join(&opts.snapshot.full_name).to_str().unwrap().to_string();
+    let mut steps = vec![vec!["btrfs".to_string(), "subvolume".to_string(), "snapshot".to_string(), src, opts.dst.clone()]];
+    for (path, member) in &members {
+        let member_dst = Path::new(&opts.dst).join(path).to_str().unwrap().to_string();
+        let member_src = Path::new(&member.path).join(&member.full_name).to_str().unwrap().to_string();
+        steps.push(vec!["rmdir".to_string(), member_dst.clone()]);
+        steps.push(vec!["btrfs".to_string(), "subvolume".to_string(), "snapshot".to_string(), member_src, member_dst]);
+    }
+
+    // Subvolumes created so far, deleted again if a later step fails
+    let mut created: Vec<String> = Vec::new();
+    for step in steps {
+        if opts.dry_run {
+            let sudo = if opts.sudo { "sudo " } else { "" };
+            info!("Would run the following command: {}{}", sudo, step.join(" "));
+            continue;
+        }
+
+        if let Err(e) = run_step(&step, opts.sudo, opts.verbose) {
+            delete_restored(&created, opts.sudo, opts.verbose);
+            bail!("Could not restore snapshot {} to {}: {}", &opts.snapshot.full_name, &opts.dst, e);
+        }
+        if step[0] == "btrfs" {
+            created.push(step.last().unwrap().clone());
+        }
+    }
+
+    info!("Restored snapshot {} to {}", &opts.snapshot.full_name, &opts.dst);
+
+    Ok(created)
+}
+
+/// Restores every member of a group snapshot, each to the `dst` of its options inside `dir`,
+/// or none of them
+///
+/// `dir` is created if it doesn't exist yet. If a member can't be restored, the members
+/// restored before it are deleted again, along with `dir` if it was created.
+pub fn restore_group(restores: &[RestoreOpts], dir: &str, sudo: bool, dry_run: bool, verbose: i32) -> Result<()> {
+    let create_dir = !Path::new(dir).exists();
+    if create_dir {
+        let step = ["mkdir", "-p", dir].map(String::from);
+        if dry_run {
+            info!("Would create directory {}", dir);
+        } else if let Err(e) = run_step(&step, sudo, verbose) {
+            bail!("Could not create directory {}: {}", dir, e);
+        }
+    }
+
+    let mut created = Vec::new();
+    for opts in restores {
+        match restore_recorded(opts) {
+            Ok(member_created) => created.extend(member_created),
+            Err(e) => {
+                delete_restored(&created, sudo, verbose);
+                if create_dir && !dry_run {
+                    if let Err(e) = run_step(&["rmdir", dir].map(String::from), sudo, verbose) {
+                        warn!("Could not delete directory {} of an incomplete restore: {}", dir, e);
+                    }
+                }
+                return Err(e);
+            },
+        }
+    }
 
     Ok(())
 }
 
+/// Runs `step`, a program and its arguments, through sudo if `sudo` is set, failing with its
+/// error output
+fn run_step(step: &[String], sudo: bool, verbose: i32) -> Result<()> {
+    let (program, args) = if sudo { ("sudo", step) } else { (step[0].as_str(), &step[1..]) };
+    if verbose > 0 {
+        debug!("{} {}", program, args.join(" "));
+    }
+
+    let output = Command::new(program)
+        .args(args)
+        .output()?;
+    if !output.status.success() {
+        bail!("{}", str::from_utf8(&output.stderr).unwrap().trim());
+    }
+
+    Ok(())
+}
+
+/// Deletes the subvolumes `created` by a restore that could not be completed, latest first so
+/// that nested subvolumes go before the ones holding them
+fn delete_restored(created: &[String], sudo: bool, verbose: i32) {
+    for path in created.iter().rev() {
+        let step = ["btrfs", "subvolume", "delete", path].map(String::from);
+        if let Err(e) = run_step(&step, sudo, verbose) {
+            warn!("Could not delete subvolume {} of an incomplete restore: {}", path, e);
+        }
+    }
+}
+
 #[derive(Clone,Debug,Default)]
 pub struct SnapshotRepositoryLocation {
     pub user: Option<String>,
     pub host: Option<String>,
     pub port: Option<u16>,
     pub path: String,
+    pub ssh: SshOpts,
+    /// Cap on the rate snapshots are sent to this location at
+    pub bwlimit: BandwidthLimit,
+    /// How to verify snapshots after they were received at this location, if at all
+    pub verify: Option<VerifyMode>,
+    /// Free space to keep at this location after receiving a snapshot
+    pub min_free: Option<Threshold>,
+    /// Which snapshots to keep at this location after a synchronization, if it is pruned at all
+    pub retention: Option<RetentionPolicy>,
 }
 
 impl SnapshotRepositoryLocation {
-    fn is_remote(&self) -> bool {
+    pub fn is_remote(&self) -> bool {
         self.host.is_some()
     }
+
+    /// Returns the namespace of `machine` within this repository, or the repository itself if
+    /// `machine` is empty
+    pub fn for_machine(&self, machine: &str) -> Self {
+        let mut location = self.clone();
+        if !machine.is_empty() {
+            location.path = Path::new(&self.path).join(machine).to_str().unwrap().to_string();
+        }
+        location
+    }
 }
 
-pub fn list_snapshots(name: &str, dst: &SnapshotRepositoryLocation, sudo: bool, verbose: i32) -> Result<Vec<Snapshot>> {
-    let (program, args) = if dst.is_remote() {
-        eprintln!("Listing remote snapshots");
-        if let Some(host) = dst.host.clone() {
-            if let Some(user) = dst.user.clone() {
-                let base_url = format!("{}@{}", user, host);
-                if let Some(port) = dst.port {
-                    if sudo {
-                        ("ssh", vec!["-p".to_string(), format!("{}", port), base_url, "sudo".to_string(), "btrfs".to_string(), "subvolume".to_string(), "list".to_string(), dst.path.clone()])
-                    } else {
-                        ("ssh", vec!["-p".to_string(), format!("{}", port), base_url, "btrfs".to_string(), "subvolume".to_string(), "list".to_string(), dst.path.clone()])
-                    }
-                } else {
-                    if sudo {
-                        ("ssh", vec![base_url, "sudo".to_string(), "btrfs".to_string(), "subvolume".to_string(), "list".to_string(), dst.path.clone()])
-                    } else {
-                        ("ssh", vec![base_url, "btrfs".to_string(), "subvolume".to_string(), "list".to_string(), dst.path.clone()])
-                    }
-                }
-            } else {
-                if sudo {
-                    ("ssh", vec![host, "sudo".to_string(), "btrfs".to_string(), "subvolume".to_string(), "list".to_string(), dst.path.clone()])
-                } else {
-                    ("ssh", vec![host, "btrfs".to_string(), "subvolume".to_string(), "list".to_string(), dst.path.clone()])
-                }
+/// Formats the location the way [`parse_sync_location`] parses it
+impl fmt::Display for SnapshotRepositoryLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(host) = &self.host {
+            // Without a user, only the scheme tells a remote from a local path
+            match &self.user {
+                Some(user) => write!(f, "{}@", user)?,
+                None => write!(f, "ssh://")?,
+            }
+            write!(f, "{}:", host)?;
+            if let Some(port) = self.port {
+                write!(f, "{}:", port)?;
             }
-        } else {
-            bail!("Could not sync remotely without host specified!");
         }
+        write!(f, "{}", &self.path)
+    }
+}
+
+pub fn list_snapshots(name: &str, dst: &SnapshotRepositoryLocation, sudo: bool, verbose: i32) -> Result<Vec<Snapshot>> {
+    let entries = list_entries(dst, sudo, verbose)
+        .map_err(|e| anyhow!("Could not list snapshots with name {}: {}", name, e))?;
+
+    // Only entries directly in `dst.path` count, so that other machines' namespaces and
+    // snapshots elsewhere on the same filesystem are never mistaken for ours
+    let snapshot_list: Vec<String> = entries.into_iter()
+        .filter(|entry| entry.split_once('@').is_some_and(|(v, _)| v == name))
+        .collect();
+
+    parse_snapshot_list(&snapshot_list, &dst.path)
+}
+
+/// Lists the names of the entries in `dst.path`, which is empty if it doesn't exist yet
+///
+/// Directories are listed with a trailing slash.
+pub fn list_entries(dst: &SnapshotRepositoryLocation, sudo: bool, verbose: i32) -> Result<Vec<String>> {
+    let (program, args) = if dst.is_remote() {
+        let script = r#"test ! -d "$1" || ls -1 -p "$1""#;
+        ssh::remote_command(dst, sudo, &["sh", "-c", script, "sh", &dst.path])?
     } else {
         if !Path::new(&dst.path).exists() {
             return Ok(Vec::new());
         }
 
-        eprintln!("Listing local snapshots");
         if sudo {
-            ("sudo", vec!["ls".to_string(), dst.path.clone()])
+            ("sudo", vec!["ls".to_string(), "-1".to_string(), "-p".to_string(), dst.path.clone()])
         } else {
-            ("ls", vec![dst.path.clone()])
+            ("ls", vec!["-1".to_string(), "-p".to_string(), dst.path.clone()])
         }
     };
 
@@ -168,26 +492,54 @@ pub fn list_snapshots(name: &str, dst: &SnapshotRepositoryLocation, sudo: bool,
         .output()?;
 
     if !output.status.success() {
-        bail!("Could not list snapshots with name {}: {}", name, str::from_utf8(&output.stderr).unwrap());
+        bail!("{}", str::from_utf8(&output.stderr).unwrap());
     }
 
     let stdout = str::from_utf8(&output.stdout).unwrap();
-    let snapshot_list: Vec<String> = stdout
-        .trim()
-        .split("\n")
-        .map(|line| line.split(" ").last().unwrap().to_string())
-        .filter(|last_field| {
-            let mut tokens = last_field.split("@");
-            let a = tokens.next();
-            if let Some(v) = a {
-                v == name && tokens.next().is_some()
-            } else {
-                return false
-            }
-        })
-        .collect();
+    Ok(stdout.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.trim_end_matches('/').to_string())
+        .collect())
+}
 
-    Ok(parse_snapshot_list(&snapshot_list, &dst.path)?)
+/// Returns the machines with their own namespace in the repository at `location`
+///
+/// Only directories holding snapshots count, so e.g. `lost+found` isn't taken for a machine.
+pub fn list_machines(location: &SnapshotRepositoryLocation, sudo: bool, verbose: i32) -> Result<Vec<String>> {
+    let entries = list_entries(location, sudo, verbose)
+        .map_err(|e| anyhow!("Could not list machines at {}: {}", location, e))?;
+    Ok(entries.into_iter()
+        .filter(|entry| !entry.contains('@'))
+        .filter(|entry| list_entries(&location.for_machine(entry), sudo, verbose)
+            .unwrap_or_default()
+            .iter()
+            .any(|snapshot| snapshot.contains('@')))
+        .collect())
+}
+
+/// Creates `location.path` if it doesn't exist yet
+pub fn ensure_directory(location: &SnapshotRepositoryLocation, sudo: bool, verbose: i32) -> Result<()> {
+    let (program, args) = if location.is_remote() {
+        ssh::remote_command(location, sudo, &["mkdir", "-p", &location.path])?
+    } else if sudo {
+        ("sudo", vec!["mkdir".to_string(), "-p".to_string(), location.path.clone()])
+    } else {
+        ("mkdir", vec!["-p".to_string(), location.path.clone()])
+    };
+
+    if verbose > 0 {
+        debug!("{} {}", program, args.join(" "));
+    }
+
+    let output = Command::new(program)
+        .args(args)
+        .output()?;
+
+    if !output.status.success() {
+        bail!("Could not create directory {}: {}", &location.path, str::from_utf8(&output.stderr).unwrap());
+    }
+
+    Ok(())
 }
 
 /// Parses a list of snapshot name strings into list of Snapshot instances
@@ -202,7 +554,7 @@ fn parse_snapshot_list(snapshot_list: &[String], path: &str) -> Result<Vec<Snaps
 }
 
 /// Transforms snapshot name string into a Snapshot instance
-fn parse_snapshot_name(snapshot_name: &str, path: &str) -> Result<Snapshot> {
+pub(crate) fn parse_snapshot_name(snapshot_name: &str, path: &str) -> Result<Snapshot> {
     let mut split = snapshot_name.split("@");
     let name = split.next().ok_or_else(|| FridgeError::ParseSnapshot{what: "name", snapshot: snapshot_name.to_string()})?;
     let datetime_and_suffix = split.next().ok_or_else(|| FridgeError::ParseSnapshot{what: "datetime and suffix", snapshot: snapshot_name.to_string()})?;
@@ -227,17 +579,18 @@ fn parse_snapshot_name(snapshot_name: &str, path: &str) -> Result<Snapshot> {
 ///
 /// It will automatically determine whether the snapshot repository location is remote or local
 /// based on whether or not the URL contains @ character which it assumes to mean there's a user
-/// portion (e.g. user@192.168.0.1).
+/// portion (e.g. user@192.168.0.1), or starts with ssh:// for remotes without a user.
 pub fn parse_sync_location(url: &str) -> Result<SnapshotRepositoryLocation> {
-    eprintln!("Parsing {url}");
     let mut sync_location = SnapshotRepositoryLocation::default();
-    let tokens: Vec<&str> = url.split("@").collect();
-    let is_remote = tokens.len() >= 2;
+    let remote = match url.strip_prefix("ssh://") {
+        Some(remote) => Some(remote.split_once('@').map_or((None, remote), |(user, host)| (Some(user), host))),
+        None => url.split_once('@').map(|(user, host)| (Some(user), host)),
+    };
 
-    if is_remote {
-        sync_location.user = Some(tokens[0].to_string());
+    if let Some((user, remote)) = remote {
+        sync_location.user = user.map(|user| user.to_string());
 
-        let tokens: Vec<&str> = tokens[1].split(":").collect();
+        let tokens: Vec<&str> = remote.split(":").collect();
         if tokens.len() >= 1 {
             sync_location.host = Some(tokens[0].to_string());
         }
@@ -281,360 +634,650 @@ pub struct TransferOpts {
     pub src_sudo: bool,
     pub dst: SnapshotRepositoryLocation,
     pub dst_sudo: bool,
+    pub priority: Priority,
     pub dry_run: bool,
     pub verbose: i32,
 }
 
 pub fn transfer(opts: &TransferOpts) -> Result<()> {
-    if opts.src.is_remote() {
-         unimplemented!()
-    } else {
-        let snapshot_path = Path::new(&opts.src.path).join(&opts.snapshot).to_str().unwrap().to_string();
-        let mut send_output_child = if let Some(parent_snapshot) = &opts.parent_snapshot {
-            let parent_snapshot_path = Path::new(&opts.src.path).join(parent_snapshot).to_str().unwrap().to_string();
-            if opts.dry_run {
-                info!("Would transfer snapshot {} with parent {}", &snapshot_path, &parent_snapshot_path);
-                return Ok(());
-            }
-            info!("Transferring snapshot {} with parent {}", &snapshot_path, &parent_snapshot_path);
-            let (program, args) = if opts.src_sudo {
-                ("sudo", vec!["btrfs", "send", "-p", &parent_snapshot_path, &snapshot_path])
-            } else {
-                ("btrfs", vec!["send", "-p", &parent_snapshot_path, &snapshot_path])
-            };
-            Command::new(program)
-                .args(args)
-                .stdout(Stdio::piped())
-                .spawn()?
-        } else {
-            if opts.dry_run {
-                info!("Would transfer snapshot {}", &snapshot_path);
-                return Ok(());
-            }
-            let (program, args) = if opts.src_sudo {
-                ("sudo", vec!["btrfs", "send", &snapshot_path])
-            } else {
-                ("btrfs", vec!["send", &snapshot_path])
-            };
-            info!("Transferring snapshot {}", &snapshot_path);
-            Command::new(program)
-                .args(args)
-                .stdout(Stdio::piped())
-                .spawn()?
-        };
-
-        if let Some(send_output) = send_output_child.stdout.take() {
-            let (program, args) = if opts.dst.is_remote() {
-                if let Some(host) = &opts.dst.host {
-                    if let Some(user) = &opts.dst.user {
-                        let base_url = format!("{}@{}", user, host);
-                        if let Some(port) = opts.dst.port {
-                            if opts.dst_sudo {
-                                ("ssh", vec!["-p".to_string(), format!("{}", port), base_url, "sudo".to_string(), "btrfs".to_string(), "receive".to_string(), opts.dst.path.clone()])
-                            } else {
-                                ("ssh", vec!["-p".to_string(), format!("{}", port), base_url, "btrfs".to_string(), "receive".to_string(), opts.dst.path.clone()])
-                            }
-                        } else {
-                            if opts.dst_sudo {
-                                ("ssh", vec![base_url, "sudo".to_string(), "btrfs".to_string(), "receive".to_string(), opts.dst.path.clone()])
-                            } else {
-                                ("ssh", vec![base_url, "btrfs".to_string(), "receive".to_string(), opts.dst.path.clone()])
-                            }
-                        }
-                    } else {
-                        if opts.dst_sudo {
-                            ("ssh", vec![host.clone(), "sudo".to_string(), "btrfs".to_string(), "receive".to_string(), opts.dst.path.clone()])
-                        } else {
-                            ("ssh", vec![host.clone(), "btrfs".to_string(), "receive".to_string(), opts.dst.path.clone()])
-                        }
-                    }
-                } else {
-                    bail!("Could not sync remotely without host specified!");
-                }
-            } else {
-                if opts.dst_sudo {
-                    ("btrfs", vec!["sudo".to_string(), "receive".to_string(), opts.dst.path.to_string()])
-                } else {
-                    ("btrfs", vec!["receive".to_string(), opts.dst.path.to_string()])
-                }
-            };
-
-            if opts.dry_run {
-                info!("Would run the following command: {} {}", &program, args.join(" "));
-                return Ok(());
-            }
-
-            if opts.verbose > 0 {
-                info!("{} {}", &program, args.join(" "));
-            }
-
-            let mut receive_output_child = Command::new(program)
-                .stdin(send_output)
-                .args(args)
-                .stdout(Stdio::piped())
-                .spawn()?;
+    let fan_out_opts = FanOutOpts {
+        snapshot: opts.snapshot.clone(),
+        src: opts.src.clone(),
+        src_sudo: opts.src_sudo,
+        dsts: vec![TransferDestination {
+            location: opts.dst.clone(),
+            sudo: opts.dst_sudo,
+            parent_snapshot: opts.parent_snapshot.clone(),
+        }],
+        priority: opts.priority.clone(),
+        cancel: CancelToken::new(),
+        dry_run: opts.dry_run,
+        verbose: opts.verbose,
+    };
 
-            send_output_child.wait()?;
-            receive_output_child.wait()?;
-        }
-    }
+    transfer_fan_out(&fan_out_opts)?
+        .pop()
+        .unwrap_or(Ok(()))
+}
 
-    Ok(())
+#[derive(Clone, Debug, Default)]
+pub struct TransferDestination {
+    pub location: SnapshotRepositoryLocation,
+    pub sudo: bool,
+    /// Snapshot the destination already has to send incrementally against
+    pub parent_snapshot: Option<String>,
 }
 
-/*
 #[derive(Default)]
-pub struct SyncOpts {
-    pub name: String,
-    pub src: String,
+pub struct FanOutOpts {
+    pub snapshot: String,
+    pub src: SnapshotRepositoryLocation,
     pub src_sudo: bool,
-    pub src_suffix: String,
-    pub dst: String,
-    pub dst_sudo: bool,
-    pub dst_suffix: String,
+    pub dsts: Vec<TransferDestination>,
+    pub priority: Priority,
+    /// Terminates the send and receive processes when cancelled
+    pub cancel: CancelToken,
     pub dry_run: bool,
     pub verbose: i32,
 }
 
-pub fn sync(opts: &SyncOpts) -> Result<()> {
-    let src = parse_sync_location(&opts.src, &opts.src_suffix)?;
-    let dst = parse_sync_location(&opts.dst, &opts.dst_suffix)?;
-    let src_list_string = list_snapshots(&opts.name, &src, opts.src_sudo, opts.verbose)?;
-    let dst_list_string = list_snapshots(&opts.name, &dst, opts.dst_sudo, opts.verbose)?;
-    let src_list: Vec<&str> = src_list_string.iter().map(|s| s.full_name.as_str()).collect();
-    let dst_list: Vec<&str> = dst_list_string.iter().map(|s| s.full_name.as_str()).collect();
-    let missing_snapshots_in_destination: Vec<&str> = src_list.iter()
-        .filter(|snapshot_name| dst_list.contains(snapshot_name))
-        .map(|snapshot_name| *snapshot_name)
-        .collect();
-    if missing_snapshots_in_destination.len() == 0 {
-        info!("Already up-to-date");
-        return Ok(());
+/// Transfers a snapshot to several destinations while reading it from disk as few times as
+/// possible
+///
+/// Destinations that share the same parent snapshot receive the same `btrfs send` stream, so
+/// only one send runs per distinct parent, once the destinations were checked to have room for
+/// it. Destinations with a bandwidth limit get a send of their own so they don't slow down the
+/// others. Failures are reported per destination, in the same
+/// order as `opts.dsts`, and never abort the transfer to the other destinations.
+pub fn transfer_fan_out(opts: &FanOutOpts) -> Result<Vec<Result<()>>> {
+    if opts.src.is_remote() {
+        bail!("Could not send from {}: sending from a remote repository is not supported", opts.src);
     }
-    let mut parent: Option<&str> = find_parent_snapshot_to(missing_snapshots_in_destination[0], &src_list);
 
-    for snapshot in missing_snapshots_in_destination {
-        let mut transfer_opts = TransferOpts::default();
-        transfer_opts.parent_snapshot = parent.map_or(None, |s| Some(s.to_string()));
-        transfer_opts.snapshot = snapshot.to_string();
-        transfer_opts.src = src.clone();
-        transfer_opts.src_sudo = opts.src_sudo;
-        transfer_opts.dst = dst.clone();
-        transfer_opts.dst_sudo = opts.dst_sudo;
-        transfer_opts.dry_run = opts.dry_run;
-        transfer_opts.verbose = opts.verbose;
-        transfer(&transfer_opts)?;
-        parent = Some(snapshot);
+    let mut results: Vec<Option<Result<()>>> = opts.dsts.iter().map(|_| None).collect();
+    for ((parent_snapshot, _), indices) in stream_groups(&opts.dsts) {
+        // Destinations without room for the stream fail before anything is sent
+        let indices = if opts.dry_run {
+            indices
+        } else {
+            match space::estimate_stream_size(&opts.src.path, &opts.snapshot, parent_snapshot, opts.src_sudo) {
+                Ok(Some(size)) => indices.into_iter()
+                    .filter(|i| {
+                        let dst = &opts.dsts[*i];
+                        let started = Utc::now();
+                        let result = space::check_receive(&dst.location, dst.sudo, &opts.snapshot, size);
+                        if result.is_err() {
+                            let subvolume = opts.snapshot.split_once('@').map_or(opts.snapshot.as_str(), |(name, _)| name);
+                            history::record(Record::new(Operation::Transfer, started, subvolume, Some(&opts.snapshot), &dst.location.to_string(), None, &result));
+                        }
+                        let ok = result.is_ok();
+                        results[*i] = result.err().map(Err);
+                        ok
+                    })
+                    .collect(),
+                Ok(None) => indices,
+                Err(e) => {
+                    warn!("Could not estimate the size of snapshot {}: {}", &opts.snapshot, e);
+                    indices
+                },
+            }
+        };
+        if indices.is_empty() {
+            continue;
+        }
+        let dsts: Vec<&TransferDestination> = indices.iter().map(|i| &opts.dsts[*i]).collect();
+        match transfer_group(opts, parent_snapshot, &dsts) {
+            Ok(group_results) => {
+                for (i, result) in indices.into_iter().zip(group_results) {
+                    results[i] = Some(result);
+                }
+            },
+            Err(e) => {
+                for i in indices {
+                    results[i] = Some(Err(anyhow!("Could not send snapshot {}: {}", &opts.snapshot, e)));
+                }
+            },
+        }
     }
 
-    Ok(())
+    Ok(results.into_iter().map(|result| result.unwrap_or(Ok(()))).collect())
 }
 
-#[derive(Default)]
-pub struct RestoreOpts {
-    pub src: String,
-    pub dst: String,
-    pub name: String,
-    pub suffix: Option<String>,
-    pub sudo: bool,
-    pub dry_run: bool,
-    pub verbose: i32,
+/// Destinations sharing a `btrfs send` stream, by parent snapshot and by the destination with a
+/// bandwidth limit if any
+///
+/// Each destination with a bandwidth limit gets a stream of its own, since the shared stream
+/// is read at the pace of its slowest destination.
+fn stream_groups(dsts: &[TransferDestination]) -> BTreeMap<(Option<&str>, Option<usize>), Vec<usize>> {
+    let mut groups: BTreeMap<(Option<&str>, Option<usize>), Vec<usize>> = BTreeMap::new();
+    for (i, dst) in dsts.iter().enumerate() {
+        let limited = if dst.location.bwlimit.is_unlimited() { None } else { Some(i) };
+        groups.entry((dst.parent_snapshot.as_deref(), limited)).or_default().push(i);
+    }
+
+    groups
 }
 
-pub fn restore(opts: &RestoreOpts) -> Result<()> {
-    if opts.dry_run {
-        info!("Would restore snapshot {} to {}", &opts.src, &opts.dst);
-        return Ok(());
+fn send_command(opts: &FanOutOpts, snapshot_path: &str, parent_snapshot_path: Option<&str>) -> (&'static str, Vec<String>) {
+    let mut args = vec!["btrfs".to_string(), "send".to_string()];
+    if let Some(parent_snapshot_path) = parent_snapshot_path {
+        args.push("-p".to_string());
+        args.push(parent_snapshot_path.to_string());
     }
+    args.push(snapshot_path.to_string());
 
-    // Rename subvolume
-    let dst_old = format!("{}.old", &opts.dst);
-    let output = Command::new("mv")
-        .args([&opts.dst, &dst_old])
-        .output()?;
+    if opts.src_sudo {
+        opts.priority.wrap("sudo", args)
+    } else {
+        opts.priority.wrap("btrfs", args.split_off(1))
+    }
+}
 
-    if !output.status.success() {
-        bail!("Could not restore snapshot: Unable to rename subvolume {}, {}", &opts.dst, str::from_utf8(&output.stderr).unwrap());
+fn receive_command(dst: &TransferDestination, priority: &Priority) -> Result<(&'static str, Vec<String>)> {
+    if dst.location.is_remote() {
+        ssh::remote_command(&dst.location, dst.sudo, &["btrfs", "receive", &dst.location.path])
+    } else if dst.sudo {
+        Ok(priority.wrap("sudo", vec!["btrfs".to_string(), "receive".to_string(), dst.location.path.clone()]))
+    } else {
+        Ok(priority.wrap("btrfs", vec!["receive".to_string(), dst.location.path.clone()]))
     }
+}
 
-    // Restore snapshot
-    let output = Command::new("btrfs")
-        .args(["subvolume", "snapshot", &opts.src, &opts.dst])
-        .output()?;
+/// A running `btrfs receive` with the thread reading its stderr
+type Receiver = (Child, JoinHandle<Vec<u8>>, Registration);
 
-    if !output.status.success() {
-        bail!("Could not restore snapshot {}: {}", &opts.src, str::from_utf8(&output.stderr).unwrap());
+/// Runs one `btrfs send` and relays its stream to every destination in `dsts`
+fn transfer_group(opts: &FanOutOpts, parent_snapshot: Option<&str>, dsts: &[&TransferDestination]) -> Result<Vec<Result<()>>> {
+    let snapshot_path = Path::new(&opts.src.path).join(&opts.snapshot).to_str().unwrap().to_string();
+    let parent_snapshot_path = parent_snapshot.map(|parent_snapshot| Path::new(&opts.src.path).join(parent_snapshot).to_str().unwrap().to_string());
+
+    if opts.dry_run {
+        if let Some(parent_snapshot_path) = &parent_snapshot_path {
+            info!("Would transfer snapshot {} with parent {}", &snapshot_path, parent_snapshot_path);
+        } else {
+            info!("Would transfer snapshot {}", &snapshot_path);
+        }
+        let mut results = Vec::new();
+        for dst in dsts {
+            results.push(receive_command(dst, &opts.priority).map(|(program, args)| {
+                info!("Would run the following command: {} {}", &program, args.join(" "));
+            }));
+        }
+        return Ok(results);
     }
 
-    // Unmount subvolume
-    let output = Command::new("umount")
-        .args([&opts.dst])
-        .output()?;
+    let started = Utc::now();
 
-    if !output.status.success() {
-        bail!("Could not restore snapshot: Unable to unmount subvolume {}: {}", &opts.dst, str::from_utf8(&output.stderr).unwrap());
+    // Start receivers first so a destination that can't be reached is reported on its own
+    let mut receivers: Vec<Result<Receiver>> = Vec::new();
+    for dst in dsts {
+        receivers.push(receive_command(dst, &opts.priority).and_then(|(program, args)| {
+            if opts.verbose > 0 {
+                info!("{} {}", &program, args.join(" "));
+            }
+            let (child, stderr) = spawn_drained(Command::new(program)
+                .args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null()))?;
+            let registration = opts.cancel.register(child.id());
+            Ok((child, stderr, registration))
+        }));
     }
 
-    // Re-mount subvolume
-    let output = Command::new("mount")
-        .args([&opts.dst])
-        .output()?;
+    if let Some(parent_snapshot_path) = &parent_snapshot_path {
+        info!("Transferring snapshot {} with parent {}", &snapshot_path, parent_snapshot_path);
+    } else {
+        info!("Transferring snapshot {}", &snapshot_path);
+    }
 
-    if !output.status.success() {
-        bail!("Could not restore snapshot: Unable to re-mount subvolume {}: {}", &opts.dst, str::from_utf8(&output.stderr).unwrap());
+    // Whatever goes wrong with the send, every receiver is waited for and its partial
+    // snapshot discarded, so none is left running or taken for complete later
+    let mut send = send_stream(opts, &snapshot_path, parent_snapshot_path.as_deref(), dsts, &mut receivers);
+
+    let subvolume = opts.snapshot.split_once('@').map_or(opts.snapshot.as_str(), |(name, _)| name);
+    let mut results = Vec::new();
+    for (i, receiver) in receivers.into_iter().enumerate() {
+        let relay_result = send.as_mut().ok().and_then(|send| send.relayed[i].take());
+        let bytes = relay_result.as_ref().and_then(|relay_result| relay_result.as_ref().ok().copied());
+        let result = receiver.and_then(|(mut child, stderr, registration)| {
+            if send.is_err() {
+                let _ = child.kill();
+            }
+            let (status, stderr) = wait_drained(child, stderr)?;
+            drop(registration);
+            opts.cancel.check()?;
+            let send = match &send {
+                Ok(send) => send,
+                Err(e) => bail!("Could not send snapshot {}: {}", &snapshot_path, e),
+            };
+            if !status.success() {
+                bail!("Could not receive snapshot at {}: {}", &dsts[i].location.path, str::from_utf8(&stderr).unwrap());
+            }
+            if !send.status.success() {
+                bail!("Could not send snapshot {}: {}", &snapshot_path, str::from_utf8(&send.stderr).unwrap());
+            }
+            match relay_result {
+                Some(Ok(_)) => Ok(()),
+                Some(Err(e)) => bail!("Could not stream snapshot to {}: {}", &dsts[i].location.path, e),
+                None => bail!("Could not stream snapshot to {}", &dsts[i].location.path),
+            }
+        });
+        history::record(Record::new(Operation::Transfer, started, subvolume, Some(&opts.snapshot), &dsts[i].location.to_string(), bytes, &result));
+        if result.is_err() {
+            discard_partial(dsts[i], &opts.snapshot, opts.verbose);
+        }
+        results.push(result);
     }
 
-    Ok(())
+    Ok(results)
 }
 
-#[derive(Default)]
-pub struct ListOpts {
-    pub name: String,
-    pub path: String,
-    pub sudo: bool,
-    pub verbose: i32,
-    pub suffix: String,
+/// How a `btrfs send` went, with what was relayed to each destination of its group
+struct SendOutcome {
+    status: ExitStatus,
+    stderr: Vec<u8>,
+    /// Bytes relayed to each destination, `None` for the ones without a running receiver
+    relayed: Vec<Option<Result<u64>>>,
 }
 
-pub fn list(opts: &ListOpts) -> Result<()> {
-    let sync_location = parse_sync_location(&opts.path, &opts.suffix).unwrap();
+/// Runs `btrfs send` and relays its stream to the standard input of the running `receivers`
+fn send_stream(opts: &FanOutOpts, snapshot_path: &str, parent_snapshot_path: Option<&str>, dsts: &[&TransferDestination], receivers: &mut [Result<Receiver>]) -> Result<SendOutcome> {
+    let (program, args) = send_command(opts, snapshot_path, parent_snapshot_path);
     if opts.verbose > 0 {
-        debug!("Is remote? {}", sync_location.is_remote());
+        info!("{} {}", &program, args.join(" "));
     }
-    let snapshots = list_snapshots(&opts.name, &sync_location, opts.sudo, opts.verbose)?;
-    for snapshot in &snapshots {
-        println!("{}", &snapshot.full_name);
+    let (mut send_child, send_stderr) = spawn_drained(Command::new(program)
+        .args(args)
+        .stdout(Stdio::piped()))?;
+    let send_registration = opts.cancel.register(send_child.id());
+
+    let mut sinks = Vec::new();
+    let mut sink_indices = Vec::new();
+    for (i, receiver) in receivers.iter_mut().enumerate() {
+        if let Ok((child, _, _)) = receiver {
+            if let Some(stdin) = child.stdin.take() {
+                sinks.push(Sink {
+                    writer: Box::new(stdin),
+                    limit: dsts[i].location.bwlimit.clone(),
+                });
+                sink_indices.push(i);
+            }
+        }
+    }
+
+    let relay_results = match send_child.stdout.take() {
+        Some(send_output) => relay::relay(send_output, sinks),
+        None => Err(anyhow!("Could not capture output of btrfs send")),
+    };
+    if relay_results.is_err() {
+        let _ = send_child.kill();
+    }
+    let (status, stderr) = wait_drained(send_child, send_stderr)?;
+    drop(send_registration);
+
+    let mut relayed: Vec<Option<Result<u64>>> = dsts.iter().map(|_| None).collect();
+    match relay_results {
+        Ok(relay_results) => {
+            for (i, relay_result) in sink_indices.into_iter().zip(relay_results) {
+                relayed[i] = Some(relay_result);
+            }
+        },
+        Err(e) => bail!("Could not read snapshot stream: {}", e),
+    }
+
+    Ok(SendOutcome {
+        status,
+        stderr,
+        relayed,
+    })
+}
+
+/// Spawns `command` with its stderr read on a thread
+///
+/// The send and receive processes of a transfer only finish once the relay has moved the
+/// whole stream, so a full stderr pipe nobody reads would block them for good.
+fn spawn_drained(command: &mut Command) -> Result<(Child, JoinHandle<Vec<u8>>)> {
+    let mut child = command.stderr(Stdio::piped()).spawn()?;
+    let mut stderr = child.stderr.take().ok_or_else(|| anyhow!("Could not capture error output"))?;
+    let reader = thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = stderr.read_to_end(&mut buf);
+        buf
+    });
+    Ok((child, reader))
+}
+
+/// Waits for a child started with [`spawn_drained`] and returns its status and stderr
+fn wait_drained(mut child: Child, stderr: JoinHandle<Vec<u8>>) -> Result<(ExitStatus, Vec<u8>)> {
+    let status = child.wait()?;
+    let stderr = stderr.join().map_err(|_| anyhow!("Could not read error output"))?;
+    Ok((status, stderr))
+}
+
+/// Deletes what a failed or cancelled transfer left of `snapshot` at `dst`
+///
+/// `btrfs receive` only sets the received UUID once the whole stream arrived, so a snapshot
+/// without one is incomplete and would otherwise be mistaken for a parent by the next transfer.
+fn discard_partial(dst: &TransferDestination, snapshot: &str, verbose: i32) {
+    let result = list_entries(&dst.location, dst.sudo, verbose).and_then(|entries| {
+        if !entries.iter().any(|entry| entry.trim_end_matches('/') == snapshot) {
+            return Ok(());
+        }
+        if verify::subvolume_info(&dst.location, dst.sudo, snapshot, verbose)?.received_uuid.is_some() {
+            return Ok(());
+        }
+        parse_snapshot_name(snapshot, &dst.location.path)?.delete_from(&dst.location, dst.sudo, verbose)?;
+        info!("Deleted partially received snapshot {} at {}", snapshot, dst.location);
+        Ok(())
+    });
+    if let Err(e) = result {
+        warn!("Could not delete partially received snapshot {} at {}: {}", snapshot, dst.location, e);
     }
-    Ok(())
 }
 
-pub struct RunOpts {
+#[derive(Default)]
+pub struct SyncFanOutOpts {
+    pub name: String,
+    pub src: SnapshotRepositoryLocation,
+    pub src_sudo: bool,
+    /// Destinations and whether to use sudo on them
+    pub dsts: Vec<(SnapshotRepositoryLocation, bool)>,
+    /// Only send these snapshots instead of every missing one, if given
+    pub snapshots: Option<Vec<String>>,
+    /// Names and local repositories of the other members of the group `name` is in, pruned
+    /// along with it like [`PruneOpts::members`]. Their snapshots are sent separately, before
+    /// these.
+    pub members: Vec<(String, SnapshotRepositoryLocation)>,
+    pub priority: Priority,
+    /// Stops the synchronization after the snapshot being sent when cancelled
+    pub cancel: CancelToken,
     pub dry_run: bool,
     pub verbose: i32,
 }
 
-pub fn run(opts: &RunOpts) -> Result<()> {
-    let cfg = config::load();
-
-    if opts.verbose > 0 {
-        info!("{:?}", &cfg);
+/// Synchronizes snapshots with the given name from `src` to every destination
+///
+/// Each snapshot missing on one or more destinations is sent once and fanned out to all of
+/// them, incrementally against the newest snapshot each destination already has. Destinations
+/// with a verification mode check every snapshot they received against its source, and
+/// destinations with a retention policy are pruned once they are up-to-date. A destination that
+/// fails is skipped for the rest of the synchronization. Returns the outcome per destination in
+/// the same order as `opts.dsts`.
+pub fn sync_fan_out(opts: &SyncFanOutOpts) -> Result<Vec<Result<()>>> {
+    let name = opts.name.as_str();
+    let src_snapshots = list_snapshots(name, &opts.src, opts.src_sudo, opts.verbose)?;
+
+    let mut results: Vec<Result<()>> = Vec::new();
+    let mut dst_lists: Vec<Vec<String>> = Vec::new();
+    for (dst, sudo) in &opts.dsts {
+        let dst_snapshots = if opts.dry_run {
+            list_snapshots(name, dst, *sudo, opts.verbose)
+        } else {
+            ensure_directory(dst, *sudo, opts.verbose).and_then(|_| list_snapshots(name, dst, *sudo, opts.verbose))
+        };
+        match dst_snapshots {
+            Ok(snapshots) => {
+                dst_lists.push(snapshots.into_iter().map(|s| s.full_name).collect());
+                results.push(Ok(()));
+            },
+            Err(e) => {
+                dst_lists.push(Vec::new());
+                results.push(Err(e));
+            },
+        }
     }
 
-    info!("Running snapshots");
+    for (i, snapshot) in src_snapshots.iter().enumerate() {
+        opts.cancel.check()?;
+        if opts.snapshots.as_ref().is_some_and(|only| !only.contains(&snapshot.full_name)) {
+            continue;
+        }
 
-    // Run snapshots
-    for snapshot_cfg in &cfg.snapshots {
-        let snapshot_location = SnapshotRepositoryLocation{
-            user: None,
-            host: None,
-            port: None,
-            path: format!("{}{}", &cfg.local.path, &cfg.local.suffix),
+        let mut transfer_dsts = Vec::new();
+        let mut transfer_indices = Vec::new();
+        for (j, (dst, sudo)) in opts.dsts.iter().enumerate() {
+            if results[j].is_err() || dst_lists[j].contains(&snapshot.full_name) {
+                continue;
+            }
+            let parent_snapshot = src_snapshots[..i].iter()
+                .rev()
+                .find(|s| dst_lists[j].contains(&s.full_name))
+                .map(|s| s.full_name.clone());
+            transfer_dsts.push(TransferDestination {
+                location: dst.clone(),
+                sudo: *sudo,
+                parent_snapshot,
+            });
+            transfer_indices.push(j);
+        }
+
+        if transfer_dsts.is_empty() {
+            continue;
+        }
+
+        let fan_out_opts = FanOutOpts {
+            snapshot: snapshot.full_name.clone(),
+            src: opts.src.clone(),
+            src_sudo: opts.src_sudo,
+            dsts: transfer_dsts,
+            priority: opts.priority.clone(),
+            cancel: opts.cancel.clone(),
+            dry_run: opts.dry_run,
+            verbose: opts.verbose,
         };
-        let sudo = cfg.local.sudo;
-        let snapshots = list_snapshots(&snapshot_cfg.name, &snapshot_location, sudo, opts.verbose)?;
-        run_snapshot(snapshot_cfg, &snapshots, "hourly", Duration::hours(1), sudo, opts.dry_run, opts.verbose)?;
-        run_snapshot(snapshot_cfg, &snapshots, "daily", Duration::days(1), sudo, opts.dry_run, opts.verbose)?;
-        run_snapshot(snapshot_cfg, &snapshots, "weekly", Duration::weeks(1), sudo, opts.dry_run, opts.verbose)?;
-        run_snapshot(snapshot_cfg, &snapshots, "monthly", Duration::days(31), sudo, opts.dry_run, opts.verbose)?;
-        run_snapshot(snapshot_cfg, &snapshots, "yearly", Duration::days(365), sudo, opts.dry_run, opts.verbose)?;
-    }
-
-    info!("Running synchronizations");
-
-    // Run synchronizations
-    for remote_cfg in &cfg.remotes {
-        if let Some(host) = &remote_cfg.host {
-            if let Some(port) = remote_cfg.port {
-                let addr_str = format!("{}:{}", host, port);
-                let addr = SocketAddr::from_str(&addr_str)?;
-                if let Err(e) = TcpStream::connect_timeout(&addr, std::time::Duration::new(10, 0)) {
-                    warn!("Could not connect to {}: {}", &addr, &e);
-                    continue;
-                }
+        for (j, result) in transfer_indices.into_iter().zip(transfer_fan_out(&fan_out_opts)?) {
+            let (dst, sudo) = &opts.dsts[j];
+            let result = match dst.verify {
+                Some(mode) if !opts.dry_run => result.and_then(|_| verify_received(opts, dst, *sudo, mode, &snapshot.full_name)),
+                _ => result,
+            };
+            match result {
+                Ok(()) => dst_lists[j].push(snapshot.full_name.clone()),
+                Err(e) => results[j] = Err(e),
             }
         }
+    }
 
-        for snapshot_cfg in &cfg.snapshots {
-            let sync_opts = SyncOpts {
-                name: snapshot_cfg.name.clone(),
-                src: cfg.local.path.clone(),
-                src_sudo: cfg.local.sudo,
-                src_suffix: cfg.local.suffix.clone(),
-                dst: remote_cfg.location_string()?,
-                dst_sudo: remote_cfg.sudo,
-                dst_suffix: remote_cfg.suffix.clone(),
+    // Members of recursive snapshots follow the snapshots they belong to, and are pruned along
+    // with them rather than by a retention policy of their own
+    if !name.contains(nested::SEPARATOR) {
+        for member in nested::member_names(name, &opts.src, opts.src_sudo, opts.verbose)? {
+            opts.cancel.check()?;
+            let indices: Vec<usize> = (0..opts.dsts.len()).filter(|j| results[*j].is_ok()).collect();
+            if indices.is_empty() {
+                break;
+            }
+            let member_opts = SyncFanOutOpts {
+                name: member.clone(),
+                src: opts.src.clone(),
+                src_sudo: opts.src_sudo,
+                dsts: indices.iter().map(|j| {
+                    let (dst, sudo) = &opts.dsts[*j];
+                    (SnapshotRepositoryLocation { retention: None, ..dst.clone() }, *sudo)
+                }).collect(),
+                snapshots: opts.snapshots.as_ref().map(|only| only.iter()
+                    .filter_map(|full_name| full_name.split_once('@'))
+                    .map(|(_, stamp)| format!("{}@{}", member, stamp))
+                    .collect()),
+                members: Vec::new(),
+                priority: opts.priority.clone(),
+                cancel: opts.cancel.clone(),
                 dry_run: opts.dry_run,
                 verbose: opts.verbose,
             };
-
-            sync(&sync_opts)?;
+            for (j, result) in indices.into_iter().zip(sync_fan_out(&member_opts)?) {
+                results[j] = result;
+            }
         }
     }
 
-    Ok(())
-}
-
-pub fn run_snapshot(cfg: &SnapshotConfig, snapshots: &[Snapshot], suffix: &str, duration: Duration, sudo: bool, dry_run: bool, verbose: i32) -> Result<()> {
-    let mut snapshots: Vec<&Snapshot> = snapshots.iter().filter(|s| s.name == cfg.name && s.suffix == suffix).collect();
-    let last_snapshot = snapshots.last();
-
-    if let Some(last_snapshot) = last_snapshot {
-        let now = Utc::now();
-        let elapsed = now.signed_duration_since(last_snapshot.datetime);
-        if elapsed < duration {
-            return Ok(())
+    opts.cancel.check()?;
+    for (j, (dst, sudo)) in opts.dsts.iter().enumerate() {
+        if let (Some(policy), Ok(())) = (&dst.retention, &results[j]) {
+            let prune_opts = PruneOpts {
+                name: opts.name.clone(),
+                members: opts.members.clone(),
+                src: opts.src.clone(),
+                src_sudo: opts.src_sudo,
+                dst: dst.clone(),
+                dst_sudo: *sudo,
+                policy: policy.clone(),
+                dry_run: opts.dry_run,
+                verbose: opts.verbose,
+            };
+            if let Err(e) = retention::prune(&prune_opts) {
+                results[j] = Err(anyhow!("Could not prune snapshots at {}: {}", dst, e));
+            }
         }
     }
 
-    let opts = SnapshotOpts {
-        name: cfg.name.clone(),
-        src: cfg.path.clone(),
-        suffix: Some(String::from(suffix)),
-        sudo,
-        dry_run,
-        verbose,
-    };
-    snapshot(&opts)?;
-
-    if opts.dry_run {
-        return Ok(());
-    }
+    Ok(results)
+}
 
-    let max_snapshot_count = match suffix {
-        "hourly" => cfg.hourly,
-        "daily" => cfg.daily,
-        "weekly" => cfg.weekly,
-        "monthly" => cfg.monthly,
-        "yearly" => cfg.yearly,
-        _ => 100,
+fn verify_received(opts: &SyncFanOutOpts, dst: &SnapshotRepositoryLocation, sudo: bool, mode: VerifyMode, snapshot: &str) -> Result<()> {
+    let verify_opts = VerifyOpts {
+        name: opts.name.clone(),
+        src: opts.src.clone(),
+        src_sudo: opts.src_sudo,
+        dst: dst.clone(),
+        dst_sudo: sudo,
+        mode: Some(mode),
+        verbose: opts.verbose,
     };
-
-    while snapshots.len() > max_snapshot_count {
-        if let Some(snapshot) = snapshots.first() {
-            snapshot.delete(sudo, opts.verbose)?;
-            info!("Deleted snapshot {}", &snapshot.full_name);
-        }
-        snapshots.remove(0);
+    let report = verify::verify_snapshot(&verify_opts, snapshot)?;
+    if !report.is_ok() {
+        bail!("Could not verify snapshot {} at {}: {}", snapshot, dst, report.problems.join(", "));
     }
 
     Ok(())
 }
-*/
 
 #[cfg(test)]
 mod tests {
     use chrono::{Utc,TimeZone};
+    use crate::testutil::TempDir;
     use super::*;
 
     #[test]
     fn test_snapshot() {
         let mut opts = SnapshotOpts::default();
         opts.src = String::from("/");
+        opts.dst = String::from("/.snapshots");
         opts.name = String::from("test");
         opts.dry_run = true;
         super::snapshot(&opts).unwrap();
     }
 
+    #[test]
+    fn test_transfer_fan_out() {
+        let dst = |path: &str, parent_snapshot: Option<&str>| TransferDestination {
+            location: super::parse_sync_location(path).unwrap(),
+            sudo: false,
+            parent_snapshot: parent_snapshot.map(|s| s.to_string()),
+        };
+        let opts = FanOutOpts {
+            snapshot: "root@2000-01-02_03:04:05_daily".to_string(),
+            src: super::parse_sync_location("/.snapshots").unwrap(),
+            dsts: vec![
+                dst("/run/media/EXTERNAL_HDD", Some("root@2000-01-01_03:04:05_daily")),
+                dst("root@192.168.1.2:/backup", None),
+                dst("/run/media/OTHER_HDD", Some("root@2000-01-01_03:04:05_daily")),
+            ],
+            dry_run: true,
+            ..Default::default()
+        };
+        let results = super::transfer_fan_out(&opts).unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(|result| result.is_ok()));
+
+        let opts = FanOutOpts {
+            src: super::parse_sync_location("root@192.168.1.2:/.snapshots").unwrap(),
+            ..opts
+        };
+        assert!(super::transfer_fan_out(&opts).is_err());
+    }
+
+    #[test]
+    fn test_drained_transfer() {
+        // Both ends write more to stderr than a pipe holds before the stream is done
+        let (mut sender, sender_stderr) = spawn_drained(Command::new("sh")
+            .args(["-c", "head -c 200000 /dev/zero >&2; head -c 1000000 /dev/zero"])
+            .stdout(Stdio::piped())).unwrap();
+        let (mut receiver, receiver_stderr) = spawn_drained(Command::new("sh")
+            .args(["-c", "head -c 200000 /dev/zero >&2; cat >/dev/null"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())).unwrap();
+
+        let sinks = vec![Sink {
+            writer: Box::new(receiver.stdin.take().unwrap()),
+            limit: BandwidthLimit::default(),
+        }];
+        let results = relay::relay(sender.stdout.take().unwrap(), sinks).unwrap();
+        assert_eq!(results[0].as_ref().unwrap(), &1000000);
+
+        let (status, stderr) = wait_drained(sender, sender_stderr).unwrap();
+        assert!(status.success());
+        assert_eq!(stderr.len(), 200000);
+        let (status, stderr) = wait_drained(receiver, receiver_stderr).unwrap();
+        assert!(status.success());
+        assert_eq!(stderr.len(), 200000);
+    }
+
+    #[test]
+    fn test_stream_groups() {
+        let parent = "root@2000-01-01_03:04:05_daily";
+        let dst = |path: &str, rate: Option<u64>| TransferDestination {
+            location: SnapshotRepositoryLocation {
+                bwlimit: BandwidthLimit { rate, schedule: Vec::new() },
+                ..super::parse_sync_location(path).unwrap()
+            },
+            sudo: false,
+            parent_snapshot: Some(parent.to_string()),
+        };
+        let dsts = vec![
+            dst("/run/media/EXTERNAL_HDD", None),
+            dst("root@192.168.1.2:/backup", Some(1 << 20)),
+            dst("/run/media/OTHER_HDD", None),
+            dst("root@192.168.1.3:/backup", Some(1 << 20)),
+        ];
+        let groups: Vec<Vec<usize>> = super::stream_groups(&dsts).into_values().collect();
+        assert_eq!(groups, vec![vec![0, 2], vec![1], vec![3]]);
+    }
+
+    #[test]
+    fn test_run_step() {
+        let step = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>();
+        assert!(super::run_step(&step(&["true"]), false, 0).is_ok());
+        let e = super::run_step(&step(&["sh", "-c", "echo 'Read-only file system' >&2; exit 1"]), false, 0).unwrap_err();
+        assert_eq!(e.to_string(), "Read-only file system");
+    }
+
+    #[test]
+    fn test_for_machine() {
+        let location = super::parse_sync_location("li@192.168.0.2:22:/backup").unwrap();
+        assert_eq!(location.for_machine("ThinkPad-T495").to_string(), "li@192.168.0.2:22:/backup/ThinkPad-T495");
+        assert_eq!(location.for_machine("").to_string(), "li@192.168.0.2:22:/backup");
+    }
+
+    #[test]
+    fn test_list_machines() {
+        let dir = TempDir::new("machines");
+        for entry in ["ThinkPad-T495/root@2000-01-02_03:04:05_daily", "desktop/home@2000-01-02_03:04:05_daily", "lost+found", "notes/2000", "root@2000-01-02_03:04:05_daily"] {
+            std::fs::create_dir_all(dir.join(entry)).unwrap();
+        }
+        let location = super::parse_sync_location(dir.to_str().unwrap()).unwrap();
+
+        let machines = super::list_machines(&location, false, 0).unwrap();
+        let snapshots = super::list_snapshots("root", &location.for_machine("ThinkPad-T495"), false, 0).unwrap();
+        let flat_snapshots = super::list_snapshots("root", &location, false, 0).unwrap();
+        let missing_snapshots = super::list_snapshots("root", &location.for_machine("laptop"), false, 0).unwrap();
+
+        assert_eq!(machines, ["ThinkPad-T495", "desktop"]);
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(flat_snapshots.len(), 1);
+        assert!(missing_snapshots.is_empty());
+    }
+
     #[test]
     fn test_parse_sync_location() {
         {
@@ -681,6 +1324,15 @@ mod tests {
             assert_eq!(&sync_location.path, "/home");
             assert_eq!(sync_location.is_remote(), true);
         }
+        {
+            let dst = "ssh://192.168.1.2:22222:/home";
+            let sync_location = super::parse_sync_location(dst).unwrap();
+            assert_eq!(sync_location.user, None);
+            assert_eq!(sync_location.host.as_ref().unwrap(), "192.168.1.2");
+            assert_eq!(sync_location.port.unwrap(), 22222);
+            assert_eq!(&sync_location.path, "/home");
+            assert_eq!(sync_location.to_string(), dst);
+        }
         {
             let dst = "root@192.168.1.2:/home";
             let sync_location = super::parse_sync_location(dst).unwrap();
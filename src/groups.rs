@@ -0,0 +1,209 @@
+//! Groups of snapshot configurations whose snapshots are taken, pruned, sent and restored
+//! together
+//!
+//! A group snapshot is one snapshot of every member, taken back-to-back after a single
+//! filesystem sync and under the same date and tier, so that / from 14:00 is never restored
+//! along with /home from 13:00. Its ID is the group name followed by that date and tier, e.g.
+//! "system@2000-01-02_03:04:05_daily", and its members are the snapshots of the member
+//! configurations with the same date and tier.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+
+use crate::config::{Config, SnapshotConfig};
+use crate::fridge::{parse_snapshot_name, Snapshot};
+use crate::retention::{self, RetentionPolicy};
+
+/// Snapshot configurations whose snapshots are taken together: the members of a group, or a
+/// configuration in no group on its own
+pub struct Unit<'a> {
+    /// Name of the group, or of the configuration in no group
+    pub name: String,
+    pub members: Vec<&'a SnapshotConfig>,
+}
+
+impl Unit<'_> {
+    pub fn is_group(&self) -> bool {
+        self.members.len() != 1 || self.members[0].name != self.name
+    }
+}
+
+/// Units of the snapshot configurations of `cfg`, each where its first member is configured
+pub fn units(cfg: &Config) -> Vec<Unit<'_>> {
+    let mut units: Vec<Unit> = Vec::new();
+    for snapshot_cfg in &cfg.snapshots {
+        match cfg.groups.iter().find(|group| group.snapshots.contains(&snapshot_cfg.name)) {
+            Some(group) if units.iter().any(|unit| unit.name == group.name) => (),
+            Some(group) => units.push(Unit {
+                name: group.name.clone(),
+                members: group.snapshots.iter()
+                    .filter_map(|name| cfg.snapshots.iter().find(|snapshot_cfg| &snapshot_cfg.name == name))
+                    .collect(),
+            }),
+            None => units.push(Unit {
+                name: snapshot_cfg.name.clone(),
+                members: vec![snapshot_cfg],
+            }),
+        }
+    }
+
+    units
+}
+
+/// Date and tier part of the name of a snapshot, shared by the members of a group snapshot
+pub fn stamp(full_name: &str) -> &str {
+    full_name.split_once('@').map_or("", |(_, stamp)| stamp)
+}
+
+/// ID of the snapshot of `group` with the date and tier `stamp`
+pub fn group_id(group: &str, stamp: &str) -> String {
+    format!("{}@{}", group, stamp)
+}
+
+/// Stamps of the snapshots in `member_snapshots`, given one list per member
+pub fn stamps(member_snapshots: &[Vec<Snapshot>]) -> Vec<String> {
+    let mut stamps: Vec<String> = member_snapshots.iter()
+        .flatten()
+        .map(|snapshot| stamp(&snapshot.full_name).to_string())
+        .collect();
+    stamps.sort();
+    stamps.dedup();
+    stamps
+}
+
+/// Stamps of the group snapshots that every member has a snapshot of
+pub fn complete_stamps(member_snapshots: &[Vec<Snapshot>]) -> Vec<String> {
+    stamps(member_snapshots).into_iter()
+        .filter(|s| member_snapshots.iter().all(|snapshots| snapshots.iter().any(|snapshot| stamp(&snapshot.full_name) == s)))
+        .collect()
+}
+
+/// Names of the snapshots of each member to send, one list per member: those of the group
+/// snapshots every member has, or only of the ones that `only` names a snapshot of
+///
+/// A group snapshot missing a member is left out, since it can't be restored as a whole.
+pub fn to_send(member_snapshots: &[Vec<Snapshot>], only: Option<&[String]>) -> Vec<Vec<String>> {
+    let named: Vec<&str> = member_snapshots.iter()
+        .flatten()
+        .filter(|snapshot| only.is_none_or(|only| only.contains(&snapshot.full_name)))
+        .map(|snapshot| stamp(&snapshot.full_name))
+        .collect();
+    let stamps: Vec<String> = complete_stamps(member_snapshots).into_iter()
+        .filter(|s| named.contains(&s.as_str()))
+        .collect();
+    member_snapshots.iter()
+        .map(|snapshots| snapshots.iter()
+            .filter(|snapshot| stamps.iter().any(|s| s == stamp(&snapshot.full_name)))
+            .map(|snapshot| snapshot.full_name.clone())
+            .collect())
+        .collect()
+}
+
+/// Stamps of the snapshots of the unit named `name` that `policy` prunes, given the snapshots
+/// of each member and, if the policy has a budget, their exclusive sizes by name, oldest first
+///
+/// A group snapshot counts once however many members it has, with the size of all of them,
+/// and is kept as a whole if any of its members is named in `protected`.
+pub fn select_for_pruning(name: &str, member_snapshots: &[Vec<Snapshot>], policy: &RetentionPolicy, now: DateTime<Utc>, protected: &[&str], sizes: &HashMap<String, u64>) -> Result<Vec<String>> {
+    let snapshots: Vec<Snapshot> = stamps(member_snapshots).iter()
+        .map(|stamp| parse_snapshot_name(&group_id(name, stamp), ""))
+        .collect::<Result<_>>()?;
+    let protected: Vec<String> = member_snapshots.iter()
+        .flatten()
+        .filter(|snapshot| protected.contains(&snapshot.full_name.as_str()))
+        .map(|snapshot| group_id(name, stamp(&snapshot.full_name)))
+        .collect();
+    let protected: Vec<&str> = protected.iter().map(|id| id.as_str()).collect();
+
+    let mut pruned = retention::select_for_pruning(&snapshots, policy, now, &protected);
+    if policy.budget.is_some() {
+        let mut stamp_sizes: HashMap<String, u64> = HashMap::new();
+        for snapshot in member_snapshots.iter().flatten() {
+            *stamp_sizes.entry(group_id(name, stamp(&snapshot.full_name))).or_default() += sizes.get(&snapshot.full_name).copied().unwrap_or_default();
+        }
+        let kept: Vec<&Snapshot> = snapshots.iter()
+            .filter(|snapshot| !pruned.iter().any(|other| other.full_name == snapshot.full_name))
+            .collect();
+        pruned.extend(retention::select_for_budget(&kept, &stamp_sizes, policy, &protected));
+        pruned.sort_by_key(|snapshot| snapshot.datetime);
+    }
+
+    Ok(pruned.into_iter()
+        .map(|snapshot| stamp(&snapshot.full_name).to_string())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::config::{self, GroupConfig};
+
+    use super::*;
+
+    fn snapshots(names: &[&str]) -> Vec<Snapshot> {
+        names.iter().map(|name| parse_snapshot_name(name, "/.snapshots").unwrap()).collect()
+    }
+
+    #[test]
+    fn test_units() {
+        let mut cfg = config::DEFAULT_CONFIG.clone();
+        cfg.snapshots.push(SnapshotConfig::new("srv", "/srv"));
+        let names = |cfg: &Config| -> Vec<(String, Vec<String>)> {
+            units(cfg).iter().map(|unit| (unit.name.clone(), unit.members.iter().map(|member| member.name.clone()).collect())).collect()
+        };
+        assert_eq!(names(&cfg), vec![
+            ("root".to_string(), vec!["root".to_string()]),
+            ("home".to_string(), vec!["home".to_string()]),
+            ("srv".to_string(), vec!["srv".to_string()]),
+        ]);
+        assert!(units(&cfg).iter().all(|unit| !unit.is_group()));
+
+        cfg.groups.push(GroupConfig {
+            name: "system".to_string(),
+            snapshots: vec!["home".to_string(), "srv".to_string()],
+        });
+        assert_eq!(names(&cfg), vec![
+            ("root".to_string(), vec!["root".to_string()]),
+            ("system".to_string(), vec!["home".to_string(), "srv".to_string()]),
+        ]);
+        assert!(units(&cfg)[1].is_group());
+    }
+
+    #[test]
+    fn test_select_for_pruning() {
+        let member_snapshots = vec![
+            snapshots(&["root@2000-01-01_00:00:00_daily", "root@2000-01-02_00:00:00_daily", "root@2000-01-03_00:00:00_daily"]),
+            snapshots(&["home@2000-01-01_00:00:00_daily", "home@2000-01-02_00:00:00_daily", "home@2000-01-03_00:00:00_daily", "home@2000-01-04_00:00:00_daily"]),
+        ];
+        assert_eq!(complete_stamps(&member_snapshots), vec!["2000-01-01_00:00:00_daily", "2000-01-02_00:00:00_daily", "2000-01-03_00:00:00_daily"]);
+        assert_eq!(to_send(&member_snapshots, Some(&["home@2000-01-02_00:00:00_daily".to_string(), "home@2000-01-04_00:00:00_daily".to_string(), "srv@2000-01-03_00:00:00_daily".to_string()])), vec![
+            vec!["root@2000-01-02_00:00:00_daily".to_string()],
+            vec!["home@2000-01-02_00:00:00_daily".to_string()],
+        ]);
+        assert_eq!(to_send(&member_snapshots, None)[1].len(), 3);
+
+        let policy = RetentionPolicy {
+            daily: Some(2),
+            ..Default::default()
+        };
+        let now = Utc.ymd(2000, 1, 5).and_hms(0, 0, 0);
+        assert_eq!(select_for_pruning("system", &member_snapshots, &policy, now, &[], &HashMap::new()).unwrap(), vec!["2000-01-01_00:00:00_daily", "2000-01-02_00:00:00_daily"]);
+        // Pinning one member keeps the whole group snapshot
+        assert_eq!(select_for_pruning("system", &member_snapshots, &policy, now, &["home@2000-01-01_00:00:00_daily"], &HashMap::new()).unwrap(), vec!["2000-01-02_00:00:00_daily"]);
+
+        // Under a budget, a group snapshot weighs as much as all of its members
+        let policy = RetentionPolicy {
+            daily: Some(1),
+            budget: Some(60),
+            ..Default::default()
+        };
+        let sizes: HashMap<String, u64> = member_snapshots.iter()
+            .flatten()
+            .map(|snapshot| (snapshot.full_name.clone(), 10))
+            .collect();
+        assert_eq!(select_for_pruning("system", &member_snapshots, &policy, now, &[], &sizes).unwrap(), vec!["2000-01-01_00:00:00_daily"]);
+    }
+}
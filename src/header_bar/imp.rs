@@ -7,6 +7,8 @@ use gtk::glib::subclass::InitializingObject;
 #[derive(CompositeTemplate, Default)]
 #[template(resource = "/co/veand/fridge/HeaderBar.ui")]
 pub struct HeaderBar {
+    #[template_child]
+    pub switcher: TemplateChild<adw::ViewSwitcherTitle>,
 }
 
 // The central trait for subclassing a GObject
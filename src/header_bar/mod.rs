@@ -1,6 +1,7 @@
 mod imp;
 
 use gtk::glib;
+use gtk::subclass::prelude::ObjectSubclassIsExt;
 
 glib::wrapper! {
     pub struct HeaderBar(ObjectSubclass<imp::HeaderBar>)
@@ -9,4 +10,8 @@ glib::wrapper! {
 }
 
 impl HeaderBar {
+    /// Lets the title switch between the pages of `stack`
+    pub fn set_stack(&self, stack: &adw::ViewStack) {
+        self.imp().switcher.set_stack(Some(stack));
+    }
 }
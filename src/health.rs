@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+/// Outcome of the recent synchronizations to a remote
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct RemoteHealth {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_success: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error_at: Option<DateTime<Utc>>,
+    pub consecutive_failures: u32,
+}
+
+impl RemoteHealth {
+    pub fn record_success(&mut self, at: DateTime<Utc>) {
+        self.last_success = Some(at);
+        self.consecutive_failures = 0;
+    }
+
+    pub fn record_failure(&mut self, error: &str, at: DateTime<Utc>) {
+        self.last_error = Some(error.to_string());
+        self.last_error_at = Some(at);
+        self.consecutive_failures += 1;
+    }
+
+    /// Whether the last synchronization succeeded
+    pub fn is_healthy(&self) -> bool {
+        self.consecutive_failures == 0 && self.last_success.is_some()
+    }
+
+    /// One line description of the health, e.g. "OK, last synchronized at 2023-01-02 03:04:05"
+    pub fn summary(&self) -> String {
+        let format_time = |at: &DateTime<Utc>| at.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string();
+        let last_success = match &self.last_success {
+            Some(at) => format!("last synchronized at {}", format_time(at)),
+            None => "never synchronized".to_string(),
+        };
+
+        if self.consecutive_failures == 0 {
+            if self.last_success.is_some() {
+                format!("OK, {}", last_success)
+            } else {
+                "Never synchronized".to_string()
+            }
+        } else {
+            format!(
+                "{} consecutive failure(s), {}: {}",
+                self.consecutive_failures,
+                last_success,
+                self.last_error.as_deref().unwrap_or_default(),
+            )
+        }
+    }
+}
+
+/// Health of every remote, keyed by location (e.g. "li@192.168.0.2:22:/backup")
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct HealthStore {
+    #[serde(default)]
+    pub remotes: BTreeMap<String, RemoteHealth>,
+}
+
+const HEALTH_FILE: &str = "health.toml";
+
+fn health_path() -> PathBuf {
+    config::state_dir().join(HEALTH_FILE)
+}
+
+impl HealthStore {
+    pub fn load() -> Result<Self> {
+        Self::load_from(&health_path())
+    }
+
+    /// Loads the store at `path`, which is empty if the file doesn't exist yet
+    pub fn load_from(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let s = fs::read_to_string(path)?;
+        Ok(toml::from_str(&s)?)
+    }
+
+    /// Saves the store for every user, through sudo unless running as root
+    pub fn save(&self) -> Result<()> {
+        self.save_with(config::save_state)
+    }
+
+    /// Saves the store with `save_state`, given the name of the state file, its contents and
+    /// whether to append them
+    fn save_with(&self, save_state: impl FnOnce(&str, &str, bool) -> Result<()>) -> Result<()> {
+        save_state(HEALTH_FILE, &toml::to_string(self)?, false)
+    }
+
+    pub fn get(&self, remote: &str) -> RemoteHealth {
+        self.remotes.get(remote).cloned().unwrap_or_default()
+    }
+
+    pub fn record<T>(&mut self, remote: &str, result: &Result<T>) {
+        let health = self.remotes.entry(remote.to_string()).or_default();
+        match result {
+            Ok(_) => health.record_success(Utc::now()),
+            Err(e) => health.record_failure(&format!("{:#}", e), Utc::now()),
+        }
+    }
+}
+
+/// Records the outcome of a synchronization to `remote` in the persisted store
+pub fn record<T>(remote: &str, result: &Result<T>) -> Result<()> {
+    let mut store = HealthStore::load()?;
+    store.record(remote, result);
+    store.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use chrono::TimeZone;
+
+    use crate::testutil::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut health = RemoteHealth::default();
+        assert_eq!(health.summary(), "Never synchronized");
+
+        let at = Utc.datetime_from_str("2023-01-02 03:04:05", "%Y-%m-%d %H:%M:%S").unwrap();
+        health.record_failure("TCP check failed: Connection refused", at);
+        health.record_failure("TCP check failed: Connection refused", at);
+        assert_eq!(health.consecutive_failures, 2);
+        assert!(health.summary().starts_with("2 consecutive failure(s), never synchronized"));
+        assert!(!health.is_healthy());
+
+        health.record_success(at);
+        assert_eq!(health.consecutive_failures, 0);
+        assert_eq!(health.last_success, Some(at));
+        assert_eq!(health.last_error.as_deref(), Some("TCP check failed: Connection refused"));
+        assert!(health.summary().starts_with("OK"));
+        assert!(health.is_healthy());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = TempDir::new("health");
+        let path = dir.join(HEALTH_FILE);
+        assert_eq!(HealthStore::load_from(&path).unwrap(), HealthStore::default());
+
+        let mut store = HealthStore::default();
+        store.record::<()>("li@192.168.0.2:22:/backup", &Err(anyhow!("Connection refused")));
+        store.record("/run/media/backup", &Ok(()));
+        store.save_with(|name, contents, append| config::write_state_at(&dir, name, contents, append)).unwrap();
+
+        let loaded = HealthStore::load_from(&path).unwrap();
+        assert_eq!(loaded, store);
+        assert_eq!(loaded.get("li@192.168.0.2:22:/backup").consecutive_failures, 1);
+        assert_eq!(loaded.get("unknown"), RemoteHealth::default());
+    }
+}
@@ -0,0 +1,269 @@
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Result, bail};
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+/// Kind of operation recorded in the history
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Snapshot,
+    Prune,
+    Transfer,
+    /// Deletion of a snapshot the user asked for
+    Delete,
+    /// Restoration of a snapshot as a new subvolume
+    Restore,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Operation::Snapshot => "snapshot",
+            Operation::Prune => "prune",
+            Operation::Transfer => "transfer",
+            Operation::Delete => "delete",
+            Operation::Restore => "restore",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for Operation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "snapshot" => Ok(Operation::Snapshot),
+            "prune" => Ok(Operation::Prune),
+            "transfer" => Ok(Operation::Transfer),
+            "delete" => Ok(Operation::Delete),
+            "restore" => Ok(Operation::Restore),
+            _ => bail!("Could not parse operation {:?}, expected snapshot, prune, transfer, delete or restore", s),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Success,
+    Failure,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Status::Success => "success",
+            Status::Failure => "failure",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for Status {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "success" => Ok(Status::Success),
+            "failure" => Ok(Status::Failure),
+            _ => bail!("Could not parse status {:?}, expected success or failure", s),
+        }
+    }
+}
+
+/// One operation fridge carried out
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Record {
+    pub operation: Operation,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    /// Name of the snapshotted subvolume (e.g. "root")
+    pub subvolume: String,
+    /// Snapshot that was created, deleted, transferred or restored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<String>,
+    /// Location the operation was carried out at, such as a remote
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    pub status: Status,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Record {
+    /// Creates a record of an operation that started at `started` and just ended with `result`
+    pub fn new<T>(operation: Operation, started: DateTime<Utc>, subvolume: &str, snapshot: Option<&str>, target: &str, bytes: Option<u64>, result: &Result<T>) -> Self {
+        Self {
+            operation,
+            started,
+            finished: Utc::now(),
+            subvolume: subvolume.to_string(),
+            snapshot: snapshot.map(|snapshot| snapshot.to_string()),
+            target: target.to_string(),
+            bytes,
+            status: if result.is_ok() { Status::Success } else { Status::Failure },
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        }
+    }
+
+    pub fn duration(&self) -> chrono::Duration {
+        self.finished.signed_duration_since(self.started)
+    }
+}
+
+/// Criteria for selecting records, each of which is ignored if not set
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    pub operation: Option<Operation>,
+    pub subvolume: Option<String>,
+    /// Matches targets containing this text
+    pub target: Option<String>,
+    pub status: Option<Status>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl Filter {
+    pub fn matches(&self, record: &Record) -> bool {
+        self.operation.is_none_or(|operation| record.operation == operation)
+            && self.subvolume.as_ref().is_none_or(|subvolume| record.subvolume == *subvolume)
+            && self.target.as_ref().is_none_or(|target| record.target.contains(target.as_str()))
+            && self.status.is_none_or(|status| record.status == status)
+            && self.since.is_none_or(|since| record.started >= since)
+            && self.until.is_none_or(|until| record.started < until)
+    }
+}
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+fn history_path() -> PathBuf {
+    config::state_dir().join(HISTORY_FILE)
+}
+
+/// Appends a record to the history shared by every user, logging rather than failing if it
+/// can't be written
+pub fn record(record: Record) {
+    let result = to_line(&record).and_then(|line| config::save_state(HISTORY_FILE, &line, true));
+    if let Err(e) = result {
+        warn!("Could not record {} in history: {}", record.operation, e);
+    }
+}
+
+/// Formats a record as a line of JSON, which a single write appends without interleaving with
+/// the records of concurrent writers
+fn to_line(record: &Record) -> Result<String> {
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    Ok(line)
+}
+
+/// Returns the records matching `filter`, oldest first
+pub fn load(filter: &Filter) -> Result<Vec<Record>> {
+    load_from(&history_path(), filter)
+}
+
+pub fn load_from(path: &Path, filter: &Filter) -> Result<Vec<Record>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut records = Vec::new();
+    for (i, line) in BufReader::new(fs::File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Record>(&line) {
+            Ok(record) if filter.matches(&record) => records.push(record),
+            Ok(_) => (),
+            Err(e) => warn!("Could not parse line {} of {}: {}", i + 1, path.display(), e),
+        }
+    }
+
+    Ok(records)
+}
+
+/// Formats a number of bytes with a binary unit, e.g. "1.5 GiB"
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use chrono::TimeZone;
+
+    use crate::testutil::TempDir;
+
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        Utc.datetime_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = TempDir::new("history");
+        let path = dir.join("history.jsonl");
+        assert!(load_from(&path, &Filter::default()).unwrap().is_empty());
+
+        let mut transfer = Record::new(Operation::Transfer, at("2000-01-02 03:04:05"), "root", Some("root@2000-01-02_03:04:05_daily"), "li@192.168.0.2:22:/backup", Some(1024), &Ok(()));
+        transfer.finished = at("2000-01-02 03:05:05");
+        let prune = Record::new::<()>(Operation::Prune, at("2000-01-03 03:04:05"), "home", Some("home@2000-01-01_03:04:05_daily"), "/run/media/backup", None, &Err(anyhow!("Device or resource busy")));
+        let mut restore = Record::new(Operation::Restore, at("2000-01-04 03:04:05"), "home", Some("home@2000-01-01_03:04:05_daily"), "/mnt/restore", None, &Ok(()));
+        restore.finished = at("2000-01-04 03:04:06");
+        let lines: Vec<String> = [&transfer, &prune, &restore].iter().map(|record| to_line(record).unwrap()).collect();
+        fs::write(&path, format!("{}not json\n", lines.concat())).unwrap();
+
+        let all = load_from(&path, &Filter::default()).unwrap();
+        let failures = load_from(&path, &Filter { status: Some(Status::Failure), ..Default::default() }).unwrap();
+        let remote = load_from(&path, &Filter { target: Some("192.168.0.2".to_string()), ..Default::default() }).unwrap();
+        let since = load_from(&path, &Filter { since: Some(at("2000-01-03 00:00:00")), until: Some(at("2000-01-04 00:00:00")), ..Default::default() }).unwrap();
+        let restores = load_from(&path, &Filter { operation: Some(Operation::Restore), ..Default::default() }).unwrap();
+
+        assert_eq!(all, [transfer.clone(), prune.clone(), restore.clone()]);
+        assert_eq!(restores, [restore]);
+        assert_eq!(failures, vec![prune.clone()]);
+        assert_eq!(failures[0].error.as_deref(), Some("Device or resource busy"));
+        assert_eq!(remote, [transfer.clone()]);
+        assert_eq!(remote[0].duration(), chrono::Duration::minutes(1));
+        assert_eq!(since, [prune]);
+    }
+
+    #[test]
+    fn test_operation() {
+        for operation in [Operation::Snapshot, Operation::Prune, Operation::Transfer, Operation::Delete, Operation::Restore] {
+            assert_eq!(operation.to_string().parse::<Operation>().unwrap(), operation);
+        }
+        assert!("rollback".parse::<Operation>().is_err());
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(10 * 1024 * 1024 * 1024), "10.0 GiB");
+    }
+}
@@ -0,0 +1,59 @@
+use adw::prelude::*;
+use gtk::subclass::prelude::*;
+use gtk::{glib, CompositeTemplate};
+use gtk::glib::subclass::InitializingObject;
+use std::cell::RefCell;
+
+use crate::history::Record;
+
+// Object holding the state
+#[derive(CompositeTemplate, Default)]
+#[template(resource = "/co/veand/fridge/HistoryPage.ui")]
+pub struct HistoryPage {
+    #[template_child]
+    pub search_entry: TemplateChild<gtk::SearchEntry>,
+    #[template_child]
+    pub status_dropdown: TemplateChild<gtk::DropDown>,
+    #[template_child]
+    pub refresh_button: TemplateChild<gtk::Button>,
+    #[template_child]
+    pub list: TemplateChild<gtk::ListBox>,
+    /// Records loaded from the history, newest first
+    pub records: RefCell<Vec<Record>>,
+}
+
+// The central trait for subclassing a GObject
+#[glib::object_subclass]
+impl ObjectSubclass for HistoryPage {
+    // `NAME` needs to match `class` attribute of template
+    const NAME: &'static str = "HistoryPage";
+    type Type = super::HistoryPage;
+    type ParentType = gtk::Box;
+
+    fn class_init(klass: &mut Self::Class) {
+        klass.bind_template();
+    }
+
+    fn instance_init(obj: &InitializingObject<Self>) {
+        obj.init_template();
+    }
+}
+
+// Trait shared by all GObjects
+impl ObjectImpl for HistoryPage {
+    fn constructed(&self) {
+        // Call "constructed" on parent
+        self.parent_constructed();
+
+        let obj = self.obj();
+        obj.setup_callbacks();
+        if let Err(e) = obj.refresh() {
+            log::warn!("Could not load history: {}", e);
+        }
+    }
+}
+
+// Trait shared by all widgets
+impl WidgetImpl for HistoryPage {}
+
+impl BoxImpl for HistoryPage {}
@@ -0,0 +1,98 @@
+mod imp;
+
+use adw::prelude::*;
+use anyhow::Result;
+use gtk::glib;
+use gtk::glib::clone;
+use gtk::subclass::prelude::ObjectSubclassIsExt;
+
+use crate::history::{self, Filter, Record, Status};
+
+/// Maximum number of records shown at once
+const MAX_ROWS: usize = 500;
+
+glib::wrapper! {
+    pub struct HistoryPage(ObjectSubclass<imp::HistoryPage>)
+        @extends gtk::Box, gtk::Widget,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::Orientable;
+}
+
+impl HistoryPage {
+    fn setup_callbacks(&self) {
+        let imp = self.imp();
+        imp.search_entry.connect_search_changed(clone!(@weak self as page => move |_| {
+            page.update_list();
+        }));
+        imp.status_dropdown.connect_selected_notify(clone!(@weak self as page => move |_| {
+            page.update_list();
+        }));
+        imp.refresh_button.connect_clicked(clone!(@weak self as page => move |_| {
+            if let Err(e) = page.refresh() {
+                log::warn!("Could not load history: {}", e);
+            }
+        }));
+    }
+
+    /// Reloads the history and shows the records matching the filters
+    pub fn refresh(&self) -> Result<()> {
+        let mut records = history::load(&Filter::default())?;
+        records.reverse();
+        self.imp().records.replace(records);
+        self.update_list();
+
+        Ok(())
+    }
+
+    fn status_filter(&self) -> Option<Status> {
+        match self.imp().status_dropdown.selected() {
+            1 => Some(Status::Success),
+            2 => Some(Status::Failure),
+            _ => None,
+        }
+    }
+
+    fn update_list(&self) {
+        let imp = self.imp();
+        while let Some(row) = imp.list.first_child() {
+            imp.list.remove(&row);
+        }
+
+        let text = imp.search_entry.text().to_string();
+        let status = self.status_filter();
+        let records = imp.records.borrow();
+        let matching = records.iter()
+            .filter(|record| status.is_none_or(|status| record.status == status))
+            .filter(|record| text.is_empty() || record.subvolume.contains(&text) || record.target.contains(&text))
+            .take(MAX_ROWS);
+        for record in matching {
+            imp.list.append(&Self::row(record));
+        }
+    }
+
+    fn row(record: &Record) -> adw::ActionRow {
+        let title = match &record.snapshot {
+            Some(snapshot) => format!("{} {}", record.operation, snapshot),
+            None => format!("{} {}", record.operation, record.subvolume),
+        };
+        let mut subtitle = format!("{} · {} · {}s", record.started.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"), record.target, record.duration().num_seconds());
+        if let Some(bytes) = record.bytes {
+            subtitle.push_str(&format!(" · {}", history::format_bytes(bytes)));
+        }
+        if let Some(error) = &record.error {
+            subtitle.push('\n');
+            subtitle.push_str(error);
+        }
+
+        let row = adw::ActionRow::builder()
+            .title(&glib::markup_escape_text(&title))
+            .subtitle(&glib::markup_escape_text(&subtitle))
+            .build();
+        let icon = match record.status {
+            Status::Success => "emblem-ok-symbolic",
+            Status::Failure => "dialog-error-symbolic",
+        };
+        row.add_prefix(&gtk::Image::from_icon_name(icon));
+
+        row
+    }
+}
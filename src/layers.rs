@@ -0,0 +1,395 @@
+//! Merging of the configuration file, its drop-in files and environment overrides
+//!
+//! Layers are merged in order, later ones winning:
+//!
+//! - Tables are merged key by key, so a drop-in file only needs the keys it changes.
+//! - Values, including arrays like `ssh_options`, replace the value of an earlier layer.
+//! - Entries of `snapshots` and `groups` are merged with the entry of an earlier layer with
+//!   the same `name`, and entries of `remotes` with the one with the same `name`, or the same
+//!   `host` and `path` if they have no name. Other entries are added after the earlier ones.
+//!   Entries in the same layer are never merged with each other.
+//!
+//! Environment variables like `FRIDGE__LOCAL__SUDO=true` or `FRIDGE__SNAPSHOTS__home__DAILY=14`
+//! override single values: the segments between double underscores are the keys, lowercased,
+//! except for the name of a snapshot, group or remote following `SNAPSHOTS`, `GROUPS` or
+//! `REMOTES`. The value is read as TOML if it can be, and as a string otherwise.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use anyhow::{Result, bail};
+use toml::value::{Table, Value};
+
+/// Prefix of the environment variables overriding configuration values
+pub const ENV_PREFIX: &str = "FRIDGE__";
+
+/// Arrays of tables whose entries are merged by name
+const NAMED_ARRAYS: &[&str] = &["snapshots", "groups", "remotes"];
+
+/// Where a value of the merged configuration comes from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Origin {
+    /// File or environment variable that set it
+    pub source: String,
+    /// Path of the value in that source, e.g. "snapshots.1.daily"
+    pub path: String,
+}
+
+/// Configuration merged from several layers, remembering where each value comes from
+#[derive(Clone, Debug)]
+pub struct Layers {
+    pub value: Table,
+    /// Origin of every table and value, by path in the merged configuration
+    pub origins: BTreeMap<String, Origin>,
+}
+
+impl Default for Layers {
+    fn default() -> Self {
+        Self {
+            value: Table::new(),
+            origins: BTreeMap::new(),
+        }
+    }
+}
+
+impl Layers {
+    /// Merges `layer` read from `source` over the layers added before
+    pub fn add(&mut self, source: &str, layer: Table) {
+        merge_table(&mut self.value, layer, "", "", source, &mut self.origins);
+    }
+
+    /// Merges the overrides in environment variables, given as (name, value)
+    pub fn add_env<'a>(&mut self, vars: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<()> {
+        for (name, value) in vars {
+            self.add(name, env_layer(name, value)?);
+        }
+
+        Ok(())
+    }
+
+    /// Sources other than `source` that set anything in entry `i` of the `key` array
+    pub fn other_sources(&self, key: &str, i: usize, source: &str) -> Vec<&str> {
+        let path = format!("{}.{}", key, i);
+        let prefix = format!("{}.", path);
+        let mut sources: Vec<&str> = self.origins.iter()
+            .filter(|(other, origin)| (**other == path || other.starts_with(&prefix)) && origin.source != source)
+            .map(|(_, origin)| origin.source.as_str())
+            .collect();
+        sources.sort();
+        sources.dedup();
+        sources
+    }
+
+    /// Prints the merged configuration as TOML, with the origin of each value in a comment
+    pub fn show(&self) -> String {
+        let mut out = String::new();
+        self.write_table(&mut out, &self.value, "", "");
+        out
+    }
+
+    fn write_table(&self, out: &mut String, table: &Table, path: &str, header: &str) {
+        for (key, value) in table {
+            if value.is_table() || is_array_of_tables(value) {
+                continue;
+            }
+            let _ = write!(out, "{} = {}", key, value);
+            if let Some(origin) = self.origins.get(&join(path, key)) {
+                let _ = write!(out, "  # {}", origin.source);
+            }
+            out.push('\n');
+        }
+
+        for (key, value) in table {
+            let header = join(header, key);
+            match value {
+                Value::Table(child) => {
+                    let _ = writeln!(out, "\n[{}]", header);
+                    self.write_table(out, child, &join(path, key), &header);
+                },
+                Value::Array(array) if is_array_of_tables(value) => {
+                    for (i, entry) in array.iter().enumerate() {
+                        let _ = writeln!(out, "\n[[{}]]", header);
+                        if let Some(entry) = entry.as_table() {
+                            self.write_table(out, entry, &format!("{}.{}", join(path, key), i), &header);
+                        }
+                    }
+                },
+                _ => (),
+            }
+        }
+    }
+}
+
+fn merge_table(base: &mut Table, layer: Table, path: &str, layer_path: &str, source: &str, origins: &mut BTreeMap<String, Origin>) {
+    for (key, value) in layer {
+        let key_path = join(path, &key);
+        let key_layer_path = join(layer_path, &key);
+        let origin = Origin {
+            source: source.to_string(),
+            path: key_layer_path.clone(),
+        };
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base_table)), Value::Table(table)) => {
+                merge_table(base_table, table, &key_path, &key_layer_path, source, origins);
+            },
+            (Some(Value::Array(base_array)), Value::Array(array)) if path.is_empty() && NAMED_ARRAYS.contains(&key.as_str()) => {
+                merge_entries(base_array, array, &key, &key_path, &key_layer_path, source, origins);
+            },
+            (_, value) => {
+                // A replaced table or array no longer holds what the earlier layers put there
+                let prefix = format!("{}.", key_path);
+                origins.retain(|path, _| !path.starts_with(&prefix));
+                record_origins(&value, &key_path, &key_layer_path, source, origins);
+                origins.insert(key_path, origin);
+                base.insert(key, value);
+            },
+        }
+    }
+}
+
+/// Merges the entries of `array` with the entries of `base` with the same name, or adds them
+fn merge_entries(base: &mut Vec<Value>, array: Vec<Value>, key: &str, path: &str, layer_path: &str, source: &str, origins: &mut BTreeMap<String, Origin>) {
+    let earlier = base.len();
+    for (i, entry) in array.into_iter().enumerate() {
+        let entry_layer_path = format!("{}.{}", layer_path, i);
+        let name = entry_name(key, &entry);
+        let matching = name.as_ref().and_then(|name| base[..earlier].iter().position(|other| entry_name(key, other).as_ref() == Some(name)));
+        match (matching, entry) {
+            (Some(j), Value::Table(mut table)) => {
+                // What the entries matched by is the same in both, and stays where it was set
+                for field in ["name", "host", "path"] {
+                    if base[j].get(field) == table.get(field) {
+                        table.remove(field);
+                    }
+                }
+                if let Some(base_table) = base[j].as_table_mut() {
+                    merge_table(base_table, table, &format!("{}.{}", path, j), &entry_layer_path, source, origins);
+                }
+            },
+            (_, entry) => {
+                let entry_path = format!("{}.{}", path, base.len());
+                record_origins(&entry, &entry_path, &entry_layer_path, source, origins);
+                origins.insert(entry_path, Origin {
+                    source: source.to_string(),
+                    path: entry_layer_path,
+                });
+                base.push(entry);
+            },
+        }
+    }
+}
+
+/// Name an entry of the `key` array is merged by
+fn entry_name(key: &str, entry: &Value) -> Option<String> {
+    let get = |field: &str| entry.get(field).and_then(Value::as_str);
+    match (get("name"), key) {
+        (Some(name), _) => Some(name.to_string()),
+        (None, "remotes") => Some(format!("{}:{}", get("host").unwrap_or_default(), get("path")?)),
+        (None, _) => None,
+    }
+}
+
+/// Records `source` as origin of everything inside `value`
+fn record_origins(value: &Value, path: &str, layer_path: &str, source: &str, origins: &mut BTreeMap<String, Origin>) {
+    let children: Vec<(String, &Value)> = match value {
+        Value::Table(table) => table.iter().map(|(key, value)| (key.clone(), value)).collect(),
+        Value::Array(array) if is_array_of_tables(value) => array.iter().enumerate().map(|(i, value)| (i.to_string(), value)).collect(),
+        _ => return,
+    };
+    for (key, child) in children {
+        let child_path = join(path, &key);
+        let child_layer_path = join(layer_path, &key);
+        record_origins(child, &child_path, &child_layer_path, source, origins);
+        origins.insert(child_path, Origin {
+            source: source.to_string(),
+            path: child_layer_path,
+        });
+    }
+}
+
+/// Layer setting the single value of the environment variable `name`
+fn env_layer(name: &str, value: &str) -> Result<Table> {
+    let keys = match name.strip_prefix(ENV_PREFIX) {
+        Some(keys) => keys,
+        None => bail!("Could not read {}: it doesn't start with {}", name, ENV_PREFIX),
+    };
+    let mut segments: Vec<String> = keys.split("__").map(|segment| segment.to_string()).collect();
+    if segments.len() < 2 || segments.iter().any(|segment| segment.is_empty()) {
+        bail!("Could not read {}: expected {}<SECTION>__<KEY>", name, ENV_PREFIX);
+    }
+    let named = NAMED_ARRAYS.contains(&segments[0].to_lowercase().as_str());
+    for (i, segment) in segments.iter_mut().enumerate() {
+        // The name of a snapshot, group or remote is kept as it is
+        if !(named && i == 1) {
+            *segment = segment.to_lowercase();
+        }
+    }
+    if named && segments.len() < 3 {
+        bail!("Could not read {}: expected {}{}__<NAME>__<KEY>", name, ENV_PREFIX, segments[0].to_uppercase());
+    }
+
+    let value = match format!("value = {}", value).parse::<Value>() {
+        Ok(Value::Table(mut table)) => table.remove("value").unwrap_or_else(|| Value::String(value.to_string())),
+        _ => Value::String(value.to_string()),
+    };
+
+    let mut layer = Table::new();
+    let (last, parents) = segments.split_last().unwrap();
+    let mut table = &mut layer;
+    let mut i = 0;
+    while i < parents.len() {
+        let segment = &parents[i];
+        if i == 0 && named {
+            let mut entry = Table::new();
+            entry.insert("name".to_string(), Value::String(parents[1].clone()));
+            table.insert(segment.clone(), Value::Array(vec![Value::Table(entry)]));
+            table = match table.get_mut(segment.as_str()) {
+                Some(Value::Array(array)) => array[0].as_table_mut().unwrap(),
+                _ => unreachable!(),
+            };
+            i += 2;
+        } else {
+            table.insert(segment.clone(), Value::Table(Table::new()));
+            table = table.get_mut(segment.as_str()).and_then(Value::as_table_mut).unwrap();
+            i += 1;
+        }
+    }
+    table.insert(last.clone(), value);
+
+    Ok(layer)
+}
+
+fn is_array_of_tables(value: &Value) -> bool {
+    match value {
+        Value::Array(array) => !array.is_empty() && array.iter().all(Value::is_table),
+        _ => false,
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(document: &str) -> Table {
+        toml::from_str(document).unwrap()
+    }
+
+    const MAIN: &str = r#"
+[local]
+sudo = true
+nice = 10
+
+[[snapshots]]
+name = "root"
+path = "/"
+hourly = 24
+
+[[snapshots]]
+name = "home"
+path = "/home"
+daily = 7
+
+[[remotes]]
+host = "nas"
+path = "/backup"
+ssh_options = ["Compression=yes"]
+"#;
+
+    const DROP_IN: &str = r#"
+[local]
+nice = 5
+
+[[snapshots]]
+name = "home"
+daily = 14
+
+[[snapshots]]
+name = "srv"
+path = "/srv"
+
+[[remotes]]
+host = "nas"
+path = "/backup"
+ssh_options = []
+"#;
+
+    #[test]
+    fn test_merge() {
+        let mut layers = Layers::default();
+        layers.add("fridge.toml", table(MAIN));
+        layers.add("conf.d/10-srv.toml", table(DROP_IN));
+
+        let merged: Value = layers.value.clone().into();
+        assert_eq!(merged["local"]["sudo"].as_bool(), Some(true));
+        assert_eq!(merged["local"]["nice"].as_integer(), Some(5));
+        let snapshots = merged["snapshots"].as_array().unwrap();
+        assert_eq!(snapshots.len(), 3);
+        assert_eq!(snapshots[1]["path"].as_str(), Some("/home"));
+        assert_eq!(snapshots[1]["daily"].as_integer(), Some(14));
+        assert_eq!(snapshots[2]["name"].as_str(), Some("srv"));
+        let remotes = merged["remotes"].as_array().unwrap();
+        assert_eq!(remotes.len(), 1);
+        assert_eq!(remotes[0]["ssh_options"].as_array().map(Vec::len), Some(0));
+
+        let origin = |path: &str| layers.origins.get(path).cloned().unwrap();
+        assert_eq!(origin("local.sudo"), Origin { source: "fridge.toml".to_string(), path: "local.sudo".to_string() });
+        assert_eq!(origin("snapshots.1.daily"), Origin { source: "conf.d/10-srv.toml".to_string(), path: "snapshots.0.daily".to_string() });
+        assert_eq!(origin("snapshots.1.path").source, "fridge.toml");
+        assert_eq!(origin("snapshots.2"), Origin { source: "conf.d/10-srv.toml".to_string(), path: "snapshots.1".to_string() });
+        assert_eq!(origin("snapshots.2.path").path, "snapshots.1.path");
+
+        assert!(layers.other_sources("snapshots", 0, "fridge.toml").is_empty());
+        assert_eq!(layers.other_sources("snapshots", 1, "fridge.toml"), ["conf.d/10-srv.toml"]);
+        assert_eq!(layers.other_sources("snapshots", 2, "fridge.toml"), ["conf.d/10-srv.toml"]);
+    }
+
+    #[test]
+    fn test_same_layer_not_merged() {
+        let mut layers = Layers::default();
+        layers.add("fridge.toml", table("[[snapshots]]\nname = \"root\"\npath = \"/\"\n\n[[snapshots]]\nname = \"root\"\npath = \"/home\"\n"));
+        assert_eq!(layers.value["snapshots"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_env() {
+        let mut layers = Layers::default();
+        layers.add("fridge.toml", table(MAIN));
+        layers.add_env([
+            ("FRIDGE__LOCAL__SUDO", "false"),
+            ("FRIDGE__SNAPSHOTS__home__DAILY", "30"),
+            ("FRIDGE__NOTIFICATIONS__BACKUP_OVERDUE_AFTER", "1w"),
+        ]).unwrap();
+
+        let merged: Value = layers.value.clone().into();
+        assert_eq!(merged["local"]["sudo"].as_bool(), Some(false));
+        assert_eq!(merged["snapshots"][1]["daily"].as_integer(), Some(30));
+        assert_eq!(merged["snapshots"].as_array().unwrap().len(), 2);
+        assert_eq!(merged["notifications"]["backup_overdue_after"].as_str(), Some("1w"));
+        assert_eq!(layers.origins["snapshots.1.daily"].source, "FRIDGE__SNAPSHOTS__home__DAILY");
+
+        assert!(Layers::default().add_env([("FRIDGE__SUDO", "true")]).is_err());
+        assert!(Layers::default().add_env([("FRIDGE__SNAPSHOTS__DAILY", "1")]).is_err());
+        assert!(Layers::default().add_env([("FRIDGE__LOCAL____SUDO", "1")]).is_err());
+    }
+
+    #[test]
+    fn test_show() {
+        let mut layers = Layers::default();
+        layers.add("fridge.toml", table("[local]\nsudo = true\n\n[[snapshots]]\nname = \"root\"\npath = \"/\"\n"));
+        layers.add_env([("FRIDGE__SNAPSHOTS__root__HOURLY", "12")]).unwrap();
+
+        let shown = layers.show();
+        assert!(shown.contains("[local]\nsudo = true  # fridge.toml\n"));
+        assert!(shown.contains("[[snapshots]]\nhourly = 12  # FRIDGE__SNAPSHOTS__root__HOURLY\nname = \"root\"  # fridge.toml\n"));
+        // The merged configuration reads back the same
+        assert_eq!(table(&shown), layers.value);
+    }
+}
@@ -1,19 +1,50 @@
+#[macro_use]
+extern crate lazy_static;
+
 mod window;
 mod header_bar;
+mod backups_page;
+mod history_page;
+mod remote_config_editor;
+mod setup_assistant;
+mod snapshot_config_editor;
+mod snapshot_list;
+mod snapshot_object;
 mod preferences_window;
-mod config_hourly_snapshot;
-mod config_max_hourly_snapshots;
-mod config_remote_backup;
-mod config_remote_user;
-mod config_remote_host;
-mod config_remote_directory;
+mod cancel;
+mod check;
+mod cli;
+mod compare;
+mod conditions;
+mod discover;
+mod config;
 mod fridge;
+mod groups;
+mod health;
+mod history;
+mod layers;
+mod migrate;
+mod nested;
+mod notify;
+mod pins;
+mod preflight;
+mod relay;
+mod retention;
+mod runner;
+mod space;
+mod ssh;
+mod throttle;
+mod usage;
+mod verify;
+#[cfg(test)]
+mod testutil;
 
 use gio::SimpleAction;
 use glib::clone;
 use gtk::prelude::*;
 use gtk::gio;
 use adw::Application;
+use clap::Parser;
 use window::Window;
 use preferences_window::PreferencesWindow;
 
@@ -37,6 +68,16 @@ fn build_ui(app: &Application) {
 fn main() {
     pretty_env_logger::init();
 
+    // Run the command line interface instead of the window when arguments are given
+    if std::env::args().len() > 1 {
+        let cli = cli::Cli::parse();
+        if let Err(e) = cli::run(&cli) {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Register and include resources
     gio::resources_register_include!("fridge.gresource")
         .expect("Could not register resources.");
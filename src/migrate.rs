@@ -0,0 +1,173 @@
+use std::process::Command;
+use std::str;
+
+use anyhow::{Result, bail};
+use log::info;
+
+use crate::config::{self, RemoteConfig};
+
+/// Where older versions kept their settings in dconf, through GSettings
+const DCONF_DIR: &str = "/co/veand/Fridge/";
+
+/// Settings older versions kept in GSettings instead of the configuration file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LegacySettings {
+    pub remote_user: Option<String>,
+    pub remote_host: Option<String>,
+    pub remote_directory: Option<String>,
+    pub max_hourly_snapshots: Option<usize>,
+}
+
+impl LegacySettings {
+    /// Reads the settings straight from dconf, since their keys are no longer in the schema
+    pub fn load() -> Result<Self> {
+        Ok(Self {
+            remote_user: dconf_read("remote-user")?,
+            remote_host: dconf_read("remote-host")?,
+            remote_directory: dconf_read("remote-directory")?,
+            max_hourly_snapshots: dconf_read("max-hourly-snapshots")?.and_then(|value| value.parse().ok()),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Remote the window backed up to, the way it reached it: on port 22 and with sudo
+    fn remote(&self) -> Option<RemoteConfig> {
+        let host = self.remote_host.as_deref().filter(|host| !host.is_empty())?;
+        let mut remote = RemoteConfig::new(Some(host), self.remote_directory.as_deref().unwrap_or("/"));
+        remote.user = self.remote_user.clone().filter(|user| !user.is_empty());
+        remote.port = Some(22);
+        remote.sudo = true;
+        Some(remote)
+    }
+
+    /// Returns `document` with these settings added where it doesn't configure the same
+    /// already, or `None` if there is nothing to add
+    pub fn apply(&self, document: &str) -> Result<Option<String>> {
+        let cfg = config::parse_document(document)?;
+        let mut document = document.to_string();
+        let mut changed = false;
+
+        if let Some(remote) = self.remote() {
+            let location = remote.to_location()?.to_string();
+            let exists = cfg.remotes.iter().any(|other| other.to_location().is_ok_and(|other| other.to_string() == location));
+            if !exists {
+                let mut remotes = cfg.remotes.clone();
+                remotes.push(remote);
+                document = config::replace_section(&document, "remotes", &remotes)?;
+                changed = true;
+            }
+        }
+
+        // The preferences only ever changed the number of hourly snapshots away from the default
+        if let Some(hourly) = self.max_hourly_snapshots {
+            let mut snapshots = cfg.snapshots.clone();
+            for snapshot_cfg in snapshots.iter_mut().filter(|snapshot_cfg| snapshot_cfg.hourly == config::DEFAULT_HOURLY) {
+                snapshot_cfg.hourly = hourly;
+            }
+            if snapshots != cfg.snapshots {
+                document = config::replace_section(&document, "snapshots", &snapshots)?;
+                changed = true;
+            }
+        }
+
+        Ok(changed.then_some(document))
+    }
+}
+
+/// Moves the settings older versions kept in GSettings into the configuration file, then
+/// forgets them so that this happens once
+///
+/// Returns whether the configuration file changed.
+pub fn migrate() -> Result<bool> {
+    let legacy = LegacySettings::load()?;
+    if legacy.is_empty() {
+        return Ok(false);
+    }
+
+    let changed = match legacy.apply(&config::read_document()?)? {
+        Some(document) => {
+            config::install(&document)?;
+            info!("Moved settings from GSettings to {}", config::CONFIG_PATH);
+            true
+        },
+        None => false,
+    };
+    dconf_reset()?;
+
+    Ok(changed)
+}
+
+/// Reads the value of `key`, or `None` if it isn't set
+fn dconf_read(key: &str) -> Result<Option<String>> {
+    let output = match Command::new("dconf").args(["read", &format!("{}{}", DCONF_DIR, key)]).output() {
+        Ok(output) => output,
+        // Without dconf, there is nothing to migrate
+        Err(_) => return Ok(None),
+    };
+    if !output.status.success() {
+        bail!("Could not read {} from dconf: {}", key, str::from_utf8(&output.stderr).unwrap_or_default().trim());
+    }
+
+    Ok(parse_gvariant(str::from_utf8(&output.stdout)?))
+}
+
+fn dconf_reset() -> Result<()> {
+    let output = Command::new("dconf").args(["reset", "-f", DCONF_DIR]).output()?;
+    if !output.status.success() {
+        bail!("Could not reset {} in dconf: {}", DCONF_DIR, str::from_utf8(&output.stderr).unwrap_or_default().trim());
+    }
+
+    Ok(())
+}
+
+/// Parses a string or number in the text format dconf prints values in, e.g. 'li' or uint32 24
+fn parse_gvariant(text: &str) -> Option<String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+
+    match text.strip_prefix('\'').and_then(|text| text.strip_suffix('\'')) {
+        Some(quoted) => Some(quoted.replace("\\'", "'").replace("\\\\", "\\")),
+        None => text.split_whitespace().last().map(|value| value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gvariant() {
+        assert_eq!(parse_gvariant("'li'\n"), Some("li".to_string()));
+        assert_eq!(parse_gvariant("'it\\'s'"), Some("it's".to_string()));
+        assert_eq!(parse_gvariant("uint32 24"), Some("24".to_string()));
+        assert_eq!(parse_gvariant("12"), Some("12".to_string()));
+        assert_eq!(parse_gvariant("\n"), None);
+    }
+
+    #[test]
+    fn test_apply() {
+        let legacy = LegacySettings {
+            remote_user: Some("li".to_string()),
+            remote_host: Some("192.168.0.2".to_string()),
+            remote_directory: Some("/backup".to_string()),
+            max_hourly_snapshots: Some(12),
+        };
+        let document = config::replace_section("", "snapshots", &config::DEFAULT_CONFIG.snapshots).unwrap();
+
+        let migrated = legacy.apply(&document).unwrap().unwrap();
+        let cfg = config::parse_document(&migrated).unwrap();
+        assert_eq!(cfg.remotes.len(), 1);
+        assert_eq!(cfg.remotes[0].to_location().unwrap().to_string(), "li@192.168.0.2:22:/backup");
+        assert!(cfg.remotes[0].sudo);
+        assert!(cfg.snapshots.iter().all(|snapshot_cfg| snapshot_cfg.hourly == 12));
+
+        // Applying them again changes nothing
+        assert_eq!(legacy.apply(&migrated).unwrap(), None);
+        assert_eq!(LegacySettings::default().apply(&document).unwrap(), None);
+    }
+}
@@ -0,0 +1,312 @@
+//! Recursive snapshots of a subvolume together with the subvolumes nested in it
+//!
+//! `btrfs subvolume snapshot` stops at nested subvolumes and leaves empty directories in their
+//! place. A recursive snapshot is a set instead: the snapshot of the subvolume, plus a member
+//! snapshot of every nested subvolume taking part, kept next to it under the same date and tier.
+//! The member of "root@2000-01-02_03:04:05_daily" for /var/lib/machines is
+//! "root+var+lib+machines@2000-01-02_03:04:05_daily". Members are sent, pruned, deleted and
+//! restored along with the snapshot they belong to, and never listed as snapshots of their own.
+
+use std::path::Path;
+use std::process::Command;
+use std::str;
+
+use anyhow::{Result, anyhow, bail};
+use chrono::Utc;
+use log::{info, warn};
+
+use crate::config::{Config, SnapshotConfig};
+use crate::discover::{self, TreeEntry};
+use crate::fridge::{self, Snapshot, SnapshotRepositoryLocation};
+use crate::history::{self, Operation, Record};
+
+/// Separates the name of a snapshot configuration from the path of a nested subvolume in the
+/// names of member snapshots, and stands for / in that path
+pub const SEPARATOR: char = '+';
+
+/// ID of the top-level subvolume of every btrfs filesystem
+const TOP_LEVEL_ID: u64 = 5;
+
+/// Name that the member snapshots of the subvolume nested at `path` in the source of `name`
+/// are taken under
+pub fn member_name(name: &str, path: &str) -> String {
+    format!("{}{}{}", name, SEPARATOR, path.replace('/', &SEPARATOR.to_string()))
+}
+
+/// Path of the nested subvolume, relative to the source of `name`, that the snapshots named
+/// `member` are members for, if they belong to `name` at all
+pub fn member_path(name: &str, member: &str) -> Option<String> {
+    member.strip_prefix(name)?
+        .strip_prefix(SEPARATOR)
+        .filter(|path| !path.is_empty())
+        .map(|path| path.replace(SEPARATOR, "/"))
+}
+
+/// Nested subvolumes taking part in the recursive snapshots of `snapshot_cfg`, relative to its
+/// source and parents first, or none if it isn't recursive
+///
+/// Snapshot directories and subvolumes with a snapshot configuration of their own never take
+/// part.
+pub fn for_config(snapshot_cfg: &SnapshotConfig, cfg: &Config, sudo: bool) -> Result<Vec<String>> {
+    if !snapshot_cfg.recursive {
+        return Ok(Vec::new());
+    }
+
+    let mut skipped = Vec::new();
+    for other in &cfg.snapshots {
+        skipped.push(other.snapshot_location(&cfg.local).path);
+        if other.name != snapshot_cfg.name {
+            skipped.push(other.path.clone());
+        }
+    }
+    let skipped: Vec<String> = skipped.iter()
+        .filter_map(|path| Path::new(path).strip_prefix(&snapshot_cfg.path).ok())
+        .filter_map(|path| path.to_str().map(|path| path.to_string()))
+        .filter(|path| !path.is_empty())
+        .collect();
+    let nested: Vec<String> = nested_subvolumes(&snapshot_cfg.path, sudo)?.into_iter()
+        .filter(|path| !path.split('/').any(|component| component == cfg.local.suffix || component == ".snapshots"))
+        .collect();
+
+    Ok(select(&nested, &snapshot_cfg.include, &snapshot_cfg.exclude, &skipped))
+}
+
+/// Paths of the subvolumes nested in the one at `path`, at any depth, relative to it and parents
+/// first
+pub fn nested_subvolumes(path: &str, sudo: bool) -> Result<Vec<String>> {
+    let id = subvolume_id(path, sudo)?;
+    Ok(descendants(&discover::list_subvolumes(path, sudo)?, id))
+}
+
+/// ID of the subvolume at `path`
+pub fn subvolume_id(path: &str, sudo: bool) -> Result<u64> {
+    let (program, args) = if sudo {
+        ("sudo", vec!["btrfs", "subvolume", "show", path])
+    } else {
+        ("btrfs", vec!["subvolume", "show", path])
+    };
+    let output = Command::new(program)
+        .args(args)
+        .output()?;
+
+    if !output.status.success() {
+        bail!("Could not find a btrfs subvolume at {}: {}", path, str::from_utf8(&output.stderr).unwrap().trim());
+    }
+
+    parse_subvolume_id(str::from_utf8(&output.stdout)?)
+        .ok_or_else(|| anyhow!("Could not find the ID of the subvolume at {}", path))
+}
+
+/// Parses the ID out of the output of `btrfs subvolume show`
+pub fn parse_subvolume_id(text: &str) -> Option<u64> {
+    text.lines()
+        .find_map(|line| line.trim().strip_prefix("Subvolume ID:"))
+        .and_then(|id| id.trim().parse().ok())
+}
+
+/// Paths of the subvolumes nested in subvolume `id` of `tree`, at any depth, relative to it and
+/// parents first
+pub fn descendants(tree: &[TreeEntry], id: u64) -> Vec<String> {
+    // The top-level subvolume isn't listed, and every path is relative to it
+    let base = if id == TOP_LEVEL_ID {
+        "/".to_string()
+    } else {
+        match tree.iter().find(|entry| entry.id == id) {
+            Some(entry) => format!("{}/", entry.path),
+            None => return Vec::new(),
+        }
+    };
+
+    let mut paths = Vec::new();
+    let mut parents = vec![id];
+    while let Some(parent) = parents.pop() {
+        for entry in tree.iter().filter(|entry| entry.parent == parent) {
+            if let Some(path) = entry.path.strip_prefix(&base) {
+                paths.push(path.to_string());
+                parents.push(entry.id);
+            }
+        }
+    }
+
+    // Sorting puts every path before the ones below it
+    paths.sort();
+    paths
+}
+
+/// Nested subvolumes out of `nested`, which lists parents first, that take part in recursive
+/// snapshots
+///
+/// A subvolume takes part if it or one nested in it matches a pattern of `include`, or if
+/// `include` is empty, and neither it nor one it is nested in matches a pattern of `exclude` or
+/// is in `skipped`. A subvolume nested in one that doesn't take part is left out too, since
+/// there would be nothing to restore it into.
+pub fn select(nested: &[String], include: &[String], exclude: &[String], skipped: &[String]) -> Vec<String> {
+    let mut selected = Vec::new();
+    let mut left_out: Vec<&str> = Vec::new();
+    for path in nested {
+        if left_out.iter().any(|other| is_within(path, other)) {
+            continue;
+        }
+
+        let included = include.is_empty() || nested.iter()
+            .filter(|other| is_within(other, path))
+            .any(|other| include.iter().any(|pattern| matches(pattern, other)));
+        let excluded = exclude.iter().any(|pattern| matches(pattern, path))
+            || skipped.iter().any(|other| is_within(path, other));
+        if !included || excluded {
+            left_out.push(path);
+            continue;
+        }
+        if path.contains(['@', SEPARATOR]) {
+            warn!("Leaving nested subvolume {} out of recursive snapshots: its path contains @ or {}", path, SEPARATOR);
+            left_out.push(path);
+            continue;
+        }
+
+        selected.push(path.clone());
+    }
+
+    selected
+}
+
+/// Whether `path` is `dir` or below it
+fn is_within(path: &str, dir: &str) -> bool {
+    path.strip_prefix(dir).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Matches a relative path against a pattern such as "var/lib/*" or "**/docker", where `*`
+/// stands for any part of a path component, `?` for any character and `**` for any number of
+/// components
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.trim_matches('/').split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    matches_components(&pattern, &path)
+}
+
+fn matches_components(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|i| matches_components(rest, &path[i..])),
+        Some((component, rest)) => match path.split_first() {
+            Some((first, path_rest)) => {
+                let component: Vec<char> = component.chars().collect();
+                let first: Vec<char> = first.chars().collect();
+                matches_component(&component, &first) && matches_components(rest, path_rest)
+            },
+            None => false,
+        },
+    }
+}
+
+fn matches_component(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|i| matches_component(rest, &text[i..])),
+        Some(('?', rest)) => !text.is_empty() && matches_component(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && matches_component(rest, &text[1..]),
+    }
+}
+
+/// Names of the member snapshots of `name` at `location`, i.e. of every nested subvolume that
+/// took part in one of its recursive snapshots
+pub fn member_names(name: &str, location: &SnapshotRepositoryLocation, sudo: bool, verbose: i32) -> Result<Vec<String>> {
+    let mut names: Vec<String> = fridge::list_entries(location, sudo, verbose)?.into_iter()
+        .filter_map(|entry| entry.split_once('@').map(|(member, _)| member.to_string()))
+        .filter(|member| member_path(name, member).is_some())
+        .collect();
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+/// Member snapshots of `snapshot` at `location`, along with the paths of the nested subvolumes
+/// they were taken of, parents first
+pub fn members(snapshot: &Snapshot, location: &SnapshotRepositoryLocation, sudo: bool, verbose: i32) -> Result<Vec<(String, Snapshot)>> {
+    let stamp = snapshot.full_name.split_once('@').map_or("", |(_, stamp)| stamp);
+    let mut members = Vec::new();
+    for entry in fridge::list_entries(location, sudo, verbose)? {
+        let entry = entry.trim_end_matches('/');
+        let (member, _) = match entry.split_once('@') {
+            Some(split) if split.1 == stamp => split,
+            _ => continue,
+        };
+        if let Some(path) = member_path(&snapshot.name, member) {
+            members.push((path, fridge::parse_snapshot_name(entry, &location.path)?));
+        }
+    }
+
+    members.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(members)
+}
+
+/// Deletes the member snapshots of `snapshot` from `location`, recording each deletion as
+/// `operation`
+pub fn delete_members(snapshot: &Snapshot, location: &SnapshotRepositoryLocation, sudo: bool, operation: Operation, verbose: i32) -> Result<()> {
+    for (_, member) in members(snapshot, location, sudo, verbose)? {
+        let started = Utc::now();
+        let result = member.delete_from(location, sudo, verbose);
+        history::record(Record::new(operation, started, &snapshot.name, Some(&member.full_name), &location.to_string(), None, &result));
+        result?;
+        info!("Deleted snapshot {} from {}", &member.full_name, location);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPENSUSE_SUBVOLUMES: &str = include_str!("../tests/fixtures/subvolumes-opensuse.txt");
+
+    fn paths(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|path| path.to_string()).collect()
+    }
+
+    #[test]
+    fn test_member_name() {
+        assert_eq!(member_name("root", "var/lib/machines"), "root+var+lib+machines");
+        assert_eq!(member_path("root", "root+var+lib+machines").as_deref(), Some("var/lib/machines"));
+        assert_eq!(member_path("root", "root"), None);
+        assert_eq!(member_path("root", "root-home+var"), None);
+        assert_eq!(member_path("home", "root+var"), None);
+    }
+
+    #[test]
+    fn test_descendants() {
+        let tree = discover::parse_subvolume_list(OPENSUSE_SUBVOLUMES);
+        let nested = descendants(&tree, 256);
+        assert_eq!(&nested[..3], &paths(&[".snapshots", ".snapshots/1/snapshot", ".snapshots/2/snapshot"]));
+        assert!(nested.contains(&"var/lib/machines".to_string()));
+        assert!(nested.iter().position(|path| path == "var") < nested.iter().position(|path| path == "var/lib/machines"));
+
+        assert_eq!(descendants(&tree, 258), paths(&["lib/machines"]));
+        assert!(descendants(&tree, 264).is_empty());
+        assert!(descendants(&tree, TOP_LEVEL_ID).contains(&"@/var/lib/machines".to_string()));
+        assert_eq!(parse_subvolume_id("@/home\n\tName: \t\t\thome\n\tSubvolume ID: \t\t264\n"), Some(264));
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("var/lib/machines", "var/lib/machines"));
+        assert!(matches("var/lib/*", "var/lib/machines"));
+        assert!(!matches("var/lib/*", "var/lib/docker/btrfs"));
+        assert!(matches("var/lib/docker/**", "var/lib/docker/btrfs/subvolumes/abc"));
+        assert!(matches("**/docker", "var/lib/docker"));
+        assert!(matches("/srv/", "srv"));
+        assert!(matches("opt?", "opt1"));
+        assert!(!matches("var", "var/lib/machines"));
+    }
+
+    #[test]
+    fn test_select() {
+        let nested = paths(&["home", "srv", "var", "var/lib/docker", "var/lib/docker/btrfs/1", "var/lib/machines"]);
+        assert_eq!(select(&nested, &[], &[], &paths(&["home"])), paths(&["srv", "var", "var/lib/docker", "var/lib/docker/btrfs/1", "var/lib/machines"]));
+        assert_eq!(select(&nested, &[], &paths(&["var/lib/docker"]), &[]), paths(&["home", "srv", "var", "var/lib/machines"]));
+        // The subvolume a selected one is nested in takes part along with it
+        assert_eq!(select(&nested, &paths(&["var/lib/machines"]), &[], &[]), paths(&["var", "var/lib/machines"]));
+        // Nothing is restored into a subvolume that doesn't take part
+        assert_eq!(select(&nested, &paths(&["var/lib/machines"]), &paths(&["var"]), &[]), Vec::<String>::new());
+        assert_eq!(select(&paths(&["srv/a+b", "srv/a+b/c"]), &[], &[], &[]), Vec::<String>::new());
+    }
+}
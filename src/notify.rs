@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use zbus::blocking::Connection;
+use zbus::dbus_proxy;
+use zbus::zvariant::Value;
+
+use crate::APP_ID;
+
+#[dbus_proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+
+    #[dbus_proxy(signal)]
+    fn action_invoked(&self, id: u32, action_key: &str) -> zbus::Result<()>;
+}
+
+/// Action invoked by clicking the notification itself
+pub const ACTION_OPEN: &str = "default";
+pub const ACTION_SNAPSHOT: &str = "snapshot";
+pub const ACTION_BACKUP: &str = "backup";
+
+/// Urgency hint values of the notification specification
+const URGENCY_NORMAL: u8 = 1;
+const URGENCY_CRITICAL: u8 = 2;
+
+/// Something that happened that the user may want to know about
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    SnapshotFailed(String),
+    BackupFailed(String),
+    /// A backup succeeded after running for the given time
+    BackupCompleted(Duration),
+    /// The last successful backup, if any, is older than allowed
+    BackupOverdue(Option<DateTime<Utc>>),
+}
+
+/// Which events to send notifications for
+#[derive(Clone, Debug, PartialEq)]
+pub struct NotifyOpts {
+    pub snapshot_failed: bool,
+    pub backup_failed: bool,
+    /// Notify about backups that ran at least this long, or never if `None`
+    pub backup_completed_after: Option<Duration>,
+    /// Notify when the last successful backup is older than this, or never if `None`
+    pub backup_overdue_after: Option<chrono::Duration>,
+}
+
+impl Default for NotifyOpts {
+    fn default() -> Self {
+        Self {
+            snapshot_failed: true,
+            backup_failed: true,
+            backup_completed_after: Some(Duration::from_secs(10 * 60)),
+            backup_overdue_after: Some(chrono::Duration::days(3)),
+        }
+    }
+}
+
+impl NotifyOpts {
+    /// Returns the notification to send for `event`, or `None` if it isn't wanted
+    pub fn notification(&self, event: &Event) -> Option<Notification> {
+        let open = (ACTION_OPEN.to_string(), "Open Fridge".to_string());
+        match event {
+            Event::SnapshotFailed(error) if self.snapshot_failed => Some(Notification {
+                summary: "Snapshot failed".to_string(),
+                body: error.clone(),
+                actions: vec![(ACTION_SNAPSHOT.to_string(), "Retry".to_string()), open],
+                urgent: true,
+            }),
+            Event::BackupFailed(error) if self.backup_failed => Some(Notification {
+                summary: "Backup failed".to_string(),
+                body: error.clone(),
+                actions: vec![(ACTION_BACKUP.to_string(), "Retry".to_string()), open],
+                urgent: true,
+            }),
+            Event::BackupCompleted(elapsed) if self.backup_completed_after.is_some_and(|after| *elapsed >= after) => Some(Notification {
+                summary: "Backup completed".to_string(),
+                body: format!("Snapshots were backed up in {} minute(s)", elapsed.as_secs() / 60),
+                actions: vec![open],
+                urgent: false,
+            }),
+            Event::BackupOverdue(last_success) if self.backup_overdue_after.is_some() => Some(Notification {
+                summary: "Backup overdue".to_string(),
+                body: match last_success {
+                    Some(at) => format!("The last successful backup was {} day(s) ago", Utc::now().signed_duration_since(*at).num_days()),
+                    None => "No backup has succeeded yet".to_string(),
+                },
+                actions: vec![(ACTION_BACKUP.to_string(), "Back Up Now".to_string()), open],
+                urgent: false,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether a backup that last succeeded at `last_success` is overdue at `now`
+    pub fn is_backup_overdue(&self, last_success: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+        match (self.backup_overdue_after, last_success) {
+            (Some(after), Some(at)) => now.signed_duration_since(at) > after,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notification {
+    pub summary: String,
+    pub body: String,
+    /// Action keys and their labels
+    pub actions: Vec<(String, String)>,
+    pub urgent: bool,
+}
+
+/// Client of the desktop notification server
+pub struct Notifier {
+    proxy: NotificationsProxyBlocking<'static>,
+}
+
+impl Notifier {
+    /// Connects to the notification server on the session bus
+    pub fn new() -> Result<Self> {
+        Self::on(&Connection::session()?)
+    }
+
+    pub fn on(connection: &Connection) -> Result<Self> {
+        let proxy = NotificationsProxyBlocking::new(connection)?;
+        Ok(Self { proxy })
+    }
+
+    /// Shows a notification and returns its ID
+    pub fn send(&self, notification: &Notification) -> Result<u32> {
+        let actions: Vec<&str> = notification.actions.iter()
+            .flat_map(|(key, label)| [key.as_str(), label.as_str()])
+            .collect();
+        let urgency = if notification.urgent { URGENCY_CRITICAL } else { URGENCY_NORMAL };
+        let hints = HashMap::from([
+            ("desktop-entry", Value::from(APP_ID)),
+            ("urgency", Value::from(urgency)),
+        ]);
+
+        Ok(self.proxy.notify("Fridge", 0, APP_ID, &notification.summary, &notification.body, &actions, hints, -1)?)
+    }
+
+    /// Blocks and calls `f` with the notification ID and action key of every invoked action,
+    /// until `f` returns false
+    pub fn listen_for_actions(&self, mut f: impl FnMut(u32, &str) -> bool) -> Result<()> {
+        for signal in self.proxy.receive_action_invoked()? {
+            let args = signal.args()?;
+            if !f(*args.id(), args.action_key()) {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use zbus::blocking::{Connection, ConnectionBuilder};
+    use zbus::{dbus_interface, Guid, SignalContext};
+
+    use super::*;
+
+    /// Summaries and actions of the notifications received by the stub server
+    type Received = Arc<Mutex<Vec<(String, Vec<String>)>>>;
+
+    /// Notification server that records notifications and invokes their first action right away
+    struct StubServer {
+        notifications: Received,
+    }
+
+    #[dbus_interface(name = "org.freedesktop.Notifications")]
+    impl StubServer {
+        #[allow(clippy::too_many_arguments)]
+        async fn notify(
+            &self,
+            #[zbus(signal_context)] ctxt: SignalContext<'_>,
+            _app_name: &str,
+            _replaces_id: u32,
+            _app_icon: &str,
+            summary: &str,
+            _body: &str,
+            actions: Vec<&str>,
+            _hints: HashMap<&str, Value<'_>>,
+            _expire_timeout: i32,
+        ) -> u32 {
+            let id = {
+                let mut notifications = self.notifications.lock().unwrap();
+                notifications.push((summary.to_string(), actions.iter().map(|action| action.to_string()).collect()));
+                notifications.len() as u32
+            };
+            if let Some(action) = actions.first() {
+                Self::action_invoked(&ctxt, id, action).await.unwrap();
+            }
+            id
+        }
+
+        #[dbus_interface(signal)]
+        async fn action_invoked(ctxt: &SignalContext<'_>, id: u32, action_key: &str) -> zbus::Result<()>;
+    }
+
+    #[test]
+    fn test_notifier() {
+        let notifications = Received::default();
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let guid = Guid::generate();
+        let server_notifications = notifications.clone();
+        let server = thread::spawn(move || {
+            ConnectionBuilder::unix_stream(server_stream)
+                .server(&guid)
+                .p2p()
+                .serve_at("/org/freedesktop/Notifications", StubServer { notifications: server_notifications }).unwrap()
+                .build()
+                .unwrap()
+        });
+        let client: Connection = ConnectionBuilder::unix_stream(client_stream)
+            .p2p()
+            .build()
+            .unwrap();
+        let _server = server.join().unwrap();
+
+        let notifier = Notifier::on(&client).unwrap();
+        let actions = thread::spawn({
+            let client = client.clone();
+            move || {
+                let mut actions = Vec::new();
+                Notifier::on(&client).unwrap().listen_for_actions(|id, action| {
+                    actions.push((id, action.to_string()));
+                    false
+                }).unwrap();
+                actions
+            }
+        });
+        // Give the listener time to subscribe before the signal is emitted
+        thread::sleep(Duration::from_millis(200));
+
+        let opts = NotifyOpts::default();
+        let notification = opts.notification(&Event::BackupFailed("TCP check failed".to_string())).unwrap();
+        let id = notifier.send(&notification).unwrap();
+
+        assert_eq!(id, 1);
+        assert_eq!(*notifications.lock().unwrap(), [
+            ("Backup failed".to_string(), vec!["backup".to_string(), "Retry".to_string(), "default".to_string(), "Open Fridge".to_string()]),
+        ]);
+        assert_eq!(actions.join().unwrap(), [(1, "backup".to_string())]);
+    }
+
+    #[test]
+    fn test_notification() {
+        let opts = NotifyOpts {
+            snapshot_failed: false,
+            ..Default::default()
+        };
+        assert!(opts.notification(&Event::SnapshotFailed("".to_string())).is_none());
+        assert!(opts.notification(&Event::BackupCompleted(Duration::from_secs(60))).is_none());
+        assert!(opts.notification(&Event::BackupCompleted(Duration::from_secs(3600))).is_some());
+
+        let now = Utc::now();
+        assert!(opts.is_backup_overdue(None, now));
+        assert!(opts.is_backup_overdue(Some(now - chrono::Duration::days(4)), now));
+        assert!(!opts.is_backup_overdue(Some(now - chrono::Duration::days(1)), now));
+        let opts = NotifyOpts {
+            backup_overdue_after: None,
+            ..Default::default()
+        };
+        assert!(!opts.is_backup_overdue(None, now));
+    }
+}
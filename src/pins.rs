@@ -0,0 +1,96 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+/// Snapshots the user pinned, which are never deleted by retention
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct PinStore {
+    /// Full names of the pinned snapshots (e.g. "root@2023-01-02_03:04:05_daily")
+    #[serde(default)]
+    pub snapshots: BTreeSet<String>,
+}
+
+const PINS_FILE: &str = "pins.toml";
+
+fn pins_path() -> PathBuf {
+    config::state_dir().join(PINS_FILE)
+}
+
+impl PinStore {
+    pub fn load() -> Result<Self> {
+        Self::load_from(&pins_path())
+    }
+
+    /// Loads the store at `path`, which is empty if the file doesn't exist yet
+    pub fn load_from(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let s = fs::read_to_string(path)?;
+        Ok(toml::from_str(&s)?)
+    }
+
+    /// Saves the store for every user, so that pruning as root keeps what was pinned in the
+    /// window, through sudo unless running as root
+    pub fn save(&self) -> Result<()> {
+        self.save_with(config::save_state)
+    }
+
+    /// Saves the store with `save_state`, given the name of the state file, its contents and
+    /// whether to append them
+    fn save_with(&self, save_state: impl FnOnce(&str, &str, bool) -> Result<()>) -> Result<()> {
+        save_state(PINS_FILE, &toml::to_string(self)?, false)
+    }
+
+    pub fn is_pinned(&self, snapshot: &str) -> bool {
+        self.snapshots.contains(snapshot)
+    }
+
+    pub fn set_pinned(&mut self, snapshot: &str, pinned: bool) {
+        if pinned {
+            self.snapshots.insert(snapshot.to_string());
+        } else {
+            self.snapshots.remove(snapshot);
+        }
+    }
+}
+
+/// Pins or unpins the given snapshots in the persisted store
+pub fn set_pinned(snapshots: &[String], pinned: bool) -> Result<()> {
+    let mut store = PinStore::load()?;
+    for snapshot in snapshots {
+        store.set_pinned(snapshot, pinned);
+    }
+    store.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testutil::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = TempDir::new("pins");
+        let path = dir.join(PINS_FILE);
+        assert_eq!(PinStore::load_from(&path).unwrap(), PinStore::default());
+
+        let mut store = PinStore::default();
+        store.set_pinned("root@2023-01-02_03:04:05_daily", true);
+        store.set_pinned("home@2023-01-02_03:04:05_daily", true);
+        store.set_pinned("home@2023-01-02_03:04:05_daily", false);
+        store.save_with(|name, contents, append| config::write_state_at(&dir, name, contents, append)).unwrap();
+        let loaded = PinStore::load_from(&path).unwrap();
+
+        assert_eq!(loaded, store);
+        assert!(loaded.is_pinned("root@2023-01-02_03:04:05_daily"));
+        assert!(!loaded.is_pinned("home@2023-01-02_03:04:05_daily"));
+    }
+}
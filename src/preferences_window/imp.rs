@@ -2,30 +2,27 @@ use adw::prelude::*;
 use adw::subclass::prelude::*;
 use gtk::{glib, CompositeTemplate};
 use gtk::glib::subclass::InitializingObject;
+use std::cell::RefCell;
 
-use crate::config_hourly_snapshot::ConfigHourlySnapshot;
-use crate::config_max_hourly_snapshots::ConfigMaxHourlySnapshots;
-use crate::config_remote_backup::ConfigRemoteBackup;
-use crate::config_remote_user::ConfigRemoteUser;
-use crate::config_remote_host::ConfigRemoteHost;
-use crate::config_remote_directory::ConfigRemoteDirectory;
+use crate::remote_config_editor::RemoteConfigEditor;
+use crate::snapshot_config_editor::SnapshotConfigEditor;
 
 // Object holding the state
 #[derive(CompositeTemplate, Default)]
 #[template(resource = "/co/veand/fridge/PreferencesWindow.ui")]
 pub struct PreferencesWindow {
     #[template_child]
-    pub hourly_snapshot_schedule: TemplateChild<ConfigHourlySnapshot>,
+    pub config_file_row: TemplateChild<adw::ActionRow>,
     #[template_child]
-    pub max_hourly_snapshots: TemplateChild<ConfigMaxHourlySnapshots>,
+    pub import_button: TemplateChild<gtk::Button>,
     #[template_child]
-    pub remote_backup: TemplateChild<ConfigRemoteBackup>,
+    pub export_button: TemplateChild<gtk::Button>,
     #[template_child]
-    pub remote_user: TemplateChild<ConfigRemoteUser>,
+    pub snapshot_config_editor: TemplateChild<SnapshotConfigEditor>,
     #[template_child]
-    pub remote_host: TemplateChild<ConfigRemoteHost>,
-    #[template_child]
-    pub remote_directory: TemplateChild<ConfigRemoteDirectory>,
+    pub remote_config_editor: TemplateChild<RemoteConfigEditor>,
+    /// Dialog choosing the file to import or export, kept alive while it is shown
+    pub file_chooser: RefCell<Option<gtk::FileChooserNative>>,
 }
 
 // The central trait for subclassing a GObject
@@ -50,6 +47,9 @@ impl ObjectImpl for PreferencesWindow {
     fn constructed(&self) {
         // Call "constructed" on parent
         self.parent_constructed();
+
+        let obj = self.obj();
+        obj.setup_callbacks();
     }
 }
 
@@ -1,11 +1,21 @@
 mod imp;
 
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+
 use adw::Application;
+use adw::prelude::*;
+use anyhow::{Result, anyhow};
 use gtk::{glib::{self, Object}};
+use gtk::glib::{clone, Continue};
+use gtk::subclass::prelude::ObjectSubclassIsExt;
+
+use crate::config;
 
 glib::wrapper! {
     pub struct PreferencesWindow(ObjectSubclass<imp::PreferencesWindow>)
-        @extends adw::Window, gtk::Window, gtk::Widget,
+        @extends adw::PreferencesWindow, adw::Window, gtk::Window, gtk::Widget,
         @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::Native, gtk::Root, gtk::ShortcutManager;
 }
 
@@ -15,4 +25,74 @@ impl PreferencesWindow {
         //Object::new(&[("application", app)]).expect("Could not create PreferencesWindow")
         Object::builder::<Self>().property("application", app).build()
     }
+
+    fn setup_callbacks(&self) {
+        let imp = self.imp();
+        imp.config_file_row.set_subtitle(config::CONFIG_PATH);
+        imp.import_button.connect_clicked(clone!(@weak self as window => move |_| {
+            window.choose_file("Import Configuration", gtk::FileChooserAction::Open, "Import", |window, path| {
+                window.import(path);
+            });
+        }));
+        imp.export_button.connect_clicked(clone!(@weak self as window => move |_| {
+            window.choose_file("Export Configuration", gtk::FileChooserAction::Save, "Export", |window, path| {
+                let result = config::read_document()
+                    .and_then(|document| fs::write(&path, document).map_err(|e| anyhow!("Could not write {}: {}", path.display(), e)));
+                match result {
+                    Ok(()) => window.toast(&format!("Exported configuration to {}", path.display())),
+                    Err(e) => window.toast(&format!("{:#}", e)),
+                }
+            });
+        }));
+    }
+
+    /// Asks for a file to import from or export to and calls `f` with it
+    fn choose_file(&self, title: &str, action: gtk::FileChooserAction, accept_label: &str, f: impl Fn(&Self, PathBuf) + 'static) {
+        let dialog = gtk::FileChooserNative::new(Some(title), Some(self), action, Some(accept_label), Some("Cancel"));
+        dialog.set_modal(true);
+        if action == gtk::FileChooserAction::Save {
+            dialog.set_current_name("fridge.toml");
+        }
+        dialog.connect_response(clone!(@weak self as window => move |dialog, response| {
+            if response == gtk::ResponseType::Accept {
+                if let Some(path) = dialog.file().and_then(|file| file.path()) {
+                    f(&window, path);
+                }
+            }
+            window.imp().file_chooser.replace(None);
+        }));
+        dialog.show();
+        self.imp().file_chooser.replace(Some(dialog));
+    }
+
+    /// Replaces the configuration with the file at `path` in the background, then shows it
+    fn import(&self, path: PathBuf) {
+        let imp = self.imp();
+        imp.import_button.set_sensitive(false);
+
+        let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        thread::spawn(move || {
+            let result = fs::read_to_string(&path)
+                .map_err(|e| anyhow!("Could not read {}: {}", path.display(), e))
+                .and_then(|document| config::install(&document));
+            let _ = sender.send(result);
+        });
+        receiver.attach(None, clone!(@weak self as window => @default-return Continue(false), move |result: Result<()>| {
+            let imp = window.imp();
+            imp.import_button.set_sensitive(true);
+            match result {
+                Ok(()) => {
+                    imp.snapshot_config_editor.load();
+                    imp.remote_config_editor.load();
+                    window.toast("Imported configuration");
+                },
+                Err(e) => window.toast(&format!("{:#}", e)),
+            }
+            Continue(false)
+        }));
+    }
+
+    fn toast(&self, message: &str) {
+        self.add_toast(&adw::Toast::new(&glib::markup_escape_text(message)));
+    }
 }
@@ -0,0 +1,365 @@
+use std::fmt;
+use std::io::Read;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::str;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Error, Result, bail};
+use log::{info, warn};
+
+use crate::cancel::{CancelToken, Cancelled};
+use crate::fridge::SnapshotRepositoryLocation;
+use crate::ssh;
+
+/// Stage of a preflight check, in the order they run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PreflightStep {
+    Resolve,
+    Connect,
+    Authenticate,
+    Sudo,
+    Path,
+}
+
+impl fmt::Display for PreflightStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            PreflightStep::Resolve => "DNS",
+            PreflightStep::Connect => "TCP",
+            PreflightStep::Authenticate => "SSH authentication",
+            PreflightStep::Sudo => "sudo",
+            PreflightStep::Path => "btrfs path",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Failure of a preflight check
+#[derive(Debug)]
+pub struct PreflightError {
+    pub step: PreflightStep,
+    pub message: String,
+    /// Whether retrying later may succeed, e.g. because the remote is still booting
+    pub transient: bool,
+}
+
+impl fmt::Display for PreflightError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} check failed: {}", self.step, self.message)
+    }
+}
+
+impl std::error::Error for PreflightError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PreflightOpts {
+    /// Timeout of the TCP connection and of the SSH handshake
+    pub connect_timeout: Duration,
+    /// Timeout of each command run on the remote
+    pub command_timeout: Duration,
+}
+
+impl Default for PreflightOpts {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            command_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+fn fail(step: PreflightStep, message: String, transient: bool) -> PreflightError {
+    PreflightError { step, message, transient }
+}
+
+/// Checks that `dst` can receive snapshots: that its host resolves and accepts TCP connections,
+/// that we can log in over SSH and use sudo if needed, and that its path is a writable
+/// directory on a btrfs filesystem
+pub fn preflight(dst: &SnapshotRepositoryLocation, sudo: bool, opts: &PreflightOpts) -> Result<(), PreflightError> {
+    if dst.is_remote() {
+        let host = dst.host.clone().unwrap_or_default();
+        let port = dst.port.unwrap_or(22);
+
+        let addrs: Vec<_> = (host.as_str(), port).to_socket_addrs()
+            .map_err(|e| fail(PreflightStep::Resolve, format!("Could not resolve {}: {}", &host, e), true))?
+            .collect();
+
+        let mut connect_error = None;
+        for addr in &addrs {
+            match TcpStream::connect_timeout(addr, opts.connect_timeout) {
+                Ok(_) => {
+                    connect_error = None;
+                    break;
+                },
+                Err(e) => connect_error = Some(format!("Could not connect to {}: {}", addr, e)),
+            }
+        }
+        if let Some(message) = connect_error {
+            return Err(fail(PreflightStep::Connect, message, true));
+        }
+
+        let mut dst = dst.clone();
+        dst.ssh.options.push("BatchMode=yes".to_string());
+        dst.ssh.options.push(format!("ConnectTimeout={}", opts.connect_timeout.as_secs().max(1)));
+
+        run_check(&dst, &["true"], opts, PreflightStep::Authenticate)?;
+        if sudo {
+            run_check(&dst, &["sudo", "-n", "true"], opts, PreflightStep::Sudo)?;
+        }
+        check_path(&dst, sudo, opts)?;
+    } else {
+        check_path(dst, sudo, opts)?;
+    }
+
+    info!("Preflight check of {} passed", &dst.path);
+
+    Ok(())
+}
+
+fn check_path(dst: &SnapshotRepositoryLocation, sudo: bool, opts: &PreflightOpts) -> Result<(), PreflightError> {
+    let sudo_args: &[&str] = if sudo { &["sudo", "-n"] } else { &[] };
+
+    let mut args = sudo_args.to_vec();
+    args.extend(["test", "-d", &dst.path, "-a", "-w", &dst.path]);
+    run_check(dst, &args, opts, PreflightStep::Path)
+        .map_err(|e| fail(PreflightStep::Path, format!("{} is not a writable directory: {}", &dst.path, e.message), false))?;
+
+    let output = run_check(dst, &["stat", "-f", "-c", "%T", &dst.path], opts, PreflightStep::Path)?;
+    let fs_type = str::from_utf8(&output.stdout).unwrap_or_default().trim().to_string();
+    if fs_type != "btrfs" {
+        return Err(fail(PreflightStep::Path, format!("{} is on {}, not btrfs", &dst.path, fs_type), false));
+    }
+
+    Ok(())
+}
+
+/// Runs a command on the host of `dst`, locally if `dst` is local
+fn run_check(dst: &SnapshotRepositoryLocation, args: &[&str], opts: &PreflightOpts, step: PreflightStep) -> Result<Output, PreflightError> {
+    let (program, args) = if dst.is_remote() {
+        let (program, args) = ssh::remote_command(dst, false, args).map_err(|e| fail(step, e.to_string(), false))?;
+        (program.to_string(), args)
+    } else if !Path::new(&dst.path).exists() {
+        return Err(fail(step, format!("{} does not exist", &dst.path), false));
+    } else {
+        let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let program = args.remove(0);
+        (program, args)
+    };
+
+    let mut command = Command::new(program);
+    command.args(args);
+    let output = output_with_timeout(command, opts.command_timeout)
+        .map_err(|e| fail(step, e.to_string(), true))?;
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or_default().trim().to_string();
+        // ssh exits with 255 on connection errors, which may go away by themselves
+        let transient = step == PreflightStep::Authenticate && output.status.code() == Some(255) && stderr.contains("timed out");
+        return Err(fail(step, stderr, transient));
+    }
+
+    Ok(output)
+}
+
+/// Runs a command to completion, killing it if it takes longer than `timeout`
+pub fn output_with_timeout(mut command: Command, timeout: Duration) -> Result<Output> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Drain the pipes while waiting so a chatty command can't block on a full pipe
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    let stdout_reader = thread::spawn(move || {
+        let mut buf = Vec::new();
+        stdout.read_to_end(&mut buf).map(|_| buf)
+    });
+    let stderr_reader = thread::spawn(move || {
+        let mut buf = Vec::new();
+        stderr.read_to_end(&mut buf).map(|_| buf)
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() > timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("Command timed out after {} seconds", timeout.as_secs());
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout_reader.join().unwrap_or_else(|_| Ok(Vec::new()))?,
+        stderr: stderr_reader.join().unwrap_or_else(|_| Ok(Vec::new()))?,
+    })
+}
+
+/// How often and how patiently to retry an operation that failed transiently
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            initial_delay: Duration::from_secs(5),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the given retry, doubling after every attempt
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self.initial_delay.saturating_mul(2u32.saturating_pow(retry));
+        delay.min(self.max_delay)
+    }
+}
+
+/// Whether an error is a preflight failure that may go away by itself
+pub fn is_transient(e: &Error) -> bool {
+    e.downcast_ref::<PreflightError>().is_some_and(|e| e.transient)
+}
+
+/// Calls `f` until it succeeds, fails with an error that isn't transient, or runs out of
+/// attempts, backing off exponentially in between unless `cancel` is cancelled
+pub fn retry<T>(policy: &RetryPolicy, cancel: &CancelToken, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(e) if is_transient(&e) && attempt + 1 < policy.attempts => {
+                let delay = policy.delay(attempt);
+                warn!("{}, retrying in {} seconds", e, delay.as_secs());
+                cancel.wait(delay)?;
+                attempt += 1;
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Runs a synchronization against `dst` after a preflight check, retrying transient failures
+///
+/// If the synchronization itself fails, another preflight check determines whether the remote
+/// went away in the meantime, in which case it is retried as well. A cancelled
+/// synchronization is never retried.
+pub fn with_preflight<T>(dst: &SnapshotRepositoryLocation, sudo: bool, opts: &PreflightOpts, policy: &RetryPolicy, cancel: &CancelToken, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    retry(policy, cancel, || {
+        preflight(dst, sudo, opts)?;
+        f().map_err(|e| {
+            if e.is::<Cancelled>() {
+                return e;
+            }
+            match preflight(dst, sudo, opts) {
+                Err(preflight_error) if preflight_error.transient => preflight_error.into(),
+                _ => e,
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 3,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+        }
+    }
+
+    #[test]
+    fn test_retry() {
+        let calls = Cell::new(0);
+        let result = retry(&policy(), &CancelToken::new(), || {
+            calls.set(calls.get() + 1);
+            if calls.get() < 3 {
+                Err(fail(PreflightStep::Connect, "Connection refused".to_string(), true).into())
+            } else {
+                Ok(calls.get())
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let calls = Cell::new(0);
+        let result: Result<()> = retry(&policy(), &CancelToken::new(), || {
+            calls.set(calls.get() + 1);
+            Err(fail(PreflightStep::Connect, "Connection refused".to_string(), true).into())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 3);
+
+        let calls = Cell::new(0);
+        let result: Result<()> = retry(&policy(), &CancelToken::new(), || {
+            calls.set(calls.get() + 1);
+            Err(anyhow!("Permission denied"))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+
+        // A cancelled synchronization stops waiting to retry
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let calls = Cell::new(0);
+        let result: Result<()> = retry(&policy(), &cancel, || {
+            calls.set(calls.get() + 1);
+            Err(fail(PreflightStep::Connect, "Connection refused".to_string(), true).into())
+        });
+        assert!(result.unwrap_err().is::<Cancelled>());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.delay(0), Duration::from_secs(5));
+        assert_eq!(policy.delay(1), Duration::from_secs(10));
+        assert_eq!(policy.delay(2), Duration::from_secs(20));
+        assert_eq!(policy.delay(10), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_preflight_local() {
+        let dst = SnapshotRepositoryLocation {
+            path: "/nonexistent/.snapshots".to_string(),
+            ..Default::default()
+        };
+        let e = preflight(&dst, false, &PreflightOpts::default()).unwrap_err();
+        assert_eq!(e.step, PreflightStep::Path);
+        assert!(!e.transient);
+    }
+
+    #[test]
+    fn test_output_with_timeout() {
+        let mut command = Command::new("sleep");
+        command.arg("5");
+        assert!(output_with_timeout(command, Duration::from_millis(100)).is_err());
+
+        let mut command = Command::new("echo");
+        command.arg("hello");
+        let output = output_with_timeout(command, Duration::from_secs(5)).unwrap();
+        assert_eq!(output.stdout, b"hello\n");
+    }
+}
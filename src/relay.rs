@@ -0,0 +1,159 @@
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread;
+
+use anyhow::{Result, anyhow};
+
+use crate::throttle::{BandwidthLimit, Throttle};
+
+const CHUNK_SIZE: usize = 128 * 1024;
+
+/// Number of chunks a sink may lag behind before the source stops being read
+const CHANNEL_DEPTH: usize = 16;
+
+/// Destination of a relayed stream
+pub struct Sink {
+    pub writer: Box<dyn Write + Send>,
+    pub limit: BandwidthLimit,
+}
+
+/// Copies everything read from `src` into every sink
+///
+/// Each sink is written from its own thread, so a slow sink only stalls the others once its
+/// buffer is full, and a failing sink is dropped without affecting the rest. Sinks are closed
+/// once the source is exhausted. Returns the number of bytes written to each sink, in the same
+/// order as `sinks`, or an error if the source itself could not be read.
+///
+/// Bandwidth limits are enforced per sink on its writer thread. Since the source is read at the
+/// pace of the slowest sink, a limited sink holds back the others once its buffer is full, which
+/// is why `transfer_fan_out` gives each limited destination a stream of its own.
+pub fn relay<R: Read>(mut src: R, sinks: Vec<Sink>) -> Result<Vec<Result<u64>>> {
+    let mut senders: Vec<Option<SyncSender<Arc<Vec<u8>>>>> = Vec::new();
+    let mut handles = Vec::new();
+
+    for sink in sinks {
+        let (sender, receiver) = sync_channel::<Arc<Vec<u8>>>(CHANNEL_DEPTH);
+        senders.push(Some(sender));
+        handles.push(thread::spawn(move || -> io::Result<u64> {
+            let mut writer = sink.writer;
+            let mut throttle = Throttle::new(sink.limit);
+            let mut written = 0;
+            for chunk in receiver {
+                writer.write_all(&chunk)?;
+                written += chunk.len() as u64;
+                throttle.consume(chunk.len());
+            }
+            writer.flush()?;
+            Ok(written)
+        }));
+    }
+
+    let mut buf = vec![0; CHUNK_SIZE];
+    let read_result = loop {
+        let n = match src.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        };
+
+        let chunk = Arc::new(buf[..n].to_vec());
+        for sender in senders.iter_mut() {
+            // A failed send means the sink thread has exited with an error
+            if sender.as_ref().is_some_and(|s| s.send(chunk.clone()).is_err()) {
+                *sender = None;
+            }
+        }
+        if senders.iter().all(|sender| sender.is_none()) {
+            break Ok(());
+        }
+    };
+    drop(senders);
+
+    let results = handles.into_iter()
+        .map(|handle| match handle.join() {
+            Ok(result) => result.map_err(|e| e.into()),
+            Err(_) => Err(anyhow!("Relay thread panicked")),
+        })
+        .collect();
+
+    read_result?;
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    use crate::throttle::BandwidthLimit;
+    use super::Sink;
+
+    #[derive(Clone, Default)]
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct BrokenSink;
+
+    impl Write for BrokenSink {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "receiver went away"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_relay() {
+        let data: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+        let a = SharedSink::default();
+        let b = SharedSink::default();
+        let sink = |writer: Box<dyn Write + Send>| Sink {
+            writer,
+            limit: BandwidthLimit::default(),
+        };
+        let results = super::relay(Cursor::new(data.clone()), vec![
+            sink(Box::new(a.clone())),
+            sink(Box::new(BrokenSink)),
+            sink(Box::new(b.clone())),
+        ]).unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(*results[0].as_ref().unwrap(), data.len() as u64);
+        assert!(results[1].is_err());
+        assert_eq!(*results[2].as_ref().unwrap(), data.len() as u64);
+        assert_eq!(*a.0.lock().unwrap(), data);
+        assert_eq!(*b.0.lock().unwrap(), data);
+    }
+
+    #[test]
+    fn test_relay_bandwidth_limit() {
+        let data = vec![0u8; 512 * 1024];
+        let sink = SharedSink::default();
+        let started = Instant::now();
+        let results = super::relay(Cursor::new(data.clone()), vec![Sink {
+            writer: Box::new(sink.clone()),
+            limit: BandwidthLimit {
+                rate: Some(1024 * 1024),
+                schedule: Vec::new(),
+            },
+        }]).unwrap();
+
+        assert_eq!(*results[0].as_ref().unwrap(), data.len() as u64);
+        assert!(started.elapsed().as_millis() >= 450);
+    }
+}
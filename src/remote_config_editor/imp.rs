@@ -0,0 +1,55 @@
+use adw::prelude::*;
+use gtk::subclass::prelude::*;
+use gtk::{glib, CompositeTemplate};
+use gtk::glib::subclass::InitializingObject;
+use std::cell::RefCell;
+
+use super::RemoteRow;
+
+// Object holding the state
+#[derive(CompositeTemplate, Default)]
+#[template(resource = "/co/veand/fridge/RemoteConfigEditor.ui")]
+pub struct RemoteConfigEditor {
+    #[template_child]
+    pub list_box: TemplateChild<gtk::ListBox>,
+    #[template_child]
+    pub add_button: TemplateChild<gtk::Button>,
+    #[template_child]
+    pub save_button: TemplateChild<gtk::Button>,
+    /// Widgets editing each remote configuration, in the order they are shown
+    pub rows: RefCell<Vec<RemoteRow>>,
+}
+
+// The central trait for subclassing a GObject
+#[glib::object_subclass]
+impl ObjectSubclass for RemoteConfigEditor {
+    // `NAME` needs to match `class` attribute of template
+    const NAME: &'static str = "RemoteConfigEditor";
+    type Type = super::RemoteConfigEditor;
+    type ParentType = gtk::Box;
+
+    fn class_init(klass: &mut Self::Class) {
+        klass.bind_template();
+    }
+
+    fn instance_init(obj: &InitializingObject<Self>) {
+        obj.init_template();
+    }
+}
+
+// Trait shared by all GObjects
+impl ObjectImpl for RemoteConfigEditor {
+    fn constructed(&self) {
+        // Call "constructed" on parent
+        self.parent_constructed();
+
+        let obj = self.obj();
+        obj.setup_callbacks();
+        obj.load();
+    }
+}
+
+// Trait shared by all widgets
+impl WidgetImpl for RemoteConfigEditor {}
+
+impl BoxImpl for RemoteConfigEditor {}
@@ -0,0 +1,380 @@
+mod imp;
+
+use std::thread;
+
+use adw::prelude::*;
+use anyhow::Result;
+use gtk::glib;
+use gtk::glib::{clone, Continue};
+use gtk::subclass::prelude::ObjectSubclassIsExt;
+
+use crate::config::{self, RemoteConfig, RetentionConfig};
+use crate::fridge::SnapshotRepositoryLocation;
+use crate::preflight;
+
+/// Positions of the remote types in the type drop-down
+const TYPE_LOCAL: u32 = 0;
+const TYPE_SSH: u32 = 1;
+
+const DEFAULT_SSH_PORT: u16 = 22;
+
+/// Titles of the rows with the number of snapshots to keep on the remote of each tier, from
+/// hourly to yearly
+const TIER_TITLES: [&str; 5] = ["Hourly snapshots", "Daily snapshots", "Weekly snapshots", "Monthly snapshots", "Yearly snapshots"];
+
+/// Widgets editing one remote configuration
+pub struct RemoteRow {
+    expander: adw::ExpanderRow,
+    enabled_switch: gtk::Switch,
+    type_drop_down: gtk::DropDown,
+    /// Host of the remote, optionally prefixed with "user@"
+    address_entry: gtk::Entry,
+    port_spin: gtk::SpinButton,
+    path_entry: gtk::Entry,
+    sudo_switch: gtk::Switch,
+    /// Number of snapshots to keep of each tier, from hourly to yearly, -1 to keep all
+    count_spins: Vec<gtk::SpinButton>,
+    max_age_entry: gtk::Entry,
+    budget_entry: gtk::Entry,
+    test_row: adw::ActionRow,
+    /// Configuration as loaded, keeping the settings the editor doesn't show
+    base: RemoteConfig,
+}
+
+impl RemoteRow {
+    fn config(&self) -> RemoteConfig {
+        let mut remote = self.base.clone();
+        remote.enabled = self.enabled_switch.is_active();
+        if self.type_drop_down.selected() == TYPE_SSH {
+            let address = self.address_entry.text().trim().to_string();
+            let (user, host) = match address.rsplit_once('@') {
+                Some((user, host)) => (Some(user.to_string()), host.to_string()),
+                None => (None, address),
+            };
+            let port = self.port_spin.value_as_int() as u16;
+            remote.user = user;
+            remote.host = Some(host);
+            // Leaving the default port out keeps the location, and the health recorded for
+            // it, as it was
+            remote.port = (port != DEFAULT_SSH_PORT || self.base.port.is_some()).then_some(port);
+        } else {
+            remote.user = None;
+            remote.host = None;
+            remote.port = None;
+        }
+        remote.path = self.path_entry.text().trim().to_string();
+        remote.sudo = self.sudo_switch.is_active();
+
+        let counts: Vec<Option<usize>> = self.count_spins.iter()
+            .map(|spin| usize::try_from(spin.value_as_int()).ok())
+            .collect();
+        let max_age = self.max_age_entry.text().trim().to_string();
+        let budget = self.budget_entry.text().trim().to_string();
+        remote.retention = if counts.iter().all(Option::is_none) && max_age.is_empty() && budget.is_empty() {
+            None
+        } else {
+            Some(RetentionConfig {
+                hourly: counts[0],
+                daily: counts[1],
+                weekly: counts[2],
+                monthly: counts[3],
+                yearly: counts[4],
+                max_age: (!max_age.is_empty()).then_some(max_age),
+                budget: (!budget.is_empty()).then_some(budget),
+            })
+        };
+
+        remote
+    }
+}
+
+glib::wrapper! {
+    pub struct RemoteConfigEditor(ObjectSubclass<imp::RemoteConfigEditor>)
+        @extends gtk::Box, gtk::Widget,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::Orientable;
+}
+
+impl RemoteConfigEditor {
+    fn setup_callbacks(&self) {
+        let imp = self.imp();
+        imp.add_button.connect_clicked(clone!(@weak self as editor => move |_| {
+            let expander = editor.add_row(&RemoteConfig::new(Some(""), ""));
+            expander.set_expanded(true);
+        }));
+        imp.save_button.connect_clicked(clone!(@weak self as editor => move |_| {
+            editor.save();
+        }));
+    }
+
+    /// Shows the remote configurations as they are in the configuration file
+    pub fn load(&self) {
+        let imp = self.imp();
+        for row in imp.rows.take() {
+            imp.list_box.remove(&row.expander);
+        }
+        // Saving what couldn't be loaded would throw the configuration file away
+        let cfg = match config::load() {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                log::warn!("{:#}", e);
+                imp.save_button.set_sensitive(false);
+                self.toast(&format!("{:#}", e));
+                return;
+            },
+        };
+        imp.save_button.set_sensitive(true);
+        for remote in &cfg.remotes {
+            self.add_row(remote);
+        }
+    }
+
+    fn add_row(&self, remote: &RemoteConfig) -> adw::ExpanderRow {
+        let expander = adw::ExpanderRow::builder()
+            .title(&Self::title(remote))
+            .build();
+
+        let enabled_switch = gtk::Switch::builder()
+            .active(remote.enabled)
+            .valign(gtk::Align::Center)
+            .tooltip_text("Back up to this remote")
+            .build();
+        expander.add_action(&enabled_switch);
+
+        let type_drop_down = gtk::DropDown::from_strings(&["Local", "SSH"]);
+        type_drop_down.set_selected(if remote.host.is_some() { TYPE_SSH } else { TYPE_LOCAL });
+        type_drop_down.set_valign(gtk::Align::Center);
+        expander.add_row(&Self::row("Type", Some("Local disks are mounted on this machine"), &type_drop_down));
+
+        let address = match (&remote.user, &remote.host) {
+            (Some(user), Some(host)) => format!("{}@{}", user, host),
+            (None, Some(host)) => host.clone(),
+            _ => String::new(),
+        };
+        let address_entry = gtk::Entry::builder()
+            .text(&address)
+            .placeholder_text("user@host")
+            .valign(gtk::Align::Center)
+            .build();
+        expander.add_row(&Self::row("Address", None, &address_entry));
+
+        let port_spin = gtk::SpinButton::with_range(1.0, u16::MAX as f64, 1.0);
+        port_spin.set_value(remote.port.unwrap_or(DEFAULT_SSH_PORT) as f64);
+        port_spin.set_valign(gtk::Align::Center);
+        expander.add_row(&Self::row("Port", None, &port_spin));
+
+        let path_entry = gtk::Entry::builder()
+            .text(&remote.path)
+            .placeholder_text("/backup")
+            .valign(gtk::Align::Center)
+            .build();
+        expander.add_row(&Self::row("Path", Some("Btrfs directory receiving the snapshots"), &path_entry));
+
+        let sudo_switch = gtk::Switch::builder()
+            .active(remote.sudo)
+            .valign(gtk::Align::Center)
+            .build();
+        expander.add_row(&Self::row("Use sudo", Some("Receive snapshots as root on the remote"), &sudo_switch));
+
+        let retention = remote.retention.clone().unwrap_or_default();
+        let counts = [retention.hourly, retention.daily, retention.weekly, retention.monthly, retention.yearly];
+        let mut count_spins = Vec::new();
+        for (title, count) in TIER_TITLES.iter().zip(counts) {
+            let spin = Self::count_spin(count);
+            expander.add_row(&Self::row(title, Some("Number to keep on the remote"), &spin));
+            count_spins.push(spin);
+        }
+        let max_age_entry = gtk::Entry::builder()
+            .text(retention.max_age.as_deref().unwrap_or_default())
+            .placeholder_text("No limit")
+            .valign(gtk::Align::Center)
+            .build();
+        expander.add_row(&Self::row("Maximum age", Some("Older snapshots are deleted, e.g. 26w or 1y"), &max_age_entry));
+        let budget_entry = gtk::Entry::builder()
+            .text(retention.budget.as_deref().unwrap_or_default())
+            .placeholder_text("No budget")
+            .valign(gtk::Align::Center)
+            .build();
+        expander.add_row(&Self::row("Space budget", Some("Space the snapshots may use on the remote, e.g. 500G, keeping at least the numbers above"), &budget_entry));
+
+        let test_button = gtk::Button::builder()
+            .label("Test Connection")
+            .valign(gtk::Align::Center)
+            .build();
+        test_button.connect_clicked(clone!(@weak self as editor, @weak expander => move |button| {
+            editor.test_connection(&expander, button);
+        }));
+        let test_row = Self::row("Connection", None, &test_button);
+        expander.add_row(&test_row);
+
+        let remove_button = gtk::Button::builder()
+            .label("Remove")
+            .valign(gtk::Align::Center)
+            .build();
+        remove_button.add_css_class("destructive-action");
+        remove_button.connect_clicked(clone!(@weak self as editor, @weak expander => move |_| {
+            editor.remove_row(&expander);
+        }));
+        expander.add_row(&Self::row("Remove remote", Some("Snapshots on the remote are kept"), &remove_button));
+
+        type_drop_down.connect_selected_notify(clone!(@weak self as editor, @weak expander => move |_| {
+            editor.update_row(&expander);
+        }));
+        address_entry.connect_changed(clone!(@weak self as editor, @weak expander => move |_| {
+            editor.update_row(&expander);
+        }));
+        port_spin.connect_value_changed(clone!(@weak self as editor, @weak expander => move |_| {
+            editor.update_row(&expander);
+        }));
+        path_entry.connect_changed(clone!(@weak self as editor, @weak expander => move |_| {
+            editor.update_row(&expander);
+        }));
+
+        let imp = self.imp();
+        imp.list_box.append(&expander);
+        imp.rows.borrow_mut().push(RemoteRow {
+            expander: expander.clone(),
+            enabled_switch,
+            type_drop_down,
+            address_entry,
+            port_spin,
+            path_entry,
+            sudo_switch,
+            count_spins,
+            max_age_entry,
+            budget_entry,
+            test_row,
+            base: remote.clone(),
+        });
+        self.update_row(&expander);
+
+        expander
+    }
+
+    fn row(title: &str, subtitle: Option<&str>, suffix: &impl IsA<gtk::Widget>) -> adw::ActionRow {
+        let row = adw::ActionRow::builder()
+            .title(title)
+            .build();
+        if let Some(subtitle) = subtitle {
+            row.set_subtitle(subtitle);
+        }
+        row.add_suffix(suffix);
+        row
+    }
+
+    /// Spin button for the number of snapshots of a tier to keep, showing "All" when the tier
+    /// isn't pruned
+    fn count_spin(count: Option<usize>) -> gtk::SpinButton {
+        let spin = gtk::SpinButton::with_range(-1.0, 1000.0, 1.0);
+        spin.set_value(count.map_or(-1.0, |count| count as f64));
+        spin.set_valign(gtk::Align::Center);
+        spin.connect_output(|spin| {
+            if spin.value_as_int() < 0 {
+                spin.set_text("All");
+                gtk::Inhibit(true)
+            } else {
+                gtk::Inhibit(false)
+            }
+        });
+        spin.connect_input(|spin| {
+            if spin.text().trim().eq_ignore_ascii_case("all") {
+                Some(Ok(-1.0))
+            } else {
+                None
+            }
+        });
+        spin
+    }
+
+    /// Names a remote after its location, e.g. "li@192.168.0.2:/backup"
+    fn title(remote: &RemoteConfig) -> String {
+        if remote.path.is_empty() {
+            return "New remote".to_string();
+        }
+
+        let location = SnapshotRepositoryLocation {
+            user: remote.user.clone(),
+            host: remote.host.clone(),
+            port: remote.port,
+            path: remote.path.clone(),
+            ..Default::default()
+        };
+        glib::markup_escape_text(&location.to_string()).to_string()
+    }
+
+    /// Updates the title of the remote in `expander` and shows only the settings that apply to
+    /// its type
+    fn update_row(&self, expander: &adw::ExpanderRow) {
+        let rows = self.imp().rows.borrow();
+        let row = match rows.iter().find(|row| &row.expander == expander) {
+            Some(row) => row,
+            None => return,
+        };
+        let is_ssh = row.type_drop_down.selected() == TYPE_SSH;
+        row.address_entry.set_sensitive(is_ssh);
+        row.port_spin.set_sensitive(is_ssh);
+        expander.set_title(&Self::title(&row.config()));
+    }
+
+    fn remove_row(&self, expander: &adw::ExpanderRow) {
+        let imp = self.imp();
+        imp.list_box.remove(expander);
+        imp.rows.borrow_mut().retain(|row| &row.expander != expander);
+    }
+
+    /// Runs a preflight check of the remote in `expander` as it is currently edited, showing
+    /// which step failed if any
+    fn test_connection(&self, expander: &adw::ExpanderRow, button: &gtk::Button) {
+        let rows = self.imp().rows.borrow();
+        let row = match rows.iter().find(|row| &row.expander == expander) {
+            Some(row) => row,
+            None => return,
+        };
+        let remote = row.config();
+        let test_row = row.test_row.clone();
+        test_row.set_subtitle("Testing…");
+        button.set_sensitive(false);
+
+        let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        thread::spawn(move || {
+            let result = remote.validate().and_then(|_| {
+                Ok(preflight::preflight(&remote.to_location()?, remote.sudo, &remote.preflight_opts())?)
+            });
+            let _ = sender.send(result);
+        });
+        receiver.attach(None, clone!(@weak button => @default-return Continue(false), move |result: Result<()>| {
+            button.set_sensitive(true);
+            let status = match result {
+                Ok(()) => "Ready to receive snapshots".to_string(),
+                Err(e) => format!("{:#}", e),
+            };
+            test_row.set_subtitle(&glib::markup_escape_text(&status));
+            Continue(false)
+        }));
+    }
+
+    /// Writes the remote configurations to the configuration file if they are valid
+    fn save(&self) {
+        let imp = self.imp();
+        let remotes: Vec<RemoteConfig> = imp.rows.borrow().iter().map(RemoteRow::config).collect();
+        imp.save_button.set_sensitive(false);
+
+        let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        thread::spawn(move || {
+            let _ = sender.send(config::save_remotes(&remotes));
+        });
+        receiver.attach(None, clone!(@weak self as editor => @default-return Continue(false), move |result: Result<()>| {
+            editor.imp().save_button.set_sensitive(true);
+            match result {
+                Ok(()) => editor.toast("Saved remotes"),
+                Err(e) => editor.toast(&format!("{:#}", e)),
+            }
+            Continue(false)
+        }));
+    }
+
+    fn toast(&self, message: &str) {
+        if let Some(window) = self.root().and_then(|root| root.downcast::<adw::PreferencesWindow>().ok()) {
+            window.add_toast(&adw::Toast::new(&glib::markup_escape_text(message)));
+        }
+    }
+}
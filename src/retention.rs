@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, Duration, Utc};
+use log::info;
+
+use crate::fridge::{list_snapshots, Snapshot, SnapshotRepositoryLocation};
+use crate::groups;
+use crate::history::{self, Operation, Record};
+use crate::nested;
+use crate::pins::PinStore;
+use crate::usage;
+
+/// Which snapshots to keep on a destination
+///
+/// A snapshot is pruned once there are more snapshots of its tier (its suffix, e.g. "daily")
+/// than the count configured for that tier, or once it is older than `max_age`. Tiers without
+/// a count and snapshots of other suffixes are only pruned by age.
+///
+/// With a `budget`, the counts are minimums instead: snapshots are kept until their exclusive
+/// size exceeds the budget, and then thinned out with [`select_for_budget`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RetentionPolicy {
+    pub hourly: Option<usize>,
+    pub daily: Option<usize>,
+    pub weekly: Option<usize>,
+    pub monthly: Option<usize>,
+    pub yearly: Option<usize>,
+    pub max_age: Option<Duration>,
+    /// Bytes the snapshots may use exclusively
+    pub budget: Option<u64>,
+}
+
+impl RetentionPolicy {
+    fn count(&self, suffix: &str) -> Option<usize> {
+        match suffix {
+            "hourly" => self.hourly,
+            "daily" => self.daily,
+            "weekly" => self.weekly,
+            "monthly" => self.monthly,
+            "yearly" => self.yearly,
+            _ => None,
+        }
+    }
+}
+
+/// Order in which snapshots of a tier are given up to meet a budget, hourly ones first
+///
+/// Snapshots of other suffixes, such as manual ones, go last.
+fn tier_rank(tier: &str) -> usize {
+    match tier {
+        "hourly" => 0,
+        "daily" => 1,
+        "weekly" => 2,
+        "monthly" => 3,
+        "yearly" => 4,
+        _ => 5,
+    }
+}
+
+/// Time between two snapshots of a tier, such as a day for "daily"
+pub fn tier_interval(tier: &str) -> Option<Duration> {
+    match tier {
+        "hourly" => Some(Duration::hours(1)),
+        "daily" => Some(Duration::days(1)),
+        "weekly" => Some(Duration::weeks(1)),
+        "monthly" => Some(Duration::days(30)),
+        "yearly" => Some(Duration::days(365)),
+        _ => None,
+    }
+}
+
+/// Parses an age such as "36h", "90d", "12w" or "2y"
+pub fn parse_age(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let (digits, unit) = s.split_at(s.len() - s.chars().last().map_or(0, |c| c.len_utf8()));
+    let value: i64 = digits.parse().map_err(|_| anyhow!("Could not parse age {:?}", s))?;
+    if value <= 0 {
+        bail!("Could not use age {:?}: it must be positive", s);
+    }
+    let unit_seconds: i64 = match unit {
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        "y" => 365 * 24 * 60 * 60,
+        _ => bail!("Could not parse age {:?}, expected a number followed by h, d, w or y", s),
+    };
+    // Durations are kept in milliseconds
+    match value.checked_mul(unit_seconds) {
+        Some(seconds) if seconds <= i64::MAX / 1000 => Ok(Duration::seconds(seconds)),
+        _ => bail!("Could not use age {:?}: it is too long", s),
+    }
+}
+
+/// Selects the snapshots that `policy` prunes, oldest first
+///
+/// The snapshots named in `protected` are always kept, such as the one the next
+/// synchronization sends incrementally against and the ones the user pinned.
+pub fn select_for_pruning<'a>(snapshots: &'a [Snapshot], policy: &RetentionPolicy, now: DateTime<Utc>, protected: &[&str]) -> Vec<&'a Snapshot> {
+    let mut snapshots: Vec<&Snapshot> = snapshots.iter().collect();
+    snapshots.sort_by_key(|snapshot| snapshot.datetime);
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    let mut pruned = Vec::new();
+    for snapshot in snapshots.into_iter().rev() {
+        let count = counts.entry(&snapshot.suffix).or_default();
+        *count += 1;
+
+        let over_count = policy.budget.is_none() && policy.count(&snapshot.suffix).is_some_and(|max| *count > max);
+        let too_old = policy.max_age.is_some_and(|max_age| now.signed_duration_since(snapshot.datetime) > max_age);
+        if (over_count || too_old) && !protected.contains(&snapshot.full_name.as_str()) {
+            pruned.push(snapshot);
+        }
+    }
+
+    pruned.reverse();
+    pruned
+}
+
+/// Selects the snapshots to delete for the rest to fit in the budget of `policy`, given their
+/// exclusive sizes by name, oldest first
+///
+/// Snapshots are given up by tier, hourly ones first, and oldest first within a tier. The
+/// newest snapshots of each tier up to its count and the ones named in `protected` are kept
+/// even if the budget isn't met then. Deleting a snapshot makes the data it shared with only
+/// one other exclusive to that one, so the next pruning may find the budget still exceeded.
+pub fn select_for_budget<'a>(snapshots: &[&'a Snapshot], sizes: &HashMap<String, u64>, policy: &RetentionPolicy, protected: &[&str]) -> Vec<&'a Snapshot> {
+    let budget = match policy.budget {
+        Some(budget) => budget,
+        None => return Vec::new(),
+    };
+    let size = |snapshot: &Snapshot| sizes.get(&snapshot.full_name).copied().unwrap_or_default();
+    let mut total: u64 = snapshots.iter().map(|snapshot| size(snapshot)).sum();
+
+    let mut newest_first = snapshots.to_vec();
+    newest_first.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.datetime));
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    let mut candidates: Vec<&Snapshot> = newest_first.into_iter()
+        .filter(|snapshot| {
+            let count = counts.entry(&snapshot.suffix).or_default();
+            *count += 1;
+            *count > policy.count(&snapshot.suffix).unwrap_or_default() && !protected.contains(&snapshot.full_name.as_str())
+        })
+        .collect();
+    candidates.sort_by_key(|snapshot| (tier_rank(&snapshot.suffix), snapshot.datetime));
+
+    let mut pruned = Vec::new();
+    for snapshot in candidates {
+        if total <= budget {
+            break;
+        }
+        total -= size(snapshot);
+        pruned.push(snapshot);
+    }
+
+    pruned.sort_by_key(|snapshot| snapshot.datetime);
+    pruned
+}
+
+#[derive(Default)]
+pub struct PruneOpts {
+    pub name: String,
+    /// Names and local repositories of the other members of the group `name` is in, whose
+    /// snapshots are pruned along with its own, see [`crate::groups`]
+    pub members: Vec<(String, SnapshotRepositoryLocation)>,
+    pub src: SnapshotRepositoryLocation,
+    pub src_sudo: bool,
+    pub dst: SnapshotRepositoryLocation,
+    pub dst_sudo: bool,
+    pub policy: RetentionPolicy,
+    pub dry_run: bool,
+    pub verbose: i32,
+}
+
+/// Deletes the snapshots with the given name that the retention policy prunes from `opts.dst`,
+/// along with the snapshots of the other members of its group taken with them
+///
+/// The newest snapshot the destination shares with the source and pinned snapshots are never
+/// deleted, nor are the other snapshots of their group. Returns the names of the pruned
+/// snapshots, or of the ones that would be pruned in a dry run.
+pub fn prune(opts: &PruneOpts) -> Result<Vec<String>> {
+    let pins = PinStore::load()?;
+    let mut protected: Vec<String> = pins.snapshots.iter().cloned().collect();
+    let mut member_snapshots = Vec::new();
+    for (name, src) in std::iter::once((&opts.name, &opts.src)).chain(opts.members.iter().map(|(name, src)| (name, src))) {
+        let src_snapshots = list_snapshots(name, src, opts.src_sudo, opts.verbose)?;
+        let dst_snapshots = list_snapshots(name, &opts.dst, opts.dst_sudo, opts.verbose)?;
+        protected.extend(dst_snapshots.iter()
+            .filter(|snapshot| src_snapshots.iter().any(|s| s.full_name == snapshot.full_name))
+            .max_by_key(|snapshot| snapshot.datetime)
+            .map(|snapshot| snapshot.full_name.clone()));
+        member_snapshots.push(dst_snapshots);
+    }
+    let protected: Vec<&str> = protected.iter().map(|snapshot| snapshot.as_str()).collect();
+    let mut sizes = HashMap::new();
+    if opts.policy.budget.is_some() {
+        for snapshots in &member_snapshots {
+            sizes.extend(usage::exclusive_sizes(&opts.dst, snapshots, opts.dst_sudo)?);
+        }
+    }
+
+    let mut pruned = Vec::new();
+    for stamp in groups::select_for_pruning(&opts.name, &member_snapshots, &opts.policy, Utc::now(), &protected, &sizes)? {
+        for snapshot in member_snapshots.iter().flatten().filter(|snapshot| groups::stamp(&snapshot.full_name) == stamp) {
+            if opts.dry_run {
+                info!("Would delete snapshot {} from {}", &snapshot.full_name, &opts.dst);
+            } else {
+                nested::delete_members(snapshot, &opts.dst, opts.dst_sudo, Operation::Prune, opts.verbose)?;
+                let started = Utc::now();
+                let result = snapshot.delete_from(&opts.dst, opts.dst_sudo, opts.verbose);
+                history::record(Record::new(Operation::Prune, started, &snapshot.name, Some(&snapshot.full_name), &opts.dst.to_string(), None, &result));
+                result?;
+                info!("Deleted snapshot {} from {}", &snapshot.full_name, &opts.dst);
+            }
+            pruned.push(snapshot.full_name.clone());
+        }
+    }
+
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fridge::parse_snapshot_name;
+
+    use super::*;
+
+    fn snapshots(names: &[&str]) -> Vec<Snapshot> {
+        names.iter().map(|name| parse_snapshot_name(name, "/backup").unwrap()).collect()
+    }
+
+    fn names(snapshots: Vec<&Snapshot>) -> Vec<&str> {
+        snapshots.into_iter().map(|snapshot| snapshot.full_name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_select_for_pruning() {
+        let snapshots = snapshots(&[
+            "root@2000-01-01_00:00:00_daily",
+            "root@2000-01-02_00:00:00_daily",
+            "root@2000-01-03_00:00:00_daily",
+            "root@2000-01-03_00:00:00_weekly",
+            "root@2000-01-03_12:00:00_manual",
+            "root@2000-01-04_00:00:00_daily",
+        ]);
+        let now = parse_snapshot_name("root@2000-01-04_12:00:00_daily", "/").unwrap().datetime;
+
+        let policy = RetentionPolicy {
+            daily: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(names(select_for_pruning(&snapshots, &policy, now, &[])), [
+            "root@2000-01-01_00:00:00_daily",
+            "root@2000-01-02_00:00:00_daily",
+        ]);
+
+        let policy = RetentionPolicy {
+            max_age: Some(Duration::days(2)),
+            ..Default::default()
+        };
+        assert_eq!(names(select_for_pruning(&snapshots, &policy, now, &[])), [
+            "root@2000-01-01_00:00:00_daily",
+            "root@2000-01-02_00:00:00_daily",
+        ]);
+
+        // The parent of the next synchronization survives even if the policy prunes it
+        let policy = RetentionPolicy {
+            daily: Some(0),
+            weekly: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(names(select_for_pruning(&snapshots, &policy, now, &["root@2000-01-04_00:00:00_daily"])), [
+            "root@2000-01-01_00:00:00_daily",
+            "root@2000-01-02_00:00:00_daily",
+            "root@2000-01-03_00:00:00_daily",
+            "root@2000-01-03_00:00:00_weekly",
+        ]);
+
+        assert!(select_for_pruning(&snapshots, &RetentionPolicy::default(), now, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_select_for_budget() {
+        let snapshots = snapshots(&[
+            "root@2000-01-01_00:00:00_daily",
+            "root@2000-01-01_00:00:00_weekly",
+            "root@2000-01-02_00:00:00_daily",
+            "root@2000-01-02_12:00:00_manual",
+            "root@2000-01-03_00:00:00_daily",
+        ]);
+        let all: Vec<&Snapshot> = snapshots.iter().collect();
+        let sizes: HashMap<String, u64> = snapshots.iter().map(|snapshot| (snapshot.full_name.clone(), 10)).collect();
+        let now = parse_snapshot_name("root@2000-01-04_00:00:00_daily", "/").unwrap().datetime;
+
+        let policy = RetentionPolicy {
+            daily: Some(1),
+            weekly: Some(0),
+            budget: Some(25),
+            ..Default::default()
+        };
+        // Counts are minimums under a budget
+        assert!(select_for_pruning(&snapshots, &policy, now, &[]).is_empty());
+        // Daily snapshots go before the weekly one, oldest first, and the manual one is spared
+        assert_eq!(names(select_for_budget(&all, &sizes, &policy, &[])), [
+            "root@2000-01-01_00:00:00_daily",
+            "root@2000-01-01_00:00:00_weekly",
+            "root@2000-01-02_00:00:00_daily",
+        ]);
+
+        // Neither the minimum counts nor protected snapshots are given up
+        let policy = RetentionPolicy {
+            daily: Some(2),
+            weekly: Some(1),
+            budget: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(names(select_for_budget(&all, &sizes, &policy, &["root@2000-01-02_12:00:00_manual"])), [
+            "root@2000-01-01_00:00:00_daily",
+        ]);
+
+        let policy = RetentionPolicy {
+            budget: Some(50),
+            ..Default::default()
+        };
+        assert!(select_for_budget(&all, &sizes, &policy, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(parse_age("36h").unwrap(), Duration::hours(36));
+        assert_eq!(parse_age("90d").unwrap(), Duration::days(90));
+        assert_eq!(parse_age("12w").unwrap(), Duration::weeks(12));
+        assert_eq!(parse_age("2y").unwrap(), Duration::days(730));
+        assert!(parse_age("90").is_err());
+        assert!(parse_age("").is_err());
+        assert!(parse_age("-5d").is_err());
+        assert!(parse_age("0d").is_err());
+        assert!(parse_age("99999999999999y").is_err());
+    }
+}
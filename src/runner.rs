@@ -0,0 +1,506 @@
+//! Taking, backing up and pruning snapshots the same way whether the window or a timer running
+//! `fridge run` asks for it
+//!
+//! Both defer jobs until their run conditions are met, and report failures, long backups and
+//! backups that are overdue as desktop notifications.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+
+use crate::cancel::{CancelToken, Cancelled};
+use crate::conditions::{self, Verdict};
+use crate::config::{self, Config, RemoteConfig};
+use crate::fridge::{self, list_snapshots, sync_fan_out, SnapshotOpts, SnapshotRepositoryLocation, SyncFanOutOpts};
+use crate::groups::{self, Unit};
+use crate::health::{self, HealthStore};
+use crate::history::{self, Operation, Record};
+use crate::nested;
+use crate::notify::{Event, Notification, Notifier};
+use crate::pins::PinStore;
+use crate::preflight;
+use crate::retention;
+use crate::space;
+use crate::ssh;
+use crate::usage;
+
+/// Time between checks of the run conditions of deferred jobs
+pub const DEFERRED_JOB_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Outcome of sending snapshots to each remote, along with how long it took
+pub type SendResults = Vec<(SnapshotRepositoryLocation, Result<Duration>)>;
+
+/// Operations that run on their own, from the window or a timer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Job {
+    Snapshot,
+    Backup,
+}
+
+impl Job {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Job::Snapshot => "take snapshot",
+            Job::Backup => "do backup",
+        }
+    }
+}
+
+/// Runs `jobs` one after another once their run conditions are met, carrying on past failing
+/// ones, and notifies about their outcome and about an overdue backup
+///
+/// Deferred jobs are retried every minute for up to `wait`, and skipped if their conditions
+/// are still not met by then. Fails with the first job that failed.
+pub fn run(jobs: &[Job], wait: Duration, cancel: &CancelToken) -> Result<()> {
+    let cfg = config::load()?;
+    let mut deferred = Deferred::default();
+    for job in jobs {
+        deferred.push(*job);
+    }
+
+    let started = Instant::now();
+    let mut result = Ok(());
+    loop {
+        deferred.retry(|job| check_conditions(&cfg, job), |job| {
+            let job_result = match job {
+                Job::Snapshot => snapshot(&cfg, cancel),
+                Job::Backup => backup(&cfg, cancel),
+            };
+            if let Some(event) = failure_event(job, &job_result) {
+                notify(&event);
+            }
+            match job_result {
+                Err(e) if result.is_ok() => result = Err(e),
+                Err(e) => warn!("Could not {}: {:#}", job.description(), e),
+                Ok(()) => (),
+            }
+            true
+        });
+        if deferred.is_empty() {
+            break;
+        }
+        if started.elapsed() >= wait {
+            for job in &deferred.jobs {
+                info!("Skipping the attempt to {}: its run conditions are not met", job.description());
+            }
+            break;
+        }
+        cancel.wait(DEFERRED_JOB_RETRY_INTERVAL)?;
+    }
+
+    match backup_overdue(&cfg) {
+        Ok((true, last_success)) => notify(&Event::BackupOverdue(last_success)),
+        Ok((false, _)) => (),
+        Err(e) => warn!("Could not check for overdue backup: {:#}", e),
+    }
+
+    result
+}
+
+/// Whether the run conditions of `job` let it start now, or why it should wait
+///
+/// Conditions that can't be checked count as met.
+pub fn check_conditions(cfg: &Config, job: Job) -> Verdict {
+    let conditions = match job {
+        Job::Snapshot => &cfg.conditions.snapshots,
+        Job::Backup => &cfg.conditions.syncs,
+    };
+    match conditions::check(conditions) {
+        Ok(Verdict::Defer(reason)) => {
+            info!("Deferring the attempt to {}: {}", job.description(), reason);
+            Verdict::Defer(reason)
+        },
+        Ok(Verdict::Run) => Verdict::Run,
+        Err(e) => {
+            warn!("Could not check run conditions: {:#}", e);
+            Verdict::Run
+        },
+    }
+}
+
+/// Jobs waiting for their run conditions to be met
+#[derive(Debug, Default)]
+pub struct Deferred {
+    jobs: Vec<Job>,
+}
+
+impl Deferred {
+    pub fn push(&mut self, job: Job) {
+        if !self.jobs.contains(&job) {
+            self.jobs.push(job);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /// Starts each job whose run conditions `verdict` finds met with `start`, keeping the
+    /// ones that have to wait or that `start` couldn't start
+    pub fn retry(&mut self, mut verdict: impl FnMut(Job) -> Verdict, mut start: impl FnMut(Job) -> bool) {
+        self.jobs.retain(|job| verdict(*job) != Verdict::Run || !start(*job));
+    }
+}
+
+/// Sends a desktop notification about `event` if the configuration asks for one, and returns
+/// its ID
+pub fn send_notification(event: &Event) -> Result<Option<u32>> {
+    match notification(event)? {
+        Some(notification) => Notifier::new()?.send(&notification).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Notification about `event`, if the configuration asks for one
+fn notification(event: &Event) -> Result<Option<Notification>> {
+    Ok(config::load()?.notifications.to_notify_opts()?.notification(event))
+}
+
+/// Sends a desktop notification about `event`, only logging why it couldn't
+///
+/// Only the window listens for the actions of its notifications, so these have none.
+fn notify(event: &Event) {
+    let result = notification(event).and_then(|notification| match notification {
+        Some(notification) => Notifier::new()?.send(&Notification {
+            actions: Vec::new(),
+            ..notification
+        }).map(|_| ()),
+        None => Ok(()),
+    });
+    if let Err(e) = result {
+        warn!("Could not send notification: {:#}", e);
+    }
+}
+
+/// Event to notify about when `job` ended with `result`, none if it succeeded or was cancelled
+pub fn failure_event(job: Job, result: &Result<()>) -> Option<Event> {
+    match result {
+        Err(e) if !e.is::<Cancelled>() => {
+            let error = format!("{:#}", e);
+            Some(match job {
+                Job::Snapshot => Event::SnapshotFailed(error),
+                Job::Backup => Event::BackupFailed(error),
+            })
+        },
+        _ => None,
+    }
+}
+
+/// Events about the remotes that were backed up to, and the first remote that failed
+pub fn send_outcome(results: SendResults) -> (Vec<Event>, Result<()>) {
+    let mut events = Vec::new();
+    let mut result = Ok(());
+    for (remote, remote_result) in results {
+        match remote_result {
+            Ok(elapsed) => events.push(Event::BackupCompleted(elapsed)),
+            Err(e) if result.is_ok() => result = Err(e),
+            Err(e) => warn!("Could not send snapshots to {}: {:#}", remote, e),
+        }
+    }
+
+    (events, result)
+}
+
+/// Whether the last successful backup to the remote furthest behind is too old, along with
+/// when it was
+///
+/// Only remotes that were backed up to at least once, successfully or not, count, so a new
+/// setup or remote isn't overdue before its first backup.
+pub fn backup_overdue(cfg: &Config) -> Result<(bool, Option<DateTime<Utc>>)> {
+    let opts = cfg.notifications.to_notify_opts()?;
+    let locations = enabled_remotes(cfg).iter().map(RemoteConfig::to_location).collect::<Result<Vec<_>>>()?;
+    let locations: Vec<String> = locations.iter().map(|location| location.to_string()).collect();
+    match last_success(&HealthStore::load()?, &locations) {
+        Some(last_success) => Ok((opts.is_backup_overdue(last_success, Utc::now()), last_success)),
+        None => Ok((false, None)),
+    }
+}
+
+/// When the remote at `locations` furthest behind was last backed up to successfully, if
+/// ever, or `None` if none of them was backed up to yet
+fn last_success(store: &HealthStore, locations: &[String]) -> Option<Option<DateTime<Utc>>> {
+    // Never having succeeded counts as furthest behind of all
+    locations.iter()
+        .filter_map(|location| store.remotes.get(location))
+        .map(|health| health.last_success)
+        .min()
+}
+
+/// Remotes to back up to, the ones enabled in the configuration
+pub fn enabled_remotes(cfg: &Config) -> Vec<RemoteConfig> {
+    cfg.remotes.iter().filter(|remote| remote.enabled).cloned().collect()
+}
+
+/// Whether local snapshots are taken and sent through sudo, which the window always needs
+fn local_sudo(cfg: &Config) -> bool {
+    cfg.local.sudo || !config::is_root()
+}
+
+/// Takes the snapshots that are due and prunes the ones beyond the configured counts
+///
+/// A subvolume or group that fails doesn't keep the others from their snapshots, and all
+/// failures are reported together at the end.
+pub fn snapshot(cfg: &Config, cancel: &CancelToken) -> Result<()> {
+    let mut errors = Vec::new();
+    for unit in groups::units(cfg) {
+        cancel.check()?;
+        if let Err(e) = do_snapshot(&unit, cfg) {
+            warn!("Could not take snapshot of {}: {:#}", unit.name, e);
+            errors.push(format!("{}: {:#}", unit.name, e));
+        }
+    }
+
+    match errors.as_slice() {
+        [] => Ok(()),
+        [error] => bail!("Could not take snapshot of {}", error),
+        _ => bail!("Could not take snapshots of {} subvolumes or groups: {}", errors.len(), errors.join("; ")),
+    }
+}
+
+/// Sends the missing snapshots to every enabled remote, notifying about each long backup
+pub fn backup(cfg: &Config, cancel: &CancelToken) -> Result<()> {
+    let remotes = enabled_remotes(cfg);
+    if remotes.is_empty() {
+        return Err(anyhow!("Could not back up: no remotes are enabled"));
+    }
+
+    let (events, result) = send_outcome(send_to_remotes(cfg, &remotes, None, cancel)?);
+    for event in &events {
+        notify(event);
+    }
+
+    result
+}
+
+/// Sends snapshots to `remotes`, carrying on past failing ones
+///
+/// Sends the snapshots missing on each remote, or only the ones named in `snapshots`.
+pub fn send_to_remotes(cfg: &Config, remotes: &[RemoteConfig], snapshots: Option<&[String]>, cancel: &CancelToken) -> Result<SendResults> {
+    let machine = cfg.local.machine_id()?;
+    let mut results = Vec::new();
+    for remote in remotes {
+        cancel.check()?;
+        let location = remote.to_location()?;
+        let result = send_to_remote(remote, &location, &machine, cfg, snapshots, cancel);
+        results.push((location, result));
+    }
+
+    Ok(results)
+}
+
+/// Sends the snapshots missing on the remote at `location`, or only the ones named in
+/// `snapshots`, and returns how long it took
+///
+/// The members of a group snapshot are sent one after another, and only once every member
+/// has been taken.
+fn send_to_remote(remote: &RemoteConfig, location: &SnapshotRepositoryLocation, machine: &str, cfg: &Config, snapshots: Option<&[String]>, cancel: &CancelToken) -> Result<Duration> {
+    let started = Instant::now();
+    let sudo = local_sudo(cfg);
+    let result = cancel.attribute(preflight::with_preflight(location, remote.sudo, &remote.preflight_opts(), &remote.retry_policy(), cancel, || {
+        let _session = ssh::Session::open(location)?;
+        let dst = location.for_machine(machine);
+        for unit in groups::units(cfg) {
+            let srcs: Vec<(String, SnapshotRepositoryLocation)> = unit.members.iter()
+                .map(|member| (member.name.clone(), member.snapshot_location(&cfg.local)))
+                .collect();
+            if !unit.is_group() {
+                let (name, src) = &srcs[0];
+                do_backup(name, src, &dst, remote.sudo, snapshots, &[], cfg, cancel)?;
+                continue;
+            }
+
+            let member_snapshots = srcs.iter()
+                .map(|(name, src)| list_snapshots(name, src, sudo, 0))
+                .collect::<Result<Vec<_>>>()?;
+            let to_send = groups::to_send(&member_snapshots, snapshots);
+            // The group is pruned once its last member is up-to-date
+            let (last, others) = srcs.split_last().unwrap();
+            for ((name, src), names) in others.iter().zip(&to_send) {
+                let member_dst = SnapshotRepositoryLocation { retention: None, ..dst.clone() };
+                do_backup(name, src, &member_dst, remote.sudo, Some(names), &[], cfg, cancel)?;
+            }
+            do_backup(&last.0, &last.1, &dst, remote.sudo, to_send.last().map(|names| names.as_slice()), others, cfg, cancel)?;
+        }
+        Ok(())
+    }));
+
+    // A cancelled backup says nothing about the health of the remote
+    if !cancel.is_cancelled() {
+        if let Err(e) = health::record(&location.to_string(), &result) {
+            warn!("Could not record health of {}: {}", &location, e);
+        }
+    }
+
+    result.map(|_| started.elapsed())
+}
+
+/// Takes a group snapshot of every tier that is due once there is enough free space for it,
+/// then deletes the group snapshots beyond the configured counts
+fn do_snapshot(unit: &Unit, cfg: &Config) -> Result<()> {
+    let sudo = local_sudo(cfg);
+    let locations: Vec<SnapshotRepositoryLocation> = unit.members.iter().map(|member| member.snapshot_location(&cfg.local)).collect();
+    let nested_paths = unit.members.iter()
+        .map(|member| nested::for_config(member, cfg, sudo))
+        .collect::<Result<Vec<_>>>()?;
+    let list = || unit.members.iter().zip(&locations)
+        .map(|(member, dst)| list_snapshots(&member.name, dst, sudo, 0))
+        .collect::<Result<Vec<_>>>();
+    let snapshots = list()?;
+    let guard = cfg.local.space_guard()?;
+    let members: Vec<(String, SnapshotRepositoryLocation)> = unit.members.iter().map(|member| member.name.clone()).zip(locations.iter().cloned()).collect();
+    let now = Utc::now();
+    // Members of a group keep the same tiers, so the first one stands for all of them
+    for (tier, count) in unit.members[0].tiers() {
+        let interval = match retention::tier_interval(tier) {
+            Some(interval) if count > 0 => interval,
+            _ => continue,
+        };
+        let last_snapshot = snapshots.iter()
+            .flatten()
+            .filter(|snapshot| snapshot.suffix == tier)
+            .map(|snapshot| snapshot.datetime)
+            .max();
+        if last_snapshot.is_none_or(|last_snapshot| now.signed_duration_since(last_snapshot) >= interval) {
+            let opts: Vec<SnapshotOpts> = unit.members.iter().zip(&nested_paths)
+                .map(|(member, nested_paths)| SnapshotOpts {
+                    nested: nested_paths.clone(),
+                    ..member.to_snapshot_opts(&cfg.local, Some(tier), sudo, false, 0)
+                })
+                .collect();
+            if let Some(guard) = &guard {
+                space::guard_snapshot(&unit.name, &members, guard, sudo, 0)?;
+            }
+            fridge::snapshot_group(&opts)?;
+        }
+    }
+
+    // Pinned snapshots are kept without counting towards the maximum, along with the rest
+    // of their group. Under a budget they still use their share of it.
+    let pins = PinStore::load()?;
+    let policy = unit.members[0].retention_policy();
+    let mut snapshots = list()?;
+    let mut sizes = HashMap::new();
+    let mut protected: Vec<&str> = Vec::new();
+    if policy.budget.is_some() {
+        for (member_snapshots, dst) in snapshots.iter().zip(&locations) {
+            sizes.extend(usage::exclusive_sizes(dst, member_snapshots, sudo)?);
+        }
+        protected.extend(pins.snapshots.iter().map(|snapshot| snapshot.as_str()));
+    } else {
+        let pinned: Vec<String> = snapshots.iter()
+            .flatten()
+            .filter(|snapshot| pins.is_pinned(&snapshot.full_name))
+            .map(|snapshot| groups::stamp(&snapshot.full_name).to_string())
+            .collect();
+        for member_snapshots in &mut snapshots {
+            member_snapshots.retain(|snapshot| !pinned.iter().any(|stamp| stamp == groups::stamp(&snapshot.full_name)));
+        }
+    }
+    for stamp in groups::select_for_pruning(&unit.name, &snapshots, &policy, Utc::now(), &protected, &sizes)? {
+        for (member_snapshots, dst) in snapshots.iter().zip(&locations) {
+            for snapshot in member_snapshots.iter().filter(|snapshot| groups::stamp(&snapshot.full_name) == stamp) {
+                nested::delete_members(snapshot, dst, sudo, Operation::Prune, 0)?;
+                let started = Utc::now();
+                let result = snapshot.delete(sudo, 0);
+                history::record(Record::new(Operation::Prune, started, &snapshot.name, Some(&snapshot.full_name), &dst.path, None, &result));
+                result?;
+                info!("Deleted snapshot {}", &snapshot.full_name);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends the snapshots of `name` at `src` to `dst`, at the priority configured in [local]
+#[allow(clippy::too_many_arguments)]
+fn do_backup(name: &str, src: &SnapshotRepositoryLocation, dst: &SnapshotRepositoryLocation, dst_sudo: bool, snapshots: Option<&[String]>, members: &[(String, SnapshotRepositoryLocation)], cfg: &Config, cancel: &CancelToken) -> Result<()> {
+    let opts = SyncFanOutOpts {
+        name: name.to_string(),
+        src: src.clone(),
+        src_sudo: local_sudo(cfg),
+        dsts: vec![(dst.clone(), dst_sudo)],
+        snapshots: snapshots.map(|snapshots| snapshots.to_vec()),
+        members: members.to_vec(),
+        priority: cfg.local.priority()?,
+        cancel: cancel.clone(),
+        ..Default::default()
+    };
+    for result in sync_fan_out(&opts)? {
+        result?;
+    }
+    info!("Remote snapshots of {} are up-to-date", name);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_event() {
+        assert_eq!(failure_event(Job::Snapshot, &Ok(())), None);
+        assert_eq!(failure_event(Job::Backup, &Err(Cancelled.into())), None);
+        assert_eq!(failure_event(Job::Backup, &Err(anyhow!("Connection refused"))), Some(Event::BackupFailed("Connection refused".to_string())));
+        assert_eq!(failure_event(Job::Snapshot, &Err(anyhow!("No space left"))), Some(Event::SnapshotFailed("No space left".to_string())));
+    }
+
+    #[test]
+    fn test_last_success() {
+        let at = Utc::now();
+        let locations = ["li@nas:22:/backup".to_string(), "/run/media/backup".to_string()];
+        let mut store = HealthStore::default();
+        assert_eq!(last_success(&store, &locations), None);
+
+        store.remotes.entry(locations[0].clone()).or_default().record_failure("Connection refused", at);
+        assert_eq!(last_success(&store, &locations), Some(None));
+
+        store.remotes.entry(locations[0].clone()).or_default().record_success(at);
+        assert_eq!(last_success(&store, &locations), Some(Some(at)));
+        assert_eq!(last_success(&store, &locations[1..]), None);
+    }
+
+    #[test]
+    fn test_deferred() {
+        let mut deferred = Deferred::default();
+        deferred.push(Job::Snapshot);
+        deferred.push(Job::Backup);
+        deferred.push(Job::Snapshot);
+        assert_eq!(deferred.jobs, vec![Job::Snapshot, Job::Backup]);
+
+        // Only jobs whose conditions are met are started, and only started jobs are done
+        let mut started = Vec::new();
+        let verdict = |job| if job == Job::Backup { Verdict::Defer("metered".to_string()) } else { Verdict::Run };
+        deferred.retry(verdict, |job| {
+            started.push(job);
+            true
+        });
+        assert_eq!(started, vec![Job::Snapshot]);
+        assert_eq!(deferred.jobs, vec![Job::Backup]);
+
+        deferred.retry(|_| Verdict::Run, |_| false);
+        assert_eq!(deferred.jobs, vec![Job::Backup]);
+        deferred.retry(|_| Verdict::Run, |_| true);
+        assert!(deferred.is_empty());
+    }
+
+    #[test]
+    fn test_send_outcome() {
+        let remote = |path: &str| SnapshotRepositoryLocation { path: path.to_string(), ..Default::default() };
+        let (events, result) = send_outcome(vec![
+            (remote("/a"), Err(anyhow!("Connection refused"))),
+            (remote("/b"), Ok(Duration::from_secs(600))),
+            (remote("/c"), Err(anyhow!("No space left"))),
+        ]);
+        assert_eq!(events, vec![Event::BackupCompleted(Duration::from_secs(600))]);
+        assert_eq!(result.unwrap_err().to_string(), "Connection refused");
+
+        let (events, result) = send_outcome(Vec::new());
+        assert!(events.is_empty());
+        assert!(result.is_ok());
+    }
+}
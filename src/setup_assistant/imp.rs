@@ -0,0 +1,61 @@
+use adw::prelude::*;
+use adw::subclass::prelude::*;
+use gtk::{glib, CompositeTemplate};
+use gtk::glib::subclass::InitializingObject;
+use std::cell::RefCell;
+
+use crate::config::SnapshotConfig;
+
+// Object holding the state
+#[derive(CompositeTemplate, Default)]
+#[template(resource = "/co/veand/fridge/SetupAssistant.ui")]
+pub struct SetupAssistant {
+    #[template_child]
+    pub skip_button: TemplateChild<gtk::Button>,
+    #[template_child]
+    pub apply_button: TemplateChild<gtk::Button>,
+    #[template_child]
+    pub spinner: TemplateChild<gtk::Spinner>,
+    #[template_child]
+    pub status_label: TemplateChild<gtk::Label>,
+    #[template_child]
+    pub list_box: TemplateChild<gtk::ListBox>,
+    /// Proposed snapshot configurations, along with the check buttons selecting them
+    pub proposals: RefCell<Vec<(gtk::CheckButton, SnapshotConfig)>>,
+}
+
+// The central trait for subclassing a GObject
+#[glib::object_subclass]
+impl ObjectSubclass for SetupAssistant {
+    // `NAME` needs to match `class` attribute of template
+    const NAME: &'static str = "SetupAssistant";
+    type Type = super::SetupAssistant;
+    type ParentType = adw::Window;
+
+    fn class_init(klass: &mut Self::Class) {
+        klass.bind_template();
+    }
+
+    fn instance_init(obj: &InitializingObject<Self>) {
+        obj.init_template();
+    }
+}
+
+// Trait shared by all GObjects
+impl ObjectImpl for SetupAssistant {
+    fn constructed(&self) {
+        // Call "constructed" on parent
+        self.parent_constructed();
+
+        let obj = self.obj();
+        obj.setup_callbacks();
+        obj.discover();
+    }
+}
+
+// Trait shared by all widgets
+impl WidgetImpl for SetupAssistant {}
+
+impl WindowImpl for SetupAssistant {}
+
+impl AdwWindowImpl for SetupAssistant {}
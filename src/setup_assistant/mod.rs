@@ -0,0 +1,116 @@
+mod imp;
+
+use std::thread;
+
+use adw::prelude::*;
+use anyhow::Result;
+use gtk::glib::{self, Object};
+use gtk::glib::{clone, Continue};
+use gtk::subclass::prelude::ObjectSubclassIsExt;
+
+use crate::config::{self, SnapshotConfig};
+use crate::discover::{self, Subvolume};
+
+glib::wrapper! {
+    pub struct SetupAssistant(ObjectSubclass<imp::SetupAssistant>)
+        @extends adw::Window, gtk::Window, gtk::Widget,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::Native, gtk::Root, gtk::ShortcutManager;
+}
+
+impl SetupAssistant {
+    pub fn new(parent: &impl IsA<gtk::Window>) -> Self {
+        Object::builder::<Self>().property("transient-for", parent).build()
+    }
+
+    fn setup_callbacks(&self) {
+        let imp = self.imp();
+        imp.skip_button.connect_clicked(clone!(@weak self as assistant => move |_| {
+            assistant.close();
+        }));
+        imp.apply_button.connect_clicked(clone!(@weak self as assistant => move |_| {
+            assistant.apply();
+        }));
+    }
+
+    /// Looks for subvolumes in the background, then lists the snapshot configurations proposed
+    /// for them, all selected
+    fn discover(&self) {
+        let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        thread::spawn(move || {
+            let _ = sender.send(discover::discover(!config::is_root()));
+        });
+        receiver.attach(None, clone!(@weak self as assistant => @default-return Continue(false), move |result: Result<Vec<Subvolume>>| {
+            let imp = assistant.imp();
+            imp.spinner.set_spinning(false);
+            imp.spinner.set_visible(false);
+            match result {
+                Ok(subvolumes) if subvolumes.is_empty() => {
+                    imp.status_label.set_label("No btrfs subvolumes were found. Snapshots of / and /home are taken until others are configured in Preferences.");
+                },
+                Ok(subvolumes) => {
+                    imp.status_label.set_label(&format!("Found {} subvolume(s)", subvolumes.len()));
+                    for subvolume in &subvolumes {
+                        assistant.add_row(subvolume);
+                    }
+                    imp.list_box.set_visible(true);
+                    imp.apply_button.set_sensitive(true);
+                },
+                Err(e) => imp.status_label.set_label(&format!("{:#}", e)),
+            }
+            Continue(false)
+        }));
+    }
+
+    fn add_row(&self, subvolume: &Subvolume) {
+        let proposal = subvolume.proposal();
+        let check_button = gtk::CheckButton::builder()
+            .active(true)
+            .valign(gtk::Align::Center)
+            .build();
+        let subtitle = match &proposal.snapshot_dir {
+            Some(snapshot_dir) => format!("{}, snapshots kept in {}", subvolume.description(), snapshot_dir),
+            None => subvolume.description(),
+        };
+        let row = adw::ActionRow::builder()
+            .title(&glib::markup_escape_text(&subvolume.mount_point))
+            .subtitle(&glib::markup_escape_text(&subtitle))
+            .activatable_widget(&check_button)
+            .build();
+        row.add_prefix(&check_button);
+
+        let imp = self.imp();
+        imp.list_box.append(&row);
+        imp.proposals.borrow_mut().push((check_button, proposal));
+    }
+
+    /// Saves the selected snapshot configurations in the background, then closes
+    fn apply(&self) {
+        let imp = self.imp();
+        let selected: Vec<SnapshotConfig> = imp.proposals.borrow().iter()
+            .filter(|(check_button, _)| check_button.is_active())
+            .map(|(_, proposal)| proposal.clone())
+            .collect();
+        if selected.is_empty() {
+            imp.status_label.set_label("Select at least one subvolume, or skip to keep the defaults");
+            return;
+        }
+
+        imp.apply_button.set_sensitive(false);
+        imp.status_label.set_label("Saving…");
+        let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        thread::spawn(move || {
+            let _ = sender.send(config::save_snapshots(&selected));
+        });
+        receiver.attach(None, clone!(@weak self as assistant => @default-return Continue(false), move |result: Result<()>| {
+            match result {
+                Ok(()) => assistant.close(),
+                Err(e) => {
+                    let imp = assistant.imp();
+                    imp.status_label.set_label(&format!("{:#}", e));
+                    imp.apply_button.set_sensitive(true);
+                },
+            }
+            Continue(false)
+        }));
+    }
+}
@@ -0,0 +1,60 @@
+use adw::prelude::*;
+use gtk::subclass::prelude::*;
+use gtk::{glib, CompositeTemplate};
+use gtk::glib::subclass::InitializingObject;
+use std::cell::RefCell;
+
+use super::ConfigRow;
+use crate::config::LocalConfig;
+
+// Object holding the state
+#[derive(CompositeTemplate, Default)]
+#[template(resource = "/co/veand/fridge/SnapshotConfigEditor.ui")]
+pub struct SnapshotConfigEditor {
+    #[template_child]
+    pub list_box: TemplateChild<gtk::ListBox>,
+    #[template_child]
+    pub add_button: TemplateChild<gtk::Button>,
+    #[template_child]
+    pub save_button: TemplateChild<gtk::Button>,
+    /// Widgets editing each snapshot configuration, in the order they are shown
+    pub rows: RefCell<Vec<ConfigRow>>,
+    /// Settings locating the snapshot directories of configurations without their own
+    pub local: RefCell<LocalConfig>,
+    /// Dialog choosing a source, kept alive while it is shown
+    pub file_chooser: RefCell<Option<gtk::FileChooserNative>>,
+}
+
+// The central trait for subclassing a GObject
+#[glib::object_subclass]
+impl ObjectSubclass for SnapshotConfigEditor {
+    // `NAME` needs to match `class` attribute of template
+    const NAME: &'static str = "SnapshotConfigEditor";
+    type Type = super::SnapshotConfigEditor;
+    type ParentType = gtk::Box;
+
+    fn class_init(klass: &mut Self::Class) {
+        klass.bind_template();
+    }
+
+    fn instance_init(obj: &InitializingObject<Self>) {
+        obj.init_template();
+    }
+}
+
+// Trait shared by all GObjects
+impl ObjectImpl for SnapshotConfigEditor {
+    fn constructed(&self) {
+        // Call "constructed" on parent
+        self.parent_constructed();
+
+        let obj = self.obj();
+        obj.setup_callbacks();
+        obj.load();
+    }
+}
+
+// Trait shared by all widgets
+impl WidgetImpl for SnapshotConfigEditor {}
+
+impl BoxImpl for SnapshotConfigEditor {}
@@ -0,0 +1,311 @@
+mod imp;
+
+use std::path::Path;
+use std::thread;
+
+use adw::prelude::*;
+use anyhow::Result;
+use gtk::glib;
+use gtk::glib::{clone, Continue};
+use gtk::subclass::prelude::ObjectSubclassIsExt;
+
+use crate::config::{self, SnapshotConfig};
+use crate::fridge;
+
+/// Titles of the rows with the number of snapshots to keep of each tier, from hourly to yearly
+const TIER_TITLES: [&str; 5] = ["Hourly snapshots", "Daily snapshots", "Weekly snapshots", "Monthly snapshots", "Yearly snapshots"];
+
+/// Widgets editing one snapshot configuration
+pub struct ConfigRow {
+    expander: adw::ExpanderRow,
+    name_entry: gtk::Entry,
+    source_row: adw::ActionRow,
+    /// Path of the source subvolume, empty until one is chosen
+    source_label: gtk::Label,
+    snapshot_dir_entry: gtk::Entry,
+    recursive_switch: gtk::Switch,
+    /// Patterns of nested subvolumes, which are only set in the configuration file
+    include: Vec<String>,
+    exclude: Vec<String>,
+    /// Number of snapshots to keep of each tier, from hourly to yearly
+    count_spins: Vec<gtk::SpinButton>,
+    budget_entry: gtk::Entry,
+}
+
+impl ConfigRow {
+    fn config(&self) -> SnapshotConfig {
+        let counts: Vec<usize> = self.count_spins.iter().map(|spin| spin.value_as_int().max(0) as usize).collect();
+        let snapshot_dir = self.snapshot_dir_entry.text().trim().to_string();
+        let budget = self.budget_entry.text().trim().to_string();
+        SnapshotConfig {
+            name: self.name_entry.text().trim().to_string(),
+            path: self.source_label.label().to_string(),
+            hourly: counts[0],
+            daily: counts[1],
+            weekly: counts[2],
+            monthly: counts[3],
+            yearly: counts[4],
+            snapshot_dir: (!snapshot_dir.is_empty()).then_some(snapshot_dir),
+            recursive: self.recursive_switch.is_active(),
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            budget: (!budget.is_empty()).then_some(budget),
+        }
+    }
+}
+
+glib::wrapper! {
+    pub struct SnapshotConfigEditor(ObjectSubclass<imp::SnapshotConfigEditor>)
+        @extends gtk::Box, gtk::Widget,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::Orientable;
+}
+
+impl SnapshotConfigEditor {
+    fn setup_callbacks(&self) {
+        let imp = self.imp();
+        imp.add_button.connect_clicked(clone!(@weak self as editor => move |_| {
+            let expander = editor.add_row(&SnapshotConfig::new("", ""));
+            expander.set_expanded(true);
+        }));
+        imp.save_button.connect_clicked(clone!(@weak self as editor => move |_| {
+            editor.save();
+        }));
+    }
+
+    /// Shows the snapshot configurations as they are in the configuration file
+    pub fn load(&self) {
+        let imp = self.imp();
+        for row in imp.rows.take() {
+            imp.list_box.remove(&row.expander);
+        }
+        // Saving what couldn't be loaded would throw the configuration file away
+        let cfg = match config::load() {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                log::warn!("{:#}", e);
+                imp.save_button.set_sensitive(false);
+                self.toast(&format!("{:#}", e));
+                return;
+            },
+        };
+        imp.save_button.set_sensitive(true);
+        imp.local.replace(cfg.local.clone());
+        for snapshot_cfg in &cfg.snapshots {
+            self.add_row(snapshot_cfg);
+        }
+    }
+
+    fn add_row(&self, snapshot_cfg: &SnapshotConfig) -> adw::ExpanderRow {
+        let expander = adw::ExpanderRow::builder()
+            .title(&Self::title(&snapshot_cfg.name))
+            .subtitle(&glib::markup_escape_text(&snapshot_cfg.path))
+            .build();
+
+        let name_entry = gtk::Entry::builder()
+            .text(&snapshot_cfg.name)
+            .valign(gtk::Align::Center)
+            .build();
+        name_entry.connect_changed(clone!(@weak expander => move |entry| {
+            expander.set_title(&Self::title(&entry.text()));
+        }));
+        expander.add_row(&Self::row("Name", None, &name_entry));
+
+        let source_label = gtk::Label::new(Some(snapshot_cfg.path.as_str()));
+        let choose_button = gtk::Button::builder()
+            .label("Choose…")
+            .valign(gtk::Align::Center)
+            .build();
+        choose_button.connect_clicked(clone!(@weak self as editor, @weak expander => move |_| {
+            editor.choose_source(&expander);
+        }));
+        let source_row = adw::ActionRow::builder()
+            .title("Source")
+            .build();
+        source_row.add_suffix(&source_label);
+        source_row.add_suffix(&choose_button);
+        expander.add_row(&source_row);
+
+        let placeholder = match &self.imp().local.borrow().snapshot_root {
+            Some(path) => format!("{}/<name>", path.trim_end_matches('/')),
+            None => format!("<source>/{}", self.imp().local.borrow().suffix),
+        };
+        let snapshot_dir_entry = gtk::Entry::builder()
+            .text(snapshot_cfg.snapshot_dir.as_deref().unwrap_or_default())
+            .placeholder_text(&placeholder)
+            .valign(gtk::Align::Center)
+            .build();
+        expander.add_row(&Self::row("Snapshot directory", Some("Where the snapshots are kept"), &snapshot_dir_entry));
+
+        let recursive_switch = gtk::Switch::builder()
+            .active(snapshot_cfg.recursive)
+            .valign(gtk::Align::Center)
+            .build();
+        expander.add_row(&Self::row("Nested subvolumes", Some("Snapshot the subvolumes inside this one along with it"), &recursive_switch));
+
+        let mut count_spins = Vec::new();
+        for (title, (_, count)) in TIER_TITLES.iter().zip(snapshot_cfg.tiers()) {
+            let spin = gtk::SpinButton::with_range(0.0, 1000.0, 1.0);
+            spin.set_value(count as f64);
+            spin.set_valign(gtk::Align::Center);
+            expander.add_row(&Self::row(title, Some("Number to keep, or 0 to take none"), &spin));
+            count_spins.push(spin);
+        }
+        let budget_entry = gtk::Entry::builder()
+            .text(snapshot_cfg.budget.as_deref().unwrap_or_default())
+            .placeholder_text("No budget")
+            .valign(gtk::Align::Center)
+            .build();
+        expander.add_row(&Self::row("Space budget", Some("Space the snapshots may use, e.g. 50G, keeping at least the numbers above"), &budget_entry));
+
+        let remove_button = gtk::Button::builder()
+            .label("Remove")
+            .valign(gtk::Align::Center)
+            .build();
+        remove_button.add_css_class("destructive-action");
+        remove_button.connect_clicked(clone!(@weak self as editor, @weak expander => move |_| {
+            editor.remove_row(&expander);
+        }));
+        expander.add_row(&Self::row("Remove subvolume", Some("Existing snapshots are kept"), &remove_button));
+
+        let imp = self.imp();
+        imp.list_box.append(&expander);
+        imp.rows.borrow_mut().push(ConfigRow {
+            expander: expander.clone(),
+            name_entry,
+            source_row,
+            source_label,
+            snapshot_dir_entry,
+            recursive_switch,
+            include: snapshot_cfg.include.clone(),
+            exclude: snapshot_cfg.exclude.clone(),
+            count_spins,
+            budget_entry,
+        });
+
+        expander
+    }
+
+    fn row(title: &str, subtitle: Option<&str>, suffix: &impl IsA<gtk::Widget>) -> adw::ActionRow {
+        let row = adw::ActionRow::builder()
+            .title(title)
+            .build();
+        if let Some(subtitle) = subtitle {
+            row.set_subtitle(subtitle);
+        }
+        row.add_suffix(suffix);
+        row
+    }
+
+    fn title(name: &str) -> String {
+        if name.trim().is_empty() {
+            "New subvolume".to_string()
+        } else {
+            glib::markup_escape_text(name.trim()).to_string()
+        }
+    }
+
+    fn remove_row(&self, expander: &adw::ExpanderRow) {
+        let imp = self.imp();
+        imp.list_box.remove(expander);
+        imp.rows.borrow_mut().retain(|row| &row.expander != expander);
+    }
+
+    fn choose_source(&self, expander: &adw::ExpanderRow) {
+        let window = self.root().and_then(|root| root.downcast::<gtk::Window>().ok());
+        let dialog = gtk::FileChooserNative::new(
+            Some("Choose Subvolume"),
+            window.as_ref(),
+            gtk::FileChooserAction::SelectFolder,
+            Some("Choose"),
+            Some("Cancel"),
+        );
+        dialog.set_modal(true);
+        dialog.connect_response(clone!(@weak self as editor, @weak expander => move |dialog, response| {
+            if response == gtk::ResponseType::Accept {
+                if let Some(path) = dialog.file().and_then(|file| file.path()) {
+                    editor.set_source(&expander, &path.to_string_lossy());
+                }
+            }
+            editor.imp().file_chooser.replace(None);
+        }));
+        dialog.show();
+        self.imp().file_chooser.replace(Some(dialog));
+    }
+
+    /// Shows `path` as the source of the configuration in `expander`, then checks in the
+    /// background that it is a btrfs subvolume
+    fn set_source(&self, expander: &adw::ExpanderRow, path: &str) {
+        let rows = self.imp().rows.borrow();
+        let row = match rows.iter().find(|row| &row.expander == expander) {
+            Some(row) => row,
+            None => return,
+        };
+        row.source_label.set_label(path);
+        expander.set_subtitle(&glib::markup_escape_text(path));
+        if row.name_entry.text().trim().is_empty() {
+            row.name_entry.set_text(&Self::default_name(path));
+        }
+
+        row.source_row.set_subtitle("Checking…");
+        let source_row = row.source_row.clone();
+        let path = path.to_string();
+        let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        thread::spawn(move || {
+            let _ = sender.send(fridge::check_subvolume(&path, true));
+        });
+        receiver.attach(None, move |result: Result<()>| {
+            let status = match result {
+                Ok(()) => "Btrfs subvolume".to_string(),
+                Err(e) => format!("{:#}", e),
+            };
+            source_row.set_subtitle(&glib::markup_escape_text(&status));
+            Continue(false)
+        });
+    }
+
+    /// Names a snapshot configuration after its source, e.g. "home" for /home
+    fn default_name(path: &str) -> String {
+        match Path::new(path).file_name() {
+            Some(name) => name.to_string_lossy().replace('@', ""),
+            None => "root".to_string(),
+        }
+    }
+
+    /// Checks the snapshot configurations in the background and writes them to the
+    /// configuration file if they are fine
+    fn save(&self) {
+        let imp = self.imp();
+        let snapshots: Vec<SnapshotConfig> = imp.rows.borrow().iter().map(ConfigRow::config).collect();
+        imp.save_button.set_sensitive(false);
+
+        let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        thread::spawn(move || {
+            let _ = sender.send(Self::check(&snapshots).and_then(|_| config::save_snapshots(&snapshots)));
+        });
+        receiver.attach(None, clone!(@weak self as editor => @default-return Continue(false), move |result: Result<()>| {
+            editor.imp().save_button.set_sensitive(true);
+            match result {
+                Ok(()) => editor.toast("Saved subvolumes"),
+                Err(e) => editor.toast(&format!("{:#}", e)),
+            }
+            Continue(false)
+        }));
+    }
+
+    /// Checks that the snapshot configurations are valid and that every source is a btrfs
+    /// subvolume
+    fn check(snapshots: &[SnapshotConfig]) -> Result<()> {
+        config::validate_snapshots(snapshots)?;
+        for snapshot_cfg in snapshots {
+            fridge::check_subvolume(&snapshot_cfg.path, true)?;
+        }
+
+        Ok(())
+    }
+
+    fn toast(&self, message: &str) {
+        if let Some(window) = self.root().and_then(|root| root.downcast::<adw::PreferencesWindow>().ok()) {
+            window.add_toast(&adw::Toast::new(&glib::markup_escape_text(message)));
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use adw::prelude::*;
+use gtk::subclass::prelude::*;
+use gtk::{gio, glib, CompositeTemplate};
+use gtk::glib::subclass::InitializingObject;
+use once_cell::sync::OnceCell;
+use std::cell::Cell;
+
+// Object holding the state
+#[derive(CompositeTemplate, Default)]
+#[template(resource = "/co/veand/fridge/SnapshotList.ui")]
+pub struct SnapshotList {
+    #[template_child]
+    pub search_entry: TemplateChild<gtk::SearchEntry>,
+    #[template_child]
+    pub suffix_dropdown: TemplateChild<gtk::DropDown>,
+    #[template_child]
+    pub column_view: TemplateChild<gtk::ColumnView>,
+    #[template_child]
+    pub usage_label: TemplateChild<gtk::Label>,
+    pub store: OnceCell<gio::ListStore>,
+    pub filter: OnceCell<gtk::CustomFilter>,
+    pub selection: OnceCell<gtk::MultiSelection>,
+    /// Number of the latest refresh, whose updates are the only ones still applied
+    pub generation: Cell<u64>,
+}
+
+// The central trait for subclassing a GObject
+#[glib::object_subclass]
+impl ObjectSubclass for SnapshotList {
+    // `NAME` needs to match `class` attribute of template
+    const NAME: &'static str = "SnapshotList";
+    type Type = super::SnapshotList;
+    type ParentType = gtk::Box;
+
+    fn class_init(klass: &mut Self::Class) {
+        klass.bind_template();
+    }
+
+    fn instance_init(obj: &InitializingObject<Self>) {
+        obj.init_template();
+    }
+}
+
+// Trait shared by all GObjects
+impl ObjectImpl for SnapshotList {
+    fn constructed(&self) {
+        // Call "constructed" on parent
+        self.parent_constructed();
+
+        let obj = self.obj();
+        obj.setup_columns();
+        obj.setup_model();
+        obj.setup_callbacks();
+    }
+}
+
+// Trait shared by all widgets
+impl WidgetImpl for SnapshotList {}
+
+impl BoxImpl for SnapshotList {}
@@ -0,0 +1,344 @@
+mod imp;
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::thread;
+
+use adw::prelude::*;
+use chrono::Local;
+use gtk::{gio, glib};
+use gtk::glib::{clone, Continue};
+use gtk::subclass::prelude::ObjectSubclassIsExt;
+
+use crate::config;
+use crate::fridge::{list_snapshots, Snapshot, SnapshotRepositoryLocation};
+use crate::history::format_bytes;
+use crate::pins::PinStore;
+use crate::snapshot_object::{SnapshotData, SnapshotObject};
+use crate::usage::{self, Usage};
+
+/// Suffixes offered by the tier filter, in the order of its items after "All Tiers"
+const SUFFIXES: [&str; 6] = ["hourly", "daily", "weekly", "monthly", "yearly", "manual"];
+
+/// Details looked up in the background
+enum Update {
+    /// Local snapshots of every configured subvolume
+    Listed(Vec<SnapshotData>),
+    /// Space used by the snapshot with the given full name
+    Usage(String, Usage),
+    /// Remotes holding each snapshot, keyed by full name
+    Remotes(BTreeMap<String, Vec<String>>),
+}
+
+glib::wrapper! {
+    pub struct SnapshotList(ObjectSubclass<imp::SnapshotList>)
+        @extends gtk::Box, gtk::Widget,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::Orientable;
+}
+
+impl SnapshotList {
+    fn setup_columns(&self) {
+        self.add_column("Subvolume", |data| data.subvolume.clone(), |a, b| a.subvolume.cmp(&b.subvolume));
+        let date_column = self.add_column(
+            "Date",
+            |data| data.datetime.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string(),
+            |a, b| a.datetime.cmp(&b.datetime),
+        );
+        self.add_column("Tier", |data| data.suffix.clone(), |a, b| a.suffix.cmp(&b.suffix));
+        self.add_column(
+            "Exclusive",
+            |data| data.usage.map_or("…".to_string(), |usage| format_usage(usage.exclusive, usage.estimated)),
+            |a, b| a.usage.map(|usage| usage.exclusive).cmp(&b.usage.map(|usage| usage.exclusive)),
+        );
+        self.add_column(
+            "Referenced",
+            |data| data.usage.map_or("…".to_string(), |usage| format_usage(usage.referenced, usage.estimated)),
+            |a, b| a.usage.map(|usage| usage.referenced).cmp(&b.usage.map(|usage| usage.referenced)),
+        );
+        self.add_column(
+            "Pinned",
+            |data| if data.pinned { "Yes".to_string() } else { String::new() },
+            |a, b| a.pinned.cmp(&b.pinned),
+        );
+        self.add_column(
+            "On Remotes",
+            |data| match &data.remotes {
+                Some(remotes) if remotes.is_empty() => "None".to_string(),
+                Some(remotes) => remotes.join(", "),
+                None => "…".to_string(),
+            },
+            |a, b| a.remotes.cmp(&b.remotes),
+        );
+
+        self.imp().column_view.sort_by_column(Some(&date_column), gtk::SortType::Descending);
+    }
+
+    /// Appends a sortable column showing `text` of each snapshot
+    fn add_column(
+        &self,
+        title: &str,
+        text: impl Fn(&SnapshotData) -> String + 'static,
+        compare: impl Fn(&SnapshotData, &SnapshotData) -> Ordering + 'static,
+    ) -> gtk::ColumnViewColumn {
+        let factory = gtk::SignalListItemFactory::new();
+        factory.connect_setup(|_, list_item| {
+            let label = gtk::Label::builder().xalign(0.0).build();
+            list_item.set_child(Some(&label));
+        });
+        factory.connect_bind(move |_, list_item| {
+            let object = list_item.item()
+                .expect("The item has to exist.")
+                .downcast::<SnapshotObject>()
+                .expect("The item has to be a `SnapshotObject`.");
+            let label = list_item.child()
+                .expect("The child has to exist.")
+                .downcast::<gtk::Label>()
+                .expect("The child has to be a `Label`.");
+            label.set_label(&text(&object.info()));
+        });
+
+        let sorter = gtk::CustomSorter::new(move |a, b| {
+            let a = a.downcast_ref::<SnapshotObject>().expect("The item has to be a `SnapshotObject`.");
+            let b = b.downcast_ref::<SnapshotObject>().expect("The item has to be a `SnapshotObject`.");
+            compare(&a.info(), &b.info()).into()
+        });
+
+        let column = gtk::ColumnViewColumn::new(Some(title), Some(&factory));
+        column.set_sorter(Some(&sorter));
+        column.set_resizable(true);
+        self.imp().column_view.append_column(&column);
+        column
+    }
+
+    fn setup_model(&self) {
+        let imp = self.imp();
+        let store = gio::ListStore::new(SnapshotObject::static_type());
+        let filter = gtk::CustomFilter::new(clone!(@weak self as list => @default-return true, move |object| {
+            let object = object.downcast_ref::<SnapshotObject>().expect("The item has to be a `SnapshotObject`.");
+            list.matches(&object.info())
+        }));
+        let filter_model = gtk::FilterListModel::new(Some(&store), Some(&filter));
+        let sort_model = gtk::SortListModel::new(Some(&filter_model), imp.column_view.sorter().as_ref());
+        let selection = gtk::MultiSelection::new(Some(&sort_model));
+        imp.column_view.set_model(Some(&selection));
+
+        imp.store.set(store).expect("Could not set store.");
+        imp.filter.set(filter).expect("Could not set filter.");
+        imp.selection.set(selection).expect("Could not set selection.");
+    }
+
+    fn setup_callbacks(&self) {
+        let imp = self.imp();
+        imp.search_entry.connect_search_changed(clone!(@weak self as list => move |_| {
+            list.filter().changed(gtk::FilterChange::Different);
+        }));
+        imp.suffix_dropdown.connect_selected_notify(clone!(@weak self as list => move |_| {
+            list.filter().changed(gtk::FilterChange::Different);
+        }));
+    }
+
+    fn store(&self) -> &gio::ListStore {
+        self.imp().store.get().expect("Could not get store.")
+    }
+
+    fn filter(&self) -> &gtk::CustomFilter {
+        self.imp().filter.get().expect("Could not get filter.")
+    }
+
+    pub fn selection(&self) -> &gtk::MultiSelection {
+        self.imp().selection.get().expect("Could not get selection.")
+    }
+
+    fn matches(&self, data: &SnapshotData) -> bool {
+        let imp = self.imp();
+        let text = imp.search_entry.text();
+        let suffix = match imp.suffix_dropdown.selected() {
+            0 => None,
+            i => SUFFIXES.get(i as usize - 1),
+        };
+
+        data.full_name.contains(text.as_str()) && suffix.is_none_or(|suffix| data.suffix == *suffix)
+    }
+
+    /// Returns the selected snapshots
+    pub fn selected(&self) -> Vec<SnapshotData> {
+        let selection = self.selection();
+        (0..selection.n_items())
+            .filter(|i| selection.is_selected(*i))
+            .filter_map(|i| selection.item(i))
+            .filter_map(|object| object.downcast::<SnapshotObject>().ok())
+            .map(|object| object.info().clone())
+            .collect()
+    }
+
+    /// Lists the local snapshots of every configured subvolume, then measures the space they
+    /// use and looks them up on `remotes`, given with whether to use sudo on each, all in the
+    /// background
+    pub fn refresh(&self, remotes: &[(SnapshotRepositoryLocation, bool)]) {
+        // A refresh still running when another starts would otherwise overwrite newer updates
+        let generation = self.imp().generation.get() + 1;
+        self.imp().generation.set(generation);
+        let remotes = remotes.to_vec();
+        let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        thread::spawn(move || {
+            let cfg = match config::load() {
+                Ok(cfg) => cfg,
+                Err(e) => {
+                    log::warn!("Could not list snapshots: {:#}", e);
+                    return;
+                },
+            };
+            let listed = PinStore::load().and_then(|pins| {
+                let mut snapshots = Vec::new();
+                for snapshot_cfg in &cfg.snapshots {
+                    for snapshot in list_snapshots(&snapshot_cfg.name, &snapshot_cfg.snapshot_location(&cfg.local), true, 0)? {
+                        let pinned = pins.is_pinned(&snapshot.full_name);
+                        snapshots.push((SnapshotData::new(&snapshot, pinned), snapshot));
+                    }
+                }
+                Ok(snapshots)
+            });
+            let (data, snapshots): (Vec<SnapshotData>, Vec<Snapshot>) = match listed {
+                Ok(listed) => listed.into_iter().unzip(),
+                Err(e) => {
+                    log::warn!("Could not list snapshots: {}", e);
+                    return;
+                },
+            };
+            if sender.send(Update::Listed(data)).is_err() {
+                return;
+            }
+
+            let mut on_remotes: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            for (remote, sudo) in &remotes {
+                let remote_name = remote.host.clone().unwrap_or_else(|| remote.path.clone());
+                for snapshot_cfg in &cfg.snapshots {
+                    match list_snapshots(&snapshot_cfg.name, remote, *sudo, 0) {
+                        Ok(remote_snapshots) => {
+                            for snapshot in remote_snapshots {
+                                on_remotes.entry(snapshot.full_name).or_default().push(remote_name.clone());
+                            }
+                        },
+                        Err(e) => log::warn!("Could not list snapshots of {} on {}: {}", snapshot_cfg.name, remote, e),
+                    }
+                }
+            }
+            if sender.send(Update::Remotes(on_remotes)).is_err() {
+                return;
+            }
+
+            // Quota groups are looked up once per snapshot directory
+            let mut qgroups = HashMap::new();
+            for snapshot in snapshots {
+                if !qgroups.contains_key(&snapshot.path) {
+                    let dir_qgroups = usage::qgroups(&snapshot.path, true).unwrap_or_else(|e| {
+                        log::warn!("Could not look up quota groups at {}: {}", snapshot.path, e);
+                        None
+                    });
+                    qgroups.insert(snapshot.path.clone(), dir_qgroups);
+                }
+                match usage::measure(&snapshot, qgroups[&snapshot.path].as_ref(), true) {
+                    Ok(usage) => {
+                        if sender.send(Update::Usage(snapshot.full_name, usage)).is_err() {
+                            return;
+                        }
+                    },
+                    Err(e) => log::warn!("Could not measure snapshot {}: {}", snapshot.full_name, e),
+                }
+            }
+        });
+        receiver.attach(None, clone!(@weak self as list => @default-return Continue(false), move |update| {
+            // Dropping the receiver of a stale refresh also stops its thread at the next update
+            if list.imp().generation.get() != generation {
+                return Continue(false);
+            }
+            list.apply(update);
+            Continue(true)
+        }));
+    }
+
+    fn apply(&self, update: Update) {
+        match update {
+            Update::Listed(snapshots) => {
+                let store = self.store();
+                store.remove_all();
+                for data in snapshots {
+                    store.append(&SnapshotObject::new(data));
+                }
+                self.refresh_usage_label();
+            },
+            Update::Usage(full_name, usage) => {
+                self.update_where(
+                    |data| data.full_name == full_name,
+                    |data| data.usage = Some(usage),
+                );
+                self.refresh_usage_label();
+            },
+            Update::Remotes(on_remotes) => self.update_where(
+                |_| true,
+                |data| data.remotes = Some(on_remotes.get(&data.full_name).cloned().unwrap_or_default()),
+            ),
+        }
+    }
+
+    /// Shows the total exclusive size of the snapshots of each subvolume measured so far
+    fn refresh_usage_label(&self) {
+        let store = self.store();
+        let mut usages: BTreeMap<String, Vec<Usage>> = BTreeMap::new();
+        for i in 0..store.n_items() {
+            if let Some(object) = store.item(i).and_then(|object| object.downcast::<SnapshotObject>().ok()) {
+                let data = object.info();
+                if let Some(usage) = data.usage {
+                    usages.entry(data.subvolume.clone()).or_default().push(usage);
+                }
+            }
+        }
+
+        let totals: Vec<(String, u64, bool)> = usages.iter()
+            .map(|(subvolume, usages)| {
+                let (total, estimated) = usage::total_exclusive(usages);
+                (subvolume.clone(), total, estimated)
+            })
+            .collect();
+        let label = totals.iter()
+            .map(|(subvolume, total, estimated)| format!("{}: {}", subvolume, format_usage(*total, *estimated)))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let usage_label = &self.imp().usage_label;
+        usage_label.set_label(&label);
+        if totals.iter().any(|(_, _, estimated)| *estimated) {
+            usage_label.set_tooltip_text(Some(&format!("Exclusive size of the snapshots of each subvolume, estimated with btrfs filesystem du since quota groups are off. Run fridge usage --enable-qgroups for exact counts. {}.", usage::QGROUP_WARNING)));
+        } else {
+            usage_label.set_tooltip_text(Some("Exclusive size of the snapshots of each subvolume, counted by quota groups"));
+        }
+    }
+
+    /// Marks the snapshots with the given full names as pinned or not
+    pub fn set_pinned(&self, full_names: &[String], pinned: bool) {
+        self.update_where(|data| full_names.contains(&data.full_name), |data| data.pinned = pinned);
+    }
+
+    /// Applies `f` to every snapshot `predicate` selects and redraws their rows
+    fn update_where(&self, predicate: impl Fn(&SnapshotData) -> bool, f: impl Fn(&mut SnapshotData)) {
+        let store = self.store();
+        for i in 0..store.n_items() {
+            let object = match store.item(i).and_then(|object| object.downcast::<SnapshotObject>().ok()) {
+                Some(object) => object,
+                None => continue,
+            };
+            if predicate(&object.info()) {
+                object.update(&f);
+                store.items_changed(i, 1, 1);
+            }
+        }
+    }
+}
+
+/// `bytes` formatted for display, marked as approximate if `estimated`
+fn format_usage(bytes: u64, estimated: bool) -> String {
+    if estimated {
+        format!("~{}", format_bytes(bytes))
+    } else {
+        format_bytes(bytes)
+    }
+}
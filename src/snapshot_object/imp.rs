@@ -0,0 +1,21 @@
+use gtk::glib;
+use gtk::subclass::prelude::*;
+use std::cell::RefCell;
+
+use super::SnapshotData;
+
+// Object holding the state
+#[derive(Default)]
+pub struct SnapshotObject {
+    pub data: RefCell<SnapshotData>,
+}
+
+// The central trait for subclassing a GObject
+#[glib::object_subclass]
+impl ObjectSubclass for SnapshotObject {
+    const NAME: &'static str = "SnapshotObject";
+    type Type = super::SnapshotObject;
+}
+
+// Trait shared by all GObjects
+impl ObjectImpl for SnapshotObject {}
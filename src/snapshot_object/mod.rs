@@ -0,0 +1,62 @@
+mod imp;
+
+use std::cell::Ref;
+
+use chrono::{DateTime, Utc};
+use glib::Object;
+use gtk::glib;
+use gtk::subclass::prelude::ObjectSubclassIsExt;
+
+use crate::fridge::Snapshot;
+use crate::usage::Usage;
+
+glib::wrapper! {
+    pub struct SnapshotObject(ObjectSubclass<imp::SnapshotObject>);
+}
+
+/// A local snapshot as shown in the snapshot list
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SnapshotData {
+    pub subvolume: String,
+    pub full_name: String,
+    /// Directory holding the snapshot
+    pub path: String,
+    pub suffix: String,
+    pub datetime: DateTime<Utc>,
+    /// Space used by the snapshot, if measured yet
+    pub usage: Option<Usage>,
+    pub pinned: bool,
+    /// Remotes holding the snapshot, if looked up yet
+    pub remotes: Option<Vec<String>>,
+}
+
+impl SnapshotData {
+    pub fn new(snapshot: &Snapshot, pinned: bool) -> Self {
+        Self {
+            subvolume: snapshot.name.clone(),
+            full_name: snapshot.full_name.clone(),
+            path: snapshot.path.clone(),
+            suffix: snapshot.suffix.clone(),
+            datetime: snapshot.datetime,
+            usage: None,
+            pinned,
+            remotes: None,
+        }
+    }
+}
+
+impl SnapshotObject {
+    pub fn new(data: SnapshotData) -> Self {
+        let object: Self = Object::builder().build();
+        object.imp().data.replace(data);
+        object
+    }
+
+    pub fn info(&self) -> Ref<'_, SnapshotData> {
+        self.imp().data.borrow()
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut SnapshotData)) {
+        f(&mut self.imp().data.borrow_mut());
+    }
+}
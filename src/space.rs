@@ -0,0 +1,297 @@
+//! Free-space guards for local snapshots and remote receives
+//!
+//! A full filesystem is the usual way backups stop working: btrfs needs room for its own
+//! metadata, so a full root filesystem can't even delete snapshots easily, and a receive that
+//! runs out of space leaves a partial snapshot behind after sending gigabytes for nothing.
+//!
+//! Snapshots aren't taken below the free space configured in `[local]`, or make room first by
+//! deleting the oldest unpinned snapshots. Each transfer checks that the destination has room
+//! for the estimated size of the stream, plus the free space configured for the remote.
+
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+use std::str;
+
+use anyhow::{Result, anyhow, bail};
+use chrono::Utc;
+use log::{info, warn};
+
+use crate::fridge::{list_snapshots, parse_snapshot_name, SnapshotRepositoryLocation};
+use crate::groups;
+use crate::history::{self, format_bytes, Operation, Record};
+use crate::nested;
+use crate::pins::PinStore;
+use crate::ssh;
+use crate::usage;
+
+/// Amount of free space, absolute or relative to the size of the filesystem
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Threshold {
+    Bytes(u64),
+    Percent(u64),
+}
+
+impl Threshold {
+    /// Parses a size such as "512M", "10G" or "1T" (binary multiples), or a percentage of the
+    /// filesystem such as "5%"
+    pub fn parse(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if let Some(percent) = s.strip_suffix('%') {
+            return match percent.trim().parse() {
+                Ok(percent) if percent <= 100 => Ok(Self::Percent(percent)),
+                _ => bail!("Could not parse free space {:?}, expected a percentage from 0% to 100%", s),
+            };
+        }
+
+        let bytes = parse_size(s).map_err(|_| anyhow!("Could not parse free space {:?}, expected a size like 10G or a percentage like 5%", s))?;
+        Ok(Self::Bytes(bytes))
+    }
+
+    /// Number of bytes this is on a filesystem of `size` bytes
+    pub fn bytes(&self, size: u64) -> u64 {
+        match self {
+            Self::Bytes(bytes) => *bytes,
+            Self::Percent(percent) => size / 100 * percent,
+        }
+    }
+}
+
+impl fmt::Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bytes(bytes) => write!(f, "{}", format_bytes(*bytes)),
+            Self::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+/// Parses a size such as "512M", "10G" or "1T" (binary multiples) into bytes
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        Some('T') => (&s[..s.len() - 1], 1 << 40),
+        _ => (s, 1),
+    };
+    let value: u64 = digits.trim().parse().map_err(|_| anyhow!("Could not parse size {:?}, expected a number of bytes or a size like 50G", s))?;
+
+    value.checked_mul(multiplier).ok_or_else(|| anyhow!("Could not use size {:?}: it is too large", s))
+}
+
+/// What to do when a snapshot is due but the filesystem is low on space
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LowSpaceAction {
+    /// Skip the snapshot and report it as failed
+    #[default]
+    Refuse,
+    /// Delete the oldest unpinned snapshots until there is enough free space
+    Prune,
+}
+
+impl LowSpaceAction {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "refuse" => Ok(Self::Refuse),
+            "prune" => Ok(Self::Prune),
+            _ => bail!("Could not parse low space action {:?}, expected refuse or prune", s),
+        }
+    }
+}
+
+/// Free space to keep where snapshots are taken, and how
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpaceGuard {
+    pub min_free: Threshold,
+    pub action: LowSpaceAction,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FreeSpace {
+    /// Bytes available to new data
+    pub available: u64,
+    /// Size of the filesystem in bytes
+    pub size: u64,
+}
+
+/// Free space of the filesystem holding `location`, or the closest directory above it that
+/// exists if it is local
+pub fn free_space(location: &SnapshotRepositoryLocation, sudo: bool) -> Result<FreeSpace> {
+    let (program, args) = if location.is_remote() {
+        ssh::remote_command(location, sudo, &["df", "-B1", "--output=avail,size", &location.path])?
+    } else {
+        let path = Path::new(&location.path).ancestors()
+            .find(|path| path.exists())
+            .and_then(|path| path.to_str())
+            .unwrap_or("/")
+            .to_string();
+        let args = vec!["df".to_string(), "-B1".to_string(), "--output=avail,size".to_string(), path];
+        if sudo {
+            ("sudo", args)
+        } else {
+            ("df", args[1..].to_vec())
+        }
+    };
+    let output = Command::new(program)
+        .args(args)
+        .output()?;
+
+    if !output.status.success() {
+        bail!("Could not find the free space at {}: {}", location, str::from_utf8(&output.stderr).unwrap().trim());
+    }
+
+    parse_df(str::from_utf8(&output.stdout)?)
+}
+
+/// Makes sure the filesystems holding the snapshots of the members of the unit `name`, given
+/// by name and local repository, have the free space `guard` asks for before taking a snapshot
+///
+/// Depending on `guard.action`, fails or deletes the oldest unpinned group snapshots of the
+/// unit until there is enough. The newest one is always kept, since the next synchronization
+/// sends incrementally against it.
+pub fn guard_snapshot(name: &str, members: &[(String, SnapshotRepositoryLocation)], guard: &SpaceGuard, sudo: bool, verbose: i32) -> Result<()> {
+    for (_, location) in members {
+        loop {
+            let free = free_space(location, sudo)?;
+            let min_free = guard.min_free.bytes(free.size);
+            if free.available >= min_free {
+                break;
+            }
+            if guard.action == LowSpaceAction::Refuse {
+                bail!("Could not take a snapshot of {}: {} is free at {}, below the minimum of {}", name, format_bytes(free.available), location, guard.min_free);
+            }
+
+            let pins = PinStore::load()?;
+            let mut member_snapshots = Vec::new();
+            for (member, member_location) in members {
+                member_snapshots.push(list_snapshots(member, member_location, sudo, verbose)?);
+            }
+            let pinned: Vec<&str> = member_snapshots.iter()
+                .flatten()
+                .filter(|snapshot| pins.is_pinned(&snapshot.full_name))
+                .map(|snapshot| groups::stamp(&snapshot.full_name))
+                .collect();
+            let stamps: Vec<String> = groups::stamps(&member_snapshots).into_iter()
+                .filter(|stamp| !pinned.contains(&stamp.as_str()))
+                .collect();
+            let oldest = match stamps.split_last() {
+                Some((_, older)) if !older.is_empty() => older[0].clone(),
+                _ => bail!("Could not take a snapshot of {}: {} is free at {}, below the minimum of {}, and no more snapshots can be pruned", name, format_bytes(free.available), location, guard.min_free),
+            };
+
+            for (snapshots, (_, member_location)) in member_snapshots.iter().zip(members) {
+                for snapshot in snapshots.iter().filter(|snapshot| groups::stamp(&snapshot.full_name) == oldest) {
+                    nested::delete_members(snapshot, member_location, sudo, Operation::Prune, verbose)?;
+                    let started = Utc::now();
+                    let result = snapshot.delete(sudo, verbose);
+                    history::record(Record::new(Operation::Prune, started, &snapshot.name, Some(&snapshot.full_name), &member_location.path, None, &result));
+                    result?;
+                    warn!("Deleted snapshot {} to make room at {}", &snapshot.full_name, member_location);
+                }
+            }
+            // The space of deleted subvolumes is freed in the background
+            sync_subvolumes(&location.path, sudo)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Waits until the space of the subvolumes deleted from the filesystem holding `path` is freed
+fn sync_subvolumes(path: &str, sudo: bool) -> Result<()> {
+    let (program, args) = if sudo {
+        ("sudo", vec!["btrfs", "subvolume", "sync", path])
+    } else {
+        ("btrfs", vec!["subvolume", "sync", path])
+    };
+    let output = Command::new(program)
+        .args(args)
+        .output()?;
+
+    if !output.status.success() {
+        bail!("Could not wait for deleted snapshots at {}: {}", path, str::from_utf8(&output.stderr).unwrap().trim());
+    }
+
+    Ok(())
+}
+
+/// Estimates the size of the stream sending `snapshot` from the local directory `dir`,
+/// incrementally against `parent` if given, from the space they use
+///
+/// Without quota groups, incremental streams aren't estimated, since measuring both snapshots
+/// would take longer than many incremental sends.
+pub fn estimate_stream_size(dir: &str, snapshot: &str, parent: Option<&str>, sudo: bool) -> Result<Option<u64>> {
+    let qgroups = usage::qgroups(dir, sudo)?;
+    if parent.is_some() && qgroups.is_none() {
+        return Ok(None);
+    }
+
+    let snapshot_usage = usage::measure(&parse_snapshot_name(snapshot, dir)?, qgroups.as_ref(), sudo)?;
+    match parent {
+        Some(parent) => {
+            let parent_usage = usage::measure(&parse_snapshot_name(parent, dir)?, qgroups.as_ref(), sudo)?;
+            // Neither is what changed since the parent, but both are at most that
+            Ok(Some(snapshot_usage.exclusive.max(snapshot_usage.referenced.saturating_sub(parent_usage.referenced))))
+        },
+        None => Ok(Some(snapshot_usage.referenced)),
+    }
+}
+
+/// Checks that `location` has room for a stream of about `size` bytes while keeping the free
+/// space configured for it
+pub fn check_receive(location: &SnapshotRepositoryLocation, sudo: bool, snapshot: &str, size: u64) -> Result<()> {
+    let free = free_space(location, sudo)?;
+    let min_free = location.min_free.map_or(0, |min_free| min_free.bytes(free.size));
+    if free.available < size.saturating_add(min_free) {
+        match location.min_free {
+            Some(threshold) => bail!("Could not send snapshot {} to {}: it needs about {} and {} must stay free, but only {} is free", snapshot, location, format_bytes(size), threshold, format_bytes(free.available)),
+            None => bail!("Could not send snapshot {} to {}: it needs about {}, but only {} is free", snapshot, location, format_bytes(size), format_bytes(free.available)),
+        }
+    }
+    info!("{} is free at {}, enough for about {}", format_bytes(free.available), location, format_bytes(size));
+
+    Ok(())
+}
+
+/// Parses the output of `df -B1 --output=avail,size`
+fn parse_df(output: &str) -> Result<FreeSpace> {
+    let fields: Vec<&str> = output.lines()
+        .nth(1)
+        .map(|line| line.split_whitespace().collect())
+        .unwrap_or_default();
+    match (fields.first().map(|v| v.parse()), fields.get(1).map(|v| v.parse())) {
+        (Some(Ok(available)), Some(Ok(size))) => Ok(FreeSpace { available, size }),
+        _ => bail!("Could not parse output of df: {:?}", output),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_threshold() {
+        assert_eq!(Threshold::parse("10G").unwrap(), Threshold::Bytes(10 << 30));
+        assert_eq!(Threshold::parse("512m").unwrap(), Threshold::Bytes(512 << 20));
+        assert_eq!(Threshold::parse("5%").unwrap(), Threshold::Percent(5));
+        assert!(Threshold::parse("150%").is_err());
+        assert!(Threshold::parse("lots").is_err());
+        assert_eq!(parse_size("50G").unwrap(), 50 << 30);
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert!(parse_size("5%").is_err());
+        assert!(parse_size("99999999999T").is_err());
+        assert!(Threshold::parse("99999999999T").is_err());
+
+        assert_eq!(Threshold::Percent(5).bytes(1000), 50);
+        assert_eq!(Threshold::Bytes(1024).bytes(1000), 1024);
+    }
+
+    #[test]
+    fn test_parse_df() {
+        let output = "       Avail        1B-blocks\n 21474836480 107374182400\n";
+        assert_eq!(parse_df(output).unwrap(), FreeSpace { available: 21474836480, size: 107374182400 });
+        assert!(parse_df("").is_err());
+    }
+}
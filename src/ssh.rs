@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str;
+use std::sync::Mutex;
+
+use anyhow::{Result, bail};
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+
+use crate::fridge::SnapshotRepositoryLocation;
+
+/// SSH settings of a remote snapshot repository
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SshOpts {
+    /// Private key to authenticate with instead of the invoking user's default identities
+    pub identity_file: Option<String>,
+    /// Extra options passed to ssh as `-o <option>` (e.g. "Compression=yes")
+    pub options: Vec<String>,
+    /// Host key fingerprint as printed by `ssh-keygen -l` (e.g. "SHA256:...")
+    ///
+    /// When set, the remote is only trusted if it presents this key and the invoking user's
+    /// known_hosts file is ignored.
+    pub host_key_fingerprint: Option<String>,
+}
+
+/// How long a master connection outlives its last command, so that one left behind by a
+/// crashed run does not linger
+const CONTROL_PERSIST: &str = "10m";
+
+/// Number of open [`Session`]s of this process per remote, keyed by [`session_key`]
+static SESSIONS: Lazy<Mutex<HashMap<String, usize>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Directory holding the control sockets and pinned known_hosts files of this user
+fn runtime_dir() -> PathBuf {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir).join("fridge"),
+        _ => {
+            let uid = fs::metadata("/proc/self").map(|m| m.uid()).unwrap_or(0);
+            std::env::temp_dir().join(format!("fridge-{}", uid))
+        }
+    }
+}
+
+fn ensure_runtime_dir() -> Result<PathBuf> {
+    let dir = runtime_dir();
+    fs::create_dir_all(&dir)?;
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    Ok(dir)
+}
+
+fn known_hosts_path(dst: &SnapshotRepositoryLocation) -> PathBuf {
+    let host = dst.host.clone().unwrap_or_default();
+    runtime_dir().join(format!("known_hosts_{}_{}", host, dst.port.unwrap_or(22)))
+}
+
+/// Returns the `[user@]host` part of an ssh command line
+fn destination(dst: &SnapshotRepositoryLocation) -> Result<String> {
+    match (&dst.user, &dst.host) {
+        (Some(user), Some(host)) => Ok(format!("{}@{}", user, host)),
+        (None, Some(host)) => Ok(host.clone()),
+        _ => bail!("Could not sync remotely without host specified!"),
+    }
+}
+
+/// Returns the control socket of connections to a remote, which is private to this process
+/// so that closing a session never cuts off the commands of another one
+fn control_path() -> PathBuf {
+    runtime_dir().join(format!("%C-{}", std::process::id()))
+}
+
+/// Builds the ssh options shared by every connection to `dst`
+fn ssh_args(dst: &SnapshotRepositoryLocation, control_master: &str) -> Vec<String> {
+    let mut args = vec![
+        "-o".to_string(), format!("ControlMaster={}", control_master),
+        "-o".to_string(), format!("ControlPath={}", control_path().to_str().unwrap()),
+    ];
+    if let Some(port) = dst.port {
+        args.push("-p".to_string());
+        args.push(format!("{}", port));
+    }
+    if let Some(identity_file) = &dst.ssh.identity_file {
+        args.push("-i".to_string());
+        args.push(identity_file.clone());
+        args.push("-o".to_string());
+        args.push("IdentitiesOnly=yes".to_string());
+    }
+    if dst.ssh.host_key_fingerprint.is_some() {
+        args.push("-o".to_string());
+        args.push(format!("UserKnownHostsFile={}", known_hosts_path(dst).to_str().unwrap()));
+        args.push("-o".to_string());
+        args.push("StrictHostKeyChecking=yes".to_string());
+    }
+    for option in &dst.ssh.options {
+        args.push("-o".to_string());
+        args.push(option.clone());
+    }
+    args
+}
+
+/// Quotes an argument for the remote shell that ssh hands the command line to
+pub fn quote(arg: &str) -> String {
+    let is_safe = !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "_-./:@=+,%".contains(c));
+    if is_safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Builds the program and arguments that run `remote_args` on the host of `dst`
+///
+/// The command shares the control socket of an open [`Session`] to the same remote, if any.
+pub fn remote_command(dst: &SnapshotRepositoryLocation, sudo: bool, remote_args: &[&str]) -> Result<(&'static str, Vec<String>)> {
+    let mut args = ssh_args(dst, "auto");
+    args.push(destination(dst)?);
+    if sudo {
+        args.push("sudo".to_string());
+    }
+    args.extend(remote_args.iter().map(|arg| quote(arg)));
+    Ok(("ssh", args))
+}
+
+/// Computes the fingerprint of a known_hosts formatted key line with the same hash
+/// algorithm as `expected`
+fn key_fingerprint(key_line: &str, expected: &str) -> Result<String> {
+    let hash = if expected.starts_with("MD5:") { "md5" } else { "sha256" };
+    let mut child = Command::new("ssh-keygen")
+        .args(["-l", "-E", hash, "-f", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(key_line.as_bytes())?;
+        stdin.write_all(b"\n")?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!("Could not compute host key fingerprint: {}", str::from_utf8(&output.stderr).unwrap());
+    }
+
+    // Output looks like "256 SHA256:... host (ED25519)"
+    match str::from_utf8(&output.stdout)?.split_whitespace().nth(1) {
+        Some(fingerprint) => Ok(fingerprint.to_string()),
+        None => bail!("Could not parse output of ssh-keygen"),
+    }
+}
+
+/// Fetches the host keys of `dst` and stores the one matching the pinned fingerprint in a
+/// known_hosts file used exclusively for that remote
+pub fn pin_host_key(dst: &SnapshotRepositoryLocation) -> Result<()> {
+    let fingerprint = match &dst.ssh.host_key_fingerprint {
+        Some(fingerprint) => fingerprint,
+        None => return Ok(()),
+    };
+    let host = match &dst.host {
+        Some(host) => host,
+        None => bail!("Could not pin host key without host specified!"),
+    };
+
+    let output = Command::new("ssh-keyscan")
+        .args(["-p", &format!("{}", dst.port.unwrap_or(22)), host])
+        .output()?;
+    if !output.status.success() {
+        bail!("Could not fetch host keys of {}: {}", host, str::from_utf8(&output.stderr).unwrap());
+    }
+
+    let key_lines = str::from_utf8(&output.stdout)?;
+    for key_line in key_lines.lines().filter(|line| !line.trim().is_empty() && !line.starts_with('#')) {
+        if key_fingerprint(key_line, fingerprint)? == *fingerprint {
+            ensure_runtime_dir()?;
+            fs::write(known_hosts_path(dst), format!("{}\n", key_line))?;
+            debug!("Pinned host key of {}: {}", host, key_line);
+            return Ok(());
+        }
+    }
+
+    bail!("Host key of {} does not match the pinned fingerprint {}", host, fingerprint)
+}
+
+/// Identifies the remote a control socket connects to, like the `%C` of its path
+fn session_key(dst: &SnapshotRepositoryLocation) -> Result<String> {
+    Ok(format!("{}:{}", destination(dst)?, dst.port.unwrap_or(22)))
+}
+
+/// Counts a session opened to the remote of `key` and returns whether it is the first one
+fn acquire(sessions: &mut HashMap<String, usize>, key: &str) -> bool {
+    let count = sessions.entry(key.to_string()).or_insert(0);
+    *count += 1;
+    *count == 1
+}
+
+/// Counts a session closed to the remote of `key` and returns whether it was the last one
+fn release(sessions: &mut HashMap<String, usize>, key: &str) -> bool {
+    match sessions.get_mut(key) {
+        Some(count) if *count > 1 => {
+            *count -= 1;
+            false
+        }
+        Some(_) => {
+            sessions.remove(key);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Multiplexed SSH connection to a remote, kept open for the duration of a run
+///
+/// Commands built by [`remote_command`] reuse its control socket, so a run only negotiates
+/// and authenticates once per remote. Sessions to the same remote share the connection,
+/// which is closed when the last of them is dropped.
+pub struct Session {
+    dst: Option<SnapshotRepositoryLocation>,
+}
+
+impl Session {
+    /// Opens a master connection to `dst`, or does nothing if `dst` is local or already has
+    /// an open session
+    pub fn open(dst: &SnapshotRepositoryLocation) -> Result<Self> {
+        if !dst.is_remote() {
+            return Ok(Self { dst: None });
+        }
+
+        // Held while connecting, so that a concurrent session waits for the master to be up
+        let key = session_key(dst)?;
+        let mut sessions = SESSIONS.lock().unwrap();
+        if !acquire(&mut sessions, &key) {
+            return Ok(Self { dst: Some(dst.clone()) });
+        }
+        if let Err(e) = Self::connect(dst) {
+            release(&mut sessions, &key);
+            return Err(e);
+        }
+
+        Ok(Self { dst: Some(dst.clone()) })
+    }
+
+    fn connect(dst: &SnapshotRepositoryLocation) -> Result<()> {
+        let dir = ensure_runtime_dir()?;
+        pin_host_key(dst)?;
+
+        let mut args = ssh_args(dst, "yes");
+        args.extend(["-o".to_string(), format!("ControlPersist={}", CONTROL_PERSIST), "-N".to_string(), "-f".to_string()]);
+        args.push(destination(dst)?);
+        debug!("ssh {}", args.join(" "));
+
+        // The backgrounded master may keep its output open, so it must not be a pipe we wait on
+        let log_path = dir.join(format!("ssh-{}.log", std::process::id()));
+        let status = Command::new("ssh")
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(fs::File::create(&log_path)?)
+            .status()?;
+        let log = fs::read_to_string(&log_path).unwrap_or_default();
+        let _ = fs::remove_file(&log_path);
+        if !status.success() {
+            bail!("Could not connect to {}: {}", destination(dst)?, log.trim());
+        }
+        info!("Opened SSH connection to {}", destination(dst)?);
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(dst) = &self.dst {
+            let mut sessions = SESSIONS.lock().unwrap();
+            let is_last = matches!(session_key(dst), Ok(key) if release(&mut sessions, &key));
+            if !is_last {
+                return;
+            }
+
+            let mut args = ssh_args(dst, "no");
+            args.extend(["-O".to_string(), "exit".to_string()]);
+            if let Ok(destination) = destination(dst) {
+                args.push(destination);
+            }
+            if let Err(e) = Command::new("ssh").args(args).output() {
+                warn!("Could not close SSH connection: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("/home/.snapshots"), "/home/.snapshots");
+        assert_eq!(quote("root@2000-01-02_03:04:05_daily"), "root@2000-01-02_03:04:05_daily");
+        assert_eq!(quote("/run/media/My Disk"), "'/run/media/My Disk'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+        assert_eq!(quote(""), "''");
+    }
+
+    #[test]
+    fn test_remote_command() {
+        let mut dst = SnapshotRepositoryLocation {
+            user: Some("li".to_string()),
+            host: Some("192.168.0.2".to_string()),
+            port: Some(2222),
+            path: "/backup".to_string(),
+            ssh: SshOpts {
+                identity_file: Some("/root/.ssh/fridge".to_string()),
+                options: vec!["Compression=yes".to_string()],
+                host_key_fingerprint: None,
+            },
+            ..Default::default()
+        };
+
+        let (program, args) = remote_command(&dst, true, &["btrfs", "receive", &dst.path]).unwrap();
+        assert_eq!(program, "ssh");
+        assert_eq!(&args[0..2], &["-o", "ControlMaster=auto"]);
+        assert_eq!(args[3], format!("ControlPath={}/%C-{}", runtime_dir().to_str().unwrap(), std::process::id()));
+        assert_eq!(&args[4..], &["-p", "2222", "-i", "/root/.ssh/fridge", "-o", "IdentitiesOnly=yes", "-o", "Compression=yes", "li@192.168.0.2", "sudo", "btrfs", "receive", "/backup"]);
+        assert!(!args.iter().any(|arg| arg.starts_with("UserKnownHostsFile")));
+
+        dst.ssh.host_key_fingerprint = Some("SHA256:abc".to_string());
+        let (_, args) = remote_command(&dst, false, &["true"]).unwrap();
+        assert!(args.contains(&format!("UserKnownHostsFile={}", known_hosts_path(&dst).to_str().unwrap())));
+        assert!(args.contains(&"StrictHostKeyChecking=yes".to_string()));
+        assert_eq!(args.last().unwrap(), "true");
+
+        dst.host = None;
+        assert!(remote_command(&dst, false, &["true"]).is_err());
+    }
+
+    #[test]
+    fn test_acquire_and_release() {
+        let mut sessions = HashMap::new();
+        assert!(acquire(&mut sessions, "li@192.168.0.2:2222"));
+        assert!(!acquire(&mut sessions, "li@192.168.0.2:2222"));
+        assert!(acquire(&mut sessions, "192.168.0.3:22"));
+
+        // The connection stays open until the last session to its remote closes
+        assert!(!release(&mut sessions, "li@192.168.0.2:2222"));
+        assert!(release(&mut sessions, "li@192.168.0.2:2222"));
+        assert!(release(&mut sessions, "192.168.0.3:22"));
+        assert!(!release(&mut sessions, "192.168.0.3:22"));
+        assert!(sessions.is_empty());
+    }
+}
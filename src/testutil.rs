@@ -0,0 +1,32 @@
+//! Helpers shared by the tests of several modules
+
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// Empty directory for a test, removed along with everything in it once dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates the directory of the test `name`, unique to this process
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("fridge-{}-test-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,263 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow, bail};
+use chrono::{Local, NaiveTime};
+
+/// Time of day window during which a different bandwidth limit applies
+#[derive(Clone, Debug, PartialEq)]
+pub struct BandwidthWindow {
+    pub from: NaiveTime,
+    pub to: NaiveTime,
+    /// Bytes per second, or `None` for unlimited
+    pub rate: Option<u64>,
+}
+
+impl BandwidthWindow {
+    /// Whether `time` falls into the window, which may wrap around midnight (e.g. 22:00-06:00)
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            self.from <= time || time < self.to
+        }
+    }
+}
+
+/// Bandwidth cap of a destination
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BandwidthLimit {
+    /// Bytes per second outside of any window, or `None` for unlimited
+    pub rate: Option<u64>,
+    /// Windows overriding `rate`, the first matching one wins
+    pub schedule: Vec<BandwidthWindow>,
+}
+
+impl BandwidthLimit {
+    pub fn rate_at(&self, time: NaiveTime) -> Option<u64> {
+        match self.schedule.iter().find(|window| window.contains(time)) {
+            Some(window) => window.rate,
+            None => self.rate,
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.rate.is_none() && self.schedule.iter().all(|window| window.rate.is_none())
+    }
+}
+
+/// Parses a rate such as "500K", "10M" or "1G" (bytes per second, binary multiples)
+///
+/// "0", "none" and "unlimited" mean no limit.
+pub fn parse_rate(s: &str) -> Result<Option<u64>> {
+    let s = s.trim();
+    if s.is_empty() || s == "0" || s.eq_ignore_ascii_case("none") || s.eq_ignore_ascii_case("unlimited") {
+        return Ok(None);
+    }
+
+    let (digits, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    let value: u64 = digits.trim().parse().map_err(|_| anyhow!("Could not parse rate {:?}", s))?;
+
+    value.checked_mul(multiplier).map(Some).ok_or_else(|| anyhow!("Could not use rate {:?}: it is too large", s))
+}
+
+/// Parses a time of day such as "08:00"
+pub fn parse_time_of_day(s: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(s.trim(), "%H:%M").map_err(|_| anyhow!("Could not parse time of day {:?}", s))
+}
+
+/// Paces writes so that they don't exceed a bandwidth limit on average
+pub struct Throttle {
+    limit: BandwidthLimit,
+    rate: Option<u64>,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    pub fn new(limit: BandwidthLimit) -> Self {
+        Self {
+            limit,
+            rate: None,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Accounts for `n` bytes that were just written, sleeping as long as needed to stay
+    /// within the limit that currently applies
+    pub fn consume(&mut self, n: usize) {
+        if self.limit.is_unlimited() {
+            return;
+        }
+
+        // Restart accounting when the schedule switches to another rate
+        let rate = self.limit.rate_at(Local::now().time());
+        if rate != self.rate {
+            self.rate = rate;
+            self.started = Instant::now();
+            self.bytes = 0;
+        }
+
+        self.bytes += n as u64;
+        if let Some(rate) = rate {
+            thread::sleep(delay(self.bytes, rate, self.started.elapsed()));
+        }
+    }
+}
+
+/// Time left to wait after writing `bytes` in `elapsed` to average `rate` bytes per second
+fn delay(bytes: u64, rate: u64, elapsed: Duration) -> Duration {
+    let expected = Duration::from_secs_f64(bytes as f64 / rate as f64);
+    expected.saturating_sub(elapsed)
+}
+
+/// Scheduling class passed to ionice
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IoClass {
+    Realtime(u8),
+    BestEffort(u8),
+    Idle,
+}
+
+impl IoClass {
+    /// Parses "idle", "best-effort", "best-effort:7", "realtime" or "realtime:0"
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut tokens = s.trim().splitn(2, ':');
+        let class = tokens.next().unwrap_or_default();
+        let level = match tokens.next() {
+            Some(level) => match level.parse::<u8>() {
+                Ok(level) if level <= 7 => Some(level),
+                _ => bail!("Could not parse I/O priority level {:?}", level),
+            },
+            None => None,
+        };
+
+        match (class, level) {
+            ("idle", None) => Ok(IoClass::Idle),
+            ("best-effort", level) => Ok(IoClass::BestEffort(level.unwrap_or(4))),
+            ("realtime", level) => Ok(IoClass::Realtime(level.unwrap_or(4))),
+            _ => bail!("Could not parse I/O priority {:?}", s),
+        }
+    }
+}
+
+/// CPU and I/O priority of the processes reading and writing snapshot streams
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Priority {
+    /// Niceness between -20 and 19
+    pub nice: Option<i32>,
+    pub io_class: Option<IoClass>,
+}
+
+impl Priority {
+    /// Prefixes a command line with the ionice and nice invocations for this priority
+    ///
+    /// The prefix goes after sudo so that it applies to the privileged process itself.
+    pub fn wrap(&self, program: &'static str, args: Vec<String>) -> (&'static str, Vec<String>) {
+        if self.nice.is_none() && self.io_class.is_none() {
+            return (program, args);
+        }
+
+        let mut command = Vec::new();
+        match self.io_class {
+            Some(IoClass::Realtime(level)) => command.extend(["ionice".to_string(), "-c1".to_string(), format!("-n{}", level)]),
+            Some(IoClass::BestEffort(level)) => command.extend(["ionice".to_string(), "-c2".to_string(), format!("-n{}", level)]),
+            Some(IoClass::Idle) => command.extend(["ionice".to_string(), "-c3".to_string()]),
+            None => (),
+        }
+        if let Some(nice) = self.nice {
+            command.extend(["nice".to_string(), "-n".to_string(), format!("{}", nice)]);
+        }
+
+        if program == "sudo" {
+            command.extend(args);
+            ("sudo", command)
+        } else {
+            let wrapper = if self.io_class.is_some() { "ionice" } else { "nice" };
+            command.remove(0);
+            command.push(program.to_string());
+            command.extend(args);
+            (wrapper, command)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("1024").unwrap(), Some(1024));
+        assert_eq!(parse_rate("500K").unwrap(), Some(500 * 1024));
+        assert_eq!(parse_rate("10m").unwrap(), Some(10 * 1024 * 1024));
+        assert_eq!(parse_rate("1G").unwrap(), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_rate("0").unwrap(), None);
+        assert_eq!(parse_rate("unlimited").unwrap(), None);
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("99999999999G").is_err());
+    }
+
+    #[test]
+    fn test_rate_at() {
+        let limit = BandwidthLimit {
+            rate: Some(1000),
+            schedule: vec![
+                BandwidthWindow {
+                    from: parse_time_of_day("22:00").unwrap(),
+                    to: parse_time_of_day("06:00").unwrap(),
+                    rate: None,
+                },
+                BandwidthWindow {
+                    from: parse_time_of_day("12:00").unwrap(),
+                    to: parse_time_of_day("13:00").unwrap(),
+                    rate: Some(5000),
+                },
+            ],
+        };
+        assert_eq!(limit.rate_at(parse_time_of_day("23:30").unwrap()), None);
+        assert_eq!(limit.rate_at(parse_time_of_day("05:59").unwrap()), None);
+        assert_eq!(limit.rate_at(parse_time_of_day("06:00").unwrap()), Some(1000));
+        assert_eq!(limit.rate_at(parse_time_of_day("12:30").unwrap()), Some(5000));
+        assert_eq!(limit.rate_at(parse_time_of_day("18:00").unwrap()), Some(1000));
+        assert!(!limit.is_unlimited());
+        assert!(BandwidthLimit::default().is_unlimited());
+    }
+
+    #[test]
+    fn test_delay() {
+        assert_eq!(delay(1000, 1000, Duration::from_millis(250)), Duration::from_millis(750));
+        assert_eq!(delay(1000, 1000, Duration::from_secs(2)), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_priority_wrap() {
+        let priority = Priority {
+            nice: Some(10),
+            io_class: Some(IoClass::parse("idle").unwrap()),
+        };
+        let (program, args) = priority.wrap("sudo", vec!["btrfs".to_string(), "send".to_string(), "/a".to_string()]);
+        assert_eq!(program, "sudo");
+        assert_eq!(args, ["ionice", "-c3", "nice", "-n", "10", "btrfs", "send", "/a"]);
+
+        let priority = Priority {
+            nice: Some(5),
+            io_class: None,
+        };
+        let (program, args) = priority.wrap("btrfs", vec!["receive".to_string(), "/b".to_string()]);
+        assert_eq!(program, "nice");
+        assert_eq!(args, ["-n", "5", "btrfs", "receive", "/b"]);
+
+        assert_eq!(IoClass::parse("best-effort:7").unwrap(), IoClass::BestEffort(7));
+        assert!(IoClass::parse("best-effort:9").is_err());
+        assert!(IoClass::parse("idle:3").is_err());
+    }
+}
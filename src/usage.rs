@@ -0,0 +1,268 @@
+//! Disk usage of snapshots
+//!
+//! With quota groups (qgroups) enabled on a filesystem, btrfs counts the bytes each subvolume
+//! refers to and the ones only it refers to, which deleting it would free. Without them, both
+//! are estimated with `btrfs filesystem du`, which reads the metadata of every file of the
+//! snapshot and is slow on large ones.
+//!
+//! Quota groups are only enabled on request, since keeping count costs the whole filesystem.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::{Command, Output};
+use std::str;
+
+use anyhow::{Result, anyhow, bail};
+
+use crate::fridge::{Snapshot, SnapshotRepositoryLocation};
+use crate::nested;
+use crate::ssh;
+
+/// What enabling quota groups costs, shown wherever they can be enabled
+pub const QGROUP_WARNING: &str = "Quota groups keep count of the space of every subvolume of the filesystem, which slows down deleting snapshots, balancing and heavy writes, noticeably so with many snapshots";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Bytes the snapshot refers to, shared or not
+    pub referenced: u64,
+    /// Bytes only the snapshot refers to, i.e. that deleting it would free
+    pub exclusive: u64,
+    /// Whether it was estimated with `btrfs filesystem du` rather than counted by quota groups
+    pub estimated: bool,
+}
+
+/// Usage of every subvolume of the filesystem holding `path` by subvolume ID, or `None` if
+/// quota groups are not enabled on it
+pub fn qgroups(path: &str, sudo: bool) -> Result<Option<HashMap<u64, Usage>>> {
+    let (program, args) = if sudo {
+        ("sudo", vec!["btrfs", "qgroup", "show", "--raw", path])
+    } else {
+        ("btrfs", vec!["qgroup", "show", "--raw", path])
+    };
+    let output = Command::new(program)
+        .args(args)
+        .output()?;
+
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or_default();
+        if stderr.contains("not enabled") {
+            return Ok(None);
+        }
+        bail!("Could not list quota groups at {}: {}", path, stderr.trim());
+    }
+
+    Ok(Some(parse_qgroup_show(str::from_utf8(&output.stdout)?)))
+}
+
+/// Enables quota groups on the filesystem holding `path`, see [`QGROUP_WARNING`]
+pub fn enable_qgroups(path: &str, sudo: bool) -> Result<()> {
+    let (program, args) = if sudo {
+        ("sudo", vec!["btrfs", "quota", "enable", path])
+    } else {
+        ("btrfs", vec!["quota", "enable", path])
+    };
+    let output = Command::new(program)
+        .args(args)
+        .output()?;
+
+    if !output.status.success() {
+        bail!("Could not enable quota groups at {}: {}", path, str::from_utf8(&output.stderr).unwrap().trim());
+    }
+
+    Ok(())
+}
+
+/// Usage of `snapshot`, from `qgroups` as returned by [`qgroups`] for its directory, or
+/// estimated if quota groups are off
+pub fn measure(snapshot: &Snapshot, qgroups: Option<&HashMap<u64, Usage>>, sudo: bool) -> Result<Usage> {
+    let path = Path::new(&snapshot.path).join(&snapshot.full_name).to_str().unwrap().to_string();
+    match qgroups {
+        Some(qgroups) => {
+            let id = nested::subvolume_id(&path, sudo)?;
+            // A quota group appears once the quota rescan started when enabling them got to it
+            qgroups.get(&id).copied().ok_or_else(|| anyhow!("Could not find the quota group of snapshot at {}", path))
+        },
+        None => estimate(&path, sudo),
+    }
+}
+
+/// Estimates the usage of the snapshot at `path` with `btrfs filesystem du`
+fn estimate(path: &str, sudo: bool) -> Result<Usage> {
+    let (program, args) = if sudo {
+        ("sudo", vec!["btrfs", "filesystem", "du", "-s", "--raw", path])
+    } else {
+        ("btrfs", vec!["filesystem", "du", "-s", "--raw", path])
+    };
+    let output = Command::new(program)
+        .args(args)
+        .output()?;
+
+    if !output.status.success() {
+        bail!("Could not measure snapshot at {}: {}", path, str::from_utf8(&output.stderr).unwrap());
+    }
+
+    parse_filesystem_du(str::from_utf8(&output.stdout)?)
+}
+
+/// Exclusive sizes of `snapshots` by name, counted by the quota groups of the filesystem
+/// holding `location`, local or remote, each with the sizes of its nested member snapshots
+///
+/// Used to keep snapshots within a space budget at every pruning, where estimating each one
+/// would take far too long, so quota groups have to be enabled. Snapshots the quota rescan
+/// hasn't got to yet count as empty.
+pub fn exclusive_sizes(location: &SnapshotRepositoryLocation, snapshots: &[Snapshot], sudo: bool) -> Result<HashMap<String, u64>> {
+    let output = btrfs(location, sudo, &["qgroup", "show", "--raw", &location.path])?;
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or_default();
+        if stderr.contains("not enabled") {
+            bail!("Could not keep the snapshots at {} within their budget: quota groups are not enabled, see fridge usage --enable-qgroups", location);
+        }
+        bail!("Could not list quota groups at {}: {}", location, stderr.trim());
+    }
+    let qgroups = parse_qgroup_show(str::from_utf8(&output.stdout)?);
+
+    let output = btrfs(location, sudo, &["subvolume", "list", "-o", &location.path])?;
+    if !output.status.success() {
+        bail!("Could not list the subvolumes at {}: {}", location, str::from_utf8(&output.stderr).unwrap().trim());
+    }
+    let ids = parse_subvolume_list(str::from_utf8(&output.stdout)?);
+    let size = |full_name: &str| ids.get(full_name)
+        .and_then(|id| qgroups.get(id))
+        .map_or(0, |usage| usage.exclusive);
+
+    let mut member_names: HashMap<&str, Vec<String>> = HashMap::new();
+    let mut sizes = HashMap::new();
+    for snapshot in snapshots {
+        if !member_names.contains_key(snapshot.name.as_str()) {
+            member_names.insert(&snapshot.name, nested::member_names(&snapshot.name, location, sudo, 0)?);
+        }
+        let stamp = snapshot.full_name.split_once('@').map_or("", |(_, stamp)| stamp);
+        let members: u64 = member_names[snapshot.name.as_str()].iter()
+            .map(|member| size(&format!("{}@{}", member, stamp)))
+            .sum();
+        sizes.insert(snapshot.full_name.clone(), size(&snapshot.full_name) + members);
+    }
+
+    Ok(sizes)
+}
+
+/// Runs btrfs with `args` where `location` is, over SSH if it is remote
+fn btrfs(location: &SnapshotRepositoryLocation, sudo: bool, args: &[&str]) -> Result<Output> {
+    let (program, args) = if location.is_remote() {
+        let mut remote_args = vec!["btrfs"];
+        remote_args.extend(args);
+        ssh::remote_command(location, sudo, &remote_args)?
+    } else if sudo {
+        ("sudo", std::iter::once("btrfs").chain(args.iter().copied()).map(|arg| arg.to_string()).collect())
+    } else {
+        ("btrfs", args.iter().map(|arg| arg.to_string()).collect())
+    };
+
+    Ok(Command::new(program)
+        .args(args)
+        .output()?)
+}
+
+/// Total exclusive size of `usages`, and whether any of them is estimated
+///
+/// Data shared by several of the snapshots but nothing else isn't counted, so deleting all of
+/// them may free more.
+pub fn total_exclusive<'a>(usages: impl IntoIterator<Item = &'a Usage>) -> (u64, bool) {
+    usages.into_iter().fold((0, false), |(total, estimated), usage| (total + usage.exclusive, estimated || usage.estimated))
+}
+
+/// Parses the usage of the subvolumes, i.e. the quota groups of level 0, out of the output of
+/// `btrfs qgroup show --raw`
+fn parse_qgroup_show(output: &str) -> HashMap<u64, Usage> {
+    let mut qgroups = HashMap::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let id = match fields.first().and_then(|qgroup| qgroup.strip_prefix("0/")).and_then(|id| id.parse().ok()) {
+            Some(id) => id,
+            None => continue,
+        };
+        if let (Some(Ok(referenced)), Some(Ok(exclusive))) = (fields.get(1).map(|v| v.parse()), fields.get(2).map(|v| v.parse())) {
+            qgroups.insert(id, Usage {
+                referenced,
+                exclusive,
+                estimated: false,
+            });
+        }
+    }
+
+    qgroups
+}
+
+/// Parses the IDs of subvolumes by name, i.e. the last component of their path, out of the
+/// output of `btrfs subvolume list`
+fn parse_subvolume_list(output: &str) -> HashMap<String, u64> {
+    output.lines()
+        .filter_map(|line| {
+            let (fields, path) = line.split_once(" path ")?;
+            let id = fields.strip_prefix("ID ")?.split_whitespace().next()?.parse().ok()?;
+            let name = path.trim().rsplit('/').next()?;
+            Some((name.to_string(), id))
+        })
+        .collect()
+}
+
+/// Parses the total and exclusive sizes out of the output of `btrfs filesystem du -s --raw`
+fn parse_filesystem_du(output: &str) -> Result<Usage> {
+    let fields: Vec<&str> = output.lines()
+        .nth(1)
+        .map(|line| line.split_whitespace().collect())
+        .unwrap_or_default();
+    match (fields.first().map(|v| v.parse()), fields.get(1).map(|v| v.parse())) {
+        (Some(Ok(referenced)), Some(Ok(exclusive))) => Ok(Usage {
+            referenced,
+            exclusive,
+            estimated: true,
+        }),
+        _ => bail!("Could not parse output of btrfs filesystem du: {:?}", output),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filesystem_du() {
+        let output = "     Total   Exclusive  Set shared  Filename\n 123456789     16384   123440000  /.snapshots/root@2023-01-02_03:04:05_daily\n";
+        assert_eq!(parse_filesystem_du(output).unwrap(), Usage { referenced: 123456789, exclusive: 16384, estimated: true });
+        assert!(parse_filesystem_du("").is_err());
+    }
+
+    #[test]
+    fn test_parse_qgroup_show() {
+        let output = "qgroupid         rfer         excl \n--------         ----         ---- \n0/5             16384        16384 \n0/256      1234567890    123456789 \n1/100      1234567890            0 \n";
+        let qgroups = parse_qgroup_show(output);
+        assert_eq!(qgroups.len(), 2);
+        assert_eq!(qgroups[&256], Usage { referenced: 1234567890, exclusive: 123456789, estimated: false });
+
+        // Newer versions add the path of each subvolume
+        let output = "Qgroupid    Referenced    Exclusive   Path \n--------    ----------    ---------   ---- \n0/5              16384        16384   <toplevel>\n0/261         52428800      1048576   .snapshots/root@2023-01-02_03:04:05_daily\n";
+        assert_eq!(parse_qgroup_show(output)[&261].exclusive, 1048576);
+    }
+
+    #[test]
+    fn test_parse_subvolume_list() {
+        let output = "ID 261 gen 1204 top level 5 path .snapshots/root@2023-01-02_03:04:05_daily\nID 262 gen 1205 top level 5 path .snapshots/root+home@2023-01-02_03:04:05_daily\nID 270 gen 1300 top level 256 path var/lib/machines\n";
+        let ids = parse_subvolume_list(output);
+        assert_eq!(ids.len(), 3);
+        assert_eq!(ids["root@2023-01-02_03:04:05_daily"], 261);
+        assert_eq!(ids["root+home@2023-01-02_03:04:05_daily"], 262);
+        assert_eq!(ids["machines"], 270);
+        assert!(parse_subvolume_list("").is_empty());
+    }
+
+    #[test]
+    fn test_total_exclusive() {
+        let usages = [
+            Usage { referenced: 100, exclusive: 10, estimated: false },
+            Usage { referenced: 200, exclusive: 20, estimated: true },
+        ];
+        assert_eq!(total_exclusive(&usages), (30, true));
+        assert_eq!(total_exclusive(&usages[..1]), (10, false));
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+use std::str;
+
+use anyhow::{Result, anyhow, bail};
+use log::{debug, info};
+
+use crate::fridge::{list_snapshots, SnapshotRepositoryLocation};
+use crate::ssh;
+
+/// Number of files hashed by a sampled verification unless configured otherwise
+const DEFAULT_SAMPLE_SIZE: usize = 32;
+
+/// How thoroughly a received snapshot is compared to its source
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerifyMode {
+    /// Only compare the received UUID and read-only flag
+    Metadata,
+    /// Also compare a checksum of every file, computed on both sides
+    Checksum,
+    /// Also compare the checksums of this many files spread over the snapshot
+    Sample(usize),
+}
+
+impl VerifyMode {
+    /// Parses "metadata", "checksum", "sample" or "sample:<files>"
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut tokens = s.trim().splitn(2, ':');
+        match (tokens.next().unwrap_or_default(), tokens.next()) {
+            ("metadata", None) => Ok(VerifyMode::Metadata),
+            ("checksum", None) => Ok(VerifyMode::Checksum),
+            ("sample", None) => Ok(VerifyMode::Sample(DEFAULT_SAMPLE_SIZE)),
+            ("sample", Some(n)) => match n.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(VerifyMode::Sample(n)),
+                _ => bail!("Could not parse sample size {:?}", n),
+            },
+            _ => bail!("Could not parse verification mode {:?}", s),
+        }
+    }
+}
+
+/// Properties of a subvolume as reported by `btrfs subvolume show`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SubvolumeInfo {
+    pub uuid: String,
+    pub received_uuid: Option<String>,
+    pub readonly: bool,
+}
+
+impl SubvolumeInfo {
+    /// UUID that a copy received from this subvolume carries as its received UUID
+    ///
+    /// A subvolume that was itself received passes its received UUID on, so that copies of
+    /// copies can still be matched to the original.
+    fn origin_uuid(&self) -> &str {
+        self.received_uuid.as_deref().unwrap_or(&self.uuid)
+    }
+}
+
+/// Outcome of verifying one snapshot on a destination
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VerifyReport {
+    pub snapshot: String,
+    /// Discrepancies found, empty if the copy matches its source
+    pub problems: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+#[derive(Default)]
+pub struct VerifyOpts {
+    pub name: String,
+    pub src: SnapshotRepositoryLocation,
+    pub src_sudo: bool,
+    pub dst: SnapshotRepositoryLocation,
+    pub dst_sudo: bool,
+    pub mode: Option<VerifyMode>,
+    pub verbose: i32,
+}
+
+/// Runs a command on the host of `location` and returns its standard output
+fn run_on(location: &SnapshotRepositoryLocation, sudo: bool, args: &[&str], verbose: i32) -> Result<String> {
+    let (program, args) = if location.is_remote() {
+        ssh::remote_command(location, sudo, args)?
+    } else if sudo {
+        ("sudo", args.iter().map(|arg| arg.to_string()).collect())
+    } else {
+        let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let program = args.remove(0);
+        return run(&program, args, verbose);
+    };
+
+    run(program, args, verbose)
+}
+
+fn run(program: &str, args: Vec<String>, verbose: i32) -> Result<String> {
+    if verbose > 0 {
+        debug!("{} {}", program, args.join(" "));
+    }
+
+    let output = Command::new(program)
+        .args(args)
+        .output()?;
+
+    if !output.status.success() {
+        bail!("{}", str::from_utf8(&output.stderr).unwrap_or_default().trim());
+    }
+
+    Ok(str::from_utf8(&output.stdout)?.to_string())
+}
+
+/// Parses the output of `btrfs subvolume show`
+fn parse_subvolume_show(s: &str) -> Result<SubvolumeInfo> {
+    let mut info = SubvolumeInfo::default();
+    for line in s.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        match key {
+            "UUID" => info.uuid = value.to_string(),
+            "Received UUID" if value != "-" => info.received_uuid = Some(value.to_string()),
+            "Flags" => info.readonly = value.split_whitespace().any(|flag| flag == "readonly"),
+            _ => (),
+        }
+    }
+
+    if info.uuid.is_empty() {
+        bail!("Could not find UUID in output of btrfs subvolume show");
+    }
+
+    Ok(info)
+}
+
+pub fn subvolume_info(location: &SnapshotRepositoryLocation, sudo: bool, snapshot: &str, verbose: i32) -> Result<SubvolumeInfo> {
+    let path = Path::new(&location.path).join(snapshot).to_str().unwrap().to_string();
+    let output = run_on(location, sudo, &["btrfs", "subvolume", "show", &path], verbose)
+        .map_err(|e| anyhow!("Could not show subvolume {}: {}", &path, e))?;
+    parse_subvolume_show(&output)
+}
+
+/// Lists the files of a snapshot, relative to it and in a stable order
+fn list_files(location: &SnapshotRepositoryLocation, sudo: bool, path: &str, verbose: i32) -> Result<Vec<String>> {
+    let script = r#"cd "$1" && find . -type f -print0 | LC_ALL=C sort -z"#;
+    let output = run_on(location, sudo, &["sh", "-c", script, "sh", path], verbose)
+        .map_err(|e| anyhow!("Could not list files of {}: {}", path, e))?;
+    Ok(output.split('\0').filter(|file| !file.is_empty()).map(|file| file.to_string()).collect())
+}
+
+/// Hashes all files of a snapshot into a single checksum
+///
+/// `btrfs send` streams carry UUIDs and transaction IDs specific to each filesystem, so the
+/// contents themselves are hashed rather than the stream.
+fn checksum(location: &SnapshotRepositoryLocation, sudo: bool, path: &str, verbose: i32) -> Result<String> {
+    let script = r#"cd "$1" && find . -type f -print0 | LC_ALL=C sort -z | xargs -0r sha256sum | sha256sum"#;
+    let output = run_on(location, sudo, &["sh", "-c", script, "sh", path], verbose)
+        .map_err(|e| anyhow!("Could not compute checksum of {}: {}", path, e))?;
+    match output.split_whitespace().next() {
+        Some(checksum) => Ok(checksum.to_string()),
+        None => bail!("Could not parse output of sha256sum"),
+    }
+}
+
+/// Hashes the given files of a snapshot, returning the checksum of each file that exists
+fn file_checksums(location: &SnapshotRepositoryLocation, sudo: bool, path: &str, files: &[String], verbose: i32) -> Result<BTreeMap<String, String>> {
+    // Missing files make sha256sum fail, but it still prints the checksums of the others
+    let script = r#"cd "$1" && shift && sha256sum -- "$@" 2>/dev/null; true"#;
+    let mut args = vec!["sh", "-c", script, "sh", path];
+    args.extend(files.iter().map(|file| file.as_str()));
+    let output = run_on(location, sudo, &args, verbose)
+        .map_err(|e| anyhow!("Could not compute checksums in {}: {}", path, e))?;
+
+    Ok(output.lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(checksum, file)| (file.to_string(), checksum.to_string()))
+        .collect())
+}
+
+/// Picks `n` files spread evenly over `files`
+fn sample(files: &[String], n: usize) -> Vec<String> {
+    if files.len() <= n {
+        return files.to_vec();
+    }
+
+    (0..n).map(|i| files[i * files.len() / n].clone()).collect()
+}
+
+/// Compares the metadata of a received snapshot with its source
+fn compare_info(src: &SubvolumeInfo, dst: &SubvolumeInfo) -> Vec<String> {
+    let mut problems = Vec::new();
+    match &dst.received_uuid {
+        Some(received_uuid) if received_uuid == src.origin_uuid() => (),
+        Some(received_uuid) => problems.push(format!("received UUID {} does not match source UUID {}", received_uuid, src.origin_uuid())),
+        None => problems.push("destination has no received UUID".to_string()),
+    }
+    if !dst.readonly {
+        problems.push("destination is not read-only".to_string());
+    }
+    problems
+}
+
+/// Verifies that the copy of `snapshot` on `opts.dst` matches the one on `opts.src`
+pub fn verify_snapshot(opts: &VerifyOpts, snapshot: &str) -> Result<VerifyReport> {
+    let mode = opts.mode.unwrap_or(VerifyMode::Metadata);
+    let mut report = VerifyReport {
+        snapshot: snapshot.to_string(),
+        problems: Vec::new(),
+    };
+
+    let src_info = subvolume_info(&opts.src, opts.src_sudo, snapshot, opts.verbose)?;
+    let dst_info = subvolume_info(&opts.dst, opts.dst_sudo, snapshot, opts.verbose)?;
+    report.problems.extend(compare_info(&src_info, &dst_info));
+
+    let src_path = Path::new(&opts.src.path).join(snapshot).to_str().unwrap().to_string();
+    let dst_path = Path::new(&opts.dst.path).join(snapshot).to_str().unwrap().to_string();
+    match mode {
+        VerifyMode::Metadata => (),
+        VerifyMode::Checksum => {
+            let src_checksum = checksum(&opts.src, opts.src_sudo, &src_path, opts.verbose)?;
+            let dst_checksum = checksum(&opts.dst, opts.dst_sudo, &dst_path, opts.verbose)?;
+            if src_checksum != dst_checksum {
+                report.problems.push(format!("checksum {} does not match source checksum {}", dst_checksum, src_checksum));
+            }
+        },
+        VerifyMode::Sample(n) => {
+            let files = sample(&list_files(&opts.src, opts.src_sudo, &src_path, opts.verbose)?, n);
+            let src_checksums = file_checksums(&opts.src, opts.src_sudo, &src_path, &files, opts.verbose)?;
+            let dst_checksums = file_checksums(&opts.dst, opts.dst_sudo, &dst_path, &files, opts.verbose)?;
+            for (file, src_checksum) in &src_checksums {
+                match dst_checksums.get(file) {
+                    Some(dst_checksum) if dst_checksum == src_checksum => (),
+                    Some(_) => report.problems.push(format!("{} differs from source", file)),
+                    None => report.problems.push(format!("{} is missing", file)),
+                }
+            }
+        },
+    }
+
+    if report.is_ok() {
+        info!("Verified snapshot {} at {}", snapshot, &opts.dst);
+    }
+
+    Ok(report)
+}
+
+/// Verifies every snapshot with the given name that exists on both sides
+pub fn verify(opts: &VerifyOpts) -> Result<Vec<VerifyReport>> {
+    let src_snapshots = list_snapshots(&opts.name, &opts.src, opts.src_sudo, opts.verbose)?;
+    let dst_snapshots = list_snapshots(&opts.name, &opts.dst, opts.dst_sudo, opts.verbose)?;
+
+    let mut reports = Vec::new();
+    for snapshot in &src_snapshots {
+        if !dst_snapshots.iter().any(|s| s.full_name == snapshot.full_name) {
+            continue;
+        }
+        let report = verify_snapshot(opts, &snapshot.full_name).unwrap_or_else(|e| VerifyReport {
+            snapshot: snapshot.full_name.clone(),
+            problems: vec![e.to_string()],
+        });
+        reports.push(report);
+    }
+
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testutil::TempDir;
+
+    use super::*;
+
+    static SUBVOLUME_SHOW: &str = "/backup/root@2000-01-02_03:04:05_daily
+	Name: 			root@2000-01-02_03:04:05_daily
+	UUID: 			1a8f2c3e-0c5d-4a4e-9c3b-6f0e2d7b9a10
+	Parent UUID: 		-
+	Received UUID: 		7d3c1b5a-2e4f-4d6a-8b9c-0a1e2f3d4c5b
+	Creation time: 		2000-01-02 03:04:06 +0000
+	Subvolume ID: 		262
+	Generation: 		1187
+	Gen at creation: 	1185
+	Parent ID: 		5
+	Top level ID: 		5
+	Flags: 			readonly
+	Snapshot(s):
+";
+
+    #[test]
+    fn test_parse_subvolume_show() {
+        let info = parse_subvolume_show(SUBVOLUME_SHOW).unwrap();
+        assert_eq!(info, SubvolumeInfo {
+            uuid: "1a8f2c3e-0c5d-4a4e-9c3b-6f0e2d7b9a10".to_string(),
+            received_uuid: Some("7d3c1b5a-2e4f-4d6a-8b9c-0a1e2f3d4c5b".to_string()),
+            readonly: true,
+        });
+
+        let info = parse_subvolume_show(&SUBVOLUME_SHOW
+            .replace("7d3c1b5a-2e4f-4d6a-8b9c-0a1e2f3d4c5b", "-")
+            .replace("readonly", "-")).unwrap();
+        assert_eq!(info.received_uuid, None);
+        assert!(!info.readonly);
+
+        assert!(parse_subvolume_show("ERROR: not a subvolume").is_err());
+    }
+
+    #[test]
+    fn test_compare_info() {
+        let src = SubvolumeInfo {
+            uuid: "a".to_string(),
+            received_uuid: None,
+            readonly: true,
+        };
+        let mut dst = SubvolumeInfo {
+            uuid: "b".to_string(),
+            received_uuid: Some("a".to_string()),
+            readonly: true,
+        };
+        assert!(compare_info(&src, &dst).is_empty());
+
+        dst.readonly = false;
+        dst.received_uuid = Some("c".to_string());
+        assert_eq!(compare_info(&src, &dst).len(), 2);
+
+        // A copy of a copy carries the UUID of the original
+        let src = SubvolumeInfo {
+            uuid: "b".to_string(),
+            received_uuid: Some("a".to_string()),
+            readonly: true,
+        };
+        dst.received_uuid = Some("a".to_string());
+        dst.readonly = true;
+        assert!(compare_info(&src, &dst).is_empty());
+    }
+
+    #[test]
+    fn test_sample() {
+        let files: Vec<String> = (0..10).map(|i| format!("./{}", i)).collect();
+        assert_eq!(sample(&files, 3), ["./0", "./3", "./6"]);
+        assert_eq!(sample(&files, 20), files);
+    }
+
+    #[test]
+    fn test_checksums() {
+        let dir = TempDir::new("verify");
+        let a = dir.join("a");
+        let b = dir.join("b");
+        for path in [&a, &b] {
+            std::fs::create_dir_all(path.join("sub dir")).unwrap();
+            std::fs::write(path.join("one"), "1").unwrap();
+            std::fs::write(path.join("sub dir/two"), "2").unwrap();
+        }
+        std::fs::write(b.join("sub dir/two"), "3").unwrap();
+
+        let location = SnapshotRepositoryLocation::default();
+        let a = a.to_str().unwrap();
+        let b = b.to_str().unwrap();
+        let files = list_files(&location, false, a, 0).unwrap();
+        assert_eq!(files, ["./one", "./sub dir/two"]);
+        assert_ne!(checksum(&location, false, a, 0).unwrap(), checksum(&location, false, b, 0).unwrap());
+
+        let files = vec!["./one".to_string(), "./sub dir/two".to_string(), "./missing".to_string()];
+        let a_checksums = file_checksums(&location, false, a, &files, 0).unwrap();
+        let b_checksums = file_checksums(&location, false, b, &files, 0).unwrap();
+        assert_eq!(a_checksums.len(), 2);
+        assert_eq!(a_checksums["./one"], b_checksums["./one"]);
+        assert_ne!(a_checksums["./sub dir/two"], b_checksums["./sub dir/two"]);
+    }
+
+    #[test]
+    fn test_parse_verify_mode() {
+        assert_eq!(VerifyMode::parse("metadata").unwrap(), VerifyMode::Metadata);
+        assert_eq!(VerifyMode::parse("checksum").unwrap(), VerifyMode::Checksum);
+        assert_eq!(VerifyMode::parse("sample").unwrap(), VerifyMode::Sample(DEFAULT_SAMPLE_SIZE));
+        assert_eq!(VerifyMode::parse("sample:100").unwrap(), VerifyMode::Sample(100));
+        assert!(VerifyMode::parse("sample:0").is_err());
+        assert!(VerifyMode::parse("stream").is_err());
+    }
+}
@@ -1,25 +1,51 @@
 use adw::prelude::*;
 use adw::subclass::prelude::*;
 use gtk::{glib, CompositeTemplate};
-use gtk::gio::Settings;
 use gtk::glib::subclass::InitializingObject;
-use once_cell::sync::OnceCell;
+use std::cell::{Cell, RefCell};
 
+use crate::backups_page::BackupsPage;
+use crate::cancel::CancelToken;
 use crate::header_bar::HeaderBar;
+use crate::history_page::HistoryPage;
+use crate::snapshot_list::SnapshotList;
+use crate::runner::Deferred;
 
 // Object holding the state
 #[derive(CompositeTemplate, Default)]
 #[template(resource = "/co/veand/fridge/Window.ui")]
 pub struct Window {
+    #[template_child]
+    pub toast_overlay: TemplateChild<adw::ToastOverlay>,
     #[template_child]
     pub header: TemplateChild<HeaderBar>,
     #[template_child]
+    pub stack: TemplateChild<adw::ViewStack>,
+    #[template_child]
+    pub snapshot_list: TemplateChild<SnapshotList>,
+    #[template_child]
     pub last_snapshot_label: TemplateChild<gtk::Label>,
     #[template_child]
+    pub health_label: TemplateChild<gtk::Label>,
+    #[template_child]
+    pub spinner: TemplateChild<gtk::Spinner>,
+    #[template_child]
+    pub cancel_button: TemplateChild<gtk::Button>,
+    #[template_child]
     pub snapshot_button: TemplateChild<gtk::Button>,
     #[template_child]
     pub backup_button: TemplateChild<gtk::Button>,
-    pub settings: OnceCell<Settings>,
+    #[template_child]
+    pub backups_page: TemplateChild<BackupsPage>,
+    #[template_child]
+    pub history_page: TemplateChild<HistoryPage>,
+    pub deferred_jobs: RefCell<Deferred>,
+    pub deferred_jobs_source: RefCell<Option<glib::SourceId>>,
+    /// IDs of the notifications we sent, whose actions we respond to
+    pub notification_ids: RefCell<Vec<u32>>,
+    pub backup_overdue_notified: Cell<bool>,
+    /// Cancels the operation running in the background, if any
+    pub cancel_token: RefCell<Option<CancelToken>>,
 }
 
 // The central trait for subclassing a GObject
@@ -57,9 +83,15 @@ impl ObjectImpl for Window {
             },
         }
 
-        obj.setup_settings();
         obj.setup_callbacks();
-        obj.refresh_last_snapshot_label().unwrap();
+        obj.setup_actions();
+        obj.setup_notifications();
+        obj.refresh_last_snapshot_label();
+        if let Err(e) = obj.refresh_health_label() {
+            log::warn!("Could not show remote health: {}", e);
+        }
+        obj.refresh_snapshot_list();
+        obj.migrate_settings();
     }
 }
 
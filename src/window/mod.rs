@@ -1,21 +1,37 @@
 mod imp;
 
+use std::thread;
+
 use adw::Application;
 use adw::subclass::prelude::ObjectSubclassIsExt;
-use anyhow::Result;
-use chrono::{Duration, Utc};
+use anyhow::{Result, anyhow, bail};
+use chrono::Utc;
 use glib::Object;
-use gtk::prelude::SettingsExt;
-use gtk::traits::{WidgetExt, ButtonExt};
+use gtk::prelude::{ActionMapExt, Cast, DialogExt, SelectionModelExt};
+use gtk::traits::{GtkWindowExt, WidgetExt, ButtonExt};
 use gtk::{gio, glib};
-use gtk::gio::Settings;
-use gtk::glib::{clone, g_log, LogLevel};
+use gtk::glib::{clone, g_log, Continue, LogLevel};
 use log::{info};
 use zbus::blocking::Connection;
 use zbus_polkit::policykit1::*;
 
-use crate::APP_ID;
-use crate::fridge::{list_snapshots, parse_sync_location, find_parent_snapshot_to, transfer, TransferOpts};
+use crate::cancel::{CancelToken, Cancelled};
+use crate::conditions::Verdict;
+use crate::config::{self, RemoteConfig};
+use crate::health::{HealthStore, RemoteHealth};
+use crate::history::{self, Operation, Record};
+use crate::migrate;
+use crate::nested;
+use crate::notify::{self, Event, Notifier};
+use crate::pins;
+use crate::preflight;
+use crate::runner::{self, Job, SendResults};
+use crate::setup_assistant::SetupAssistant;
+use crate::fridge::{list_snapshots, parse_snapshot_name, SnapshotRepositoryLocation};
+use crate::snapshot_object::SnapshotData;
+
+/// Seconds between checks of whether the last successful backup is too old
+const BACKUP_OVERDUE_CHECK_INTERVAL: u32 = 60 * 60;
 
 glib::wrapper! {
     pub struct Window(ObjectSubclass<imp::Window>)
@@ -31,59 +47,532 @@ impl Window {
         Object::builder::<Self>().property("application", app).build()
     }
 
-    fn setup_settings(&self) {
-        let settings = Settings::new(APP_ID);
-        self.imp()
-            .settings
-            .set(settings)
-            .expect("Could not set `Settings`");
-    }
-
-
-    fn settings(&self) -> &Settings {
-        self.imp().settings.get().expect("Could not get settings.")
-    }
-
     fn setup_callbacks(&self) {
+        let imp = self.imp();
+        imp.header.set_stack(&imp.stack);
+        imp.stack.connect_visible_child_name_notify(clone!(@weak self as window => move |stack| {
+            match stack.visible_child_name().as_deref() {
+                Some("backups") => window.refresh_backups_page(),
+                Some("history") => window.refresh_history_page(),
+                _ => (),
+            }
+        }));
+
         self.imp().snapshot_button.connect_clicked(
             clone!(@weak self as window => move |_| {
-                if let Err(e) = window.snapshot() {
-                    g_log!(LogLevel::Error, "Could not take snapshot: {e}");
-                }
+                window.run_job(Job::Snapshot);
             }),
         );
         self.imp().backup_button.connect_clicked(
             clone!(@weak self as window => move |_| {
-                if let Err(e) = window.backup() {
-                    g_log!(LogLevel::Error, "Could not do backup: {e}");
+                window.run_job(Job::Backup);
+            }),
+        );
+        self.imp().cancel_button.connect_clicked(
+            clone!(@weak self as window => move |button| {
+                if let Some(token) = window.imp().cancel_token.borrow().as_ref() {
+                    button.set_sensitive(false);
+                    window.imp().last_snapshot_label.set_label("Cancelling…");
+                    token.cancel();
                 }
             }),
         );
     }
 
-    fn refresh_last_snapshot_label(&self) -> Result<()> {
-        let dst = crate::fridge::SnapshotRepositoryLocation{
-            user: None,
-            host: None,
-            port: None,
-            path: "/.snapshots".to_string(),
-        };
-        let snapshots = list_snapshots("root", &dst, true, 0)?;
-        if snapshots.len() == 0 {
-            self.imp().last_snapshot_label.set_label("No snapshots found");
-            return Ok(())
+    /// Runs a job now if its run conditions are met and no other operation is running,
+    /// otherwise defers it
+    fn run_job(&self, job: Job) {
+        if self.check_conditions(job) != Verdict::Run || !self.start_job(job) {
+            self.defer_job(job);
+        }
+    }
+
+    /// Starts a job in the background, unless another operation is running
+    fn start_job(&self, job: Job) -> bool {
+        match job {
+            Job::Snapshot => self.snapshot(),
+            Job::Backup => self.backup(),
+        }
+    }
+
+    fn job_done(&self, job: Job, result: Result<()>) {
+        if let Some(event) = runner::failure_event(job, &result) {
+            self.notify(event);
+        }
+        match result {
+            Ok(()) => (),
+            Err(e) if e.is::<Cancelled>() => g_log!(LogLevel::Info, "Cancelled the attempt to {}", job.description()),
+            Err(e) => {
+                g_log!(LogLevel::Warning, "Could not {}: {e}", job.description());
+                self.toast(&format!("Could not {}: {:#}", job.description(), e));
+            },
+        }
+        self.refresh_after_change();
+    }
+
+    /// Shows `message` at the bottom of the window for a few seconds
+    fn toast(&self, message: &str) {
+        self.imp().toast_overlay.add_toast(&adw::Toast::new(&glib::markup_escape_text(message)));
+    }
+
+    /// Runs `work` on a background thread while showing `status`, then passes its result to
+    /// `done` on the main loop
+    ///
+    /// Only one operation runs at a time, so this does nothing and returns `false` while another
+    /// one is running. The actions that would conflict with it are disabled until it is done, and
+    /// the Cancel button cancels the token `work` is given.
+    fn run_task<T: Send + 'static>(
+        &self,
+        status: &str,
+        work: impl FnOnce(&CancelToken) -> Result<T> + Send + 'static,
+        done: impl FnOnce(&Self, Result<T>) + 'static,
+    ) -> bool {
+        let imp = self.imp();
+        if imp.cancel_token.borrow().is_some() {
+            return false;
+        }
+
+        let token = CancelToken::new();
+        imp.cancel_token.replace(Some(token.clone()));
+        self.set_busy(Some(status));
+
+        let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        thread::spawn(move || {
+            let result = token.attribute(work(&token));
+            let _ = sender.send(result);
+        });
+        let mut done = Some(done);
+        receiver.attach(None, clone!(@weak self as window => @default-return Continue(false), move |result| {
+            window.imp().cancel_token.replace(None);
+            window.set_busy(None);
+            if let Some(done) = done.take() {
+                done(&window, result);
+            }
+            Continue(false)
+        }));
+
+        true
+    }
+
+    /// Shows `status` and the Cancel button while an operation is running, or the last snapshot
+    /// once none is
+    fn set_busy(&self, status: Option<&str>) {
+        let imp = self.imp();
+        let busy = status.is_some();
+        imp.snapshot_button.set_sensitive(!busy);
+        imp.backup_button.set_sensitive(!busy);
+        imp.cancel_button.set_sensitive(busy);
+        imp.cancel_button.set_visible(busy);
+        imp.spinner.set_visible(busy);
+        imp.spinner.set_spinning(busy);
+        match status {
+            Some(status) => imp.last_snapshot_label.set_label(status),
+            None => self.refresh_last_snapshot_label(),
         }
+        self.update_actions();
+    }
 
-        let last_snapshot = snapshots.last();
-        if let Some(last_snapshot) = last_snapshot {
-            let datetime_str = last_snapshot.datetime.format("%Y-%m-%d %H:%M:%S").to_string();
-            let label = format!("Last snapshot at {datetime_str}");
-            self.imp().last_snapshot_label.set_label(&label);
+    /// Shows the effects of an operation that may have changed the snapshots
+    fn refresh_after_change(&self) {
+        self.refresh_history_page();
+        self.refresh_snapshot_list();
+        if self.imp().stack.visible_child_name().as_deref() == Some("backups") {
+            self.refresh_backups_page();
+        }
+    }
+
+    fn refresh_history_page(&self) {
+        if let Err(e) = self.imp().history_page.refresh() {
+            g_log!(LogLevel::Warning, "Could not load history: {e}");
+        }
+    }
+
+    /// Lists the local snapshots along with the remotes holding them
+    fn refresh_snapshot_list(&self) {
+        let result = Self::remote_sudo_locations().and_then(|remotes| {
+            let machine = config::load()?.local.machine_id()?;
+            let remotes: Vec<(SnapshotRepositoryLocation, bool)> = remotes.iter().map(|(remote, sudo)| (remote.for_machine(&machine), *sudo)).collect();
+            self.imp().snapshot_list.refresh(&remotes);
+            Ok(())
+        });
+        if let Err(e) = result {
+            g_log!(LogLevel::Warning, "Could not list snapshots: {e}");
+        }
+    }
+
+    fn refresh_backups_page(&self) {
+        let result = Self::remote_sudo_locations().and_then(|remotes| self.imp().backups_page.refresh(&remotes));
+        if let Err(e) = result {
+            g_log!(LogLevel::Warning, "Could not compare snapshots with remotes: {e}");
+        }
+    }
+
+    /// Adds the actions on the snapshots selected in the snapshot list
+    fn setup_actions(&self) {
+        let delete = gio::SimpleAction::new("delete-snapshots", None);
+        delete.connect_activate(clone!(@weak self as window => move |_, _| {
+            window.confirm_delete_snapshots();
+        }));
+        let pin = gio::SimpleAction::new("pin-snapshots", None);
+        pin.connect_activate(clone!(@weak self as window => move |_, _| {
+            window.toggle_pinned_snapshots();
+        }));
+        let send = gio::SimpleAction::new("send-snapshots", None);
+        send.connect_activate(clone!(@weak self as window => move |_, _| {
+            window.confirm_send_snapshots();
+        }));
+
+        for action in [&delete, &pin, &send] {
+            action.set_enabled(false);
+            self.add_action(action);
+        }
+
+        let test_remote = gio::SimpleAction::new("test-remote", Some(glib::VariantTy::STRING));
+        test_remote.connect_activate(clone!(@weak self as window => move |_, parameter| {
+            if let Some(location) = parameter.and_then(|parameter| parameter.get::<String>()) {
+                window.test_remote(&location);
+            }
+        }));
+        let sync_remote = gio::SimpleAction::new("sync-remote", Some(glib::VariantTy::STRING));
+        sync_remote.connect_activate(clone!(@weak self as window => move |_, parameter| {
+            if let Some(location) = parameter.and_then(|parameter| parameter.get::<String>()) {
+                window.sync_remote(&location);
+            }
+        }));
+        self.add_action(&test_remote);
+        self.add_action(&sync_remote);
+        self.imp().snapshot_list.selection().connect_selection_changed(clone!(@weak self as window => move |_, _, _| {
+            window.update_actions();
+        }));
+    }
+
+    /// Enables the actions that make sense for the selected snapshots, leaving out the ones that
+    /// would conflict with a running operation
+    fn update_actions(&self) {
+        let busy = self.imp().cancel_token.borrow().is_some();
+        let selected = !self.imp().snapshot_list.selected().is_empty();
+        let actions = [
+            ("delete-snapshots", selected && !busy),
+            ("pin-snapshots", selected),
+            ("send-snapshots", selected && !busy),
+            ("test-remote", !busy),
+            ("sync-remote", !busy),
+        ];
+        for (name, enabled) in actions {
+            if let Some(action) = self.lookup_action(name).and_then(|action| action.downcast::<gio::SimpleAction>().ok()) {
+                action.set_enabled(enabled);
+            }
+        }
+    }
+
+    /// Asks whether to go ahead with something and calls `f` if the user agrees
+    fn confirm(&self, text: &str, secondary_text: &str, confirm_label: &str, f: impl Fn(&Self) + 'static) {
+        let dialog = gtk::MessageDialog::builder()
+            .transient_for(self)
+            .modal(true)
+            .message_type(gtk::MessageType::Question)
+            .text(text)
+            .secondary_text(secondary_text)
+            .build();
+        dialog.add_button("Cancel", gtk::ResponseType::Cancel);
+        dialog.add_button(confirm_label, gtk::ResponseType::Accept);
+        dialog.connect_response(clone!(@weak self as window => move |dialog, response| {
+            dialog.close();
+            if response == gtk::ResponseType::Accept {
+                f(&window);
+            }
+        }));
+        dialog.present();
+    }
+
+    fn confirm_delete_snapshots(&self) {
+        let snapshots = self.imp().snapshot_list.selected();
+        let names: Vec<&str> = snapshots.iter().map(|snapshot| snapshot.full_name.as_str()).collect();
+        self.confirm(
+            &format!("Delete {} snapshot(s)?", snapshots.len()),
+            &format!("{}\n\nThe snapshots are deleted from this computer, even if pinned. Copies on remotes are kept.", names.join("\n")),
+            "Delete",
+            move |window| {
+                let snapshots = snapshots.clone();
+                window.run_task(
+                    "Deleting snapshots…",
+                    move |cancel| Self::delete_snapshots(&snapshots, cancel),
+                    |window, result| {
+                        match result {
+                            Ok(()) => (),
+                            Err(e) if e.is::<Cancelled>() => g_log!(LogLevel::Info, "Cancelled deleting snapshots"),
+                            Err(e) => {
+                                g_log!(LogLevel::Warning, "Could not delete snapshots: {e}");
+                                window.toast(&format!("Could not delete snapshots: {:#}", e));
+                            },
+                        }
+                        window.refresh_after_change();
+                    },
+                );
+            },
+        );
+    }
+
+    fn delete_snapshots(snapshots: &[SnapshotData], cancel: &CancelToken) -> Result<()> {
+        for data in snapshots {
+            cancel.check()?;
+            let snapshot = parse_snapshot_name(&data.full_name, &data.path)?;
+            let location = SnapshotRepositoryLocation {
+                path: data.path.clone(),
+                ..Default::default()
+            };
+            nested::delete_members(&snapshot, &location, true, Operation::Delete, 0)?;
+            let started = Utc::now();
+            let result = snapshot.delete(true, 0);
+            history::record(Record::new(Operation::Delete, started, &data.subvolume, Some(&data.full_name), &data.path, None, &result));
+            result?;
+            if data.pinned {
+                pins::set_pinned(&[data.full_name.clone()], false)?;
+            }
+            info!("Deleted snapshot {}", &data.full_name);
         }
 
         Ok(())
     }
 
+    /// Pins the selected snapshots, or unpins them if they are all pinned already
+    fn toggle_pinned_snapshots(&self) {
+        let snapshot_list = &self.imp().snapshot_list;
+        let snapshots = snapshot_list.selected();
+        let pinned = !snapshots.iter().all(|snapshot| snapshot.pinned);
+        let names: Vec<String> = snapshots.into_iter().map(|snapshot| snapshot.full_name).collect();
+        match pins::set_pinned(&names, pinned) {
+            Ok(()) => snapshot_list.set_pinned(&names, pinned),
+            Err(e) => {
+                g_log!(LogLevel::Warning, "Could not pin snapshots: {e}");
+                self.toast(&format!("Could not pin snapshots: {:#}", e));
+            },
+        }
+    }
+
+    fn confirm_send_snapshots(&self) {
+        let names: Vec<String> = self.imp().snapshot_list.selected().into_iter().map(|snapshot| snapshot.full_name).collect();
+        self.confirm(
+            &format!("Send {} snapshot(s) now?", names.len()),
+            "Snapshots are sent to every remote that doesn't have them yet.",
+            "Send",
+            move |window| {
+                match Self::remotes() {
+                    Ok(remotes) => {
+                        window.send_to_remotes("Sending snapshots…", remotes, Some(names.clone()));
+                    },
+                    Err(e) => window.job_done(Job::Backup, Err(e)),
+                }
+            },
+        );
+    }
+
+    /// Checks that the remote at `location` can receive snapshots and shows the outcome
+    fn test_remote(&self, location: &str) {
+        let location = location.to_string();
+        let remote = location.clone();
+        self.run_task(
+            "Testing connection…",
+            move |_| {
+                let remote = Self::find_remote(&remote)?;
+                Ok(preflight::preflight(&remote.to_location()?, remote.sudo, &remote.preflight_opts())?)
+            },
+            move |window, result| window.imp().backups_page.set_connection_status(&location, &result),
+        );
+    }
+
+    fn sync_remote(&self, location: &str) {
+        match Self::find_remote(location) {
+            Ok(remote) => {
+                self.send_to_remotes(&format!("Synchronizing {location}…"), vec![remote], None);
+            },
+            Err(e) => self.job_done(Job::Backup, Err(e)),
+        }
+    }
+
+    /// Listens for actions invoked on our notifications and checks for overdue backups
+    fn setup_notifications(&self) {
+        let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        thread::spawn(move || {
+            let result = Notifier::new().and_then(|notifier| {
+                notifier.listen_for_actions(|id, action| sender.send((id, action.to_string())).is_ok())
+            });
+            if let Err(e) = result {
+                log::warn!("Could not listen for notification actions: {}", e);
+            }
+        });
+        receiver.attach(None, clone!(@weak self as window => @default-return Continue(false), move |(id, action)| {
+            window.handle_notification_action(id, &action);
+            Continue(true)
+        }));
+
+        self.check_backup_overdue();
+        glib::timeout_add_seconds_local(BACKUP_OVERDUE_CHECK_INTERVAL,
+            clone!(@weak self as window => @default-return Continue(false), move || {
+                window.check_backup_overdue();
+                Continue(true)
+            }),
+        );
+    }
+
+    /// Sends a desktop notification about `event` if the configuration asks for one
+    fn notify(&self, event: Event) {
+        match runner::send_notification(&event) {
+            Ok(Some(id)) => self.imp().notification_ids.borrow_mut().push(id),
+            Ok(None) => (),
+            Err(e) => g_log!(LogLevel::Warning, "Could not send notification: {e}"),
+        }
+    }
+
+    fn handle_notification_action(&self, id: u32, action: &str) {
+        if !self.imp().notification_ids.borrow().contains(&id) {
+            return;
+        }
+
+        match action {
+            notify::ACTION_SNAPSHOT => self.run_job(Job::Snapshot),
+            notify::ACTION_BACKUP => self.run_job(Job::Backup),
+            notify::ACTION_OPEN => self.present(),
+            _ => (),
+        }
+    }
+
+    /// Notifies once when the last successful backup becomes too old
+    fn check_backup_overdue(&self) {
+        match config::load().and_then(|cfg| runner::backup_overdue(&cfg)) {
+            Ok((true, last_success)) => {
+                if !self.imp().backup_overdue_notified.replace(true) {
+                    self.notify(Event::BackupOverdue(last_success));
+                }
+            },
+            Ok((false, _)) => self.imp().backup_overdue_notified.set(false),
+            Err(e) => g_log!(LogLevel::Warning, "Could not check for overdue backup: {e}"),
+        }
+    }
+
+    /// Whether the run conditions of `job` let it start now, showing why it waits otherwise
+    fn check_conditions(&self, job: Job) -> Verdict {
+        let verdict = match config::load() {
+            Ok(cfg) => runner::check_conditions(&cfg, job),
+            Err(e) => {
+                g_log!(LogLevel::Warning, "Could not check run conditions: {e:#}");
+                Verdict::Run
+            },
+        };
+        if let Verdict::Defer(reason) = &verdict {
+            self.imp().last_snapshot_label.set_label(&format!("Waiting to {}: {reason}", job.description()));
+        }
+        verdict
+    }
+
+    fn defer_job(&self, job: Job) {
+        let imp = self.imp();
+        imp.deferred_jobs.borrow_mut().push(job);
+
+        if imp.deferred_jobs_source.borrow().is_none() {
+            let source = glib::timeout_add_seconds_local(runner::DEFERRED_JOB_RETRY_INTERVAL.as_secs() as u32,
+                clone!(@weak self as window => @default-return Continue(false), move || {
+                    window.retry_deferred_jobs()
+                }),
+            );
+            imp.deferred_jobs_source.replace(Some(source));
+        }
+    }
+
+    fn retry_deferred_jobs(&self) -> Continue {
+        let mut deferred_jobs = self.imp().deferred_jobs.take();
+        deferred_jobs.retry(|job| self.check_conditions(job), |job| self.start_job(job));
+
+        let done = deferred_jobs.is_empty();
+        self.imp().deferred_jobs.replace(deferred_jobs);
+        if done {
+            self.imp().deferred_jobs_source.replace(None);
+            self.refresh_last_snapshot_label();
+        }
+
+        Continue(!done)
+    }
+
+    /// Moves the settings older versions kept in GSettings into the configuration file in the
+    /// background, then shows the remote they backed up to
+    fn migrate_settings(&self) {
+        let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        thread::spawn(move || {
+            let _ = sender.send(migrate::migrate());
+        });
+        receiver.attach(None, clone!(@weak self as window => @default-return Continue(false), move |result: Result<bool>| {
+            match result {
+                Ok(true) => {
+                    if let Err(e) = window.refresh_health_label() {
+                        g_log!(LogLevel::Warning, "Could not show remote health: {e}");
+                    }
+                    window.refresh_snapshot_list();
+                },
+                Ok(false) => (),
+                Err(e) => g_log!(LogLevel::Warning, "Could not move settings from GSettings: {e}"),
+            }
+            // Only now is it known whether the migration wrote a configuration
+            window.offer_setup();
+            Continue(false)
+        }));
+    }
+
+    /// Offers to configure the subvolumes found on this machine, as long as nothing is
+    /// configured yet
+    fn offer_setup(&self) {
+        match config::is_configured() {
+            Ok(false) => (),
+            Ok(true) => return,
+            Err(e) => {
+                g_log!(LogLevel::Warning, "Could not read configuration: {e}");
+                return;
+            },
+        }
+
+        let assistant = SetupAssistant::new(self);
+        assistant.connect_close_request(clone!(@weak self as window => @default-return gtk::Inhibit(false), move |_| {
+            window.refresh_snapshot_list();
+            window.refresh_last_snapshot_label();
+            gtk::Inhibit(false)
+        }));
+        assistant.present();
+    }
+
+    /// Shows when the last snapshot was taken, listing the snapshots in the background
+    fn refresh_last_snapshot_label(&self) {
+        let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        thread::spawn(move || {
+            let _ = sender.send(Self::last_snapshot_summary());
+        });
+        receiver.attach(None, clone!(@weak self as window => @default-return Continue(false), move |summary: Result<String>| {
+            let summary = summary.unwrap_or_else(|e| {
+                g_log!(LogLevel::Warning, "Could not list snapshots: {e}");
+                "Could not list snapshots".to_string()
+            });
+            // While an operation runs, the label shows its progress instead
+            if window.imp().cancel_token.borrow().is_none() {
+                window.imp().last_snapshot_label.set_label(&summary);
+            }
+            Continue(false)
+        }));
+    }
+
+    fn last_snapshot_summary() -> Result<String> {
+        let mut last_snapshot = None;
+        let cfg = config::load()?;
+        for snapshot_cfg in &cfg.snapshots {
+            let snapshots = list_snapshots(&snapshot_cfg.name, &snapshot_cfg.snapshot_location(&cfg.local), true, 0)?;
+            last_snapshot = last_snapshot.max(snapshots.iter().map(|snapshot| snapshot.datetime).max());
+        }
+        match last_snapshot {
+            Some(last_snapshot) => {
+                let datetime_str = last_snapshot.format("%Y-%m-%d %H:%M:%S").to_string();
+                Ok(format!("Last snapshot at {datetime_str}"))
+            },
+            None => Ok("No snapshots found".to_string()),
+        }
+    }
+
     fn authenticate(&self) -> Result<bool> {
         let connection = Connection::system()?;
         let proxy = AuthorityProxyBlocking::new(&connection)?;
@@ -105,103 +594,101 @@ impl Window {
         Ok(authorized)
     }
 
-    fn snapshot(&self) -> Result<()> {
-        let settings = self.settings();
-        let max_hourly_snapshots = settings.uint("max-hourly-snapshots") as usize;
-        Self::do_snapshot("root", "/", "/.snapshots", "hourly", max_hourly_snapshots, Duration::hours(1))?;
-        Self::do_snapshot("home", "/home", "/home/.snapshots", "hourly", max_hourly_snapshots, Duration::hours(1))?;
-        self.refresh_last_snapshot_label()?;
-
-        Ok(())
+    fn snapshot(&self) -> bool {
+        self.run_task(
+            "Taking snapshots…",
+            move |cancel| runner::snapshot(&config::load()?, cancel),
+            |window, result| window.job_done(Job::Snapshot, result),
+        )
     }
 
-    fn backup(&self) -> Result<()> {
-        let settings = self.settings();
-        let remote_user_value = settings.string("remote-user");
-        let remote_host_value = settings.string("remote-host");
-        let remote_directory_value = settings.string("remote-directory");
-        let remote_user = remote_user_value.as_str();
-        let remote_host = remote_host_value.as_str();
-        let remote_directory = remote_directory_value.as_str();
-        let dst = format!("{remote_user}@{remote_host}:22:{remote_directory}");
-        Self::do_backup("root", "/.snapshots", &dst)?;
-        Self::do_backup("home", "/home/.snapshots", &dst)?;
+    fn backup(&self) -> bool {
+        let remotes = match Self::remotes() {
+            Ok(remotes) => remotes,
+            Err(e) => {
+                self.job_done(Job::Backup, Err(e));
+                return true;
+            },
+        };
+        if remotes.is_empty() {
+            self.job_done(Job::Backup, Err(anyhow!("Could not back up: no remotes are enabled")));
+            return true;
+        }
+        self.send_to_remotes("Backing up…", remotes, None)
+    }
 
-        Ok(())
+    /// Sends snapshots to `remotes` in the background, carrying on past failing ones
+    ///
+    /// Sends the snapshots missing on each remote, or only the ones named in `snapshots`.
+    fn send_to_remotes(&self, status: &str, remotes: Vec<RemoteConfig>, snapshots: Option<Vec<String>>) -> bool {
+        self.run_task(
+            status,
+            move |cancel| runner::send_to_remotes(&config::load()?, &remotes, snapshots.as_deref(), cancel),
+            |window, results| window.sends_done(results),
+        )
     }
 
-    fn do_snapshot(name: &str, src: &str, dst: &str, suffix: &str, max_snapshot_count: usize, duration: Duration) -> Result<()> {
-        let name = name.to_string();
-        let src = src.to_string();
-        let dst = crate::fridge::SnapshotRepositoryLocation{
-            user: None,
-            host: None,
-            port: None,
-            path: dst.to_string(),
-        };
-        let mut snapshots = list_snapshots(&name, &dst, true, 0)?;
-        let last_snapshot = snapshots.last();
-        if let Some(last_snapshot) = last_snapshot {
-            let now = Utc::now();
-            let elapsed = now.signed_duration_since(last_snapshot.datetime);
-            if elapsed < duration {
-                return Ok(())
+    /// Reports the outcome of sending snapshots, failing with the first remote that failed
+    fn sends_done(&self, results: Result<SendResults>) {
+        let result = results.and_then(|results| {
+            let (events, result) = runner::send_outcome(results);
+            for event in events {
+                self.imp().backup_overdue_notified.set(false);
+                self.notify(event);
             }
+            result
+        });
+        if let Err(e) = self.refresh_health_label() {
+            g_log!(LogLevel::Warning, "Could not show remote health: {e}");
         }
-        let opts = crate::fridge::SnapshotOpts{
-            src,
-            name,
-            suffix: Some(suffix.to_string()),
-            sudo: true,
-            dry_run: false,
-            verbose: 0,
-        };
-        crate::fridge::snapshot(&opts)?;
+        self.job_done(Job::Backup, result);
+    }
+
+    /// Remotes to back up to, the ones enabled in the configuration
+    fn remotes() -> Result<Vec<RemoteConfig>> {
+        Ok(runner::enabled_remotes(&config::load()?))
+    }
 
-        while snapshots.len() > max_snapshot_count {
-            if let Some(snapshot) = snapshots.first() {
-                snapshot.delete(true, 0)?;
-                info!("Deleted snapshot {}", &snapshot.full_name);
+    /// Remotes to back up to, not yet namespaced by machine
+    fn remote_locations(&self) -> Result<Vec<SnapshotRepositoryLocation>> {
+        Self::remotes()?.iter().map(RemoteConfig::to_location).collect()
+    }
+
+    /// Remotes to back up to like [`Self::remote_locations`], with whether to use sudo on each
+    fn remote_sudo_locations() -> Result<Vec<(SnapshotRepositoryLocation, bool)>> {
+        Self::remotes()?.iter().map(|remote| Ok((remote.to_location()?, remote.sudo))).collect()
+    }
+
+    /// Configured remote at `location`, e.g. "li@192.168.0.2:22:/backup"
+    fn find_remote(location: &str) -> Result<RemoteConfig> {
+        for remote in config::load()?.remotes {
+            if remote.to_location()?.to_string() == location {
+                return Ok(remote);
             }
-            snapshots.remove(0);
         }
-
-        Ok(())
+        bail!("Could not find remote {} in configuration", location)
     }
 
-    fn do_backup(name: &str, src: &str, dst: &str) -> Result<()> {
-        let src = parse_sync_location(src)?;
-        let dst = parse_sync_location(dst)?;
-        let src_list_string = list_snapshots(name, &src, true, 0)?;
-        let dst_list_string = list_snapshots(name, &dst, true, 0)?;
-        let src_list: Vec<&str> = src_list_string.iter().map(|s| s.full_name.as_str()).collect();
-        let dst_list: Vec<&str> = dst_list_string.iter().map(|s| s.full_name.as_str()).collect();
-        g_log!(LogLevel::Debug,"Source snapshot count: {}", src_list.len());
-        g_log!(LogLevel::Debug,"Destination snapshot count: {}", dst_list.len());
-        let missing_snapshots_in_destination: Vec<&str> = src_list.iter()
-            .filter(|snapshot_name| !dst_list.contains(snapshot_name))
-            .map(|snapshot_name| *snapshot_name)
+    /// Shows the health of the remote, or how many remotes are healthy with the health of
+    /// each in the tooltip
+    pub fn refresh_health_label(&self) -> Result<()> {
+        let store = HealthStore::load()?;
+        let healths: Vec<(String, RemoteHealth)> = self.remote_locations()?.iter()
+            .map(|location| (location.to_string(), store.get(&location.to_string())))
             .collect();
-        if missing_snapshots_in_destination.len() == 0 {
-            g_log!(LogLevel::Info, "Remote snapshots are up-to-date");
-            return Ok(());
-        }
-        let mut parent: Option<&str> = find_parent_snapshot_to(missing_snapshots_in_destination[0], &src_list);
-
-        for snapshot in missing_snapshots_in_destination {
-            let mut transfer_opts = TransferOpts::default();
-            transfer_opts.parent_snapshot = parent.map_or(None, |s| Some(s.to_string()));
-            transfer_opts.snapshot = snapshot.to_string();
-            transfer_opts.src = src.clone();
-            transfer_opts.src_sudo = true;
-            transfer_opts.dst = dst.clone();
-            transfer_opts.dst_sudo = true;
-            transfer_opts.dry_run = false;
-            transfer_opts.verbose = 0;
-            g_log!(LogLevel::Info, "Sending snapshot {}", snapshot);
-            transfer(&transfer_opts)?;
-            parent = Some(snapshot);
-        }
+        let label = match healths.as_slice() {
+            [] => "Backup: no remotes enabled".to_string(),
+            [(_, health)] => format!("Backup: {}", health.summary()),
+            _ => {
+                let healthy = healths.iter().filter(|(_, health)| health.is_healthy()).count();
+                format!("Backup: {} of {} remotes OK", healthy, healths.len())
+            },
+        };
+        let tooltip: Vec<String> = healths.iter().map(|(location, health)| format!("{}: {}", location, health.summary())).collect();
+
+        let health_label = &self.imp().health_label;
+        health_label.set_label(&label);
+        health_label.set_tooltip_text(Some(&tooltip.join("\n")));
 
         Ok(())
     }